@@ -1,3 +1,10 @@
+use std::env;
+
 fn main() {
-    println!("cargo:rustc-link-lib=framework=Hypervisor");
+    // The Hypervisor framework only exists on OS X; everywhere else the crate
+    // builds against a backend that doesn't need it.
+    let target_os = env::var("CARGO_CFG_TARGET_OS").unwrap_or_default();
+    if target_os == "macos" && env::var_os("CARGO_FEATURE_HVF").is_some() {
+        println!("cargo:rustc-link-lib=framework=Hypervisor");
+    }
 }
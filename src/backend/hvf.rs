@@ -0,0 +1,135 @@
+/*
+Copyright (c) 2016 Saurav Sachidanand
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in
+all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+THE SOFTWARE.
+*/
+
+//! Hypervisor framework backend
+
+use libc::*;
+
+use ffi::*;
+use super::Backend;
+use super::super::{x86Reg, VMXCap};
+
+/// Backend that calls into the Hypervisor framework on OS X
+pub enum HypervisorFramework {}
+
+impl Backend for HypervisorFramework {
+    fn vm_create(flags: hv_vm_options_t) -> hv_return_t {
+        unsafe { hv_vm_create(flags) }
+    }
+
+    fn vm_destroy() -> hv_return_t {
+        unsafe { hv_vm_destroy() }
+    }
+
+    unsafe fn vm_map(uva: hv_uvaddr_t, gpa: hv_gpaddr_t, size: usize,
+        flags: hv_memory_flags_t) -> hv_return_t {
+        hv_vm_map(uva, gpa, size as size_t, flags)
+    }
+
+    fn vm_unmap(gpa: hv_gpaddr_t, size: usize) -> hv_return_t {
+        unsafe { hv_vm_unmap(gpa, size as size_t) }
+    }
+
+    fn vm_protect(gpa: hv_gpaddr_t, size: usize, flags: hv_memory_flags_t) -> hv_return_t {
+        unsafe { hv_vm_protect(gpa, size as size_t, flags) }
+    }
+
+    fn vm_sync_tsc(tsc: u64) -> hv_return_t {
+        unsafe { hv_vm_sync_tsc(tsc) }
+    }
+
+    fn vcpu_create(vcpu: &mut hv_vcpuid_t, flags: hv_vm_options_t) -> hv_return_t {
+        unsafe { hv_vcpu_create(vcpu, flags) }
+    }
+
+    fn vcpu_destroy(vcpu: hv_vcpuid_t) -> hv_return_t {
+        unsafe { hv_vcpu_destroy(vcpu) }
+    }
+
+    fn vcpu_run(vcpu: hv_vcpuid_t) -> hv_return_t {
+        unsafe { hv_vcpu_run(vcpu) }
+    }
+
+    fn vcpu_interrupt(vcpus: &[hv_vcpuid_t]) -> hv_return_t {
+        unsafe { hv_vcpu_interrupt(vcpus.as_ptr(), vcpus.len() as c_uint) }
+    }
+
+    fn vcpu_get_exec_time(vcpu: hv_vcpuid_t, time: &mut u64) -> hv_return_t {
+        unsafe { hv_vcpu_get_exec_time(vcpu, time) }
+    }
+
+    fn vcpu_flush(vcpu: hv_vcpuid_t) -> hv_return_t {
+        unsafe { hv_vcpu_flush(vcpu) }
+    }
+
+    fn vcpu_invalidate_tlb(vcpu: hv_vcpuid_t) -> hv_return_t {
+        unsafe { hv_vcpu_invalidate_tlb(vcpu) }
+    }
+
+    fn vcpu_read_register(vcpu: hv_vcpuid_t, reg: x86Reg, value: &mut u64) -> hv_return_t {
+        unsafe { hv_vcpu_read_register(vcpu, reg, value) }
+    }
+
+    fn vcpu_write_register(vcpu: hv_vcpuid_t, reg: x86Reg, value: u64) -> hv_return_t {
+        unsafe { hv_vcpu_write_register(vcpu, reg, value) }
+    }
+
+    fn vcpu_read_fpstate(vcpu: hv_vcpuid_t, buffer: &mut [u8]) -> hv_return_t {
+        unsafe {
+            hv_vcpu_read_fpstate(vcpu, buffer.as_mut_ptr() as *mut c_void, buffer.len() as size_t)
+        }
+    }
+
+    fn vcpu_write_fpstate(vcpu: hv_vcpuid_t, buffer: &[u8]) -> hv_return_t {
+        unsafe {
+            hv_vcpu_write_fpstate(vcpu, buffer.as_ptr() as *const c_void, buffer.len() as size_t)
+        }
+    }
+
+    fn vcpu_enable_native_msr(vcpu: hv_vcpuid_t, msr: u32, enable: bool) -> hv_return_t {
+        unsafe { hv_vcpu_enable_native_msr(vcpu, msr, enable) }
+    }
+
+    fn vcpu_read_msr(vcpu: hv_vcpuid_t, msr: u32, value: &mut u64) -> hv_return_t {
+        unsafe { hv_vcpu_read_msr(vcpu, msr, value) }
+    }
+
+    fn vcpu_write_msr(vcpu: hv_vcpuid_t, msr: u32, value: u64) -> hv_return_t {
+        unsafe { hv_vcpu_write_msr(vcpu, msr, &value) }
+    }
+
+    fn vmx_vcpu_read_vmcs(vcpu: hv_vcpuid_t, field: u32, value: &mut u64) -> hv_return_t {
+        unsafe { hv_vmx_vcpu_read_vmcs(vcpu, field, value) }
+    }
+
+    fn vmx_vcpu_write_vmcs(vcpu: hv_vcpuid_t, field: u32, value: u64) -> hv_return_t {
+        unsafe { hv_vmx_vcpu_write_vmcs(vcpu, field, value) }
+    }
+
+    fn vmx_read_capability(field: VMXCap, value: &mut u64) -> hv_return_t {
+        unsafe { hv_vmx_read_capability(field, value) }
+    }
+
+    fn vmx_vcpu_set_apic_address(vcpu: hv_vcpuid_t, gpa: hv_gpaddr_t) -> hv_return_t {
+        unsafe { hv_vmx_vcpu_set_apic_address(vcpu, gpa) }
+    }
+}
@@ -0,0 +1,1025 @@
+/*
+Copyright (c) 2016 Saurav Sachidanand
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in
+all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+THE SOFTWARE.
+*/
+
+//! Machine state, memory access and event delivery of the interpreter
+
+use std::ptr;
+
+use ffi::*;
+use consts::vmcs::*;
+use consts::vmx_cap::*;
+use consts::vmx_exit::*;
+use consts::irq::*;
+use super::{regions, Region, VcpuState, PAGE_SIZE};
+use super::super::super::x86Reg;
+
+pub const MSR_TSC            : u32 = 0x0000_0010;
+pub const MSR_APIC_BASE      : u32 = 0x0000_001b;
+pub const MSR_SYSENTER_CS    : u32 = 0x0000_0174;
+pub const MSR_SYSENTER_ESP   : u32 = 0x0000_0175;
+pub const MSR_SYSENTER_EIP   : u32 = 0x0000_0176;
+pub const MSR_EFER           : u32 = 0xc000_0080;
+pub const MSR_FS_BASE        : u32 = 0xc000_0100;
+pub const MSR_GS_BASE        : u32 = 0xc000_0101;
+pub const MSR_KERNEL_GS_BASE : u32 = 0xc000_0102;
+pub const MSR_TSC_AUX        : u32 = 0xc000_0103;
+
+pub const CR0_PE: u64 = 1 <<  0;
+pub const CR0_TS: u64 = 1 <<  3;
+pub const CR0_WP: u64 = 1 << 16;
+pub const CR0_PG: u64 = 1 << 31;
+
+pub const CR4_PSE: u64 = 1 << 4;
+pub const CR4_PAE: u64 = 1 << 5;
+
+pub const EFER_LME: u64 = 1 <<  8;
+pub const EFER_LMA: u64 = 1 << 10;
+pub const EFER_NXE: u64 = 1 << 11;
+
+pub const FLAG_CF: u64 = 1 <<  0;
+pub const FLAG_PF: u64 = 1 <<  2;
+pub const FLAG_AF: u64 = 1 <<  4;
+pub const FLAG_ZF: u64 = 1 <<  6;
+pub const FLAG_SF: u64 = 1 <<  7;
+pub const FLAG_TF: u64 = 1 <<  8;
+pub const FLAG_IF: u64 = 1 <<  9;
+pub const FLAG_DF: u64 = 1 << 10;
+pub const FLAG_OF: u64 = 1 << 11;
+pub const FLAG_NT: u64 = 1 << 14;
+pub const FLAG_RF: u64 = 1 << 16;
+pub const FLAG_VM: u64 = 1 << 17;
+pub const FLAG_AC: u64 = 1 << 18;
+
+// Segment access rights, in the VMCS format
+pub const AR_S        : u32 = 1 <<  4;
+pub const AR_P        : u32 = 1 <<  7;
+pub const AR_L        : u32 = 1 << 13;
+pub const AR_DB       : u32 = 1 << 14;
+pub const AR_UNUSABLE : u32 = 1 << 16;
+
+// Segment registers, numbered as in instruction encodings
+pub const ES   : usize = 0;
+pub const CS   : usize = 1;
+pub const SS   : usize = 2;
+pub const DS   : usize = 3;
+pub const FS   : usize = 4;
+pub const GS   : usize = 5;
+pub const LDTR : usize = 6;
+pub const TR   : usize = 7;
+
+// Guest interruptibility state
+pub const BLOCKING_STI    : u64 = 1 << 0;
+pub const BLOCKING_MOV_SS : u64 = 1 << 1;
+pub const BLOCKING_NMI    : u64 = 1 << 3;
+
+// Exception vectors
+pub const EXC_DE: u8 =  0;
+pub const EXC_BP: u8 =  3;
+pub const EXC_OF: u8 =  4;
+pub const EXC_UD: u8 =  6;
+pub const EXC_DF: u8 =  8;
+pub const EXC_NP: u8 = 11;
+pub const EXC_SS: u8 = 12;
+pub const EXC_GP: u8 = 13;
+pub const EXC_PF: u8 = 14;
+
+// Selector, base, limit and access rights of a segment register
+pub struct Segment {
+    pub selector: u16,
+    pub base: u64,
+    pub limit: u32,
+    pub ar: u32,
+}
+
+// Architectural registers, copied as a whole so that a faulting instruction
+// can be rolled back
+#[derive(Clone, Copy)]
+pub struct Registers {
+    pub gpr: [u64; 16],
+    pub rip: u64,
+    pub rflags: u64,
+    pub cr0: u64,
+    pub cr2: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+    pub cr8: u64,
+    pub efer: u64,
+    pub seg: [(u16, u64, u32, u32); 8],
+    pub gdtr: (u64, u32),
+    pub idtr: (u64, u32),
+    pub dr: [u64; 8],
+    pub interruptibility: u64,
+    pub tsc: u64,
+}
+
+impl Registers {
+    pub fn segment(&self, seg: usize) -> Segment {
+        let (selector, base, limit, ar) = self.seg[seg];
+        Segment { selector, base, limit, ar }
+    }
+
+    pub fn set_segment(&mut self, seg: usize, s: Segment) {
+        self.seg[seg] = (s.selector, s.base, s.limit, s.ar);
+    }
+}
+
+// Kind of access to guest memory
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Fetch,
+}
+
+// Cause of a VM exit, and the information to store for it
+#[derive(Default)]
+pub struct Exit {
+    pub reason: u64,
+    pub qualification: u64,
+    pub gpa: Option<u64>,
+    pub gla: Option<u64>,
+    pub instr_len: u64,
+    pub instr_info: u64,
+    pub irq_info: Option<(u32, Option<u32>)>,
+    pub idt_vectoring: Option<(u32, Option<u32>)>,
+}
+
+impl Exit {
+    pub fn new(reason: u64) -> Exit {
+        Exit { reason, ..Default::default() }
+    }
+
+    pub fn qualification(reason: u64, qualification: u64) -> Exit {
+        Exit { reason, qualification, ..Default::default() }
+    }
+
+    // Writes the exit information fields of the VMCS
+    pub fn store(&self, st: &mut VcpuState) {
+        st.set_vmcs(VMCS_RO_EXIT_REASON, self.reason);
+        st.set_vmcs(VMCS_RO_EXIT_QUALIFIC, self.qualification);
+        st.set_vmcs(VMCS_RO_VMEXIT_INSTR_LEN, self.instr_len);
+        st.set_vmcs(VMCS_RO_VMX_INSTR_INFO, self.instr_info);
+        if let Some(gpa) = self.gpa {
+            st.set_vmcs(VMCS_GUEST_PHYSICAL_ADDRESS, gpa);
+        }
+        if let Some(gla) = self.gla {
+            st.set_vmcs(VMCS_RO_GUEST_LIN_ADDR, gla);
+        }
+        let (irq_info, irq_error) = self.irq_info.unwrap_or((0, None));
+        st.set_vmcs(VMCS_RO_VMEXIT_IRQ_INFO, irq_info as u64);
+        st.set_vmcs(VMCS_RO_VMEXIT_IRQ_ERROR, irq_error.unwrap_or(0) as u64);
+        let (idt_info, idt_error) = self.idt_vectoring.unwrap_or((0, None));
+        st.set_vmcs(VMCS_RO_IDT_VECTOR_INFO, idt_info as u64);
+        st.set_vmcs(VMCS_RO_IDT_VECTOR_ERROR, idt_error.unwrap_or(0) as u64);
+    }
+}
+
+// Exception raised by an instruction
+pub struct Exception {
+    pub vector: u8,
+    pub error_code: Option<u32>,
+    // Faulting linear address of a page fault
+    pub address: Option<u64>,
+}
+
+// Why an instruction didn't complete
+pub enum Fault {
+    Exception(Exception),
+    Exit(Exit),
+}
+
+impl From<Exit> for Fault {
+    fn from(exit: Exit) -> Fault {
+        Fault::Exit(exit)
+    }
+}
+
+pub fn exception(vector: u8, error_code: Option<u32>) -> Fault {
+    Fault::Exception(Exception { vector, error_code, address: None })
+}
+
+pub fn ud() -> Fault {
+    exception(EXC_UD, None)
+}
+
+pub fn gp(error_code: u32) -> Fault {
+    exception(EXC_GP, Some(error_code))
+}
+
+// Event delivered through the IVT or IDT
+#[derive(Clone, Copy)]
+pub struct Event {
+    pub vector: u8,
+    // One of the `IRQ_INFO_*` types
+    pub kind: u32,
+    pub error_code: Option<u32>,
+    // RIP to push, which is past the instruction for software interrupts
+    pub return_rip: u64,
+}
+
+impl Event {
+    // Returns the event in the interruption-information format
+    pub fn info(&self) -> (u32, Option<u32>) {
+        let mut info = self.vector as u32 | self.kind | IRQ_INFO_VALID;
+        if self.error_code.is_some() {
+            info |= IRQ_INFO_ERROR_VALID;
+        }
+        (info, self.error_code)
+    }
+}
+
+// Exceptions that turn into a double fault when raised while delivering
+// another contributory exception or a page fault
+fn is_contributory(vector: u8) -> bool {
+    matches!(vector, 0 | 10..=13)
+}
+
+pub fn mask(size: usize) -> u64 {
+    if size >= 8 { !0 } else { (1 << (size * 8)) - 1 }
+}
+
+// Running vCPU
+pub struct Machine<'a> {
+    pub r: Registers,
+    pub st: &'a mut VcpuState,
+}
+
+impl<'a> Machine<'a> {
+    pub fn new(st: &'a mut VcpuState) -> Machine<'a> {
+        let mut gpr = [0; 16];
+        let order = [x86Reg::RAX, x86Reg::RCX, x86Reg::RDX, x86Reg::RBX, x86Reg::RSP,
+            x86Reg::RBP, x86Reg::RSI, x86Reg::RDI, x86Reg::R8, x86Reg::R9, x86Reg::R10,
+            x86Reg::R11, x86Reg::R12, x86Reg::R13, x86Reg::R14, x86Reg::R15];
+        for (i, reg) in order.iter().enumerate() {
+            gpr[i] = st.register(*reg);
+        }
+
+        let mut seg = [(0, 0, 0, 0); 8];
+        for (i, s) in SEGMENT_FIELDS.iter().enumerate() {
+            seg[i] = (st.vmcs(s.0) as u16, st.vmcs(s.1), st.vmcs(s.2) as u32, st.vmcs(s.3) as u32);
+        }
+
+        // VM entry takes EFER.LMA from the "IA-32e mode guest" control
+        let mut efer = st.vmcs(VMCS_GUEST_IA32_EFER) & !EFER_LMA;
+        if st.vmcs(VMCS_CTRL_VMENTRY_CONTROLS) & VMENTRY_GUEST_IA32E != 0 {
+            efer |= EFER_LMA;
+        }
+
+        let r = Registers {
+            gpr,
+            rip: st.vmcs(VMCS_GUEST_RIP),
+            rflags: st.vmcs(VMCS_GUEST_RFLAGS) | 0x2,
+            cr0: st.vmcs(VMCS_GUEST_CR0),
+            cr2: st.register(x86Reg::CR2),
+            cr3: st.vmcs(VMCS_GUEST_CR3),
+            cr4: st.vmcs(VMCS_GUEST_CR4),
+            cr8: st.register(x86Reg::TPR),
+            efer,
+            seg,
+            gdtr: (st.vmcs(VMCS_GUEST_GDTR_BASE), st.vmcs(VMCS_GUEST_GDTR_LIMIT) as u32),
+            idtr: (st.vmcs(VMCS_GUEST_IDTR_BASE), st.vmcs(VMCS_GUEST_IDTR_LIMIT) as u32),
+            dr: [st.register(x86Reg::DR0), st.register(x86Reg::DR1), st.register(x86Reg::DR2),
+                st.register(x86Reg::DR3), st.register(x86Reg::DR4), st.register(x86Reg::DR5),
+                st.register(x86Reg::DR6), st.vmcs(VMCS_GUEST_DR7)],
+            interruptibility: st.vmcs(VMCS_GUEST_IGNORE_IRQ),
+            tsc: st.msr(MSR_TSC),
+        };
+
+        Machine { r, st }
+    }
+
+    // Writes the registers back to the vCPU state
+    pub fn store(&mut self) {
+        let r = self.r;
+        let st = &mut *self.st;
+        let order = [x86Reg::RAX, x86Reg::RCX, x86Reg::RDX, x86Reg::RBX, x86Reg::RSP,
+            x86Reg::RBP, x86Reg::RSI, x86Reg::RDI, x86Reg::R8, x86Reg::R9, x86Reg::R10,
+            x86Reg::R11, x86Reg::R12, x86Reg::R13, x86Reg::R14, x86Reg::R15];
+        for (i, reg) in order.iter().enumerate() {
+            st.set_register(*reg, r.gpr[i]);
+        }
+        for (i, s) in SEGMENT_FIELDS.iter().enumerate() {
+            let (selector, base, limit, ar) = r.seg[i];
+            st.set_vmcs(s.0, selector as u64);
+            st.set_vmcs(s.1, base);
+            st.set_vmcs(s.2, limit as u64);
+            st.set_vmcs(s.3, ar as u64);
+        }
+        st.set_vmcs(VMCS_GUEST_RIP, r.rip);
+        st.set_vmcs(VMCS_GUEST_RFLAGS, r.rflags);
+        st.set_vmcs(VMCS_GUEST_CR0, r.cr0);
+        st.set_register(x86Reg::CR2, r.cr2);
+        st.set_vmcs(VMCS_GUEST_CR3, r.cr3);
+        st.set_vmcs(VMCS_GUEST_CR4, r.cr4);
+        st.set_register(x86Reg::TPR, r.cr8);
+        st.set_vmcs(VMCS_GUEST_IA32_EFER, r.efer);
+        st.set_vmcs(VMCS_GUEST_GDTR_BASE, r.gdtr.0);
+        st.set_vmcs(VMCS_GUEST_GDTR_LIMIT, r.gdtr.1 as u64);
+        st.set_vmcs(VMCS_GUEST_IDTR_BASE, r.idtr.0);
+        st.set_vmcs(VMCS_GUEST_IDTR_LIMIT, r.idtr.1 as u64);
+        let drs = [x86Reg::DR0, x86Reg::DR1, x86Reg::DR2, x86Reg::DR3, x86Reg::DR4,
+            x86Reg::DR5, x86Reg::DR6];
+        for (i, reg) in drs.iter().enumerate() {
+            st.set_register(*reg, r.dr[i]);
+        }
+        st.set_vmcs(VMCS_GUEST_DR7, r.dr[7]);
+        st.set_vmcs(VMCS_GUEST_IGNORE_IRQ, r.interruptibility);
+        st.msrs.insert(MSR_TSC, r.tsc);
+
+        // VM exit saves EFER.LMA into the "IA-32e mode guest" control
+        let mut entry = st.vmcs(VMCS_CTRL_VMENTRY_CONTROLS) & !VMENTRY_GUEST_IA32E;
+        if r.efer & EFER_LMA != 0 {
+            entry |= VMENTRY_GUEST_IA32E;
+        }
+        st.set_vmcs(VMCS_CTRL_VMENTRY_CONTROLS, entry);
+    }
+
+    // Modes
+
+    pub fn protected(&self) -> bool {
+        self.r.cr0 & CR0_PE != 0
+    }
+
+    pub fn long_mode(&self) -> bool {
+        self.r.efer & EFER_LMA != 0
+    }
+
+    pub fn code64(&self) -> bool {
+        self.long_mode() && self.r.seg[CS].3 & AR_L != 0
+    }
+
+    pub fn code32(&self) -> bool {
+        self.r.seg[CS].3 & AR_DB != 0
+    }
+
+    pub fn cpl(&self) -> u8 {
+        if !self.protected() {
+            0
+        } else if self.r.rflags & FLAG_VM != 0 {
+            3
+        } else {
+            ((self.r.seg[CS].3 >> 5) & 3) as u8
+        }
+    }
+
+    // Returns the address size of the stack in bytes
+    pub fn stack_size(&self) -> usize {
+        if self.code64() {
+            8
+        } else if self.r.seg[SS].3 & AR_DB != 0 {
+            4
+        } else {
+            2
+        }
+    }
+
+    pub fn control(&self, field: u32) -> u64 {
+        self.st.vmcs(field)
+    }
+
+    // Registers
+
+    pub fn reg(&self, reg: usize, size: usize) -> u64 {
+        self.r.gpr[reg] & mask(size)
+    }
+
+    pub fn set_reg(&mut self, reg: usize, size: usize, value: u64) {
+        let old = self.r.gpr[reg];
+        self.r.gpr[reg] = match size {
+            1 => (old & !0xff) | (value & 0xff),
+            2 => (old & !0xffff) | (value & 0xffff),
+            // Writes to 32-bit registers clear the upper half
+            4 => value & 0xffff_ffff,
+            _ => value,
+        };
+    }
+
+    // Memory
+
+    // Returns a host pointer to guest physical memory that doesn't cross a
+    // page boundary, or an EPT violation. The pointer is only valid while
+    // the regions stay locked.
+    fn host(regions: &[Region], gpa: u64, access: Access, gla: Option<u64>, walk: bool)
+        -> Result<*mut u8, Fault> {
+        let region = regions.iter().find(|r| r.gpa <= gpa && gpa < r.gpa + r.size);
+        let flags = region.map_or(0, |r| r.flags);
+        let needed = match access {
+            Access::Read => HV_MEMORY_READ,
+            Access::Write => HV_MEMORY_WRITE,
+            Access::Fetch => HV_MEMORY_EXEC,
+        };
+
+        match region {
+            Some(r) if flags & needed != 0 => Ok((r.uva + (gpa - r.gpa) as usize) as *mut u8),
+            _ => {
+                let mut qualification = match access {
+                    Access::Read => 1 << 0,
+                    Access::Write => 1 << 1,
+                    Access::Fetch => 1 << 2,
+                };
+                qualification |= (flags & 0x7) << 3;
+                if gla.is_some() {
+                    qualification |= 1 << 7;
+                    if !walk {
+                        qualification |= 1 << 8;
+                    }
+                }
+                let mut exit = Exit::qualification(VMX_REASON_EPT_VIOLATION, qualification);
+                exit.gpa = Some(gpa);
+                exit.gla = gla;
+                Err(Fault::Exit(exit))
+            },
+        }
+    }
+
+    // Reads a paging-structure entry, which never crosses a page boundary
+    fn read_entry(&self, gpa: u64, size: u64, gla: u64) -> Result<u64, Fault> {
+        let regions = regions();
+        let p = Machine::host(&regions, gpa, Access::Read, Some(gla), true)?;
+        Ok(unsafe {
+            if size == 8 {
+                u64::from_le(ptr::read_unaligned(p as *const u64))
+            } else {
+                u32::from_le(ptr::read_unaligned(p as *const u32)) as u64
+            }
+        })
+    }
+
+    // Translates a linear address to a guest physical address by walking
+    // the guest page tables
+    pub fn translate(&self, lin: u64, access: Access, user: bool) -> Result<u64, Fault> {
+        if self.r.cr0 & CR0_PG == 0 {
+            return Ok(lin & 0xffff_ffff);
+        }
+
+        let write = access == Access::Write;
+        let fetch = access == Access::Fetch;
+        let nxe = self.r.efer & EFER_NXE != 0;
+        let mut writable = true;
+        let mut usable = true;
+        let mut executable = true;
+
+        let page_fault = |present: bool| {
+            let mut error_code = 0;
+            if present { error_code |= 1 << 0; }
+            if write { error_code |= 1 << 1; }
+            if user { error_code |= 1 << 2; }
+            if fetch && nxe { error_code |= 1 << 4; }
+            Fault::Exception(Exception {
+                vector: EXC_PF,
+                error_code: Some(error_code),
+                address: Some(lin),
+            })
+        };
+
+        // Each level is (shift, index bits, entry size), from the root down
+        let (levels, mut table): (&[(u32, u32, u64)], u64) = if self.long_mode() {
+            (&[(39, 9, 8), (30, 9, 8), (21, 9, 8), (12, 9, 8)], self.r.cr3 & 0x000f_ffff_ffff_f000)
+        } else if self.r.cr4 & CR4_PAE != 0 {
+            (&[(30, 2, 8), (21, 9, 8), (12, 9, 8)], self.r.cr3 & 0xffff_ffe0)
+        } else {
+            (&[(22, 10, 4), (12, 10, 4)], self.r.cr3 & 0xffff_f000)
+        };
+
+        for (i, &(shift, bits, entry_size)) in levels.iter().enumerate() {
+            let index = (lin >> shift) & ((1 << bits) - 1);
+            let entry = self.read_entry(table + index * entry_size, entry_size, lin)?;
+            if entry & 1 == 0 {
+                return Err(page_fault(false));
+            }
+
+            // PAE page-directory-pointer entries carry no permissions
+            let pae_pdpte = levels.len() == 3 && i == 0;
+            if !pae_pdpte {
+                writable &= entry & (1 << 1) != 0;
+                usable &= entry & (1 << 2) != 0;
+                if nxe && entry_size == 8 {
+                    executable &= entry & (1 << 63) == 0;
+                }
+            }
+
+            // Levels that can map 1 GiB, 2 MiB or 4 MiB pages directly
+            let can_be_large = match levels.len() {
+                4 => i == 1 || i == 2,
+                3 => i == 1,
+                _ => i == 0 && self.r.cr4 & CR4_PSE != 0,
+            };
+            let last = i == levels.len() - 1;
+            let is_large = can_be_large && entry & (1 << 7) != 0;
+            let address = if entry_size == 8 {
+                entry & 0x000f_ffff_ffff_f000
+            } else {
+                entry & 0xffff_f000
+            };
+
+            if last || is_large {
+                if user && !usable {
+                    return Err(page_fault(true));
+                }
+                if write && !writable && (user || self.r.cr0 & CR0_WP != 0) {
+                    return Err(page_fault(true));
+                }
+                if fetch && !executable {
+                    return Err(page_fault(true));
+                }
+                let offset_mask = (1u64 << shift) - 1;
+                let base = if entry_size == 4 && is_large {
+                    // 4 MiB pages keep bits 39:32 of the address in bits 20:13
+                    (entry & 0xffc0_0000) | ((entry & 0x001f_e000) << 19)
+                } else {
+                    address & !offset_mask
+                };
+                return Ok(base | (lin & offset_mask));
+            }
+
+            table = address;
+        }
+
+        unreachable!()
+    }
+
+    pub fn linear(&self, seg: usize, offset: u64) -> u64 {
+        if self.code64() {
+            match seg {
+                FS | GS => self.r.seg[seg].1.wrapping_add(offset),
+                _ => offset,
+            }
+        } else {
+            self.r.seg[seg].1.wrapping_add(offset) & 0xffff_ffff
+        }
+    }
+
+    // Translates every page touched by an access into its linear address,
+    // guest physical address and length
+    fn pages(&self, lin: u64, len: usize, access: Access, user: bool)
+        -> Result<Vec<(u64, u64, usize)>, Fault> {
+        let mut pages = Vec::with_capacity(2);
+        let mut done = 0;
+        while done < len {
+            let addr = lin.wrapping_add(done as u64);
+            let chunk = ::std::cmp::min(len - done, (PAGE_SIZE - (addr % PAGE_SIZE)) as usize);
+            pages.push((addr, self.translate(addr, access, user)?, chunk));
+            done += chunk;
+        }
+        Ok(pages)
+    }
+
+    // Looks up the host memory behind every page of an access, so that
+    // nothing is written unless the whole access can complete
+    fn hosts(regions: &[Region], pages: &[(u64, u64, usize)], access: Access)
+        -> Result<Vec<(*mut u8, usize)>, Fault> {
+        let mut hosts = Vec::with_capacity(pages.len());
+        for &(addr, gpa, chunk) in pages {
+            hosts.push((Machine::host(regions, gpa, access, Some(addr), false)?, chunk));
+        }
+        Ok(hosts)
+    }
+
+    pub fn read_linear(&self, lin: u64, buf: &mut [u8], access: Access, user: bool)
+        -> Result<(), Fault> {
+        let pages = self.pages(lin, buf.len(), access, user)?;
+        let regions = regions();
+        let mut done = 0;
+        for (p, chunk) in Machine::hosts(&regions, &pages, access)? {
+            unsafe { ptr::copy_nonoverlapping(p, buf[done..].as_mut_ptr(), chunk) };
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    pub fn write_linear(&self, lin: u64, buf: &[u8], user: bool) -> Result<(), Fault> {
+        let pages = self.pages(lin, buf.len(), Access::Write, user)?;
+        let regions = regions();
+        let mut done = 0;
+        for (p, chunk) in Machine::hosts(&regions, &pages, Access::Write)? {
+            unsafe { ptr::copy_nonoverlapping(buf[done..].as_ptr(), p, chunk) };
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    // Reads a little-endian value of up to 8 bytes at a linear address
+    pub fn read_lin(&self, lin: u64, size: usize, access: Access) -> Result<u64, Fault> {
+        let mut buf = [0; 8];
+        self.read_linear(lin, &mut buf[..size], access, self.cpl() == 3)?;
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    pub fn write_lin(&self, lin: u64, size: usize, value: u64) -> Result<(), Fault> {
+        self.write_linear(lin, &value.to_le_bytes()[..size], self.cpl() == 3)
+    }
+
+    // Supervisor-mode accesses, used for descriptor tables and the TSS
+    fn read_system(&self, lin: u64, size: usize) -> Result<u64, Fault> {
+        let mut buf = [0; 8];
+        self.read_linear(lin, &mut buf[..size], Access::Read, false)?;
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    pub fn read_mem(&self, seg: usize, offset: u64, size: usize) -> Result<u64, Fault> {
+        self.read_lin(self.linear(seg, offset), size, Access::Read)
+    }
+
+    pub fn write_mem(&self, seg: usize, offset: u64, size: usize, value: u64) -> Result<(), Fault> {
+        self.write_lin(self.linear(seg, offset), size, value)
+    }
+
+    // Stack
+
+    pub fn push(&mut self, size: usize, value: u64) -> Result<(), Fault> {
+        let ss = self.stack_size();
+        let rsp = self.r.gpr[4].wrapping_sub(size as u64) & mask(ss);
+        self.write_mem(SS, rsp, size, value)?;
+        self.set_reg(4, ss, rsp);
+        Ok(())
+    }
+
+    pub fn pop(&mut self, size: usize) -> Result<u64, Fault> {
+        let ss = self.stack_size();
+        let rsp = self.r.gpr[4] & mask(ss);
+        let value = self.read_mem(SS, rsp, size)?;
+        self.set_reg(4, ss, rsp.wrapping_add(size as u64));
+        Ok(value)
+    }
+
+    // Segments
+
+    // Reads the descriptor a selector refers to
+    fn descriptor(&self, selector: u16) -> Result<u64, Fault> {
+        let index = (selector & !7) as u64;
+        let (base, limit) = if selector & 4 != 0 {
+            let ldtr = self.r.seg[LDTR];
+            if ldtr.3 & AR_UNUSABLE != 0 {
+                return Err(gp(selector as u32 & !3));
+            }
+            (ldtr.1, ldtr.2)
+        } else {
+            self.r.gdtr
+        };
+        if index + 7 > limit as u64 {
+            return Err(gp(selector as u32 & !3));
+        }
+        self.read_system(base.wrapping_add(index), 8)
+    }
+
+    // Decodes the base of a system descriptor, which is 16 bytes long in
+    // long mode
+    fn system_base(&self, selector: u16, descriptor: u64) -> Result<u64, Fault> {
+        let mut base = descriptor_base(descriptor);
+        if self.long_mode() {
+            let index = (selector & !7) as u64;
+            base |= self.read_system(self.r.gdtr.0.wrapping_add(index + 8), 4)? << 32;
+        }
+        Ok(base)
+    }
+
+    // Loads a segment register the way a MOV, POP or far transfer does
+    pub fn load_segment(&mut self, seg: usize, selector: u16) -> Result<(), Fault> {
+        if !self.protected() || self.r.rflags & FLAG_VM != 0 {
+            let old = self.r.segment(seg);
+            let ar = if old.ar == 0 || old.ar & AR_UNUSABLE != 0 {
+                if seg == CS { 0x9b } else { 0x93 }
+            } else {
+                old.ar
+            };
+            let limit = if old.ar == 0 { 0xffff } else { old.limit };
+            self.r.set_segment(seg, Segment {
+                selector,
+                base: (selector as u64) << 4,
+                limit,
+                ar,
+            });
+            return Ok(());
+        }
+
+        if selector & !3 == 0 {
+            if seg == CS || (seg == SS && !(self.code64() && self.cpl() != 3)) {
+                return Err(gp(0));
+            }
+            self.r.set_segment(seg, Segment { selector, base: 0, limit: 0, ar: AR_UNUSABLE });
+            return Ok(());
+        }
+
+        let error = selector as u32 & !3;
+        let d = self.descriptor(selector)?;
+        let ar = descriptor_ar(d);
+        let code = ar & 0x8 != 0;
+        if ar & AR_S == 0 || (seg == CS && !code) || (seg == SS && (code || ar & 0x2 == 0))
+            || (seg != CS && code && ar & 0x2 == 0) {
+            return Err(gp(error));
+        }
+        if ar & AR_P == 0 {
+            let vector = if seg == SS { EXC_SS } else { EXC_NP };
+            return Err(exception(vector, Some(error)));
+        }
+
+        self.r.set_segment(seg, Segment {
+            selector,
+            base: descriptor_base(d),
+            limit: descriptor_limit(d),
+            ar,
+        });
+        Ok(())
+    }
+
+    // Loads LDTR or TR from a system descriptor in the GDT
+    pub fn load_system_segment(&mut self, seg: usize, selector: u16) -> Result<(), Fault> {
+        if selector & !3 == 0 {
+            if seg == TR {
+                return Err(gp(0));
+            }
+            self.r.set_segment(seg, Segment { selector, base: 0, limit: 0, ar: AR_UNUSABLE });
+            return Ok(());
+        }
+
+        let error = selector as u32 & !3;
+        if selector & 4 != 0 {
+            return Err(gp(error));
+        }
+        let d = self.descriptor(selector)?;
+        let ar = descriptor_ar(d);
+        let ty = ar & 0xf;
+        let valid = if seg == LDTR { ty == 2 } else { ty == 1 || ty == 9 };
+        if ar & AR_S != 0 || !valid {
+            return Err(gp(error));
+        }
+        if ar & AR_P == 0 {
+            return Err(exception(EXC_NP, Some(error)));
+        }
+
+        // Loading TR marks the TSS busy
+        let ar = if seg == TR { ar | 0x2 } else { ar };
+        let base = self.system_base(selector, d)?;
+        self.r.set_segment(seg, Segment { selector, base, limit: descriptor_limit(d), ar });
+        Ok(())
+    }
+
+    // Events
+
+    // Returns whether an exception raised by the guest exits to the host
+    // instead of being delivered
+    pub fn intercepts(&self, vector: u8, error_code: Option<u32>) -> bool {
+        let bitmap = self.control(VMCS_CTRL_EXC_BITMAP);
+        let intercepted = bitmap & (1 << vector) != 0;
+        if vector == EXC_PF {
+            let mask = self.control(VMCS_CTRL_PF_ERROR_MASK) as u32;
+            let matched = self.control(VMCS_CTRL_PF_ERROR_MATCH) as u32;
+            intercepted == (error_code.unwrap_or(0) & mask == matched)
+        } else {
+            intercepted
+        }
+    }
+
+    // Delivers an event, handling exceptions raised along the way. Returns
+    // the exit it ended in, if any.
+    pub fn raise(&mut self, mut event: Event) -> Option<Exit> {
+        loop {
+            let rollback = self.r;
+            let e = match self.deliver(&event) {
+                Ok(()) => return None,
+                Err(Fault::Exit(mut exit)) => {
+                    self.r = rollback;
+                    exit.idt_vectoring = Some(event.info());
+                    return Some(exit);
+                },
+                Err(Fault::Exception(e)) => e,
+            };
+            self.r = rollback;
+
+            if event.kind == IRQ_INFO_HARD_EXC && event.vector == EXC_DF {
+                return Some(Exit::new(VMX_REASON_TRIPLE_FAULT));
+            }
+
+            if self.intercepts(e.vector, e.error_code) {
+                let mut exit = exception_exit(&e);
+                exit.idt_vectoring = Some(event.info());
+                return Some(exit);
+            }
+            if let Some(address) = e.address {
+                self.r.cr2 = address;
+            }
+
+            let df = event.kind == IRQ_INFO_HARD_EXC && match event.vector {
+                EXC_PF => e.vector == EXC_PF || is_contributory(e.vector),
+                vector => is_contributory(vector) && is_contributory(e.vector),
+            };
+            event = if df {
+                Event {
+                    vector: EXC_DF,
+                    kind: IRQ_INFO_HARD_EXC,
+                    error_code: Some(0),
+                    return_rip: event.return_rip,
+                }
+            } else {
+                Event {
+                    vector: e.vector,
+                    kind: IRQ_INFO_HARD_EXC,
+                    error_code: e.error_code,
+                    return_rip: event.return_rip,
+                }
+            };
+        }
+    }
+
+    fn deliver(&mut self, event: &Event) -> Result<(), Fault> {
+        if event.kind == IRQ_INFO_NMI {
+            self.r.interruptibility |= BLOCKING_NMI;
+        }
+        if !self.protected() {
+            return self.deliver_real(event);
+        }
+
+        let vector = event.vector as u64;
+        let gate_size = if self.long_mode() { 16 } else { 8 };
+        let idt_error = (vector as u32) << 3 | 2;
+        if vector * gate_size + gate_size - 1 > self.r.idtr.1 as u64 {
+            return Err(gp(idt_error));
+        }
+        let gate_addr = self.r.idtr.0.wrapping_add(vector * gate_size);
+        let gate = self.read_system(gate_addr, 8)?;
+        let ty = (gate >> 40) & 0xf;
+        let dpl = ((gate >> 45) & 3) as u8;
+        let present = gate & (1 << 47) != 0;
+        let software = event.kind == IRQ_INFO_SOFT_IRQ || event.kind == IRQ_INFO_SOFT_EXC;
+        let valid_type = if self.long_mode() { ty == 0xe || ty == 0xf } else {
+            ty == 0x6 || ty == 0x7 || ty == 0xe || ty == 0xf
+        };
+        if !valid_type || (software && dpl < self.cpl()) {
+            return Err(gp(idt_error));
+        }
+        if !present {
+            return Err(exception(EXC_NP, Some(idt_error)));
+        }
+
+        let selector = (gate >> 16) as u16;
+        let mut offset = (gate & 0xffff) | ((gate >> 32) & 0xffff_0000);
+        if self.long_mode() {
+            offset |= self.read_system(gate_addr + 8, 4)? << 32;
+        } else if ty == 0x6 || ty == 0x7 {
+            offset &= 0xffff;
+        }
+
+        let old_cpl = self.cpl();
+        let old_ss = self.r.segment(SS);
+        let old_rsp = self.r.gpr[4];
+        let old_cs = self.r.seg[CS].0;
+        let old_rflags = self.r.rflags;
+
+        let d = self.descriptor(selector)?;
+        let ar = descriptor_ar(d);
+        if ar & AR_S == 0 || ar & 0x8 == 0 {
+            return Err(gp(selector as u32 & !3));
+        }
+        if ar & AR_P == 0 {
+            return Err(exception(EXC_NP, Some(selector as u32 & !3)));
+        }
+        let new_cpl = ((ar >> 5) & 3) as u8;
+        let ist = if self.long_mode() { (gate >> 32) & 7 } else { 0 };
+
+        let tss = self.r.seg[TR].1;
+        let switch_stack = new_cpl < old_cpl || ist != 0;
+        if self.long_mode() {
+            if switch_stack {
+                let rsp = if ist != 0 {
+                    self.read_system(tss + 0x24 + (ist - 1) * 8, 8)?
+                } else {
+                    self.read_system(tss + 4 + new_cpl as u64 * 8, 8)?
+                };
+                if new_cpl < old_cpl {
+                    self.r.set_segment(SS, Segment {
+                        selector: new_cpl as u16,
+                        base: 0,
+                        limit: 0,
+                        ar: AR_UNUSABLE | (new_cpl as u32) << 5,
+                    });
+                }
+                self.r.gpr[4] = rsp;
+            }
+        } else if new_cpl < old_cpl {
+            let esp = self.read_system(tss + 4 + new_cpl as u64 * 8, 4)?;
+            let ss = self.read_system(tss + 8 + new_cpl as u64 * 8, 2)? as u16;
+            self.set_cpl(new_cpl);
+            self.load_segment(SS, ss)?;
+            self.r.gpr[4] = esp;
+        }
+
+        self.r.set_segment(CS, Segment {
+            selector: (selector & !3) | new_cpl as u16,
+            base: descriptor_base(d),
+            limit: descriptor_limit(d),
+            ar,
+        });
+
+        if self.long_mode() {
+            self.r.gpr[4] &= !0xf;
+            self.push(8, old_ss.selector as u64)?;
+            self.push(8, old_rsp)?;
+            self.push(8, old_rflags)?;
+            self.push(8, old_cs as u64)?;
+            self.push(8, event.return_rip)?;
+            if let Some(error_code) = event.error_code {
+                self.push(8, error_code as u64)?;
+            }
+        } else {
+            let size = if ty & 0x8 != 0 { 4 } else { 2 };
+            if new_cpl < old_cpl {
+                self.push(size, old_ss.selector as u64)?;
+                self.push(size, old_rsp)?;
+            }
+            self.push(size, old_rflags)?;
+            self.push(size, old_cs as u64)?;
+            self.push(size, event.return_rip)?;
+            if let Some(error_code) = event.error_code {
+                self.push(size, error_code as u64)?;
+            }
+        }
+
+        self.r.rip = offset;
+        self.r.rflags &= !(FLAG_TF | FLAG_NT | FLAG_RF | FLAG_VM);
+        if ty & 1 == 0 {
+            self.r.rflags &= !FLAG_IF;
+        }
+        Ok(())
+    }
+
+    fn deliver_real(&mut self, event: &Event) -> Result<(), Fault> {
+        let vector = event.vector as u64;
+        if vector * 4 + 3 > self.r.idtr.1 as u64 {
+            return Err(gp(0));
+        }
+        let entry = self.read_system(self.r.idtr.0.wrapping_add(vector * 4), 4)?;
+        let flags = self.r.rflags;
+        let cs = self.r.seg[CS].0;
+        self.push(2, flags)?;
+        self.push(2, cs as u64)?;
+        self.push(2, event.return_rip)?;
+        self.load_segment(CS, (entry >> 16) as u16)?;
+        self.r.rip = entry & 0xffff;
+        self.r.rflags &= !(FLAG_IF | FLAG_TF | FLAG_AC);
+        Ok(())
+    }
+
+    // Changes the privilege level recorded in CS and SS
+    pub fn set_cpl(&mut self, cpl: u8) {
+        for &seg in &[CS, SS] {
+            let ar = self.r.seg[seg].3;
+            self.r.seg[seg].3 = (ar & !0x60) | (cpl as u32) << 5;
+        }
+    }
+}
+
+// Builds the exit for an exception selected by the exception bitmap
+pub fn exception_exit(e: &Exception) -> Exit {
+    let kind = match e.vector {
+        EXC_BP | EXC_OF => IRQ_INFO_SOFT_EXC,
+        _ => IRQ_INFO_HARD_EXC,
+    };
+    let event = Event { vector: e.vector, kind, error_code: e.error_code, return_rip: 0 };
+    let mut exit = Exit::qualification(VMX_REASON_EXC_NMI, e.address.unwrap_or(0));
+    exit.irq_info = Some(event.info());
+    exit
+}
+
+pub fn descriptor_base(d: u64) -> u64 {
+    ((d >> 16) & 0xff_ffff) | ((d >> 32) & 0xff00_0000)
+}
+
+pub fn descriptor_limit(d: u64) -> u32 {
+    let limit = ((d & 0xffff) | ((d >> 32) & 0xf_0000)) as u32;
+    if d & (1 << 55) != 0 { (limit << 12) | 0xfff } else { limit }
+}
+
+pub fn descriptor_ar(d: u64) -> u32 {
+    ((d >> 40) & 0xf0ff) as u32
+}
+
+// Selector, base, limit and access-rights fields of each segment register
+const SEGMENT_FIELDS: [(u32, u32, u32, u32); 8] = [
+    (VMCS_GUEST_ES, VMCS_GUEST_ES_BASE, VMCS_GUEST_ES_LIMIT, VMCS_GUEST_ES_AR),
+    (VMCS_GUEST_CS, VMCS_GUEST_CS_BASE, VMCS_GUEST_CS_LIMIT, VMCS_GUEST_CS_AR),
+    (VMCS_GUEST_SS, VMCS_GUEST_SS_BASE, VMCS_GUEST_SS_LIMIT, VMCS_GUEST_SS_AR),
+    (VMCS_GUEST_DS, VMCS_GUEST_DS_BASE, VMCS_GUEST_DS_LIMIT, VMCS_GUEST_DS_AR),
+    (VMCS_GUEST_FS, VMCS_GUEST_FS_BASE, VMCS_GUEST_FS_LIMIT, VMCS_GUEST_FS_AR),
+    (VMCS_GUEST_GS, VMCS_GUEST_GS_BASE, VMCS_GUEST_GS_LIMIT, VMCS_GUEST_GS_AR),
+    (VMCS_GUEST_LDTR, VMCS_GUEST_LDTR_BASE, VMCS_GUEST_LDTR_LIMIT, VMCS_GUEST_LDTR_AR),
+    (VMCS_GUEST_TR, VMCS_GUEST_TR_BASE, VMCS_GUEST_TR_LIMIT, VMCS_GUEST_TR_AR),
+];
@@ -0,0 +1,2004 @@
+/*
+Copyright (c) 2016 Saurav Sachidanand
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in
+all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+THE SOFTWARE.
+*/
+
+//! Instruction decoding and execution
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use consts::vmcs::*;
+use consts::vmx_cap::*;
+use consts::vmx_exit::*;
+use consts::irq::*;
+use super::{VcpuState, msr_field};
+use super::cpu::*;
+use super::super::super::x86Reg;
+
+const ARITH_FLAGS: u64 = FLAG_CF | FLAG_PF | FLAG_AF | FLAG_ZF | FLAG_SF | FLAG_OF;
+
+// REX prefix bits
+const REX_W: u8 = 1 << 3;
+const REX_R: u8 = 1 << 2;
+const REX_X: u8 = 1 << 1;
+const REX_B: u8 = 1 << 0;
+
+// General-purpose registers, numbered as in instruction encodings
+const RAX: usize = 0;
+const RCX: usize = 1;
+const RDX: usize = 2;
+const RBX: usize = 3;
+const RSP: usize = 4;
+const RBP: usize = 5;
+const RSI: usize = 6;
+const RDI: usize = 7;
+
+/// Runs a vCPU until it exits, and stores the exit in its VMCS
+pub fn run(st: &mut VcpuState, interrupted: &AtomicBool) {
+    let mut m = Machine::new(st);
+    let exit = m.run(interrupted);
+    m.store();
+    exit.store(m.st);
+}
+
+// Instruction being decoded
+struct Insn {
+    start: u64,
+    len: u64,
+    ip_mask: u64,
+    code64: bool,
+    opsize: usize,
+    adsize: usize,
+    rex: u8,
+    seg: Option<usize>,
+    rep: u8,
+}
+
+impl Insn {
+    fn next(&self) -> u64 {
+        self.start.wrapping_add(self.len) & self.ip_mask
+    }
+
+    // Operand size of near branches and stack operations, which default to
+    // 64 bits in 64-bit mode
+    fn stack_opsize(&self) -> usize {
+        if self.code64 && self.opsize != 2 { 8 } else { self.opsize }
+    }
+}
+
+// Register or memory operand
+#[derive(Clone, Copy)]
+enum Operand {
+    Reg(usize),
+    Mem { seg: usize, offset: u64, rip_relative: bool },
+}
+
+// Where execution continues after an instruction
+enum Flow {
+    Next,
+    Jump(u64),
+    // Leaves RIP where it is, for repeated string instructions that haven't
+    // finished, and for events that have already set it
+    Stay,
+}
+
+fn sign_extend(value: u64, size: usize) -> u64 {
+    let shift = 64 - size * 8;
+    (((value << shift) as i64) >> shift) as u64
+}
+
+fn msb(value: u64, size: usize) -> u64 {
+    (value >> (size * 8 - 1)) & 1
+}
+
+impl<'a> Machine<'a> {
+    fn run(&mut self, interrupted: &AtomicBool) -> Exit {
+        if let Some(exit) = self.enter() {
+            return exit;
+        }
+
+        // The VMX-preemption timer counts down with the TSC, once per
+        // instruction, since the rate the interpreter reports is zero
+        let mut timer = if self.control(VMCS_CTRL_PIN_BASED) & PIN_BASED_PREEMPTION_TIMER != 0 {
+            Some(self.control(VMCS_GUEST_VMX_TIMER_VALUE))
+        } else {
+            None
+        };
+        let exit = self.execute_until_exit(interrupted, &mut timer);
+        if let Some(value) = timer {
+            if self.control(VMCS_CTRL_VMEXIT_CONTROLS) & VMEXIT_SAVE_VMX_TIMER != 0 {
+                self.st.set_vmcs(VMCS_GUEST_VMX_TIMER_VALUE, value);
+            }
+        }
+        exit
+    }
+
+    fn execute_until_exit(&mut self, interrupted: &AtomicBool, timer: &mut Option<u64>) -> Exit {
+        loop {
+            if interrupted.load(Ordering::Relaxed) && interrupted.swap(false, Ordering::SeqCst) {
+                return Exit::new(VMX_REASON_IRQ);
+            }
+
+            if *timer == Some(0) {
+                return Exit::new(VMX_REASON_VMX_TIMER_EXPIRED);
+            }
+
+            let cpu_based = self.control(VMCS_CTRL_CPU_BASED);
+            let blocking = self.r.interruptibility;
+            if cpu_based & CPU_BASED_IRQ_WND != 0 && self.r.rflags & FLAG_IF != 0
+                && blocking & (BLOCKING_STI | BLOCKING_MOV_SS) == 0 {
+                return Exit::new(VMX_REASON_IRQ_WND);
+            }
+            if cpu_based & CPU_BASED_VIRTUAL_NMI_WND != 0
+                && blocking & (BLOCKING_NMI | BLOCKING_MOV_SS) == 0 {
+                return Exit::new(VMX_REASON_VIRTUAL_NMI_WND);
+            }
+
+            // Every instruction counts, including one that causes a VM exit
+            let exit = self.step();
+            if let Some(ref mut value) = *timer {
+                *value -= 1;
+            }
+            if let Some(exit) = exit {
+                return exit;
+            }
+        }
+    }
+
+    // Delivers the event written to VMCS_CTRL_VMENTRY_IRQ_INFO, if any
+    fn enter(&mut self) -> Option<Exit> {
+        let info = self.control(VMCS_CTRL_VMENTRY_IRQ_INFO) as u32;
+        if info & IRQ_INFO_VALID == 0 {
+            return None;
+        }
+
+        let kind = info & 0x700;
+        let blocking = self.r.interruptibility;
+        let invalid = match kind {
+            IRQ_INFO_EXT_IRQ => self.r.rflags & FLAG_IF == 0
+                || blocking & (BLOCKING_STI | BLOCKING_MOV_SS) != 0,
+            IRQ_INFO_NMI => blocking & BLOCKING_MOV_SS != 0,
+            _ => false,
+        };
+        if invalid {
+            return Some(Exit::new(VMX_REASON_VMENTRY_GUEST | 1 << 31));
+        }
+
+        // Like VM exits, the interpreter clears the valid bit after using
+        // the event
+        self.st.set_vmcs(VMCS_CTRL_VMENTRY_IRQ_INFO, (info & !IRQ_INFO_VALID) as u64);
+
+        let error_code = if info & IRQ_INFO_ERROR_VALID != 0 {
+            Some(self.control(VMCS_CTRL_VMENTRY_EXC_ERROR) as u32)
+        } else {
+            None
+        };
+        let return_rip = match kind {
+            IRQ_INFO_SOFT_IRQ | IRQ_INFO_PRIV_SOFT_EXC | IRQ_INFO_SOFT_EXC =>
+                self.r.rip.wrapping_add(self.control(VMCS_CTRL_VMENTRY_INSTR_LEN)),
+            _ => self.r.rip,
+        };
+        self.raise(Event { vector: info as u8, kind, error_code, return_rip })
+    }
+
+    // Executes one instruction. Returns the exit it ended in, if any.
+    fn step(&mut self) -> Option<Exit> {
+        let rollback = self.r;
+        self.r.interruptibility &= !(BLOCKING_STI | BLOCKING_MOV_SS);
+
+        let code64 = self.code64();
+        let ip_mask = if code64 { !0 } else if self.code32() { 0xffff_ffff } else { 0xffff };
+        let mut insn = Insn {
+            start: self.r.rip,
+            len: 0,
+            ip_mask,
+            code64,
+            opsize: 0,
+            adsize: 0,
+            rex: 0,
+            seg: None,
+            rep: 0,
+        };
+
+        let fault = match self.execute(&mut insn) {
+            Ok(flow) => {
+                match flow {
+                    Flow::Next => self.r.rip = insn.next(),
+                    Flow::Jump(target) => self.r.rip = target,
+                    Flow::Stay => (),
+                }
+                self.r.tsc = self.r.tsc.wrapping_add(1);
+                return None;
+            },
+            Err(fault) => fault,
+        };
+
+        self.r = rollback;
+        match fault {
+            Fault::Exit(mut exit) => {
+                exit.instr_len = insn.len;
+                Some(exit)
+            },
+            Fault::Exception(e) => {
+                if self.intercepts(e.vector, e.error_code) {
+                    let mut exit = exception_exit(&e);
+                    exit.instr_len = insn.len;
+                    return Some(exit);
+                }
+                if let Some(address) = e.address {
+                    self.r.cr2 = address;
+                }
+                self.raise(Event {
+                    vector: e.vector,
+                    kind: IRQ_INFO_HARD_EXC,
+                    error_code: e.error_code,
+                    return_rip: rollback.rip,
+                })
+            },
+        }
+    }
+
+    // Decoding
+
+    fn fetch(&self, insn: &mut Insn) -> Result<u8, Fault> {
+        if insn.len >= 15 {
+            return Err(gp(0));
+        }
+        let ip = insn.start.wrapping_add(insn.len) & insn.ip_mask;
+        let byte = self.read_lin(self.linear(CS, ip), 1, Access::Fetch)? as u8;
+        insn.len += 1;
+        Ok(byte)
+    }
+
+    fn fetch_n(&self, insn: &mut Insn, size: usize) -> Result<u64, Fault> {
+        let mut value = 0;
+        for i in 0..size {
+            value |= (self.fetch(insn)? as u64) << (i * 8);
+        }
+        Ok(value)
+    }
+
+    // Fetches a sign-extended immediate of an operand size, which is at
+    // most 32 bits long
+    fn imm(&self, insn: &mut Insn, size: usize) -> Result<u64, Fault> {
+        let n = if size == 8 { 4 } else { size };
+        Ok(sign_extend(self.fetch_n(insn, n)?, n))
+    }
+
+    fn imm8(&self, insn: &mut Insn) -> Result<u64, Fault> {
+        Ok(sign_extend(self.fetch(insn)? as u64, 1))
+    }
+
+    // Decodes a ModRM byte and what follows it. Returns the register field
+    // and the register or memory operand.
+    fn modrm(&self, insn: &mut Insn) -> Result<(usize, Operand), Fault> {
+        let modrm = self.fetch(insn)?;
+        let md = modrm >> 6;
+        let reg = ((modrm >> 3) & 7) as usize | if insn.rex & REX_R != 0 { 8 } else { 0 };
+        let rm = (modrm & 7) as usize;
+
+        if md == 3 {
+            let rm = rm | if insn.rex & REX_B != 0 { 8 } else { 0 };
+            return Ok((reg, Operand::Reg(rm)));
+        }
+
+        if insn.adsize == 2 {
+            let r = &self.r.gpr;
+            let (base, default_seg) = match rm {
+                0 => (r[RBX].wrapping_add(r[RSI]), DS),
+                1 => (r[RBX].wrapping_add(r[RDI]), DS),
+                2 => (r[RBP].wrapping_add(r[RSI]), SS),
+                3 => (r[RBP].wrapping_add(r[RDI]), SS),
+                4 => (r[RSI], DS),
+                5 => (r[RDI], DS),
+                6 if md == 0 => (0, DS),
+                6 => (r[RBP], SS),
+                _ => (r[RBX], DS),
+            };
+            let disp = match md {
+                0 if rm == 6 => self.fetch_n(insn, 2)?,
+                0 => 0,
+                1 => self.imm8(insn)?,
+                _ => self.fetch_n(insn, 2)?,
+            };
+            return Ok((reg, Operand::Mem {
+                seg: insn.seg.unwrap_or(default_seg),
+                offset: base.wrapping_add(disp) & 0xffff,
+                rip_relative: false,
+            }));
+        }
+
+        let mut default_seg = DS;
+        let mut rip_relative = false;
+        let mut address = if rm == 4 {
+            let sib = self.fetch(insn)?;
+            let scale = sib >> 6;
+            let index = ((sib >> 3) & 7) as usize | if insn.rex & REX_X != 0 { 8 } else { 0 };
+            let base = (sib & 7) as usize | if insn.rex & REX_B != 0 { 8 } else { 0 };
+            let mut address = if index != 4 { self.r.gpr[index] << scale } else { 0 };
+            if base & 7 == 5 && md == 0 {
+                address = address.wrapping_add(self.imm(insn, 4)?);
+            } else {
+                address = address.wrapping_add(self.r.gpr[base]);
+                if base == RSP || base == RBP {
+                    default_seg = SS;
+                }
+            }
+            address
+        } else if rm == 5 && md == 0 {
+            rip_relative = insn.code64;
+            self.imm(insn, 4)?
+        } else {
+            let base = rm | if insn.rex & REX_B != 0 { 8 } else { 0 };
+            if base == RBP {
+                default_seg = SS;
+            }
+            self.r.gpr[base]
+        };
+
+        address = address.wrapping_add(match md {
+            1 => self.imm8(insn)?,
+            2 => self.imm(insn, 4)?,
+            _ => 0,
+        });
+
+        Ok((reg, Operand::Mem {
+            seg: insn.seg.unwrap_or(default_seg),
+            offset: address & mask(insn.adsize),
+            rip_relative,
+        }))
+    }
+
+    // Returns the offset of a memory operand, which for RIP-relative
+    // operands depends on the length of the whole instruction
+    fn offset(&self, insn: &Insn, offset: u64, rip_relative: bool) -> u64 {
+        if rip_relative { insn.next().wrapping_add(offset) } else { offset }
+    }
+
+    fn read_reg(&self, insn: &Insn, reg: usize, size: usize) -> u64 {
+        // Without a REX prefix, byte registers 4 to 7 are AH, CH, DH and BH
+        if size == 1 && insn.rex == 0 && (4..8).contains(&reg) {
+            (self.r.gpr[reg - 4] >> 8) & 0xff
+        } else {
+            self.reg(reg, size)
+        }
+    }
+
+    fn write_reg(&mut self, insn: &Insn, reg: usize, size: usize, value: u64) {
+        if size == 1 && insn.rex == 0 && (4..8).contains(&reg) {
+            let old = self.r.gpr[reg - 4];
+            self.r.gpr[reg - 4] = (old & !0xff00) | ((value & 0xff) << 8);
+        } else {
+            self.set_reg(reg, size, value);
+        }
+    }
+
+    fn read(&self, insn: &Insn, op: Operand, size: usize) -> Result<u64, Fault> {
+        match op {
+            Operand::Reg(reg) => Ok(self.read_reg(insn, reg, size)),
+            Operand::Mem { seg, offset, rip_relative } =>
+                self.read_mem(seg, self.offset(insn, offset, rip_relative), size),
+        }
+    }
+
+    fn write(&mut self, insn: &Insn, op: Operand, size: usize, value: u64) -> Result<(), Fault> {
+        match op {
+            Operand::Reg(reg) => {
+                self.write_reg(insn, reg, size, value);
+                Ok(())
+            },
+            Operand::Mem { seg, offset, rip_relative } =>
+                self.write_mem(seg, self.offset(insn, offset, rip_relative), size, value),
+        }
+    }
+
+    fn mem_operand(&self, insn: &Insn, op: Operand) -> Result<(usize, u64), Fault> {
+        match op {
+            Operand::Mem { seg, offset, rip_relative } =>
+                Ok((seg, self.offset(insn, offset, rip_relative))),
+            Operand::Reg(_) => Err(ud()),
+        }
+    }
+
+    // Flags
+
+    fn set_flags(&mut self, mask: u64, flags: u64) {
+        self.r.rflags = (self.r.rflags & !mask) | (flags & mask);
+    }
+
+    fn result_flags(result: u64, size: usize) -> u64 {
+        let result = result & mask(size);
+        let mut flags = 0;
+        if result == 0 {
+            flags |= FLAG_ZF;
+        }
+        if msb(result, size) != 0 {
+            flags |= FLAG_SF;
+        }
+        if (result & 0xff).count_ones().is_multiple_of(2) {
+            flags |= FLAG_PF;
+        }
+        flags
+    }
+
+    fn set_result_flags(&mut self, result: u64, size: usize, cf: bool, of: bool, af: bool) {
+        let mut flags = Self::result_flags(result, size);
+        if cf { flags |= FLAG_CF; }
+        if of { flags |= FLAG_OF; }
+        if af { flags |= FLAG_AF; }
+        self.set_flags(ARITH_FLAGS, flags);
+    }
+
+    fn flag(&self, flag: u64) -> bool {
+        self.r.rflags & flag != 0
+    }
+
+    fn condition(&self, cc: u8) -> bool {
+        let result = match cc >> 1 {
+            0 => self.flag(FLAG_OF),
+            1 => self.flag(FLAG_CF),
+            2 => self.flag(FLAG_ZF),
+            3 => self.flag(FLAG_CF) || self.flag(FLAG_ZF),
+            4 => self.flag(FLAG_SF),
+            5 => self.flag(FLAG_PF),
+            6 => self.flag(FLAG_SF) != self.flag(FLAG_OF),
+            _ => self.flag(FLAG_ZF) || self.flag(FLAG_SF) != self.flag(FLAG_OF),
+        };
+        result != (cc & 1 != 0)
+    }
+
+    // Arithmetic
+
+    // Performs ADD, OR, ADC, SBB, AND, SUB, XOR or CMP, numbered as in
+    // their encodings
+    fn alu(&mut self, op: u8, a: u64, b: u64, size: usize) -> u64 {
+        let m = mask(size);
+        let bits = size * 8;
+        let (a, b) = (a & m, b & m);
+        let carry = if self.flag(FLAG_CF) { 1 } else { 0 };
+
+        let (result, cf, of) = match op {
+            0 | 2 => {
+                let c = if op == 2 { carry } else { 0 };
+                let sum = a as u128 + b as u128 + c as u128;
+                let result = sum as u64 & m;
+                (result, sum >> bits != 0, msb((a ^ result) & (b ^ result), size) != 0)
+            },
+            3 | 5 | 7 => {
+                let c = if op == 3 { carry } else { 0 };
+                let result = a.wrapping_sub(b).wrapping_sub(c) & m;
+                ((result), (a as u128) < b as u128 + c as u128, msb((a ^ b) & (a ^ result), size) != 0)
+            },
+            1 => (a | b, false, false),
+            4 => (a & b, false, false),
+            _ => (a ^ b, false, false),
+        };
+
+        let af = matches!(op, 0 | 2 | 3 | 5 | 7) && (a ^ b ^ result) & 0x10 != 0;
+        self.set_result_flags(result, size, cf, of, af);
+        result
+    }
+
+    // Performs ROL, ROR, RCL, RCR, SHL, SHR, SAL or SAR, numbered as in
+    // their encodings
+    fn shift(&mut self, op: u8, value: u64, count: u64, size: usize) -> u64 {
+        let m = mask(size);
+        let bits = (size * 8) as u64;
+        let value = value & m;
+        let count = count & if size == 8 { 0x3f } else { 0x1f };
+        if count == 0 {
+            return value;
+        }
+
+        match op {
+            0 | 1 => {
+                let c = count % bits;
+                let result = if c == 0 {
+                    value
+                } else if op == 0 {
+                    ((value << c) | (value >> (bits - c))) & m
+                } else {
+                    ((value >> c) | (value << (bits - c))) & m
+                };
+                let cf = if op == 0 { result & 1 } else { msb(result, size) };
+                let of = if op == 0 {
+                    msb(result, size) ^ cf
+                } else {
+                    msb(result, size) ^ ((result >> (bits - 2)) & 1)
+                };
+                self.set_flags(FLAG_CF | FLAG_OF, (cf * FLAG_CF) | (of * FLAG_OF));
+                result
+            },
+            2 | 3 => {
+                let mut result = value;
+                let mut cf = if self.flag(FLAG_CF) { 1 } else { 0 };
+                let mut of = 0;
+                for _ in 0..count % (bits + 1) {
+                    if op == 2 {
+                        let out = msb(result, size);
+                        result = ((result << 1) | cf) & m;
+                        cf = out;
+                        of = msb(result, size) ^ cf;
+                    } else {
+                        of = msb(result, size) ^ cf;
+                        let out = result & 1;
+                        result = (result >> 1) | (cf << (bits - 1));
+                        cf = out;
+                    }
+                }
+                self.set_flags(FLAG_CF | FLAG_OF, (cf * FLAG_CF) | (of * FLAG_OF));
+                result
+            },
+            4 | 6 => {
+                let result = if count < bits { (value << count) & m } else { 0 };
+                let cf = if count <= bits { (value >> (bits - count)) & 1 } else { 0 };
+                let of = msb(result, size) ^ cf;
+                self.set_result_flags(result, size, cf != 0, of != 0, false);
+                result
+            },
+            5 => {
+                let result = if count < bits { value >> count } else { 0 };
+                let cf = if count <= bits { (value >> (count - 1)) & 1 } else { 0 };
+                self.set_result_flags(result, size, cf != 0, msb(value, size) != 0, false);
+                result
+            },
+            _ => {
+                let signed = sign_extend(value, size) as i64;
+                let result = (signed >> ::std::cmp::min(count, 63)) as u64 & m;
+                let cf = (signed >> ::std::cmp::min(count - 1, 63)) & 1;
+                self.set_result_flags(result, size, cf != 0, false, false);
+                result
+            },
+        }
+    }
+
+    // Performs SHLD or SHRD
+    fn double_shift(&mut self, left: bool, dst: u64, src: u64, count: u64, size: usize) -> u64 {
+        let m = mask(size);
+        let bits = (size * 8) as u64;
+        let count = (count & if size == 8 { 0x3f } else { 0x1f }) % bits;
+        let (dst, src) = (dst & m, src & m);
+        if count == 0 {
+            return dst;
+        }
+        let (result, cf) = if left {
+            (((dst << count) | (src >> (bits - count))) & m, (dst >> (bits - count)) & 1)
+        } else {
+            (((dst >> count) | (src << (bits - count))) & m, (dst >> (count - 1)) & 1)
+        };
+        let of = msb(result, size) != msb(dst, size);
+        self.set_result_flags(result, size, cf != 0, of, false);
+        result
+    }
+
+    // Performs MUL, IMUL, DIV or IDIV on the accumulator
+    fn multiply_divide(&mut self, op: u8, src: u64, size: usize) -> Result<(), Fault> {
+        let m = mask(size);
+        let bits = size * 8;
+        let (lo, hi) = if size == 1 {
+            (self.reg(RAX, 1), self.reg(RAX, 2) >> 8)
+        } else {
+            (self.reg(RAX, size), self.reg(RDX, size))
+        };
+        let src = src & m;
+
+        let (result_lo, result_hi) = match op {
+            4 => {
+                let product = lo as u128 * src as u128;
+                let hi = (product >> bits) as u64 & m;
+                self.set_result_flags(product as u64, size, hi != 0, hi != 0, false);
+                (product as u64 & m, hi)
+            },
+            5 => {
+                let product = sign_extend(lo, size) as i64 as i128 * sign_extend(src, size) as i64 as i128;
+                let truncated = product as u64 & m;
+                let overflow = sign_extend(truncated, size) as i64 as i128 != product;
+                self.set_result_flags(truncated, size, overflow, overflow, false);
+                (truncated, (product >> bits) as u64 & m)
+            },
+            6 => {
+                if src == 0 {
+                    return Err(exception(EXC_DE, None));
+                }
+                let dividend = ((hi as u128) << bits) | lo as u128;
+                let quotient = dividend / src as u128;
+                if quotient > m as u128 {
+                    return Err(exception(EXC_DE, None));
+                }
+                (quotient as u64, (dividend % src as u128) as u64)
+            },
+            _ => {
+                if src == 0 {
+                    return Err(exception(EXC_DE, None));
+                }
+                let dividend = ((((hi as u128) << bits) | lo as u128) << (128 - 2 * bits)) as i128
+                    >> (128 - 2 * bits);
+                let divisor = sign_extend(src, size) as i64 as i128;
+                let quotient = dividend.checked_div(divisor).ok_or_else(|| exception(EXC_DE, None))?;
+                if sign_extend(quotient as u64 & m, size) as i64 as i128 != quotient {
+                    return Err(exception(EXC_DE, None));
+                }
+                (quotient as u64 & m, (dividend % divisor) as u64 & m)
+            },
+        };
+
+        if size == 1 {
+            self.set_reg(RAX, 2, (result_hi << 8) | result_lo);
+        } else {
+            self.set_reg(RAX, size, result_lo);
+            self.set_reg(RDX, size, result_hi);
+        }
+        Ok(())
+    }
+
+    // Performs a two or three operand IMUL
+    fn imul(&mut self, a: u64, b: u64, size: usize) -> u64 {
+        let product = sign_extend(a, size) as i64 as i128 * sign_extend(b, size) as i64 as i128;
+        let truncated = product as u64 & mask(size);
+        let overflow = sign_extend(truncated, size) as i64 as i128 != product;
+        self.set_result_flags(truncated, size, overflow, overflow, false);
+        truncated
+    }
+
+    // Execution
+
+    fn execute(&mut self, insn: &mut Insn) -> Result<Flow, Fault> {
+        let mut opsize_prefix = false;
+        let mut adsize_prefix = false;
+        let opcode = loop {
+            let byte = self.fetch(insn)?;
+            match byte {
+                0x26 => insn.seg = Some(ES),
+                0x2e => insn.seg = Some(CS),
+                0x36 => insn.seg = Some(SS),
+                0x3e => insn.seg = Some(DS),
+                0x64 => insn.seg = Some(FS),
+                0x65 => insn.seg = Some(GS),
+                0x66 => opsize_prefix = true,
+                0x67 => adsize_prefix = true,
+                0xf0 => (),
+                0xf2 | 0xf3 => insn.rep = byte,
+                0x40..=0x4f if insn.code64 => {
+                    insn.rex = byte;
+                    continue;
+                },
+                _ => break byte,
+            }
+            // A REX prefix only counts when it comes right before the opcode
+            insn.rex = 0;
+        };
+
+        if insn.code64 {
+            // Segment overrides other than FS and GS are ignored
+            if insn.seg.is_some_and(|s| s != FS && s != GS) {
+                insn.seg = None;
+            }
+            insn.opsize = if insn.rex & REX_W != 0 { 8 } else if opsize_prefix { 2 } else { 4 };
+            insn.adsize = if adsize_prefix { 4 } else { 8 };
+        } else {
+            let default32 = self.code32();
+            insn.opsize = if default32 != opsize_prefix { 4 } else { 2 };
+            insn.adsize = if default32 != adsize_prefix { 4 } else { 2 };
+        }
+
+        if opcode == 0x0f {
+            let opcode = self.fetch(insn)?;
+            return self.execute_0f(insn, opcode);
+        }
+
+        let size = insn.opsize;
+        let byte_op = opcode & 1 == 0;
+        match opcode {
+            // ALU operations in their six encodings
+            0x00..=0x3f if opcode & 7 < 6 => {
+                let op = opcode >> 3;
+                let s = if byte_op { 1 } else { size };
+                match opcode & 7 {
+                    0 | 1 => {
+                        let (reg, rm) = self.modrm(insn)?;
+                        let a = self.read(insn, rm, s)?;
+                        let b = self.read_reg(insn, reg, s);
+                        let result = self.alu(op, a, b, s);
+                        if op != 7 {
+                            self.write(insn, rm, s, result)?;
+                        }
+                    },
+                    2 | 3 => {
+                        let (reg, rm) = self.modrm(insn)?;
+                        let a = self.read_reg(insn, reg, s);
+                        let b = self.read(insn, rm, s)?;
+                        let result = self.alu(op, a, b, s);
+                        if op != 7 {
+                            self.write_reg(insn, reg, s, result);
+                        }
+                    },
+                    _ => {
+                        let s = if opcode & 7 == 4 { 1 } else { size };
+                        let b = self.imm(insn, s)?;
+                        let a = self.reg(RAX, s);
+                        let result = self.alu(op, a, b, s);
+                        if op != 7 {
+                            self.set_reg(RAX, s, result);
+                        }
+                    },
+                }
+                Ok(Flow::Next)
+            },
+            0x06 | 0x0e | 0x16 | 0x1e if !insn.code64 => {
+                let selector = self.r.seg[(opcode >> 3) as usize].0;
+                self.push(size, selector as u64)?;
+                Ok(Flow::Next)
+            },
+            0x07 | 0x17 | 0x1f if !insn.code64 => {
+                let seg = (opcode >> 3) as usize;
+                let selector = self.pop(size)? as u16;
+                self.load_segment(seg, selector)?;
+                if seg == SS {
+                    self.r.interruptibility |= BLOCKING_MOV_SS;
+                }
+                Ok(Flow::Next)
+            },
+            0x40..=0x4f => {
+                let reg = (opcode & 7) as usize;
+                let carry = self.r.rflags & FLAG_CF;
+                let op = if opcode < 0x48 { 0 } else { 5 };
+                let value = self.reg(reg, size);
+                let result = self.alu(op, value, 1, size);
+                self.set_flags(FLAG_CF, carry);
+                self.set_reg(reg, size, result);
+                Ok(Flow::Next)
+            },
+            0x50..=0x57 => {
+                let reg = (opcode & 7) as usize | if insn.rex & REX_B != 0 { 8 } else { 0 };
+                let s = insn.stack_opsize();
+                let value = self.reg(reg, s);
+                self.push(s, value)?;
+                Ok(Flow::Next)
+            },
+            0x58..=0x5f => {
+                let reg = (opcode & 7) as usize | if insn.rex & REX_B != 0 { 8 } else { 0 };
+                let s = insn.stack_opsize();
+                let value = self.pop(s)?;
+                self.set_reg(reg, s, value);
+                Ok(Flow::Next)
+            },
+            0x60 if !insn.code64 => {
+                let sp = self.reg(RSP, size);
+                for reg in 0..8 {
+                    let value = if reg == RSP { sp } else { self.reg(reg, size) };
+                    self.push(size, value)?;
+                }
+                Ok(Flow::Next)
+            },
+            0x61 if !insn.code64 => {
+                for reg in (0..8).rev() {
+                    let value = self.pop(size)?;
+                    if reg != RSP {
+                        self.set_reg(reg, size, value);
+                    }
+                }
+                Ok(Flow::Next)
+            },
+            0x63 if insn.code64 => {
+                let (reg, rm) = self.modrm(insn)?;
+                let value = sign_extend(self.read(insn, rm, 4)?, 4);
+                self.set_reg(reg, size, value);
+                Ok(Flow::Next)
+            },
+            0x68 | 0x6a => {
+                let value = if opcode == 0x68 { self.imm(insn, size)? } else { self.imm8(insn)? };
+                let s = insn.stack_opsize();
+                self.push(s, value)?;
+                Ok(Flow::Next)
+            },
+            0x69 | 0x6b => {
+                let (reg, rm) = self.modrm(insn)?;
+                let b = if opcode == 0x69 { self.imm(insn, size)? } else { self.imm8(insn)? };
+                let a = self.read(insn, rm, size)?;
+                let result = self.imul(a, b, size);
+                self.set_reg(reg, size, result);
+                Ok(Flow::Next)
+            },
+            0x6c..=0x6f => {
+                let s = if byte_op { 1 } else if size == 2 { 2 } else { 4 };
+                let port = self.reg(RDX, 2) as u16;
+                self.io(insn, port, s, opcode < 0x6e, true, false)
+            },
+            0x70..=0x7f => {
+                let rel = self.imm8(insn)?;
+                self.branch(insn, self.condition(opcode & 0xf), rel)
+            },
+            0x80..=0x83 => {
+                if opcode == 0x82 && insn.code64 {
+                    return Err(ud());
+                }
+                let s = if opcode == 0x81 || opcode == 0x83 { size } else { 1 };
+                let (op, rm) = self.modrm(insn)?;
+                let b = if opcode == 0x81 { self.imm(insn, s)? } else { self.imm8(insn)? };
+                let a = self.read(insn, rm, s)?;
+                let result = self.alu((op & 7) as u8, a, b, s);
+                if op & 7 != 7 {
+                    self.write(insn, rm, s, result)?;
+                }
+                Ok(Flow::Next)
+            },
+            0x84 | 0x85 => {
+                let s = if byte_op { 1 } else { size };
+                let (reg, rm) = self.modrm(insn)?;
+                let a = self.read(insn, rm, s)?;
+                let b = self.read_reg(insn, reg, s);
+                self.alu(4, a, b, s);
+                Ok(Flow::Next)
+            },
+            0x86 | 0x87 => {
+                let s = if byte_op { 1 } else { size };
+                let (reg, rm) = self.modrm(insn)?;
+                let a = self.read(insn, rm, s)?;
+                let b = self.read_reg(insn, reg, s);
+                self.write(insn, rm, s, b)?;
+                self.write_reg(insn, reg, s, a);
+                Ok(Flow::Next)
+            },
+            0x88 | 0x89 => {
+                let s = if byte_op { 1 } else { size };
+                let (reg, rm) = self.modrm(insn)?;
+                let value = self.read_reg(insn, reg, s);
+                self.write(insn, rm, s, value)?;
+                Ok(Flow::Next)
+            },
+            0x8a | 0x8b => {
+                let s = if byte_op { 1 } else { size };
+                let (reg, rm) = self.modrm(insn)?;
+                let value = self.read(insn, rm, s)?;
+                self.write_reg(insn, reg, s, value);
+                Ok(Flow::Next)
+            },
+            0x8c => {
+                let (reg, rm) = self.modrm(insn)?;
+                if reg & 7 > GS {
+                    return Err(ud());
+                }
+                let selector = self.r.seg[reg & 7].0 as u64;
+                let s = if let Operand::Reg(_) = rm { size } else { 2 };
+                self.write(insn, rm, s, selector)?;
+                Ok(Flow::Next)
+            },
+            0x8d => {
+                let (reg, rm) = self.modrm(insn)?;
+                let (_, offset) = self.mem_operand(insn, rm)?;
+                self.set_reg(reg, size, offset & mask(insn.adsize));
+                Ok(Flow::Next)
+            },
+            0x8e => {
+                let (reg, rm) = self.modrm(insn)?;
+                let seg = reg & 7;
+                if seg == CS || seg > GS {
+                    return Err(ud());
+                }
+                let selector = self.read(insn, rm, 2)? as u16;
+                self.load_segment(seg, selector)?;
+                if seg == SS {
+                    self.r.interruptibility |= BLOCKING_MOV_SS;
+                }
+                Ok(Flow::Next)
+            },
+            0x8f => {
+                let (_, rm) = self.modrm(insn)?;
+                let s = insn.stack_opsize();
+                let value = self.pop(s)?;
+                self.write(insn, rm, s, value)?;
+                Ok(Flow::Next)
+            },
+            0x90 if insn.rex & REX_B == 0 => Ok(Flow::Next),
+            0x90..=0x97 => {
+                let reg = (opcode & 7) as usize | if insn.rex & REX_B != 0 { 8 } else { 0 };
+                let a = self.reg(RAX, size);
+                let b = self.reg(reg, size);
+                self.set_reg(RAX, size, b);
+                self.set_reg(reg, size, a);
+                Ok(Flow::Next)
+            },
+            0x98 => {
+                let half = size / 2;
+                let value = sign_extend(self.reg(RAX, half), half);
+                self.set_reg(RAX, size, value);
+                Ok(Flow::Next)
+            },
+            0x99 => {
+                let value = if msb(self.reg(RAX, size), size) != 0 { !0 } else { 0 };
+                self.set_reg(RDX, size, value);
+                Ok(Flow::Next)
+            },
+            0x9a if !insn.code64 => {
+                let offset = self.fetch_n(insn, size)?;
+                let selector = self.fetch_n(insn, 2)? as u16;
+                self.far_call(insn, selector, offset)
+            },
+            0x9b => Ok(Flow::Next),
+            0x9c => {
+                let s = insn.stack_opsize();
+                // VM and RF are never pushed
+                let flags = self.r.rflags & !(FLAG_VM | FLAG_RF);
+                self.push(s, flags)?;
+                Ok(Flow::Next)
+            },
+            0x9d => {
+                let s = insn.stack_opsize();
+                let value = self.pop(s)?;
+                self.load_flags(value, s, false);
+                Ok(Flow::Next)
+            },
+            0x9e => {
+                let ah = self.reg(RAX, 2) >> 8;
+                self.set_flags(FLAG_SF | FLAG_ZF | FLAG_AF | FLAG_PF | FLAG_CF, ah);
+                Ok(Flow::Next)
+            },
+            0x9f => {
+                let flags = (self.r.rflags & 0xd5) | 0x2;
+                let ax = (self.reg(RAX, 2) & 0xff) | (flags << 8);
+                self.set_reg(RAX, 2, ax);
+                Ok(Flow::Next)
+            },
+            0xa0..=0xa3 => {
+                let s = if byte_op { 1 } else { size };
+                let offset = self.fetch_n(insn, insn.adsize)?;
+                let seg = insn.seg.unwrap_or(DS);
+                if opcode < 0xa2 {
+                    let value = self.read_mem(seg, offset, s)?;
+                    self.set_reg(RAX, s, value);
+                } else {
+                    let value = self.reg(RAX, s);
+                    self.write_mem(seg, offset, s, value)?;
+                }
+                Ok(Flow::Next)
+            },
+            0xa4..=0xa7 | 0xaa..=0xaf => {
+                let s = if byte_op { 1 } else { size };
+                self.string(insn, opcode, s)
+            },
+            0xa8 | 0xa9 => {
+                let s = if byte_op { 1 } else { size };
+                let b = self.imm(insn, s)?;
+                let a = self.reg(RAX, s);
+                self.alu(4, a, b, s);
+                Ok(Flow::Next)
+            },
+            0xb0..=0xb7 => {
+                let reg = (opcode & 7) as usize | if insn.rex & REX_B != 0 { 8 } else { 0 };
+                let value = self.fetch(insn)? as u64;
+                self.write_reg(insn, reg, 1, value);
+                Ok(Flow::Next)
+            },
+            0xb8..=0xbf => {
+                let reg = (opcode & 7) as usize | if insn.rex & REX_B != 0 { 8 } else { 0 };
+                let value = self.fetch_n(insn, size)?;
+                self.set_reg(reg, size, value);
+                Ok(Flow::Next)
+            },
+            0xc0 | 0xc1 | 0xd0..=0xd3 => {
+                let s = if byte_op { 1 } else { size };
+                let (op, rm) = self.modrm(insn)?;
+                let count = match opcode {
+                    0xc0 | 0xc1 => self.fetch(insn)? as u64,
+                    0xd0 | 0xd1 => 1,
+                    _ => self.reg(RCX, 1),
+                };
+                let value = self.read(insn, rm, s)?;
+                let result = self.shift((op & 7) as u8, value, count, s);
+                self.write(insn, rm, s, result)?;
+                Ok(Flow::Next)
+            },
+            0xc2 | 0xc3 => {
+                let release = if opcode == 0xc2 { self.fetch_n(insn, 2)? } else { 0 };
+                let s = insn.stack_opsize();
+                let target = self.pop(s)?;
+                if release != 0 {
+                    let ss = self.stack_size();
+                    let rsp = self.reg(RSP, ss).wrapping_add(release);
+                    self.set_reg(RSP, ss, rsp);
+                }
+                Ok(Flow::Jump(target & mask(s)))
+            },
+            0xc6 | 0xc7 => {
+                let s = if byte_op { 1 } else { size };
+                let (op, rm) = self.modrm(insn)?;
+                if op & 7 != 0 {
+                    return Err(ud());
+                }
+                let value = self.imm(insn, s)?;
+                self.write(insn, rm, s, value)?;
+                Ok(Flow::Next)
+            },
+            0xc8 => {
+                let frame = self.fetch_n(insn, 2)?;
+                if self.fetch(insn)? != 0 {
+                    return Err(ud());
+                }
+                let s = insn.stack_opsize();
+                let ss = self.stack_size();
+                let rbp = self.reg(RBP, s);
+                self.push(s, rbp)?;
+                let rsp = self.reg(RSP, ss);
+                self.set_reg(RBP, s, rsp);
+                self.set_reg(RSP, ss, rsp.wrapping_sub(frame));
+                Ok(Flow::Next)
+            },
+            0xc9 => {
+                let s = insn.stack_opsize();
+                let ss = self.stack_size();
+                let rbp = self.reg(RBP, ss);
+                self.set_reg(RSP, ss, rbp);
+                let value = self.pop(s)?;
+                self.set_reg(RBP, s, value);
+                Ok(Flow::Next)
+            },
+            0xca | 0xcb => {
+                let release = if opcode == 0xca { self.fetch_n(insn, 2)? } else { 0 };
+                self.far_return(insn, release)
+            },
+            0xcc => self.software_exception(insn, EXC_BP),
+            0xcd => {
+                let vector = self.fetch(insn)?;
+                self.software_interrupt(insn, vector)
+            },
+            0xce if !insn.code64 => {
+                if self.flag(FLAG_OF) {
+                    self.software_exception(insn, EXC_OF)
+                } else {
+                    Ok(Flow::Next)
+                }
+            },
+            0xcf => self.iret(insn),
+            0xd7 => {
+                let offset = self.reg(RBX, insn.adsize).wrapping_add(self.reg(RAX, 1));
+                let value = self.read_mem(insn.seg.unwrap_or(DS), offset & mask(insn.adsize), 1)?;
+                self.set_reg(RAX, 1, value);
+                Ok(Flow::Next)
+            },
+            0xe0..=0xe3 => {
+                let rel = self.imm8(insn)?;
+                let a = insn.adsize;
+                let taken = if opcode == 0xe3 {
+                    self.reg(RCX, a) == 0
+                } else {
+                    let count = self.reg(RCX, a).wrapping_sub(1) & mask(a);
+                    self.set_reg(RCX, a, count);
+                    count != 0 && match opcode {
+                        0xe0 => !self.flag(FLAG_ZF),
+                        0xe1 => self.flag(FLAG_ZF),
+                        _ => true,
+                    }
+                };
+                self.branch(insn, taken, rel)
+            },
+            0xe4..=0xe7 | 0xec..=0xef => {
+                let s = if byte_op { 1 } else if size == 2 { 2 } else { 4 };
+                let immediate = opcode < 0xe8;
+                let port = if immediate { self.fetch(insn)? as u16 } else { self.reg(RDX, 2) as u16 };
+                self.io(insn, port, s, opcode & 2 == 0, false, immediate)
+            },
+            0xe8 => {
+                let rel = self.imm(insn, if insn.code64 { 4 } else { size })?;
+                let s = insn.stack_opsize();
+                let next = insn.next();
+                self.push(s, next)?;
+                Ok(Flow::Jump(next.wrapping_add(rel) & mask(s)))
+            },
+            0xe9 | 0xeb => {
+                let rel = if opcode == 0xeb {
+                    self.imm8(insn)?
+                } else {
+                    self.imm(insn, if insn.code64 { 4 } else { size })?
+                };
+                self.branch(insn, true, rel)
+            },
+            0xea if !insn.code64 => {
+                let offset = self.fetch_n(insn, size)?;
+                let selector = self.fetch_n(insn, 2)? as u16;
+                self.load_code_segment(selector)?;
+                Ok(Flow::Jump(offset))
+            },
+            0xf4 => {
+                self.require_cpl0()?;
+                Err(Exit::new(VMX_REASON_HLT).into())
+            },
+            0xf5 => {
+                let carry = self.r.rflags & FLAG_CF;
+                self.set_flags(FLAG_CF, !carry);
+                Ok(Flow::Next)
+            },
+            0xf6 | 0xf7 => {
+                let s = if byte_op { 1 } else { size };
+                let (op, rm) = self.modrm(insn)?;
+                match op & 7 {
+                    0 | 1 => {
+                        let b = self.imm(insn, s)?;
+                        let a = self.read(insn, rm, s)?;
+                        self.alu(4, a, b, s);
+                    },
+                    2 => {
+                        let a = self.read(insn, rm, s)?;
+                        self.write(insn, rm, s, !a)?;
+                    },
+                    3 => {
+                        let a = self.read(insn, rm, s)?;
+                        let result = self.alu(5, 0, a, s);
+                        self.write(insn, rm, s, result)?;
+                    },
+                    op => {
+                        let src = self.read(insn, rm, s)?;
+                        self.multiply_divide(op as u8, src, s)?;
+                    },
+                }
+                Ok(Flow::Next)
+            },
+            0xf8 | 0xf9 => {
+                self.set_flags(FLAG_CF, if opcode == 0xf9 { FLAG_CF } else { 0 });
+                Ok(Flow::Next)
+            },
+            0xfa | 0xfb => {
+                let iopl = ((self.r.rflags >> 12) & 3) as u8;
+                if self.protected() && self.cpl() > iopl {
+                    return Err(gp(0));
+                }
+                if opcode == 0xfb {
+                    // STI delays interrupts until after the next instruction
+                    if !self.flag(FLAG_IF) {
+                        self.r.interruptibility |= BLOCKING_STI;
+                    }
+                    self.r.rflags |= FLAG_IF;
+                } else {
+                    self.r.rflags &= !FLAG_IF;
+                }
+                Ok(Flow::Next)
+            },
+            0xfc | 0xfd => {
+                self.set_flags(FLAG_DF, if opcode == 0xfd { FLAG_DF } else { 0 });
+                Ok(Flow::Next)
+            },
+            0xfe | 0xff => {
+                let s = if byte_op { 1 } else { size };
+                let (op, rm) = self.modrm(insn)?;
+                match (op & 7, opcode) {
+                    (0, _) | (1, _) => {
+                        let carry = self.r.rflags & FLAG_CF;
+                        let value = self.read(insn, rm, s)?;
+                        let result = self.alu(if op & 7 == 0 { 0 } else { 5 }, value, 1, s);
+                        self.set_flags(FLAG_CF, carry);
+                        self.write(insn, rm, s, result)?;
+                        Ok(Flow::Next)
+                    },
+                    (2, 0xff) => {
+                        let s = insn.stack_opsize();
+                        let target = self.read(insn, rm, s)?;
+                        let next = insn.next();
+                        self.push(s, next)?;
+                        Ok(Flow::Jump(target))
+                    },
+                    (4, 0xff) => {
+                        let s = insn.stack_opsize();
+                        Ok(Flow::Jump(self.read(insn, rm, s)?))
+                    },
+                    (3, 0xff) | (5, 0xff) => {
+                        let (seg, offset) = self.mem_operand(insn, rm)?;
+                        let target = self.read_mem(seg, offset, size)?;
+                        let selector = self.read_mem(seg, offset.wrapping_add(size as u64), 2)? as u16;
+                        if op & 7 == 3 {
+                            self.far_call(insn, selector, target)
+                        } else {
+                            self.load_code_segment(selector)?;
+                            Ok(Flow::Jump(target))
+                        }
+                    },
+                    (6, 0xff) => {
+                        let s = insn.stack_opsize();
+                        let value = self.read(insn, rm, s)?;
+                        self.push(s, value)?;
+                        Ok(Flow::Next)
+                    },
+                    _ => Err(ud()),
+                }
+            },
+            _ => Err(ud()),
+        }
+    }
+
+    fn execute_0f(&mut self, insn: &mut Insn, opcode: u8) -> Result<Flow, Fault> {
+        let size = insn.opsize;
+        match opcode {
+            0x00 => {
+                if !self.protected() || self.r.rflags & FLAG_VM != 0 {
+                    return Err(ud());
+                }
+                let (op, rm) = self.modrm(insn)?;
+                match op & 7 {
+                    0 | 1 => {
+                        let seg = if op & 7 == 0 { LDTR } else { TR };
+                        let selector = self.r.seg[seg].0 as u64;
+                        let s = if let Operand::Reg(_) = rm { size } else { 2 };
+                        self.write(insn, rm, s, selector)?;
+                    },
+                    2 | 3 => {
+                        self.require_cpl0()?;
+                        let selector = self.read(insn, rm, 2)? as u16;
+                        let seg = if op & 7 == 2 { LDTR } else { TR };
+                        self.load_system_segment(seg, selector)?;
+                    },
+                    _ => return Err(ud()),
+                }
+                Ok(Flow::Next)
+            },
+            0x01 => self.execute_0f01(insn),
+            0x06 => {
+                self.require_cpl0()?;
+                let cr0 = self.r.cr0 & !CR0_TS;
+                self.write_cr0(cr0, Exit::qualification(VMX_REASON_MOV_CR, 2 << 4))?;
+                Ok(Flow::Next)
+            },
+            0x08 => {
+                self.require_cpl0()?;
+                Err(Exit::new(VMX_REASON_INVD).into())
+            },
+            0x09 => {
+                self.require_cpl0()?;
+                if self.secondary(CPU_BASED2_WBINVD) {
+                    return Err(Exit::new(VMX_REASON_WBINVD).into());
+                }
+                Ok(Flow::Next)
+            },
+            0x0b => Err(ud()),
+            0x0d | 0x18..=0x1f => {
+                // Prefetches and hinting NOPs
+                self.modrm(insn)?;
+                Ok(Flow::Next)
+            },
+            0x20 | 0x22 => {
+                let (cr, rm) = self.modrm(insn)?;
+                let gpr = match rm {
+                    Operand::Reg(reg) => reg,
+                    Operand::Mem { .. } => return Err(ud()),
+                };
+                self.require_cpl0()?;
+                if opcode == 0x20 {
+                    self.read_cr(insn, cr, gpr)?;
+                } else {
+                    self.mov_to_cr(insn, cr, gpr)?;
+                }
+                Ok(Flow::Next)
+            },
+            0x21 | 0x23 => {
+                let (dr, rm) = self.modrm(insn)?;
+                let gpr = match rm {
+                    Operand::Reg(reg) => reg,
+                    Operand::Mem { .. } => return Err(ud()),
+                };
+                self.require_cpl0()?;
+                if dr > 7 {
+                    return Err(ud());
+                }
+                if self.control(VMCS_CTRL_CPU_BASED) & CPU_BASED_MOV_DR != 0 {
+                    let direction = if opcode == 0x21 { 1 } else { 0 };
+                    let qualification = dr as u64 | direction << 4 | (gpr as u64) << 8;
+                    return Err(Exit::qualification(VMX_REASON_MOV_DR, qualification).into());
+                }
+                // DR4 and DR5 alias DR6 and DR7
+                let dr = if dr == 4 || dr == 5 { dr + 2 } else { dr };
+                let s = if insn.code64 { 8 } else { 4 };
+                if opcode == 0x21 {
+                    let value = self.r.dr[dr];
+                    self.set_reg(gpr, s, value);
+                } else {
+                    self.r.dr[dr] = self.reg(gpr, s);
+                }
+                Ok(Flow::Next)
+            },
+            0x30 | 0x32 => {
+                self.require_cpl0()?;
+                let msr = self.reg(RCX, 4) as u32;
+                if !self.st.native_msrs.contains(&msr) {
+                    let reason = if opcode == 0x30 { VMX_REASON_WRMSR } else { VMX_REASON_RDMSR };
+                    return Err(Exit::new(reason).into());
+                }
+                if opcode == 0x30 {
+                    let value = self.reg(RAX, 4) | self.reg(RDX, 4) << 32;
+                    self.write_native_msr(msr, value);
+                } else {
+                    let value = self.read_native_msr(msr);
+                    self.set_reg(RAX, 4, value);
+                    self.set_reg(RDX, 4, value >> 32);
+                }
+                Ok(Flow::Next)
+            },
+            0x31 => {
+                if self.control(VMCS_CTRL_CPU_BASED) & CPU_BASED_RDTSC != 0 {
+                    return Err(Exit::new(VMX_REASON_RDTSC).into());
+                }
+                let tsc = self.guest_tsc();
+                self.set_reg(RAX, 4, tsc);
+                self.set_reg(RDX, 4, tsc >> 32);
+                Ok(Flow::Next)
+            },
+            0x33 => {
+                if self.control(VMCS_CTRL_CPU_BASED) & CPU_BASED_RDPMC != 0 {
+                    return Err(Exit::new(VMX_REASON_RDPMC).into());
+                }
+                Err(gp(0))
+            },
+            0x40..=0x4f => {
+                let (reg, rm) = self.modrm(insn)?;
+                let value = self.read(insn, rm, size)?;
+                if self.condition(opcode & 0xf) {
+                    self.set_reg(reg, size, value);
+                } else if size == 4 {
+                    let old = self.reg(reg, 4);
+                    self.set_reg(reg, 4, old);
+                }
+                Ok(Flow::Next)
+            },
+            0x80..=0x8f => {
+                let rel = self.imm(insn, if insn.code64 { 4 } else { size })?;
+                self.branch(insn, self.condition(opcode & 0xf), rel)
+            },
+            0x90..=0x9f => {
+                let (_, rm) = self.modrm(insn)?;
+                let value = if self.condition(opcode & 0xf) { 1 } else { 0 };
+                self.write(insn, rm, 1, value)?;
+                Ok(Flow::Next)
+            },
+            0xa0 | 0xa8 => {
+                let seg = if opcode == 0xa0 { FS } else { GS };
+                let s = insn.stack_opsize();
+                let selector = self.r.seg[seg].0 as u64;
+                self.push(s, selector)?;
+                Ok(Flow::Next)
+            },
+            0xa1 | 0xa9 => {
+                let seg = if opcode == 0xa1 { FS } else { GS };
+                let s = insn.stack_opsize();
+                let selector = self.pop(s)? as u16;
+                self.load_segment(seg, selector)?;
+                Ok(Flow::Next)
+            },
+            0xa2 => Err(Exit::new(VMX_REASON_CPUID).into()),
+            0xa3 | 0xab | 0xb3 | 0xbb => {
+                let (reg, rm) = self.modrm(insn)?;
+                let offset = self.reg(reg, size);
+                let op = match opcode { 0xa3 => 0, 0xab => 1, 0xb3 => 2, _ => 3 };
+                self.bit_test(insn, op, rm, offset, true)
+            },
+            0xa4 | 0xa5 | 0xac | 0xad => {
+                let (reg, rm) = self.modrm(insn)?;
+                let count = if opcode & 1 == 0 { self.fetch(insn)? as u64 } else { self.reg(RCX, 1) };
+                let dst = self.read(insn, rm, size)?;
+                let src = self.reg(reg, size);
+                let result = self.double_shift(opcode < 0xa8, dst, src, count, size);
+                self.write(insn, rm, size, result)?;
+                Ok(Flow::Next)
+            },
+            0xae => {
+                let (op, rm) = self.modrm(insn)?;
+                match (op & 7, rm) {
+                    // LFENCE, MFENCE and SFENCE
+                    (5..=7, Operand::Reg(_)) => Ok(Flow::Next),
+                    _ => Err(ud()),
+                }
+            },
+            0xaf => {
+                let (reg, rm) = self.modrm(insn)?;
+                let b = self.read(insn, rm, size)?;
+                let a = self.reg(reg, size);
+                let result = self.imul(a, b, size);
+                self.set_reg(reg, size, result);
+                Ok(Flow::Next)
+            },
+            0xb0 | 0xb1 => {
+                let s = if opcode == 0xb0 { 1 } else { size };
+                let (reg, rm) = self.modrm(insn)?;
+                let dst = self.read(insn, rm, s)?;
+                let acc = self.reg(RAX, s);
+                self.alu(7, acc, dst, s);
+                if self.flag(FLAG_ZF) {
+                    let src = self.read_reg(insn, reg, s);
+                    self.write(insn, rm, s, src)?;
+                } else {
+                    self.set_reg(RAX, s, dst);
+                }
+                Ok(Flow::Next)
+            },
+            0xb6 | 0xb7 | 0xbe | 0xbf => {
+                let (reg, rm) = self.modrm(insn)?;
+                let s = if opcode & 1 == 0 { 1 } else { 2 };
+                let mut value = self.read(insn, rm, s)?;
+                if opcode >= 0xbe {
+                    value = sign_extend(value, s);
+                }
+                self.set_reg(reg, size, value);
+                Ok(Flow::Next)
+            },
+            0xba => {
+                let (op, rm) = self.modrm(insn)?;
+                let offset = self.fetch(insn)? as u64;
+                if op & 7 < 4 {
+                    return Err(ud());
+                }
+                self.bit_test(insn, (op & 3) as u8, rm, offset, false)
+            },
+            0xbc | 0xbd => {
+                let (reg, rm) = self.modrm(insn)?;
+                let value = self.read(insn, rm, size)?;
+                if value == 0 {
+                    self.set_flags(FLAG_ZF, FLAG_ZF);
+                } else {
+                    let index = if opcode == 0xbc {
+                        value.trailing_zeros() as u64
+                    } else {
+                        63 - value.leading_zeros() as u64
+                    };
+                    self.set_flags(FLAG_ZF, 0);
+                    self.set_reg(reg, size, index);
+                }
+                Ok(Flow::Next)
+            },
+            0xc0 | 0xc1 => {
+                let s = if opcode == 0xc0 { 1 } else { size };
+                let (reg, rm) = self.modrm(insn)?;
+                let dst = self.read(insn, rm, s)?;
+                let src = self.read_reg(insn, reg, s);
+                let sum = self.alu(0, dst, src, s);
+                self.write(insn, rm, s, sum)?;
+                self.write_reg(insn, reg, s, dst);
+                Ok(Flow::Next)
+            },
+            0xc8..=0xcf => {
+                let reg = (opcode & 7) as usize | if insn.rex & REX_B != 0 { 8 } else { 0 };
+                let value = self.reg(reg, size);
+                let swapped = if size == 8 {
+                    value.swap_bytes()
+                } else {
+                    (value as u32).swap_bytes() as u64
+                };
+                self.set_reg(reg, size, swapped);
+                Ok(Flow::Next)
+            },
+            _ => Err(ud()),
+        }
+    }
+
+    fn execute_0f01(&mut self, insn: &mut Insn) -> Result<Flow, Fault> {
+        let (op, rm) = self.modrm(insn)?;
+        let rm_reg = match rm {
+            Operand::Reg(reg) => Some(reg & 7),
+            Operand::Mem { .. } => None,
+        };
+
+        match (op & 7, rm_reg) {
+            (0, Some(1)) => Err(Exit::new(VMX_REASON_VMCALL).into()),
+            (2, Some(0)) => {
+                if self.reg(RCX, 4) != 0 {
+                    return Err(gp(0));
+                }
+                let xcr0 = self.st.register(x86Reg::XCR0);
+                self.set_reg(RAX, 4, xcr0);
+                self.set_reg(RDX, 4, xcr0 >> 32);
+                Ok(Flow::Next)
+            },
+            (2, Some(1)) => {
+                self.require_cpl0()?;
+                Err(Exit::new(VMX_REASON_XSETBV).into())
+            },
+            (7, Some(0)) if insn.code64 => {
+                self.require_cpl0()?;
+                let gs = self.r.seg[GS].1;
+                let kernel = self.st.msr(MSR_KERNEL_GS_BASE);
+                self.r.seg[GS].1 = kernel;
+                self.st.msrs.insert(MSR_KERNEL_GS_BASE, gs);
+                Ok(Flow::Next)
+            },
+            (7, Some(1)) => {
+                if self.control(VMCS_CTRL_CPU_BASED) & CPU_BASED_RDTSC != 0 {
+                    return Err(Exit::new(VMX_REASON_RDTSCP).into());
+                }
+                let tsc = self.guest_tsc();
+                let aux = self.st.msr(MSR_TSC_AUX);
+                self.set_reg(RAX, 4, tsc);
+                self.set_reg(RDX, 4, tsc >> 32);
+                self.set_reg(RCX, 4, aux);
+                Ok(Flow::Next)
+            },
+            (0, None) | (1, None) => {
+                let (seg, offset) = self.mem_operand(insn, rm)?;
+                let (base, limit) = if op & 7 == 0 { self.r.gdtr } else { self.r.idtr };
+                self.write_mem(seg, offset, 2, limit as u64)?;
+                let s = if insn.code64 { 8 } else { 4 };
+                self.write_mem(seg, offset.wrapping_add(2), s, base)?;
+                Ok(Flow::Next)
+            },
+            (2, None) | (3, None) => {
+                self.require_cpl0()?;
+                let (seg, offset) = self.mem_operand(insn, rm)?;
+                let limit = self.read_mem(seg, offset, 2)? as u32;
+                let s = if insn.code64 { 8 } else { 4 };
+                let mut base = self.read_mem(seg, offset.wrapping_add(2), s)?;
+                if !insn.code64 && insn.opsize == 2 {
+                    base &= 0xff_ffff;
+                }
+                if op & 7 == 2 {
+                    self.r.gdtr = (base, limit);
+                } else {
+                    self.r.idtr = (base, limit);
+                }
+                Ok(Flow::Next)
+            },
+            (4, _) => {
+                let cr0 = self.guest_cr0();
+                let s = if let Operand::Reg(_) = rm { insn.opsize } else { 2 };
+                self.write(insn, rm, s, cr0)?;
+                Ok(Flow::Next)
+            },
+            (6, _) => {
+                self.require_cpl0()?;
+                let source = self.read(insn, rm, 2)?;
+                // LMSW can set PE but not clear it
+                let cr0 = (self.r.cr0 & !0xe) | (source & 0xf) | (self.r.cr0 & CR0_PE);
+                let qualification = 3 << 4 | (source & 0xffff) << 16
+                    | if rm_reg.is_none() { 1 << 6 } else { 0 };
+                self.write_cr0(cr0, Exit::qualification(VMX_REASON_MOV_CR, qualification))?;
+                Ok(Flow::Next)
+            },
+            (7, None) => {
+                self.require_cpl0()?;
+                if self.control(VMCS_CTRL_CPU_BASED) & CPU_BASED_INVLPG != 0 {
+                    let (seg, offset) = self.mem_operand(insn, rm)?;
+                    let address = self.linear(seg, offset);
+                    return Err(Exit::qualification(VMX_REASON_INVLPG, address).into());
+                }
+                Ok(Flow::Next)
+            },
+            _ => Err(ud()),
+        }
+    }
+
+    // Instruction helpers
+
+    fn require_cpl0(&self) -> Result<(), Fault> {
+        if self.cpl() != 0 { Err(gp(0)) } else { Ok(()) }
+    }
+
+    fn secondary(&self, control: u64) -> bool {
+        self.control(VMCS_CTRL_CPU_BASED) & CPU_BASED_SECONDARY_CTLS != 0
+            && self.control(VMCS_CTRL_CPU_BASED2) & control != 0
+    }
+
+    fn guest_tsc(&self) -> u64 {
+        if self.control(VMCS_CTRL_CPU_BASED) & CPU_BASED_TSC_OFFSET != 0 {
+            self.r.tsc.wrapping_add(self.control(VMCS_CTRL_TSC_OFFSET))
+        } else {
+            self.r.tsc
+        }
+    }
+
+    fn branch(&self, insn: &Insn, taken: bool, rel: u64) -> Result<Flow, Fault> {
+        if !taken {
+            return Ok(Flow::Next);
+        }
+        let s = insn.stack_opsize();
+        Ok(Flow::Jump(insn.next().wrapping_add(rel) & mask(s)))
+    }
+
+    fn software_interrupt(&mut self, insn: &Insn, vector: u8) -> Result<Flow, Fault> {
+        let event = Event {
+            vector,
+            kind: IRQ_INFO_SOFT_IRQ,
+            error_code: None,
+            return_rip: insn.next(),
+        };
+        match self.raise(event) {
+            None => Ok(Flow::Stay),
+            Some(exit) => Err(exit.into()),
+        }
+    }
+
+    // INT3 and INTO, which unlike INT n can be intercepted by the exception
+    // bitmap
+    fn software_exception(&mut self, insn: &Insn, vector: u8) -> Result<Flow, Fault> {
+        if self.intercepts(vector, None) {
+            let e = Exception { vector, error_code: None, address: None };
+            return Err(exception_exit(&e).into());
+        }
+        let event = Event {
+            vector,
+            kind: IRQ_INFO_SOFT_EXC,
+            error_code: None,
+            return_rip: insn.next(),
+        };
+        match self.raise(event) {
+            None => Ok(Flow::Stay),
+            Some(exit) => Err(exit.into()),
+        }
+    }
+
+    fn io(&mut self, insn: &Insn, port: u16, size: usize, input: bool, string: bool,
+        immediate: bool) -> Result<Flow, Fault> {
+        let iopl = ((self.r.rflags >> 12) & 3) as u8;
+        if self.protected() && (self.cpl() > iopl || self.r.rflags & FLAG_VM != 0) {
+            return Err(gp(0));
+        }
+        let rep = insn.rep != 0;
+        if string && rep && self.reg(RCX, insn.adsize) == 0 {
+            return Ok(Flow::Next);
+        }
+
+        let mut qualification = (size as u64 - 1) | (port as u64) << 16;
+        if input { qualification |= 1 << 3; }
+        if string { qualification |= 1 << 4; }
+        if string && rep { qualification |= 1 << 5; }
+        if immediate { qualification |= 1 << 6; }
+
+        let mut exit = Exit::qualification(VMX_REASON_IO, qualification);
+        if string {
+            let (seg, index) = if input { (ES, RDI) } else { (insn.seg.unwrap_or(DS), RSI) };
+            let offset = self.reg(index, insn.adsize);
+            let address_size = match insn.adsize { 2 => 0, 4 => 1, _ => 2 };
+            exit.gla = Some(self.linear(seg, offset));
+            exit.instr_info = address_size << 7 | (seg as u64) << 15;
+        }
+        Err(exit.into())
+    }
+
+    fn string(&mut self, insn: &Insn, opcode: u8, size: usize) -> Result<Flow, Fault> {
+        let a = insn.adsize;
+        let rep = insn.rep != 0;
+        if rep && self.reg(RCX, a) == 0 {
+            return Ok(Flow::Next);
+        }
+
+        let delta = if self.flag(FLAG_DF) { (size as u64).wrapping_neg() } else { size as u64 };
+        let src = insn.seg.unwrap_or(DS);
+        let si = self.reg(RSI, a);
+        let di = self.reg(RDI, a);
+        let (mut use_si, mut use_di, mut compare) = (false, false, false);
+
+        match opcode & !1 {
+            0xa4 => {
+                let value = self.read_mem(src, si, size)?;
+                self.write_mem(ES, di, size, value)?;
+                use_si = true;
+                use_di = true;
+            },
+            0xa6 => {
+                let x = self.read_mem(src, si, size)?;
+                let y = self.read_mem(ES, di, size)?;
+                self.alu(7, x, y, size);
+                use_si = true;
+                use_di = true;
+                compare = true;
+            },
+            0xaa => {
+                let value = self.reg(RAX, size);
+                self.write_mem(ES, di, size, value)?;
+                use_di = true;
+            },
+            0xac => {
+                let value = self.read_mem(src, si, size)?;
+                self.set_reg(RAX, size, value);
+                use_si = true;
+            },
+            _ => {
+                let x = self.reg(RAX, size);
+                let y = self.read_mem(ES, di, size)?;
+                self.alu(7, x, y, size);
+                use_di = true;
+                compare = true;
+            },
+        }
+
+        if use_si {
+            self.set_reg(RSI, a, si.wrapping_add(delta) & mask(a));
+        }
+        if use_di {
+            self.set_reg(RDI, a, di.wrapping_add(delta) & mask(a));
+        }
+        if !rep {
+            return Ok(Flow::Next);
+        }
+
+        // Each iteration of a repeated instruction is a step of its own, so
+        // that exits and interrupts can happen in between
+        let count = self.reg(RCX, a).wrapping_sub(1) & mask(a);
+        self.set_reg(RCX, a, count);
+        let stop = compare && if insn.rep == 0xf3 { !self.flag(FLAG_ZF) } else { self.flag(FLAG_ZF) };
+        Ok(if count == 0 || stop { Flow::Next } else { Flow::Stay })
+    }
+
+    // Performs BT, BTS, BTR or BTC
+    fn bit_test(&mut self, insn: &Insn, op: u8, rm: Operand, offset: u64, register: bool)
+        -> Result<Flow, Fault> {
+        let size = insn.opsize;
+        let bits = (size * 8) as u64;
+        let (operand, bit) = match rm {
+            Operand::Mem { seg, offset: base, rip_relative } if register => {
+                // A register bit offset can address memory beyond the operand
+                let signed = sign_extend(offset & mask(size), size) as i64;
+                let displacement = (signed >> bits.trailing_zeros()) * size as i64;
+                (Operand::Mem {
+                    seg,
+                    offset: base.wrapping_add(displacement as u64) & mask(insn.adsize),
+                    rip_relative,
+                }, offset & (bits - 1))
+            },
+            _ => (rm, offset & (bits - 1)),
+        };
+
+        let value = self.read(insn, operand, size)?;
+        let set = (value >> bit) & 1;
+        self.set_flags(FLAG_CF, set * FLAG_CF);
+        let result = match op {
+            0 => return Ok(Flow::Next),
+            1 => value | 1 << bit,
+            2 => value & !(1 << bit),
+            _ => value ^ 1 << bit,
+        };
+        self.write(insn, operand, size, result)?;
+        Ok(Flow::Next)
+    }
+
+    // Loads flags popped by POPF or IRET, keeping the ones the current
+    // privilege level can't change
+    fn load_flags(&mut self, value: u64, size: usize, iret: bool) {
+        let mut changeable = FLAG_CF | FLAG_PF | FLAG_AF | FLAG_ZF | FLAG_SF | FLAG_TF | FLAG_IF
+            | FLAG_DF | FLAG_OF | 0x3000 | FLAG_NT | FLAG_AC | 1 << 21;
+        if iret {
+            changeable |= FLAG_RF;
+        }
+        let cpl = self.cpl();
+        let iopl = ((self.r.rflags >> 12) & 3) as u8;
+        if self.protected() && cpl > 0 {
+            changeable &= !0x3000;
+        }
+        if self.protected() && cpl > iopl {
+            changeable &= !FLAG_IF;
+        }
+        changeable &= mask(size);
+        self.r.rflags = (self.r.rflags & !changeable) | (value & changeable) | 0x2;
+    }
+
+    fn load_code_segment(&mut self, selector: u16) -> Result<(), Fault> {
+        if self.protected() && self.r.rflags & FLAG_VM == 0 {
+            let cpl = self.cpl();
+            self.load_segment(CS, (selector & !3) | cpl as u16)?;
+            self.set_cpl(cpl);
+            Ok(())
+        } else {
+            self.load_segment(CS, selector)
+        }
+    }
+
+    fn far_call(&mut self, insn: &Insn, selector: u16, offset: u64) -> Result<Flow, Fault> {
+        let size = insn.opsize;
+        let cs = self.r.seg[CS].0 as u64;
+        let next = insn.next();
+        self.push(size, cs)?;
+        self.push(size, next)?;
+        self.load_code_segment(selector)?;
+        Ok(Flow::Jump(offset & mask(size)))
+    }
+
+    fn far_return(&mut self, insn: &Insn, release: u64) -> Result<Flow, Fault> {
+        let size = insn.opsize;
+        let offset = self.pop(size)?;
+        let selector = self.pop(size)? as u16;
+        let ss = self.stack_size();
+        let rsp = self.reg(RSP, ss).wrapping_add(release);
+        self.set_reg(RSP, ss, rsp);
+
+        if !self.protected() || self.r.rflags & FLAG_VM != 0 {
+            self.load_segment(CS, selector)?;
+            return Ok(Flow::Jump(offset & mask(size)));
+        }
+
+        let rpl = (selector & 3) as u8;
+        if rpl < self.cpl() {
+            return Err(gp(selector as u32 & !3));
+        }
+        let outer = rpl > self.cpl();
+        let (new_rsp, new_ss) = if outer {
+            (self.pop(size)?, Some(self.pop(size)? as u16))
+        } else {
+            (0, None)
+        };
+        self.load_segment(CS, selector)?;
+        self.set_cpl(rpl);
+        if let Some(ss) = new_ss {
+            self.load_segment(SS, ss)?;
+            let s = self.stack_size();
+            self.set_reg(RSP, s, new_rsp.wrapping_add(release));
+        }
+        Ok(Flow::Jump(offset & mask(size)))
+    }
+
+    fn iret(&mut self, insn: &Insn) -> Result<Flow, Fault> {
+        let size = if insn.code64 && insn.rex & REX_W != 0 { 8 } else { insn.opsize };
+        let offset = self.pop(size)?;
+        let selector = self.pop(size)? as u16;
+        let flags = self.pop(size)?;
+
+        // IRET unblocks NMIs
+        self.r.interruptibility &= !BLOCKING_NMI;
+
+        if !self.protected() || self.r.rflags & FLAG_VM != 0 {
+            self.load_segment(CS, selector)?;
+            self.load_flags(flags, size, true);
+            return Ok(Flow::Jump(offset & mask(size)));
+        }
+        if self.flag(FLAG_NT) && !self.long_mode() {
+            // Returns from nested tasks aren't supported
+            return Err(gp(0));
+        }
+
+        let rpl = (selector & 3) as u8;
+        let cpl = self.cpl();
+        if rpl < cpl {
+            return Err(gp(selector as u32 & !3));
+        }
+        let pops_stack = insn.code64 || rpl > cpl;
+        let (new_rsp, new_ss) = if pops_stack {
+            (self.pop(size)?, Some(self.pop(size)? as u16))
+        } else {
+            (0, None)
+        };
+
+        self.load_flags(flags, size, true);
+        self.load_segment(CS, selector)?;
+        self.set_cpl(rpl);
+        if let Some(ss) = new_ss {
+            if ss & !3 == 0 && self.code64() {
+                self.r.set_segment(SS, Segment {
+                    selector: ss,
+                    base: 0,
+                    limit: 0,
+                    ar: AR_UNUSABLE | (rpl as u32) << 5,
+                });
+            } else {
+                self.load_segment(SS, ss)?;
+            }
+            self.r.gpr[RSP] = new_rsp & mask(if self.code64() { 8 } else { size });
+        }
+        Ok(Flow::Jump(offset))
+    }
+
+    // Control registers
+
+    // Returns CR0 as the guest sees it, with the bits owned by the host
+    // taken from the read shadow
+    fn guest_cr0(&self) -> u64 {
+        let mask = self.control(VMCS_CTRL_CR0_MASK);
+        (self.r.cr0 & !mask) | (self.control(VMCS_CTRL_CR0_SHADOW) & mask)
+    }
+
+    fn guest_cr4(&self) -> u64 {
+        let mask = self.control(VMCS_CTRL_CR4_MASK);
+        (self.r.cr4 & !mask) | (self.control(VMCS_CTRL_CR4_SHADOW) & mask)
+    }
+
+    fn read_cr(&mut self, insn: &Insn, cr: usize, gpr: usize) -> Result<(), Fault> {
+        let cpu_based = self.control(VMCS_CTRL_CPU_BASED);
+        let exit = Exit::qualification(VMX_REASON_MOV_CR, cr as u64 | 1 << 4 | (gpr as u64) << 8);
+        let value = match cr {
+            0 => self.guest_cr0(),
+            2 => self.r.cr2,
+            3 if cpu_based & CPU_BASED_CR3_STORE != 0 => return Err(exit.into()),
+            3 => self.r.cr3,
+            4 => self.guest_cr4(),
+            8 if cpu_based & CPU_BASED_CR8_STORE != 0 => return Err(exit.into()),
+            8 => self.r.cr8,
+            _ => return Err(ud()),
+        };
+        let s = if insn.code64 { 8 } else { 4 };
+        self.set_reg(gpr, s, value);
+        Ok(())
+    }
+
+    fn mov_to_cr(&mut self, insn: &Insn, cr: usize, gpr: usize) -> Result<(), Fault> {
+        let s = if insn.code64 { 8 } else { 4 };
+        let value = self.reg(gpr, s);
+        let cpu_based = self.control(VMCS_CTRL_CPU_BASED);
+        let exit = Exit::qualification(VMX_REASON_MOV_CR, cr as u64 | (gpr as u64) << 8);
+        match cr {
+            0 => self.write_cr0(value, exit),
+            2 => {
+                self.r.cr2 = value;
+                Ok(())
+            },
+            3 if cpu_based & CPU_BASED_CR3_LOAD != 0 => Err(exit.into()),
+            3 => {
+                self.r.cr3 = value;
+                Ok(())
+            },
+            4 => {
+                let mask = self.control(VMCS_CTRL_CR4_MASK);
+                if (value ^ self.control(VMCS_CTRL_CR4_SHADOW)) & mask != 0 {
+                    return Err(exit.into());
+                }
+                self.r.cr4 = (value & !mask) | (self.r.cr4 & mask);
+                Ok(())
+            },
+            8 if cpu_based & CPU_BASED_CR8_LOAD != 0 => Err(exit.into()),
+            8 => {
+                self.r.cr8 = value & 0xf;
+                Ok(())
+            },
+            _ => Err(ud()),
+        }
+    }
+
+    // Writes CR0, exiting instead if the write changes bits owned by the
+    // host
+    fn write_cr0(&mut self, value: u64, exit: Exit) -> Result<(), Fault> {
+        let mask = self.control(VMCS_CTRL_CR0_MASK);
+        if (value ^ self.control(VMCS_CTRL_CR0_SHADOW)) & mask != 0 {
+            return Err(exit.into());
+        }
+        let cr0 = (value & !mask) | (self.r.cr0 & mask);
+
+        // Turning paging on with EFER.LME set activates long mode
+        let paging = cr0 & CR0_PG != 0;
+        if paging && self.r.cr0 & CR0_PG == 0 && self.r.efer & EFER_LME != 0 {
+            if self.r.cr4 & CR4_PAE == 0 {
+                return Err(gp(0));
+            }
+            self.r.efer |= EFER_LMA;
+        } else if !paging {
+            self.r.efer &= !EFER_LMA;
+        }
+        self.r.cr0 = cr0;
+        Ok(())
+    }
+
+    // MSRs
+
+    fn read_native_msr(&self, msr: u32) -> u64 {
+        match msr {
+            MSR_TSC => self.guest_tsc(),
+            MSR_EFER => self.r.efer,
+            MSR_FS_BASE => self.r.seg[FS].1,
+            MSR_GS_BASE => self.r.seg[GS].1,
+            _ => match msr_field(msr) {
+                Some(field) => self.st.vmcs(field),
+                None => self.st.msr(msr),
+            },
+        }
+    }
+
+    fn write_native_msr(&mut self, msr: u32, value: u64) {
+        match msr {
+            MSR_TSC => self.r.tsc = value,
+            // EFER.LMA is read-only
+            MSR_EFER => self.r.efer = (value & !EFER_LMA) | (self.r.efer & EFER_LMA),
+            MSR_FS_BASE => self.r.seg[FS].1 = value,
+            MSR_GS_BASE => self.r.seg[GS].1 = value,
+            _ => match msr_field(msr) {
+                Some(field) => self.st.set_vmcs(field, value),
+                None => {
+                    self.st.msrs.insert(msr, value);
+                },
+            },
+        }
+    }
+}
@@ -0,0 +1,758 @@
+/*
+Copyright (c) 2016 Saurav Sachidanand
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in
+all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+THE SOFTWARE.
+*/
+
+//! Software x86 interpreter backend
+//!
+//! `Interpreter` runs guest code without hardware virtualization, by
+//! decoding and executing it straight out of the memory mapped with
+//! `vm_map`. It honors the `x86Reg` register file and the guest-state VMCS
+//! fields, follows CR0, CR4 and EFER into real, protected and long mode
+//! (with or without paging), and exits with the reasons in
+//! `consts::vmx_exit`:
+//!
+//! * `VMX_REASON_IO` for `IN`, `OUT`, `INS` and `OUTS`
+//!
+//! * `VMX_REASON_HLT`, `VMX_REASON_CPUID`, `VMX_REASON_INVD`,
+//!   `VMX_REASON_VMCALL` and `VMX_REASON_XSETBV`
+//!
+//! * `VMX_REASON_RDMSR` and `VMX_REASON_WRMSR` for MSRs that haven't been
+//!   enabled with `vcpu_enable_native_msr`
+//!
+//! * `VMX_REASON_MOV_CR` for writes to CR0 and CR4 bits owned by the host,
+//!   and for CR3 and CR8 accesses when the processor-based controls ask for it
+//!
+//! * `VMX_REASON_RDTSC`, `VMX_REASON_RDTSCP`, `VMX_REASON_RDPMC`,
+//!   `VMX_REASON_INVLPG`, `VMX_REASON_MOV_DR` and `VMX_REASON_WBINVD` when
+//!   the controls ask for them
+//!
+//! * `VMX_REASON_EPT_VIOLATION` for accesses to unmapped guest physical
+//!   addresses, or ones the mapping doesn't permit
+//!
+//! * `VMX_REASON_EXC_NMI` for exceptions selected by the exception bitmap,
+//!   and `VMX_REASON_TRIPLE_FAULT` when one can't be delivered
+//!
+//! * `VMX_REASON_IRQ_WND` and `VMX_REASON_VIRTUAL_NMI_WND` when the
+//!   window-exiting controls are set, and `VMX_REASON_IRQ` after
+//!   `vcpu_interrupt`
+//!
+//! * `VMX_REASON_VMX_TIMER_EXPIRED` when the VMX-preemption timer is
+//!   activated and runs out. It counts down once per instruction, like the
+//!   TSC.
+//!
+//! Events written to `VMCS_CTRL_VMENTRY_IRQ_INFO` are delivered through the
+//! guest IVT or IDT on entry.
+//!
+//! The common integer instruction set is covered, including string
+//! instructions, but not x87, SSE, task switches, call gates or the
+//! accessed and dirty bits of paging structures. Segment limits are not
+//! checked. Unknown opcodes raise `#UD` in the guest.
+
+mod cpu;
+mod exec;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::thread::{self, ThreadId};
+use std::time::Instant;
+
+use ffi::*;
+use consts::vmcs::*;
+use super::Backend;
+use super::super::{x86Reg, VMXCap};
+
+/// Backend that interprets guest code in software
+pub enum Interpreter {}
+
+const PAGE_SIZE: u64 = 0x1000;
+
+// Allowed-0 settings of zero and allowed-1 settings of all ones, i.e. every
+// control is accepted and none is required
+const DEFAULT_CONTROL_CAP: u64 = 0xffff_ffff_0000_0000;
+
+/// Region mapped into the guest physical address space
+#[derive(Clone, Copy)]
+struct Region {
+    uva: usize,
+    gpa: u64,
+    size: u64,
+    flags: hv_memory_flags_t,
+}
+
+/// Architectural state of a vCPU between runs
+struct VcpuState {
+    registers: [u64; x86Reg::REGISTERS_MAX as usize],
+    msrs: BTreeMap<u32, u64>,
+    native_msrs: BTreeSet<u32>,
+    vmcs: BTreeMap<u32, u64>,
+    fpstate: Vec<u8>,
+}
+
+impl VcpuState {
+    fn new() -> VcpuState {
+        let mut state = VcpuState {
+            registers: [0; x86Reg::REGISTERS_MAX as usize],
+            msrs: BTreeMap::new(),
+            native_msrs: BTreeSet::new(),
+            vmcs: BTreeMap::new(),
+            fpstate: Vec::new(),
+        };
+        // Bit 1 of RFLAGS is reserved and always set
+        state.vmcs.insert(VMCS_GUEST_RFLAGS, 0x2);
+        state
+    }
+
+    fn vmcs(&self, field: u32) -> u64 {
+        self.vmcs.get(&field).cloned().unwrap_or(0)
+    }
+
+    fn set_vmcs(&mut self, field: u32, value: u64) {
+        self.vmcs.insert(field, value);
+    }
+
+    fn msr(&self, msr: u32) -> u64 {
+        self.msrs.get(&msr).cloned().unwrap_or(0)
+    }
+
+    fn register(&self, reg: x86Reg) -> u64 {
+        match register_field(reg) {
+            Some(field) => self.vmcs(field),
+            None => self.registers[reg as usize],
+        }
+    }
+
+    fn set_register(&mut self, reg: x86Reg, value: u64) {
+        match register_field(reg) {
+            Some(field) => self.set_vmcs(field, value),
+            None => self.registers[reg as usize] = value,
+        }
+    }
+}
+
+// Registers that are also guest-state fields of the VMCS are kept only in
+// the VMCS, so that both views agree
+fn register_field(reg: x86Reg) -> Option<u32> {
+    Some(match reg {
+        x86Reg::RIP       => VMCS_GUEST_RIP,
+        x86Reg::RFLAGS    => VMCS_GUEST_RFLAGS,
+        x86Reg::RSP       => VMCS_GUEST_RSP,
+        x86Reg::CS        => VMCS_GUEST_CS,
+        x86Reg::SS        => VMCS_GUEST_SS,
+        x86Reg::DS        => VMCS_GUEST_DS,
+        x86Reg::ES        => VMCS_GUEST_ES,
+        x86Reg::FS        => VMCS_GUEST_FS,
+        x86Reg::GS        => VMCS_GUEST_GS,
+        x86Reg::IDT_BASE  => VMCS_GUEST_IDTR_BASE,
+        x86Reg::IDT_LIMIT => VMCS_GUEST_IDTR_LIMIT,
+        x86Reg::GDT_BASE  => VMCS_GUEST_GDTR_BASE,
+        x86Reg::GDT_LIMIT => VMCS_GUEST_GDTR_LIMIT,
+        x86Reg::LDTR      => VMCS_GUEST_LDTR,
+        x86Reg::LDT_BASE  => VMCS_GUEST_LDTR_BASE,
+        x86Reg::LDT_LIMIT => VMCS_GUEST_LDTR_LIMIT,
+        x86Reg::LDT_AR    => VMCS_GUEST_LDTR_AR,
+        x86Reg::TR        => VMCS_GUEST_TR,
+        x86Reg::TSS_BASE  => VMCS_GUEST_TR_BASE,
+        x86Reg::TSS_LIMIT => VMCS_GUEST_TR_LIMIT,
+        x86Reg::TSS_AR    => VMCS_GUEST_TR_AR,
+        x86Reg::CR0       => VMCS_GUEST_CR0,
+        x86Reg::CR3       => VMCS_GUEST_CR3,
+        x86Reg::CR4       => VMCS_GUEST_CR4,
+        x86Reg::DR7       => VMCS_GUEST_DR7,
+        _                 => return None,
+    })
+}
+
+// Likewise for MSRs
+fn msr_field(msr: u32) -> Option<u32> {
+    Some(match msr {
+        cpu::MSR_EFER         => VMCS_GUEST_IA32_EFER,
+        cpu::MSR_FS_BASE      => VMCS_GUEST_FS_BASE,
+        cpu::MSR_GS_BASE      => VMCS_GUEST_GS_BASE,
+        cpu::MSR_SYSENTER_CS  => VMCS_GUEST_IA32_SYSENTER_CS,
+        cpu::MSR_SYSENTER_ESP => VMCS_GUEST_SYSENTER_ESP,
+        cpu::MSR_SYSENTER_EIP => VMCS_GUEST_SYSENTER_EIP,
+        _                     => return None,
+    })
+}
+
+struct Vcpu {
+    thread: ThreadId,
+    interrupted: Arc<AtomicBool>,
+    exec_time: u64,
+    // Taken out while the vCPU runs, so that the global lock isn't held
+    // for the whole run
+    state: Option<Box<VcpuState>>,
+}
+
+struct State {
+    vm: bool,
+    vcpus: BTreeMap<hv_vcpuid_t, Vcpu>,
+    next_vcpu: hv_vcpuid_t,
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    vm: false,
+    vcpus: BTreeMap::new(),
+    next_vcpu: 0,
+});
+
+// Regions mapped into the VM. They are kept apart from the rest of the
+// state, and a running vCPU holds the read lock for every guest memory
+// access, so that the memory can't be unmapped and freed under it. Code
+// that takes both locks takes STATE first.
+static REGIONS: RwLock<Vec<Region>> = RwLock::new(Vec::new());
+
+fn state() -> MutexGuard<'static, State> {
+    STATE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn regions() -> RwLockReadGuard<'static, Vec<Region>> {
+    REGIONS.read().unwrap_or_else(|e| e.into_inner())
+}
+
+fn regions_mut() -> RwLockWriteGuard<'static, Vec<Region>> {
+    REGIONS.write().unwrap_or_else(|e| e.into_inner())
+}
+
+impl State {
+    // Looks up a vCPU, which may only be used from the thread that created
+    // it, and only while it isn't running
+    fn vcpu(&mut self, vcpu: hv_vcpuid_t) -> Result<&mut VcpuState, hv_return_t> {
+        match self.vcpus.get_mut(&vcpu) {
+            Some(v) if v.thread == thread::current().id() => match v.state {
+                Some(ref mut state) => Ok(state),
+                None => Err(HV_BUSY),
+            },
+            _ => Err(HV_BAD_ARGUMENT),
+        }
+    }
+
+    fn with_vcpu<F>(&mut self, vcpu: hv_vcpuid_t, f: F) -> hv_return_t
+        where F: FnOnce(&mut VcpuState) {
+        match self.vcpu(vcpu) {
+            Ok(state) => {
+                f(state);
+                HV_SUCCESS
+            },
+            Err(code) => code,
+        }
+    }
+}
+
+// Returns whether a region overlaps the range from `gpa` to `end`. Mapped
+// regions never wrap around, as vm_map rejects those.
+fn overlaps(region: &Region, gpa: u64, end: u64) -> bool {
+    gpa < region.gpa + region.size && region.gpa < end
+}
+
+fn aligned(value: u64) -> bool {
+    value.is_multiple_of(PAGE_SIZE)
+}
+
+impl Backend for Interpreter {
+    fn vm_create(_: hv_vm_options_t) -> hv_return_t {
+        let mut state = state();
+        if state.vm {
+            return HV_BUSY;
+        }
+        state.vm = true;
+        HV_SUCCESS
+    }
+
+    fn vm_destroy() -> hv_return_t {
+        let mut state = state();
+        if !state.vm {
+            return HV_BAD_ARGUMENT;
+        }
+        if !state.vcpus.is_empty() {
+            return HV_BUSY;
+        }
+        state.vm = false;
+        regions_mut().clear();
+        HV_SUCCESS
+    }
+
+    unsafe fn vm_map(uva: hv_uvaddr_t, gpa: hv_gpaddr_t, size: usize,
+        flags: hv_memory_flags_t) -> hv_return_t {
+        let state = state();
+        let size = size as u64;
+        if !state.vm || size == 0 || !aligned(uva as u64) || !aligned(gpa) || !aligned(size) {
+            return HV_BAD_ARGUMENT;
+        }
+        let end = match gpa.checked_add(size) {
+            Some(end) => end,
+            None => return HV_BAD_ARGUMENT,
+        };
+        let mut regions = regions_mut();
+        if regions.iter().any(|r| overlaps(r, gpa, end)) {
+            return HV_ERROR;
+        }
+        regions.push(Region { uva: uva as usize, gpa, size, flags });
+        regions.sort_by_key(|r| r.gpa);
+        HV_SUCCESS
+    }
+
+    fn vm_unmap(gpa: hv_gpaddr_t, size: usize) -> hv_return_t {
+        Self::vm_protect_or_unmap(gpa, size as u64, None)
+    }
+
+    fn vm_protect(gpa: hv_gpaddr_t, size: usize, flags: hv_memory_flags_t) -> hv_return_t {
+        Self::vm_protect_or_unmap(gpa, size as u64, Some(flags))
+    }
+
+    fn vm_sync_tsc(tsc: u64) -> hv_return_t {
+        let mut state = state();
+        if !state.vm {
+            return HV_BAD_ARGUMENT;
+        }
+        for v in state.vcpus.values_mut() {
+            if let Some(ref mut s) = v.state {
+                s.msrs.insert(cpu::MSR_TSC, tsc);
+            }
+        }
+        HV_SUCCESS
+    }
+
+    fn vcpu_create(vcpu: &mut hv_vcpuid_t, _: hv_vm_options_t) -> hv_return_t {
+        let mut state = state();
+        if !state.vm {
+            return HV_BAD_ARGUMENT;
+        }
+        let id = state.next_vcpu;
+        state.next_vcpu += 1;
+        state.vcpus.insert(id, Vcpu {
+            thread: thread::current().id(),
+            interrupted: Arc::new(AtomicBool::new(false)),
+            exec_time: 0,
+            state: Some(Box::new(VcpuState::new())),
+        });
+        *vcpu = id;
+        HV_SUCCESS
+    }
+
+    fn vcpu_destroy(vcpu: hv_vcpuid_t) -> hv_return_t {
+        let mut state = state();
+        if let Err(code) = state.vcpu(vcpu) {
+            return code;
+        }
+        state.vcpus.remove(&vcpu);
+        HV_SUCCESS
+    }
+
+    fn vcpu_run(vcpu: hv_vcpuid_t) -> hv_return_t {
+        let (mut vcpu_state, interrupted) = {
+            let mut state = state();
+            if let Err(code) = state.vcpu(vcpu) {
+                return code;
+            }
+            let v = state.vcpus.get_mut(&vcpu).unwrap();
+            (v.state.take().unwrap(), v.interrupted.clone())
+        };
+
+        // The state is put back even if the interpreter panics, so that the
+        // vCPU doesn't stay busy
+        let start = Instant::now();
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            exec::run(&mut vcpu_state, &interrupted)
+        }));
+        let elapsed = start.elapsed();
+
+        let mut state = state();
+        let v = state.vcpus.get_mut(&vcpu).unwrap();
+        v.exec_time += elapsed.as_secs() * 1_000_000_000 + elapsed.subsec_nanos() as u64;
+        v.state = Some(vcpu_state);
+        match result {
+            Ok(()) => HV_SUCCESS,
+            Err(_) => HV_ERROR,
+        }
+    }
+
+    fn vcpu_interrupt(vcpus: &[hv_vcpuid_t]) -> hv_return_t {
+        let state = state();
+        if vcpus.iter().any(|id| !state.vcpus.contains_key(id)) {
+            return HV_BAD_ARGUMENT;
+        }
+        for id in vcpus {
+            state.vcpus[id].interrupted.store(true, Ordering::SeqCst);
+        }
+        HV_SUCCESS
+    }
+
+    fn vcpu_get_exec_time(vcpu: hv_vcpuid_t, time: &mut u64) -> hv_return_t {
+        let mut state = state();
+        if let Err(code) = state.vcpu(vcpu) {
+            return code;
+        }
+        *time = state.vcpus[&vcpu].exec_time;
+        HV_SUCCESS
+    }
+
+    fn vcpu_flush(vcpu: hv_vcpuid_t) -> hv_return_t {
+        state().with_vcpu(vcpu, |_| ())
+    }
+
+    fn vcpu_invalidate_tlb(vcpu: hv_vcpuid_t) -> hv_return_t {
+        // There is no TLB; every access walks the guest page tables
+        state().with_vcpu(vcpu, |_| ())
+    }
+
+    fn vcpu_read_register(vcpu: hv_vcpuid_t, reg: x86Reg, value: &mut u64) -> hv_return_t {
+        if reg == x86Reg::REGISTERS_MAX {
+            return HV_BAD_ARGUMENT;
+        }
+        state().with_vcpu(vcpu, |s| *value = s.register(reg))
+    }
+
+    fn vcpu_write_register(vcpu: hv_vcpuid_t, reg: x86Reg, value: u64) -> hv_return_t {
+        if reg == x86Reg::REGISTERS_MAX {
+            return HV_BAD_ARGUMENT;
+        }
+        state().with_vcpu(vcpu, |s| s.set_register(reg, value))
+    }
+
+    fn vcpu_read_fpstate(vcpu: hv_vcpuid_t, buffer: &mut [u8]) -> hv_return_t {
+        state().with_vcpu(vcpu, |s| {
+            s.fpstate.resize(buffer.len(), 0);
+            buffer.copy_from_slice(&s.fpstate);
+        })
+    }
+
+    fn vcpu_write_fpstate(vcpu: hv_vcpuid_t, buffer: &[u8]) -> hv_return_t {
+        state().with_vcpu(vcpu, |s| s.fpstate = buffer.to_vec())
+    }
+
+    fn vcpu_enable_native_msr(vcpu: hv_vcpuid_t, msr: u32, enable: bool) -> hv_return_t {
+        state().with_vcpu(vcpu, |s| {
+            if enable {
+                s.native_msrs.insert(msr);
+            } else {
+                s.native_msrs.remove(&msr);
+            }
+        })
+    }
+
+    fn vcpu_read_msr(vcpu: hv_vcpuid_t, msr: u32, value: &mut u64) -> hv_return_t {
+        state().with_vcpu(vcpu, |s| *value = match msr_field(msr) {
+            Some(field) => s.vmcs(field),
+            None => s.msr(msr),
+        })
+    }
+
+    fn vcpu_write_msr(vcpu: hv_vcpuid_t, msr: u32, value: u64) -> hv_return_t {
+        state().with_vcpu(vcpu, |s| match msr_field(msr) {
+            Some(field) => s.set_vmcs(field, value),
+            None => {
+                s.msrs.insert(msr, value);
+            },
+        })
+    }
+
+    fn vmx_vcpu_read_vmcs(vcpu: hv_vcpuid_t, field: u32, value: &mut u64) -> hv_return_t {
+        state().with_vcpu(vcpu, |s| *value = s.vmcs(field))
+    }
+
+    fn vmx_vcpu_write_vmcs(vcpu: hv_vcpuid_t, field: u32, value: u64) -> hv_return_t {
+        state().with_vcpu(vcpu, |s| s.set_vmcs(field, value))
+    }
+
+    fn vmx_read_capability(field: VMXCap, value: &mut u64) -> hv_return_t {
+        *value = match field {
+            VMXCap::PREEMPTION_TIMER => 0,
+            _ => DEFAULT_CONTROL_CAP,
+        };
+        HV_SUCCESS
+    }
+
+    fn vmx_vcpu_set_apic_address(vcpu: hv_vcpuid_t, gpa: hv_gpaddr_t) -> hv_return_t {
+        state().with_vcpu(vcpu, |s| {
+            s.msrs.insert(cpu::MSR_APIC_BASE, gpa);
+        })
+    }
+}
+
+impl Interpreter {
+    // Unmaps a range, or changes its permissions, splitting the regions it
+    // only partially covers
+    fn vm_protect_or_unmap(gpa: u64, size: u64, flags: Option<hv_memory_flags_t>) -> hv_return_t {
+        let state = state();
+        if !state.vm || !aligned(gpa) || !aligned(size) {
+            return HV_BAD_ARGUMENT;
+        }
+        let end = match gpa.checked_add(size) {
+            Some(end) => end,
+            None => return HV_BAD_ARGUMENT,
+        };
+
+        let mut current = regions_mut();
+        let mut regions = Vec::new();
+        for r in current.drain(..) {
+            if !overlaps(&r, gpa, end) {
+                regions.push(r);
+                continue;
+            }
+            let r_end = r.gpa + r.size;
+            let start = ::std::cmp::max(r.gpa, gpa);
+            let stop = ::std::cmp::min(r_end, end);
+            if r.gpa < start {
+                regions.push(Region { size: start - r.gpa, ..r });
+            }
+            if let Some(flags) = flags {
+                regions.push(Region {
+                    uva: r.uva + (start - r.gpa) as usize,
+                    gpa: start,
+                    size: stop - start,
+                    flags,
+                });
+            }
+            if stop < r_end {
+                regions.push(Region {
+                    uva: r.uva + (stop - r.gpa) as usize,
+                    gpa: stop,
+                    size: r_end - stop,
+                    flags: r.flags,
+                });
+            }
+        }
+        regions.sort_by_key(|r| r.gpa);
+        *current = regions;
+        HV_SUCCESS
+    }
+}
+
+#[cfg(all(test, feature = "interp"))]
+mod tests {
+    use std::sync::{Mutex, MutexGuard};
+
+    use consts::vmx_cap::*;
+    use consts::vmx_exit::*;
+    use super::super::super::GuestMemory;
+    use super::*;
+
+    // The interpreter state is global, so tests take turns
+    static LOCK: Mutex<()> = Mutex::new(());
+
+    const RWX: hv_memory_flags_t = HV_MEMORY_READ | HV_MEMORY_WRITE | HV_MEMORY_EXEC;
+
+    // VM with one vCPU, and memory mapped at guest-physical address 0
+    struct Guest {
+        vcpu: hv_vcpuid_t,
+        memory: GuestMemory,
+        _lock: MutexGuard<'static, ()>,
+    }
+
+    impl Guest {
+        fn new(size: usize) -> Guest {
+            let lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+            assert_eq!(Interpreter::vm_create(0), HV_SUCCESS);
+            let memory = GuestMemory::new(size).unwrap();
+            let uva = memory.as_ptr() as hv_uvaddr_t;
+            assert_eq!(unsafe { Interpreter::vm_map(uva, 0, size, RWX) }, HV_SUCCESS);
+            let mut vcpu = 0;
+            assert_eq!(Interpreter::vcpu_create(&mut vcpu, 0), HV_SUCCESS);
+            Guest { vcpu, memory, _lock: lock }
+        }
+
+        // Real-address mode, running `code` from address 0
+        fn real_mode(code: &[u8]) -> Guest {
+            let guest = Guest::new(0x1000);
+            guest.write(0, code);
+            guest.set_vmcs(VMCS_GUEST_CS_LIMIT, 0xffff);
+            guest.set_vmcs(VMCS_GUEST_CS_AR, 0x9b);
+            for &(limit, ar) in &[(VMCS_GUEST_SS_LIMIT, VMCS_GUEST_SS_AR),
+                (VMCS_GUEST_DS_LIMIT, VMCS_GUEST_DS_AR), (VMCS_GUEST_ES_LIMIT, VMCS_GUEST_ES_AR)] {
+                guest.set_vmcs(limit, 0xffff);
+                guest.set_vmcs(ar, 0x93);
+            }
+            guest
+        }
+
+        fn write(&self, gpa: usize, bytes: &[u8]) {
+            self.memory.write_slice(gpa, bytes).unwrap();
+        }
+
+        fn vmcs(&self, field: u32) -> u64 {
+            let mut value = 0;
+            assert_eq!(Interpreter::vmx_vcpu_read_vmcs(self.vcpu, field, &mut value), HV_SUCCESS);
+            value
+        }
+
+        fn set_vmcs(&self, field: u32, value: u64) {
+            assert_eq!(Interpreter::vmx_vcpu_write_vmcs(self.vcpu, field, value), HV_SUCCESS);
+        }
+
+        fn reg(&self, reg: x86Reg) -> u64 {
+            let mut value = 0;
+            assert_eq!(Interpreter::vcpu_read_register(self.vcpu, reg, &mut value), HV_SUCCESS);
+            value
+        }
+
+        // Runs until the next VM exit, and returns its reason
+        fn run(&self) -> u64 {
+            assert_eq!(Interpreter::vcpu_run(self.vcpu), HV_SUCCESS);
+            self.vmcs(VMCS_RO_EXIT_REASON)
+        }
+
+        // Moves RIP past the instruction that caused the last VM exit
+        fn skip(&self) {
+            let rip = self.vmcs(VMCS_GUEST_RIP) + self.vmcs(VMCS_RO_VMEXIT_INSTR_LEN);
+            self.set_vmcs(VMCS_GUEST_RIP, rip);
+        }
+    }
+
+    impl Drop for Guest {
+        fn drop(&mut self) {
+            Interpreter::vcpu_destroy(self.vcpu);
+            Interpreter::vm_unmap(0, self.memory.size());
+            Interpreter::vm_destroy();
+        }
+    }
+
+    #[test]
+    fn real_mode_out_then_hlt() {
+        // mov al, 0x42; out 0x80, al; hlt
+        let guest = Guest::real_mode(&[0xb0, 0x42, 0xe6, 0x80, 0xf4]);
+
+        assert_eq!(guest.run(), VMX_REASON_IO);
+        let qualification = guest.vmcs(VMCS_RO_EXIT_QUALIFIC);
+        assert_eq!(qualification >> 16, 0x80);
+        assert_eq!(qualification & 0xf, 0, "1-byte OUT");
+        assert_eq!(guest.reg(x86Reg::RAX) & 0xff, 0x42);
+        assert_eq!(guest.vmcs(VMCS_GUEST_RIP), 2);
+        assert_eq!(guest.vmcs(VMCS_RO_VMEXIT_INSTR_LEN), 2);
+
+        guest.skip();
+        assert_eq!(guest.run(), VMX_REASON_HLT);
+        assert_eq!(guest.vmcs(VMCS_GUEST_RIP), 4);
+    }
+
+    #[test]
+    fn protected_to_long_mode() {
+        let guest = Guest::new(0x4000);
+        let code = [
+            0x0f, 0x20, 0xe0,                   // mov eax, cr4
+            0x83, 0xc8, 0x20,                   // or eax, CR4.PAE
+            0x0f, 0x22, 0xe0,                   // mov cr4, eax
+            0xb8, 0x00, 0x20, 0x00, 0x00,       // mov eax, 0x2000
+            0x0f, 0x22, 0xd8,                   // mov cr3, eax
+            0xb9, 0x80, 0x00, 0x00, 0xc0,       // mov ecx, IA32_EFER
+            0x0f, 0x32,                         // rdmsr
+            0x0d, 0x00, 0x01, 0x00, 0x00,       // or eax, EFER.LME
+            0x0f, 0x30,                         // wrmsr
+            0x0f, 0x20, 0xc0,                   // mov eax, cr0
+            0x0d, 0x00, 0x00, 0x00, 0x80,       // or eax, CR0.PG
+            0x0f, 0x22, 0xc0,                   // mov cr0, eax
+            0xea, 0x00, 0x11, 0x00, 0x00, 0x08, 0x00, // jmp 0x08:0x1100
+        ];
+        guest.write(0x1000, &code);
+        // mov rax, 0x123456789abcdef0; hlt
+        guest.write(0x1100, &[0x48, 0xb8, 0xf0, 0xde, 0xbc, 0x9a, 0x78, 0x56, 0x34, 0x12, 0xf4]);
+        // GDT with a 64-bit code segment at 0x08
+        guest.write(0x500, &[0; 8]);
+        guest.write(0x508, &[0xff, 0xff, 0x00, 0x00, 0x00, 0x9a, 0xaf, 0x00]);
+        // PML4 at 0x2000, and a PDPT at 0x3000 identity-mapping the first GiB
+        guest.write(0x2000, &[0x03, 0x30, 0, 0, 0, 0, 0, 0]);
+        guest.write(0x3000, &[0x83, 0, 0, 0, 0, 0, 0, 0]);
+
+        guest.set_vmcs(VMCS_GUEST_CR0, 0x11);
+        guest.set_vmcs(VMCS_GUEST_GDTR_BASE, 0x500);
+        guest.set_vmcs(VMCS_GUEST_GDTR_LIMIT, 0xf);
+        guest.set_vmcs(VMCS_GUEST_CS, 0x10);
+        guest.set_vmcs(VMCS_GUEST_CS_LIMIT, 0xffff_ffff);
+        guest.set_vmcs(VMCS_GUEST_CS_AR, 0xc09b);
+        for &(limit, ar) in &[(VMCS_GUEST_SS_LIMIT, VMCS_GUEST_SS_AR),
+            (VMCS_GUEST_DS_LIMIT, VMCS_GUEST_DS_AR)] {
+            guest.set_vmcs(limit, 0xffff_ffff);
+            guest.set_vmcs(ar, 0xc093);
+        }
+        guest.set_vmcs(VMCS_GUEST_RIP, 0x1000);
+        assert_eq!(Interpreter::vcpu_enable_native_msr(guest.vcpu, cpu::MSR_EFER, true),
+            HV_SUCCESS);
+
+        assert_eq!(guest.run(), VMX_REASON_HLT);
+        assert_eq!(guest.reg(x86Reg::RAX), 0x1234_5678_9abc_def0);
+        assert_eq!(guest.vmcs(VMCS_GUEST_RIP), 0x110a);
+        assert_eq!(guest.vmcs(VMCS_GUEST_CS), 0x08);
+        assert_ne!(guest.vmcs(VMCS_GUEST_IA32_EFER) & cpu::EFER_LMA, 0);
+        assert_ne!(guest.vmcs(VMCS_CTRL_VMENTRY_CONTROLS) & VMENTRY_GUEST_IA32E, 0);
+    }
+
+    #[test]
+    fn cpuid_and_rdmsr_exit() {
+        // cpuid; mov ecx, 0x3a; rdmsr
+        let guest = Guest::real_mode(&[0x0f, 0xa2, 0x66, 0xb9, 0x3a, 0, 0, 0, 0x0f, 0x32]);
+
+        assert_eq!(guest.run(), VMX_REASON_CPUID);
+        assert_eq!(guest.vmcs(VMCS_RO_VMEXIT_INSTR_LEN), 2);
+        guest.skip();
+
+        assert_eq!(guest.run(), VMX_REASON_RDMSR);
+        assert_eq!(guest.reg(x86Reg::RCX), 0x3a);
+        assert_eq!(guest.vmcs(VMCS_GUEST_RIP), 8);
+        assert_eq!(guest.vmcs(VMCS_RO_VMEXIT_INSTR_LEN), 2);
+    }
+
+    #[test]
+    fn unmapped_gpa_is_an_ept_violation() {
+        // mov al, [0x8000]
+        let guest = Guest::real_mode(&[0xa0, 0x00, 0x80]);
+
+        assert_eq!(guest.run(), VMX_REASON_EPT_VIOLATION);
+        assert_eq!(guest.vmcs(VMCS_GUEST_PHYSICAL_ADDRESS), 0x8000);
+        assert_eq!(guest.vmcs(VMCS_RO_EXIT_QUALIFIC) & 0x7, 0x1, "read access");
+        assert_eq!(guest.vmcs(VMCS_GUEST_RIP), 0);
+    }
+
+    #[test]
+    fn divide_by_zero_raises_de() {
+        // xor cx, cx; div cx, with the handler of vector 0 at 0x200 halting
+        let guest = Guest::real_mode(&[]);
+        guest.write(0x100, &[0x31, 0xc9, 0xf7, 0xf1]);
+        guest.write(0x200, &[0xf4]);
+        guest.write(0, &[0x00, 0x02, 0x00, 0x00]);
+        guest.set_vmcs(VMCS_GUEST_RIP, 0x100);
+        guest.set_vmcs(VMCS_GUEST_IDTR_LIMIT, 0x3ff);
+        assert_eq!(Interpreter::vcpu_write_register(guest.vcpu, x86Reg::RSP, 0x800),
+            HV_SUCCESS);
+
+        // Delivered through the IVT
+        assert_eq!(guest.run(), VMX_REASON_HLT);
+        assert_eq!(guest.vmcs(VMCS_GUEST_RIP), 0x200);
+
+        // Intercepted with the exception bitmap
+        guest.set_vmcs(VMCS_GUEST_RIP, 0x100);
+        guest.set_vmcs(VMCS_CTRL_EXC_BITMAP, 1);
+        assert_eq!(guest.run(), VMX_REASON_EXC_NMI);
+        assert_eq!(guest.vmcs(VMCS_RO_VMEXIT_IRQ_INFO), 0x8000_0300);
+        assert_eq!(guest.vmcs(VMCS_GUEST_RIP), 0x102);
+    }
+
+    #[test]
+    fn overflowing_ranges_are_rejected() {
+        let guest = Guest::real_mode(&[]);
+        let uva = guest.memory.as_ptr() as hv_uvaddr_t;
+        let gpa = 0u64.wrapping_sub(0x1000);
+        assert_eq!(unsafe { Interpreter::vm_map(uva, gpa, 0x2000, RWX) }, HV_BAD_ARGUMENT);
+        assert_eq!(Interpreter::vm_unmap(gpa, 0x2000), HV_BAD_ARGUMENT);
+        assert_eq!(Interpreter::vm_protect(gpa, 0x2000, RWX), HV_BAD_ARGUMENT);
+    }
+}
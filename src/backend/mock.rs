@@ -0,0 +1,788 @@
+/*
+Copyright (c) 2016 Saurav Sachidanand
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in
+all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+THE SOFTWARE.
+*/
+
+//! In-memory mock backend
+//!
+//! `Mock` keeps a register file, an MSR table and a VMCS field store for
+//! every vCPU, and returns VM exits that have been scripted with
+//! `push_exit` from successive runs. Like a processor, every exit clears
+//! the IDT-vectoring information and the valid bit of the VM-entry
+//! interruption information. Every call made through it is recorded and
+//! can be inspected with `calls`.
+//!
+//! Like the Hypervisor framework, the mock has a single VM per process, and
+//! its state is shared by every thread. Tests that use it should call
+//! `reset` first and must not run concurrently with each other.
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::sync::{Mutex, MutexGuard};
+use std::thread::{self, ThreadId};
+
+use ffi::*;
+use consts::vmcs::*;
+use consts::vmx_exit::VMX_REASON_IRQ;
+use super::Backend;
+use super::super::{x86Reg, VMXCap};
+
+/// Backend that records calls and replays scripted VM exits
+pub enum Mock {}
+
+/// VM exit to be returned by a run of a mock vCPU
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MockExit {
+    /// Exit reason, written to `VMCS_RO_EXIT_REASON`
+    pub reason: u64,
+    /// Exit qualification, written to `VMCS_RO_EXIT_QUALIFIC`
+    pub qualification: u64,
+    /// Guest physical address, written to `VMCS_GUEST_PHYSICAL_ADDRESS`
+    pub gpa: u64,
+    /// Any other VMCS fields to set when the exit is taken
+    pub fields: Vec<(u32, u64)>,
+}
+
+impl MockExit {
+    /// Creates an exit with the given reason
+    pub fn new(reason: u64) -> MockExit {
+        MockExit {
+            reason,
+            qualification: 0,
+            gpa: 0,
+            fields: Vec::new(),
+        }
+    }
+
+    /// Sets the exit qualification
+    pub fn qualification(mut self, qualification: u64) -> MockExit {
+        self.qualification = qualification;
+        self
+    }
+
+    /// Sets the guest physical address
+    pub fn gpa(mut self, gpa: u64) -> MockExit {
+        self.gpa = gpa;
+        self
+    }
+
+    /// Sets another VMCS field, such as `VMCS_RO_VMEXIT_INSTR_LEN`
+    pub fn field(mut self, field: u32, value: u64) -> MockExit {
+        self.fields.push((field, value));
+        self
+    }
+}
+
+/// Call made through the mock backend
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Call {
+    /// `vm_create` with its flags
+    VmCreate(hv_vm_options_t),
+    /// `vm_destroy`
+    VmDestroy,
+    /// `vm_map` of the host address `uva` to `gpa`
+    VmMap { uva: usize, gpa: u64, size: usize, flags: hv_memory_flags_t },
+    /// `vm_unmap`
+    VmUnmap { gpa: u64, size: usize },
+    /// `vm_protect` with the new permissions
+    VmProtect { gpa: u64, size: usize, flags: hv_memory_flags_t },
+    /// `vm_sync_tsc` with the TSC value
+    VmSyncTsc(u64),
+    /// `vcpu_create` with its flags
+    VcpuCreate(hv_vm_options_t),
+    /// `vcpu_destroy` of a vCPU
+    VcpuDestroy(hv_vcpuid_t),
+    /// `vcpu_run` of a vCPU
+    VcpuRun(hv_vcpuid_t),
+    /// `vcpu_interrupt` of a set of vCPUs
+    VcpuInterrupt(Vec<hv_vcpuid_t>),
+    /// `vcpu_get_exec_time` of a vCPU
+    VcpuGetExecTime(hv_vcpuid_t),
+    /// `vcpu_flush` of a vCPU
+    VcpuFlush(hv_vcpuid_t),
+    /// `vcpu_invalidate_tlb` of a vCPU
+    VcpuInvalidateTlb(hv_vcpuid_t),
+    /// `vcpu_read_register` of a vCPU and register
+    ReadRegister(hv_vcpuid_t, x86Reg),
+    /// `vcpu_write_register` of a vCPU, register and value
+    WriteRegister(hv_vcpuid_t, x86Reg, u64),
+    /// `vcpu_read_fpstate` of a vCPU, with the size of the buffer
+    ReadFpstate(hv_vcpuid_t, usize),
+    /// `vcpu_write_fpstate` of a vCPU, with the contents of the buffer
+    WriteFpstate(hv_vcpuid_t, Vec<u8>),
+    /// `vcpu_enable_native_msr` of a vCPU, MSR and setting
+    EnableNativeMsr(hv_vcpuid_t, u32, bool),
+    /// `vcpu_read_msr` of a vCPU and MSR
+    ReadMsr(hv_vcpuid_t, u32),
+    /// `vcpu_write_msr` of a vCPU, MSR and value
+    WriteMsr(hv_vcpuid_t, u32, u64),
+    /// `vmx_vcpu_read_vmcs` of a vCPU and field
+    ReadVmcs(hv_vcpuid_t, u32),
+    /// `vmx_vcpu_write_vmcs` of a vCPU, field and value
+    WriteVmcs(hv_vcpuid_t, u32, u64),
+    /// `vmx_read_capability` of a capability
+    ReadCapability(VMXCap),
+    /// `vmx_vcpu_set_apic_address` of a vCPU and guest physical address
+    SetApicAddress(hv_vcpuid_t, hv_gpaddr_t),
+}
+
+/// Region mapped into the guest physical address space of the mock VM
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MockRegion {
+    /// Host address the region starts at
+    pub uva: usize,
+    /// Guest physical address the region starts at
+    pub gpa: u64,
+    /// Size of the region in bytes
+    pub size: usize,
+    /// `HV_MEMORY_*` permissions of the region
+    pub flags: hv_memory_flags_t,
+}
+
+struct Vcpu {
+    thread: ThreadId,
+    registers: [u64; x86Reg::REGISTERS_MAX as usize],
+    msrs: BTreeMap<u32, u64>,
+    native_msrs: BTreeSet<u32>,
+    vmcs: BTreeMap<u32, u64>,
+    fpstate: Vec<u8>,
+    apic_address: Option<u64>,
+    exec_time: u64,
+    interrupted: bool,
+    exits: VecDeque<Result<MockExit, hv_return_t>>,
+}
+
+struct State {
+    vm: bool,
+    regions: Vec<MockRegion>,
+    vcpus: BTreeMap<hv_vcpuid_t, Vcpu>,
+    next_vcpu: hv_vcpuid_t,
+    capabilities: BTreeMap<u32, u64>,
+    calls: Vec<Call>,
+}
+
+static STATE: Mutex<State> = Mutex::new(State::new());
+
+// Allowed-0 settings of zero and allowed-1 settings of all ones, i.e. every
+// control is supported and none is required
+const DEFAULT_CONTROL_CAP: u64 = 0xffff_ffff_0000_0000;
+
+impl State {
+    const fn new() -> State {
+        State {
+            vm: false,
+            regions: Vec::new(),
+            vcpus: BTreeMap::new(),
+            next_vcpu: 0,
+            capabilities: BTreeMap::new(),
+            calls: Vec::new(),
+        }
+    }
+
+    // Looks up a vCPU, which like in the Hypervisor framework may only be
+    // used from the thread that created it
+    fn vcpu(&mut self, vcpu: hv_vcpuid_t) -> Result<&mut Vcpu, hv_return_t> {
+        match self.vcpus.get_mut(&vcpu) {
+            Some(v) if v.thread == thread::current().id() => Ok(v),
+            _ => Err(HV_BAD_ARGUMENT),
+        }
+    }
+}
+
+fn state() -> MutexGuard<'static, State> {
+    // A test that panicked while holding the lock must not take the others
+    // down with it
+    STATE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn record(call: Call) -> MutexGuard<'static, State> {
+    let mut state = state();
+    state.calls.push(call);
+    state
+}
+
+fn code(result: Result<(), hv_return_t>) -> hv_return_t {
+    match result {
+        Ok(()) => HV_SUCCESS,
+        Err(code) => code,
+    }
+}
+
+/// Clears all state of the mock, including recorded calls and scripted exits
+pub fn reset() {
+    *state() = State::new();
+}
+
+// Serializes the tests of every module that use the mock, which share its
+// state, and resets the mock for each
+#[cfg(test)]
+pub(crate) fn lock() -> MutexGuard<'static, ()> {
+    static LOCK: Mutex<()> = Mutex::new(());
+
+    let guard = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    reset();
+    guard
+}
+
+/// Returns every call made through the mock since the last `reset` or
+/// `take_calls`
+pub fn calls() -> Vec<Call> {
+    state().calls.clone()
+}
+
+/// Returns and clears the calls recorded so far
+pub fn take_calls() -> Vec<Call> {
+    ::std::mem::take(&mut state().calls)
+}
+
+/// Queues a VM exit to be returned by the next unanswered run of a vCPU
+pub fn push_exit(vcpu: hv_vcpuid_t, exit: MockExit) {
+    push(vcpu, Ok(exit));
+}
+
+/// Queues a failure code to be returned by the next unanswered run of a vCPU
+pub fn push_run_error(vcpu: hv_vcpuid_t, code: hv_return_t) {
+    push(vcpu, Err(code));
+}
+
+fn push(vcpu: hv_vcpuid_t, exit: Result<MockExit, hv_return_t>) {
+    if let Some(v) = state().vcpus.get_mut(&vcpu) {
+        v.exits.push_back(exit);
+    }
+}
+
+/// Returns the number of scripted exits a vCPU has not consumed yet
+pub fn pending_exits(vcpu: hv_vcpuid_t) -> usize {
+    state().vcpus.get(&vcpu).map_or(0, |v| v.exits.len())
+}
+
+/// Sets the value returned when reading a VMX capability
+///
+/// Control capabilities default to allowing every control and requiring
+/// none, and the preemption timer rate defaults to zero.
+pub fn set_capability(cap: VMXCap, value: u64) {
+    state().capabilities.insert(cap as u32, value);
+}
+
+/// Returns the regions currently mapped into the mock VM
+pub fn regions() -> Vec<MockRegion> {
+    state().regions.clone()
+}
+
+/// Returns the IDs of the vCPUs that currently exist
+pub fn vcpus() -> Vec<hv_vcpuid_t> {
+    state().vcpus.keys().cloned().collect()
+}
+
+/// Returns whether an MSR has been enabled for native use by a vCPU
+pub fn is_native_msr(vcpu: hv_vcpuid_t, msr: u32) -> bool {
+    state().vcpus.get(&vcpu).is_some_and(|v| v.native_msrs.contains(&msr))
+}
+
+/// Returns the guest APIC address set for a vCPU
+pub fn apic_address(vcpu: hv_vcpuid_t) -> Option<u64> {
+    state().vcpus.get(&vcpu).and_then(|v| v.apic_address)
+}
+
+/// Returns the value a vCPU currently holds for a VMCS field
+///
+/// Unlike reading the field through `vCPU::read_vmcs`, this isn't recorded
+/// and works from any thread.
+pub fn vmcs(vcpu: hv_vcpuid_t, field: u32) -> u64 {
+    state().vcpus.get(&vcpu).and_then(|v| v.vmcs.get(&field).cloned()).unwrap_or(0)
+}
+
+/// Returns the value a vCPU currently holds for a register, without
+/// recording a call
+pub fn register(vcpu: hv_vcpuid_t, reg: x86Reg) -> u64 {
+    state().vcpus.get(&vcpu).map_or(0, |v| v.registers[reg as usize])
+}
+
+/// Returns the value a vCPU currently holds for an MSR, without recording a
+/// call
+pub fn msr(vcpu: hv_vcpuid_t, msr: u32) -> u64 {
+    state().vcpus.get(&vcpu).and_then(|v| v.msrs.get(&msr).cloned()).unwrap_or(0)
+}
+
+// Returns the end of a range, or None if it wraps around the address space
+fn range_end(gpa: u64, size: usize) -> Option<u64> {
+    gpa.checked_add(size as u64)
+}
+
+// Returns whether a region overlaps the range from `gpa` to `end`. Mapped
+// regions never wrap around, as vm_map rejects those.
+fn overlaps(region: &MockRegion, gpa: u64, end: u64) -> bool {
+    gpa < region.gpa + region.size as u64 && region.gpa < end
+}
+
+impl Backend for Mock {
+    fn vm_create(flags: hv_vm_options_t) -> hv_return_t {
+        let mut state = record(Call::VmCreate(flags));
+        if state.vm {
+            return HV_BUSY;
+        }
+        state.vm = true;
+        HV_SUCCESS
+    }
+
+    fn vm_destroy() -> hv_return_t {
+        let mut state = record(Call::VmDestroy);
+        if !state.vm {
+            return HV_BAD_ARGUMENT;
+        }
+        if !state.vcpus.is_empty() {
+            return HV_BUSY;
+        }
+        state.vm = false;
+        state.regions.clear();
+        HV_SUCCESS
+    }
+
+    unsafe fn vm_map(uva: hv_uvaddr_t, gpa: hv_gpaddr_t, size: usize,
+        flags: hv_memory_flags_t) -> hv_return_t {
+        let uva = uva as usize;
+        let mut state = record(Call::VmMap { uva, gpa, size, flags });
+        let end = match range_end(gpa, size) {
+            Some(end) if state.vm && size != 0 => end,
+            _ => return HV_BAD_ARGUMENT,
+        };
+        if state.regions.iter().any(|r| overlaps(r, gpa, end)) {
+            return HV_ERROR;
+        }
+        state.regions.push(MockRegion { uva, gpa, size, flags });
+        HV_SUCCESS
+    }
+
+    fn vm_unmap(gpa: hv_gpaddr_t, size: usize) -> hv_return_t {
+        let mut state = record(Call::VmUnmap { gpa, size });
+        let end = match range_end(gpa, size) {
+            Some(end) if state.vm => end,
+            _ => return HV_BAD_ARGUMENT,
+        };
+
+        // Keep whatever parts of partially unmapped regions lie outside
+        // the range
+        let mut regions = Vec::new();
+        for r in state.regions.drain(..) {
+            if !overlaps(&r, gpa, end) {
+                regions.push(r);
+                continue;
+            }
+            let r_end = r.gpa + r.size as u64;
+            if r.gpa < gpa {
+                regions.push(MockRegion { size: (gpa - r.gpa) as usize, ..r });
+            }
+            if end < r_end {
+                regions.push(MockRegion {
+                    uva: r.uva + (end - r.gpa) as usize,
+                    gpa: end,
+                    size: (r_end - end) as usize,
+                    flags: r.flags,
+                });
+            }
+        }
+        regions.sort_by_key(|r| r.gpa);
+        state.regions = regions;
+        HV_SUCCESS
+    }
+
+    fn vm_protect(gpa: hv_gpaddr_t, size: usize, flags: hv_memory_flags_t) -> hv_return_t {
+        let mut state = record(Call::VmProtect { gpa, size, flags });
+        let end = match range_end(gpa, size) {
+            Some(end) if state.vm => end,
+            _ => return HV_BAD_ARGUMENT,
+        };
+
+        // Like Hypervisor.framework, refuse to protect a range that isn't
+        // entirely mapped
+        let mapped: u64 = state.regions.iter()
+            .filter(|r| overlaps(r, gpa, end))
+            .map(|r| ::std::cmp::min(r.gpa + r.size as u64, end) - ::std::cmp::max(r.gpa, gpa))
+            .sum();
+        if mapped != size as u64 {
+            return HV_ERROR;
+        }
+
+        let mut regions = Vec::new();
+        for r in state.regions.drain(..) {
+            if !overlaps(&r, gpa, end) {
+                regions.push(r);
+                continue;
+            }
+            let r_end = r.gpa + r.size as u64;
+            let start = ::std::cmp::max(r.gpa, gpa);
+            let stop = ::std::cmp::min(r_end, end);
+            if r.gpa < start {
+                regions.push(MockRegion { size: (start - r.gpa) as usize, ..r.clone() });
+            }
+            regions.push(MockRegion {
+                uva: r.uva + (start - r.gpa) as usize,
+                gpa: start,
+                size: (stop - start) as usize,
+                flags,
+            });
+            if stop < r_end {
+                regions.push(MockRegion {
+                    uva: r.uva + (stop - r.gpa) as usize,
+                    gpa: stop,
+                    size: (r_end - stop) as usize,
+                    flags: r.flags,
+                });
+            }
+        }
+        regions.sort_by_key(|r| r.gpa);
+        state.regions = regions;
+        HV_SUCCESS
+    }
+
+    fn vm_sync_tsc(tsc: u64) -> hv_return_t {
+        let mut state = record(Call::VmSyncTsc(tsc));
+        if !state.vm {
+            return HV_BAD_ARGUMENT;
+        }
+        for v in state.vcpus.values_mut() {
+            v.msrs.insert(0x10, tsc);
+        }
+        HV_SUCCESS
+    }
+
+    fn vcpu_create(vcpu: &mut hv_vcpuid_t, flags: hv_vm_options_t) -> hv_return_t {
+        let mut state = record(Call::VcpuCreate(flags));
+        if !state.vm {
+            return HV_BAD_ARGUMENT;
+        }
+        let id = state.next_vcpu;
+        state.next_vcpu += 1;
+        state.vcpus.insert(id, Vcpu {
+            thread: thread::current().id(),
+            registers: [0; x86Reg::REGISTERS_MAX as usize],
+            msrs: BTreeMap::new(),
+            native_msrs: BTreeSet::new(),
+            vmcs: BTreeMap::new(),
+            fpstate: Vec::new(),
+            apic_address: None,
+            exec_time: 0,
+            interrupted: false,
+            exits: VecDeque::new(),
+        });
+        *vcpu = id;
+        HV_SUCCESS
+    }
+
+    fn vcpu_destroy(vcpu: hv_vcpuid_t) -> hv_return_t {
+        let mut state = record(Call::VcpuDestroy(vcpu));
+        if let Err(code) = state.vcpu(vcpu) {
+            return code;
+        }
+        state.vcpus.remove(&vcpu);
+        HV_SUCCESS
+    }
+
+    fn vcpu_run(vcpu: hv_vcpuid_t) -> hv_return_t {
+        let mut state = record(Call::VcpuRun(vcpu));
+        code(state.vcpu(vcpu).and_then(|v| {
+            // A pending interrupt makes the run exit straight away, without
+            // consuming a scripted exit
+            let exit = if v.interrupted {
+                v.interrupted = false;
+                MockExit::new(VMX_REASON_IRQ)
+            } else {
+                v.exits.pop_front().unwrap_or(Err(HV_ERROR))?
+            };
+
+            v.exec_time += 1;
+            v.vmcs.insert(VMCS_RO_EXIT_REASON, exit.reason);
+            v.vmcs.insert(VMCS_RO_EXIT_QUALIFIC, exit.qualification);
+            v.vmcs.insert(VMCS_GUEST_PHYSICAL_ADDRESS, exit.gpa);
+            v.vmcs.insert(VMCS_RO_IDT_VECTOR_INFO, 0);
+            if let Some(info) = v.vmcs.get_mut(&VMCS_CTRL_VMENTRY_IRQ_INFO) {
+                *info &= !(1 << 31);
+            }
+            for &(field, value) in &exit.fields {
+                v.vmcs.insert(field, value);
+            }
+            Ok(())
+        }))
+    }
+
+    fn vcpu_interrupt(vcpus: &[hv_vcpuid_t]) -> hv_return_t {
+        let mut state = record(Call::VcpuInterrupt(vcpus.to_vec()));
+        if vcpus.iter().any(|id| !state.vcpus.contains_key(id)) {
+            return HV_BAD_ARGUMENT;
+        }
+        for id in vcpus {
+            if let Some(v) = state.vcpus.get_mut(id) {
+                v.interrupted = true;
+            }
+        }
+        HV_SUCCESS
+    }
+
+    fn vcpu_get_exec_time(vcpu: hv_vcpuid_t, time: &mut u64) -> hv_return_t {
+        let mut state = record(Call::VcpuGetExecTime(vcpu));
+        code(state.vcpu(vcpu).map(|v| *time = v.exec_time))
+    }
+
+    fn vcpu_flush(vcpu: hv_vcpuid_t) -> hv_return_t {
+        let mut state = record(Call::VcpuFlush(vcpu));
+        code(state.vcpu(vcpu).map(|_| ()))
+    }
+
+    fn vcpu_invalidate_tlb(vcpu: hv_vcpuid_t) -> hv_return_t {
+        let mut state = record(Call::VcpuInvalidateTlb(vcpu));
+        code(state.vcpu(vcpu).map(|_| ()))
+    }
+
+    fn vcpu_read_register(vcpu: hv_vcpuid_t, reg: x86Reg, value: &mut u64) -> hv_return_t {
+        let mut state = record(Call::ReadRegister(vcpu, reg));
+        if reg == x86Reg::REGISTERS_MAX {
+            return HV_BAD_ARGUMENT;
+        }
+        code(state.vcpu(vcpu).map(|v| *value = v.registers[reg as usize]))
+    }
+
+    fn vcpu_write_register(vcpu: hv_vcpuid_t, reg: x86Reg, value: u64) -> hv_return_t {
+        let mut state = record(Call::WriteRegister(vcpu, reg, value));
+        if reg == x86Reg::REGISTERS_MAX {
+            return HV_BAD_ARGUMENT;
+        }
+        code(state.vcpu(vcpu).map(|v| v.registers[reg as usize] = value))
+    }
+
+    fn vcpu_read_fpstate(vcpu: hv_vcpuid_t, buffer: &mut [u8]) -> hv_return_t {
+        let mut state = record(Call::ReadFpstate(vcpu, buffer.len()));
+        code(state.vcpu(vcpu).map(|v| {
+            v.fpstate.resize(buffer.len(), 0);
+            buffer.copy_from_slice(&v.fpstate);
+        }))
+    }
+
+    fn vcpu_write_fpstate(vcpu: hv_vcpuid_t, buffer: &[u8]) -> hv_return_t {
+        let mut state = record(Call::WriteFpstate(vcpu, buffer.to_vec()));
+        code(state.vcpu(vcpu).map(|v| v.fpstate = buffer.to_vec()))
+    }
+
+    fn vcpu_enable_native_msr(vcpu: hv_vcpuid_t, msr: u32, enable: bool) -> hv_return_t {
+        let mut state = record(Call::EnableNativeMsr(vcpu, msr, enable));
+        code(state.vcpu(vcpu).map(|v| {
+            if enable {
+                v.native_msrs.insert(msr);
+            } else {
+                v.native_msrs.remove(&msr);
+            }
+        }))
+    }
+
+    fn vcpu_read_msr(vcpu: hv_vcpuid_t, msr: u32, value: &mut u64) -> hv_return_t {
+        let mut state = record(Call::ReadMsr(vcpu, msr));
+        code(state.vcpu(vcpu).map(|v| *value = v.msrs.get(&msr).cloned().unwrap_or(0)))
+    }
+
+    fn vcpu_write_msr(vcpu: hv_vcpuid_t, msr: u32, value: u64) -> hv_return_t {
+        let mut state = record(Call::WriteMsr(vcpu, msr, value));
+        code(state.vcpu(vcpu).map(|v| {
+            v.msrs.insert(msr, value);
+        }))
+    }
+
+    fn vmx_vcpu_read_vmcs(vcpu: hv_vcpuid_t, field: u32, value: &mut u64) -> hv_return_t {
+        let mut state = record(Call::ReadVmcs(vcpu, field));
+        code(state.vcpu(vcpu).map(|v| *value = v.vmcs.get(&field).cloned().unwrap_or(0)))
+    }
+
+    fn vmx_vcpu_write_vmcs(vcpu: hv_vcpuid_t, field: u32, value: u64) -> hv_return_t {
+        let mut state = record(Call::WriteVmcs(vcpu, field, value));
+        code(state.vcpu(vcpu).map(|v| {
+            v.vmcs.insert(field, value);
+        }))
+    }
+
+    fn vmx_read_capability(field: VMXCap, value: &mut u64) -> hv_return_t {
+        let state = record(Call::ReadCapability(field));
+        *value = match state.capabilities.get(&(field as u32)) {
+            Some(&v) => v,
+            None if field == VMXCap::PREEMPTION_TIMER => 0,
+            None => DEFAULT_CONTROL_CAP,
+        };
+        HV_SUCCESS
+    }
+
+    fn vmx_vcpu_set_apic_address(vcpu: hv_vcpuid_t, gpa: hv_gpaddr_t) -> hv_return_t {
+        let mut state = record(Call::SetApicAddress(vcpu, gpa));
+        code(state.vcpu(vcpu).map(|v| v.apic_address = Some(gpa)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::MutexGuard;
+
+    use ffi::*;
+    use consts::vmcs::*;
+    use consts::vmx_exit::*;
+    use super::*;
+    use super::super::Backend;
+    use super::super::super::x86Reg;
+
+    // Starts each test with a fresh VM
+    fn setup() -> MutexGuard<'static, ()> {
+        let guard = lock();
+        assert_eq!(Mock::vm_create(HV_VM_DEFAULT), HV_SUCCESS);
+        guard
+    }
+
+    fn create_vcpu() -> hv_vcpuid_t {
+        let mut vcpu = 0;
+        assert_eq!(Mock::vcpu_create(&mut vcpu, HV_VCPU_DEFAULT), HV_SUCCESS);
+        vcpu
+    }
+
+    fn region(uva: usize, gpa: u64, size: usize, flags: hv_memory_flags_t) -> MockRegion {
+        MockRegion { uva, gpa, size, flags }
+    }
+
+    #[test]
+    fn map_and_unmap() {
+        let _guard = setup();
+        let rwx = HV_MEMORY_READ | HV_MEMORY_WRITE | HV_MEMORY_EXEC;
+
+        unsafe {
+            assert_eq!(Mock::vm_map(0x10000 as hv_uvaddr_t, 0x4000, 0x3000, rwx), HV_SUCCESS);
+            assert_eq!(Mock::vm_map(0x20000 as hv_uvaddr_t, 0x5000, 0x1000, rwx), HV_ERROR);
+        }
+        assert_eq!(regions(), vec![region(0x10000, 0x4000, 0x3000, rwx)]);
+
+        assert_eq!(Mock::vm_protect(0x5000, 0x1000, HV_MEMORY_READ), HV_SUCCESS);
+        assert_eq!(regions(), vec![
+            region(0x10000, 0x4000, 0x1000, rwx),
+            region(0x11000, 0x5000, 0x1000, HV_MEMORY_READ),
+            region(0x12000, 0x6000, 0x1000, rwx),
+        ]);
+
+        assert_eq!(Mock::vm_unmap(0x4000, 0x2000), HV_SUCCESS);
+        assert_eq!(regions(), vec![region(0x12000, 0x6000, 0x1000, rwx)]);
+
+        assert_eq!(take_calls(), vec![
+            Call::VmCreate(HV_VM_DEFAULT),
+            Call::VmMap { uva: 0x10000, gpa: 0x4000, size: 0x3000, flags: rwx },
+            Call::VmMap { uva: 0x20000, gpa: 0x5000, size: 0x1000, flags: rwx },
+            Call::VmProtect { gpa: 0x5000, size: 0x1000, flags: HV_MEMORY_READ },
+            Call::VmUnmap { gpa: 0x4000, size: 0x2000 },
+        ]);
+    }
+
+    #[test]
+    fn bad_ranges_are_rejected() {
+        let _guard = setup();
+        let rwx = HV_MEMORY_READ | HV_MEMORY_WRITE | HV_MEMORY_EXEC;
+        let top = 0u64.wrapping_sub(0x1000);
+
+        unsafe {
+            assert_eq!(Mock::vm_map(0x10000 as hv_uvaddr_t, top, 0x2000, rwx), HV_BAD_ARGUMENT);
+            assert_eq!(Mock::vm_map(0x10000 as hv_uvaddr_t, 0x4000, 0x1000, rwx), HV_SUCCESS);
+        }
+        assert_eq!(Mock::vm_unmap(top, 0x2000), HV_BAD_ARGUMENT);
+        assert_eq!(Mock::vm_protect(top, 0x2000, rwx), HV_BAD_ARGUMENT);
+
+        // Protecting memory that is partly or entirely unmapped fails
+        assert_eq!(Mock::vm_protect(0x8000, 0x1000, HV_MEMORY_READ), HV_ERROR);
+        assert_eq!(Mock::vm_protect(0x4000, 0x2000, HV_MEMORY_READ), HV_ERROR);
+        assert_eq!(regions(), vec![region(0x10000, 0x4000, 0x1000, rwx)]);
+    }
+
+    #[test]
+    fn registers_and_vmcs() {
+        let _guard = setup();
+        let vcpu = create_vcpu();
+
+        let mut value = 0;
+        assert_eq!(Mock::vcpu_write_register(vcpu, x86Reg::RAX, 0x1234), HV_SUCCESS);
+        assert_eq!(Mock::vcpu_read_register(vcpu, x86Reg::RAX, &mut value), HV_SUCCESS);
+        assert_eq!(value, 0x1234);
+        assert_eq!(register(vcpu, x86Reg::RAX), 0x1234);
+
+        assert_eq!(Mock::vmx_vcpu_write_vmcs(vcpu, VMCS_GUEST_RIP, 0xfff0), HV_SUCCESS);
+        assert_eq!(Mock::vmx_vcpu_read_vmcs(vcpu, VMCS_GUEST_RIP, &mut value), HV_SUCCESS);
+        assert_eq!(value, 0xfff0);
+        assert_eq!(vmcs(vcpu, VMCS_GUEST_RIP), 0xfff0);
+
+        assert_eq!(Mock::vcpu_write_msr(vcpu, 0xc000_0080, 0x500), HV_SUCCESS);
+        assert_eq!(msr(vcpu, 0xc000_0080), 0x500);
+
+        // Unknown vCPUs are rejected
+        assert_eq!(Mock::vcpu_read_register(vcpu + 1, x86Reg::RAX, &mut value),
+            HV_BAD_ARGUMENT);
+
+        assert_eq!(Mock::vcpu_destroy(vcpu), HV_SUCCESS);
+        assert!(vcpus().is_empty());
+    }
+
+    #[test]
+    fn vcpus_are_bound_to_their_thread() {
+        let _guard = setup();
+        let vcpu = create_vcpu();
+
+        let code = ::std::thread::spawn(move || Mock::vcpu_run(vcpu)).join().unwrap();
+        assert_eq!(code, HV_BAD_ARGUMENT);
+    }
+
+    #[test]
+    fn scripted_exits() {
+        let _guard = setup();
+        let vcpu = create_vcpu();
+
+        push_exit(vcpu, MockExit::new(VMX_REASON_IO).qualification(0x3f8_0000)
+            .field(VMCS_RO_VMEXIT_INSTR_LEN, 1));
+        push_run_error(vcpu, HV_ERROR);
+        push_exit(vcpu, MockExit::new(VMX_REASON_EPT_VIOLATION).gpa(0xfee0_0000));
+        assert_eq!(pending_exits(vcpu), 3);
+
+        assert_eq!(Mock::vcpu_run(vcpu), HV_SUCCESS);
+        assert_eq!(vmcs(vcpu, VMCS_RO_EXIT_REASON), VMX_REASON_IO);
+        assert_eq!(vmcs(vcpu, VMCS_RO_EXIT_QUALIFIC), 0x3f8_0000);
+        assert_eq!(vmcs(vcpu, VMCS_RO_VMEXIT_INSTR_LEN), 1);
+
+        assert_eq!(Mock::vcpu_run(vcpu), HV_ERROR);
+
+        // An interrupt exits without consuming the scripted exit
+        assert_eq!(Mock::vcpu_interrupt(&[vcpu]), HV_SUCCESS);
+        assert_eq!(Mock::vcpu_run(vcpu), HV_SUCCESS);
+        assert_eq!(vmcs(vcpu, VMCS_RO_EXIT_REASON), VMX_REASON_IRQ);
+
+        assert_eq!(Mock::vcpu_run(vcpu), HV_SUCCESS);
+        assert_eq!(vmcs(vcpu, VMCS_RO_EXIT_REASON), VMX_REASON_EPT_VIOLATION);
+        assert_eq!(vmcs(vcpu, VMCS_GUEST_PHYSICAL_ADDRESS), 0xfee0_0000);
+
+        // Running out of scripted exits fails the run
+        assert_eq!(pending_exits(vcpu), 0);
+        assert_eq!(Mock::vcpu_run(vcpu), HV_ERROR);
+    }
+
+    #[test]
+    fn exits_clear_the_entry_event() {
+        let _guard = setup();
+        let vcpu = create_vcpu();
+
+        assert_eq!(Mock::vmx_vcpu_write_vmcs(vcpu, VMCS_CTRL_VMENTRY_IRQ_INFO, 1 << 31 | 0x20),
+            HV_SUCCESS);
+        push_exit(vcpu, MockExit::new(VMX_REASON_HLT));
+        assert_eq!(Mock::vcpu_run(vcpu), HV_SUCCESS);
+        assert_eq!(vmcs(vcpu, VMCS_CTRL_VMENTRY_IRQ_INFO), 0x20);
+    }
+}
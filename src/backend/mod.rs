@@ -0,0 +1,159 @@
+/*
+Copyright (c) 2016 Saurav Sachidanand
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in
+all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+THE SOFTWARE.
+*/
+
+//! Hypervisor backends
+//!
+//! Every call the safe interface makes goes through the `Backend` trait.
+//! The backend used by the crate, `Platform`, is chosen at compile time:
+//!
+//! * `HypervisorFramework` on OS X when the `hvf` feature (on by default) is
+//!   enabled
+//!
+//! * `Unsupported` everywhere else, which fails every call with
+//!   `HV_UNSUPPORTED` so that code built on this crate still compiles
+//!
+//! Enabling the `interp` feature replaces either of these with
+//! `interp::Interpreter`, a software x86 interpreter that runs small guests
+//! on any host. Enabling the `mock` feature replaces any of them with
+//! `mock::Mock` for testing.
+
+#[cfg(all(target_os = "macos", feature = "hvf"))]
+mod hvf;
+mod unsupported;
+#[cfg(feature = "mock")]
+pub mod mock;
+#[cfg(feature = "interp")]
+pub mod interp;
+
+#[cfg(all(target_os = "macos", feature = "hvf"))]
+pub use self::hvf::HypervisorFramework;
+pub use self::unsupported::Unsupported;
+
+use ffi::*;
+use super::{x86Reg, VMXCap};
+
+/// Backend selected for this build
+#[cfg(feature = "mock")]
+pub type Platform = mock::Mock;
+
+/// Backend selected for this build
+#[cfg(all(not(feature = "mock"), feature = "interp"))]
+pub type Platform = interp::Interpreter;
+
+/// Backend selected for this build
+#[cfg(all(not(feature = "mock"), not(feature = "interp"), target_os = "macos", feature = "hvf"))]
+pub type Platform = HypervisorFramework;
+
+/// Backend selected for this build
+#[cfg(all(not(feature = "mock"), not(feature = "interp"),
+    not(all(target_os = "macos", feature = "hvf"))))]
+pub type Platform = Unsupported;
+
+/// Operations provided by a hypervisor
+///
+/// The methods mirror the functions in the `ffi` module, but take Rust
+/// references and slices in place of raw out-pointers. Like the Hypervisor
+/// framework, a backend has a single VM per process, so none of the methods
+/// take `self`.
+pub trait Backend {
+    /// Creates a VM instance for the current process
+    fn vm_create(flags: hv_vm_options_t) -> hv_return_t;
+
+    /// Destroys the VM instance associated with the current process
+    fn vm_destroy() -> hv_return_t;
+
+    /// Maps a region of host memory into the guest physical address space
+    /// of the VM
+    ///
+    /// # Safety
+    ///
+    /// `uva` must point to `size` bytes of memory that stay valid, and are
+    /// not otherwise aliased, until the region is unmapped.
+    unsafe fn vm_map(uva: hv_uvaddr_t, gpa: hv_gpaddr_t, size: usize,
+        flags: hv_memory_flags_t) -> hv_return_t;
+
+    /// Unmaps a region in the guest physical address space of the VM
+    fn vm_unmap(gpa: hv_gpaddr_t, size: usize) -> hv_return_t;
+
+    /// Modifies the permissions of a region in the guest physical address
+    /// space of the VM
+    fn vm_protect(gpa: hv_gpaddr_t, size: usize, flags: hv_memory_flags_t) -> hv_return_t;
+
+    /// Synchronizes guest Timestamp-Counters (TSC) across all vCPUs
+    fn vm_sync_tsc(tsc: u64) -> hv_return_t;
+
+    /// Creates a vCPU instance for the current thread
+    fn vcpu_create(vcpu: &mut hv_vcpuid_t, flags: hv_vm_options_t) -> hv_return_t;
+
+    /// Destroys a vCPU instance
+    fn vcpu_destroy(vcpu: hv_vcpuid_t) -> hv_return_t;
+
+    /// Executes a vCPU
+    fn vcpu_run(vcpu: hv_vcpuid_t) -> hv_return_t;
+
+    /// Forces an immediate VMEXIT of a set of vCPUs of the VM
+    fn vcpu_interrupt(vcpus: &[hv_vcpuid_t]) -> hv_return_t;
+
+    /// Returns the cumulative execution time of a vCPU in nanoseconds
+    fn vcpu_get_exec_time(vcpu: hv_vcpuid_t, time: &mut u64) -> hv_return_t;
+
+    /// Forces flushing of cached vCPU state
+    fn vcpu_flush(vcpu: hv_vcpuid_t) -> hv_return_t;
+
+    /// Invalidates the TLB of a vCPU
+    fn vcpu_invalidate_tlb(vcpu: hv_vcpuid_t) -> hv_return_t;
+
+    /// Returns the current value of an architectural x86 register of a vCPU
+    fn vcpu_read_register(vcpu: hv_vcpuid_t, reg: x86Reg, value: &mut u64) -> hv_return_t;
+
+    /// Sets the value of an architectural x86 register of a vCPU
+    fn vcpu_write_register(vcpu: hv_vcpuid_t, reg: x86Reg, value: u64) -> hv_return_t;
+
+    /// Returns the current architectural x86 floating point and SIMD state
+    /// of a vCPU
+    fn vcpu_read_fpstate(vcpu: hv_vcpuid_t, buffer: &mut [u8]) -> hv_return_t;
+
+    /// Sets the architectural x86 floating point and SIMD state of a vCPU
+    fn vcpu_write_fpstate(vcpu: hv_vcpuid_t, buffer: &[u8]) -> hv_return_t;
+
+    /// Enables an MSR to be used natively by the VM
+    fn vcpu_enable_native_msr(vcpu: hv_vcpuid_t, msr: u32, enable: bool) -> hv_return_t;
+
+    /// Returns the current value of an MSR of a vCPU
+    fn vcpu_read_msr(vcpu: hv_vcpuid_t, msr: u32, value: &mut u64) -> hv_return_t;
+
+    /// Set the value of an MSR of a vCPU
+    fn vcpu_write_msr(vcpu: hv_vcpuid_t, msr: u32, value: u64) -> hv_return_t;
+
+    /// Returns the current value of a VMCS field of a vCPU
+    fn vmx_vcpu_read_vmcs(vcpu: hv_vcpuid_t, field: u32, value: &mut u64) -> hv_return_t;
+
+    /// Sets the value of a VMCS field of a vCPU
+    fn vmx_vcpu_write_vmcs(vcpu: hv_vcpuid_t, field: u32, value: u64) -> hv_return_t;
+
+    /// Returns the VMX capabilities of the host processor
+    fn vmx_read_capability(field: VMXCap, value: &mut u64) -> hv_return_t;
+
+    /// Sets the address of the guest APIC for a vCPU in the guest physical
+    /// address space of the VM
+    fn vmx_vcpu_set_apic_address(vcpu: hv_vcpuid_t, gpa: hv_gpaddr_t) -> hv_return_t;
+}
@@ -0,0 +1,82 @@
+/*
+Copyright (c) 2016 Saurav Sachidanand
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in
+all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+THE SOFTWARE.
+*/
+
+//! Fallback backend for hosts without a hypervisor
+
+use ffi::*;
+use super::Backend;
+use super::super::{x86Reg, VMXCap};
+
+/// Backend that fails every call with `HV_UNSUPPORTED`
+pub enum Unsupported {}
+
+impl Backend for Unsupported {
+    fn vm_create(_: hv_vm_options_t) -> hv_return_t { HV_UNSUPPORTED }
+
+    fn vm_destroy() -> hv_return_t { HV_UNSUPPORTED }
+
+    unsafe fn vm_map(_: hv_uvaddr_t, _: hv_gpaddr_t, _: usize, _: hv_memory_flags_t) -> hv_return_t {
+        HV_UNSUPPORTED
+    }
+
+    fn vm_unmap(_: hv_gpaddr_t, _: usize) -> hv_return_t { HV_UNSUPPORTED }
+
+    fn vm_protect(_: hv_gpaddr_t, _: usize, _: hv_memory_flags_t) -> hv_return_t { HV_UNSUPPORTED }
+
+    fn vm_sync_tsc(_: u64) -> hv_return_t { HV_UNSUPPORTED }
+
+    fn vcpu_create(_: &mut hv_vcpuid_t, _: hv_vm_options_t) -> hv_return_t { HV_UNSUPPORTED }
+
+    fn vcpu_destroy(_: hv_vcpuid_t) -> hv_return_t { HV_UNSUPPORTED }
+
+    fn vcpu_run(_: hv_vcpuid_t) -> hv_return_t { HV_UNSUPPORTED }
+
+    fn vcpu_interrupt(_: &[hv_vcpuid_t]) -> hv_return_t { HV_UNSUPPORTED }
+
+    fn vcpu_get_exec_time(_: hv_vcpuid_t, _: &mut u64) -> hv_return_t { HV_UNSUPPORTED }
+
+    fn vcpu_flush(_: hv_vcpuid_t) -> hv_return_t { HV_UNSUPPORTED }
+
+    fn vcpu_invalidate_tlb(_: hv_vcpuid_t) -> hv_return_t { HV_UNSUPPORTED }
+
+    fn vcpu_read_register(_: hv_vcpuid_t, _: x86Reg, _: &mut u64) -> hv_return_t { HV_UNSUPPORTED }
+
+    fn vcpu_write_register(_: hv_vcpuid_t, _: x86Reg, _: u64) -> hv_return_t { HV_UNSUPPORTED }
+
+    fn vcpu_read_fpstate(_: hv_vcpuid_t, _: &mut [u8]) -> hv_return_t { HV_UNSUPPORTED }
+
+    fn vcpu_write_fpstate(_: hv_vcpuid_t, _: &[u8]) -> hv_return_t { HV_UNSUPPORTED }
+
+    fn vcpu_enable_native_msr(_: hv_vcpuid_t, _: u32, _: bool) -> hv_return_t { HV_UNSUPPORTED }
+
+    fn vcpu_read_msr(_: hv_vcpuid_t, _: u32, _: &mut u64) -> hv_return_t { HV_UNSUPPORTED }
+
+    fn vcpu_write_msr(_: hv_vcpuid_t, _: u32, _: u64) -> hv_return_t { HV_UNSUPPORTED }
+
+    fn vmx_vcpu_read_vmcs(_: hv_vcpuid_t, _: u32, _: &mut u64) -> hv_return_t { HV_UNSUPPORTED }
+
+    fn vmx_vcpu_write_vmcs(_: hv_vcpuid_t, _: u32, _: u64) -> hv_return_t { HV_UNSUPPORTED }
+
+    fn vmx_read_capability(_: VMXCap, _: &mut u64) -> hv_return_t { HV_UNSUPPORTED }
+
+    fn vmx_vcpu_set_apic_address(_: hv_vcpuid_t, _: hv_gpaddr_t) -> hv_return_t { HV_UNSUPPORTED }
+}
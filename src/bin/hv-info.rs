@@ -0,0 +1,52 @@
+/*
+Copyright (c) 2016 Saurav Sachidanand
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in
+all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+THE SOFTWARE.
+*/
+
+//! Prints the virtualization capabilities of the host
+//!
+//! Usage: `hv-info [--json]`
+
+extern crate hypervisor;
+
+use std::env;
+use std::process;
+
+use hypervisor::caps::CapabilityReport;
+
+fn main() {
+    let json = match env::args().nth(1) {
+        None => false,
+        Some(ref arg) if arg == "--json" => true,
+        Some(_) => {
+            eprintln!("usage: hv-info [--json]");
+            process::exit(2);
+        }
+    };
+
+    match CapabilityReport::read() {
+        Ok(ref report) if json => println!("{}", report.to_json()),
+        Ok(report) => print!("{}", report),
+        Err(e) => {
+            eprintln!("hv-info: {}", e);
+            process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,332 @@
+/*
+Copyright (c) 2016 Saurav Sachidanand
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in
+all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+THE SOFTWARE.
+*/
+
+//! Virtualization capabilities of the host
+//!
+//! `CapabilityReport` decodes the values of every `VMXCap` into the controls the host requires,
+//! allows or lacks. It can be built from raw values as well as read from the host, so values
+//! captured on one machine can be decoded anywhere.
+
+use std::fmt;
+
+use super::{read_vmx_cap, ControlCap, ControlField, HvError, VMXCap, VmxCaps};
+
+/// Whether the host lets a control be used
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Support {
+    /// The control must be 0
+    Unsupported,
+    /// The control may be 0 or 1
+    Optional,
+    /// The control must be 1
+    Required,
+}
+
+impl Support {
+    /// Returns the support for bits of a control field
+    pub fn of(cap: &ControlCap, bits: u64) -> Support {
+        if !cap.can_set(bits) {
+            Support::Unsupported
+        } else if !cap.can_clear(bits) {
+            Support::Required
+        } else {
+            Support::Optional
+        }
+    }
+
+    /// Returns the name of the support level
+    pub fn name(self) -> &'static str {
+        match self {
+            Support::Unsupported => "unsupported",
+            Support::Optional => "optional",
+            Support::Required => "required",
+        }
+    }
+}
+
+/// Named control and its support by the host
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Feature {
+    /// Name of the control in its flags type
+    pub name: &'static str,
+    /// Bits of the control
+    pub bits: u64,
+    /// Support by the host
+    pub support: Support,
+}
+
+/// Support for the controls of one VMX control field
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ControlReport {
+    field: ControlField,
+    cap: ControlCap,
+    features: Vec<Feature>,
+}
+
+impl ControlReport {
+    /// Decodes the capability of a control field
+    pub fn new(field: ControlField, cap: ControlCap) -> ControlReport {
+        ControlReport {
+            field,
+            cap,
+            features: field.flags().into_iter()
+                .map(|(name, bits)| Feature { name, bits, support: Support::of(&cap, bits) })
+                .collect(),
+        }
+    }
+
+    /// Returns the control field
+    pub fn field(&self) -> ControlField {
+        self.field
+    }
+
+    /// Returns the capability of the control field
+    pub fn cap(&self) -> ControlCap {
+        self.cap
+    }
+
+    /// Returns the named controls of the field
+    pub fn features(&self) -> &[Feature] {
+        &self.features
+    }
+}
+
+/// Virtualization capabilities of the host
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CapabilityReport {
+    controls: Vec<ControlReport>,
+    preemption_timer: u64,
+}
+
+impl CapabilityReport {
+    /// Decodes the control capabilities and the value of `VMXCap::PREEMPTION_TIMER`
+    pub fn new(caps: &VmxCaps, preemption_timer: u64) -> CapabilityReport {
+        CapabilityReport {
+            controls: ControlField::ALL.iter()
+                .map(|&field| ControlReport::new(field, caps.get(field)))
+                .collect(),
+            preemption_timer,
+        }
+    }
+
+    /// Decodes raw values of `VMXCap::PINBASED`, `PROCBASED`, `PROCBASED2`, `ENTRY`, `EXIT` and
+    /// `PREEMPTION_TIMER`
+    pub fn from_raw(pin_based: u64, cpu_based: u64, cpu_based2: u64, entry: u64, exit: u64,
+        preemption_timer: u64) -> CapabilityReport {
+        CapabilityReport::new(
+            &VmxCaps::from_raw(pin_based, cpu_based, cpu_based2, entry, exit),
+            preemption_timer
+        )
+    }
+
+    /// Reads the capabilities of the host processor
+    pub fn read() -> Result<CapabilityReport, HvError> {
+        Ok(CapabilityReport::new(&VmxCaps::read()?, read_vmx_cap(&VMXCap::PREEMPTION_TIMER)?))
+    }
+
+    /// Returns the support for the controls of each field
+    pub fn controls(&self) -> &[ControlReport] {
+        &self.controls
+    }
+
+    /// Returns the support for the controls of a field
+    pub fn control(&self, field: ControlField) -> &ControlReport {
+        &self.controls[field as usize]
+    }
+
+    /// Returns whether the host allows every bit of a control field to be 1
+    pub fn supports(&self, field: ControlField, bits: u64) -> bool {
+        self.control(field).cap.can_set(bits)
+    }
+
+    /// Returns the raw value of `VMXCap::PREEMPTION_TIMER`
+    pub fn preemption_timer(&self) -> u64 {
+        self.preemption_timer
+    }
+
+    /// Returns the rate of the VMX-preemption timer, which counts down once every
+    /// 2^rate TSC cycles
+    pub fn preemption_timer_rate(&self) -> u32 {
+        (self.preemption_timer & 0x1f) as u32
+    }
+
+    /// Returns the report as a JSON object
+    ///
+    /// Each control field is keyed by its name, with the raw capability as hex strings and the
+    /// support for each named control.
+    pub fn to_json(&self) -> String {
+        let mut json = String::from("{");
+        for control in &self.controls {
+            json.push_str(&format!(
+                "\"{}\":{{\"required\":\"{:#x}\",\"allowed\":\"{:#x}\",\"features\":{{",
+                key(control.field), control.cap.required, control.cap.allowed
+            ));
+            let features: Vec<String> = control.features.iter()
+                .map(|f| format!("\"{}\":\"{}\"", f.name, f.support.name()))
+                .collect();
+            json.push_str(&features.join(","));
+            json.push_str("}},");
+        }
+        json.push_str(&format!(
+            "\"preemption_timer\":{{\"raw\":\"{:#x}\",\"rate\":{}}}}}",
+            self.preemption_timer, self.preemption_timer_rate()
+        ));
+        json
+    }
+}
+
+// Returns the JSON key of a control field
+fn key(field: ControlField) -> &'static str {
+    match field {
+        ControlField::PinBased => "pin_based",
+        ControlField::CpuBased => "cpu_based",
+        ControlField::CpuBased2 => "cpu_based2",
+        ControlField::Entry => "entry",
+        ControlField::Exit => "exit",
+    }
+}
+
+impl fmt::Display for CapabilityReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for control in &self.controls {
+            writeln!(f, "{} (required {:#x}, allowed {:#x}):", control.field.vmcs_field().name(),
+                control.cap.required, control.cap.allowed)?;
+            for feature in &control.features {
+                writeln!(f, "  {:<28} {}", feature.name, feature.support.name())?;
+            }
+        }
+        writeln!(f, "VMX-preemption timer: rate {} ({:#x})", self.preemption_timer_rate(),
+            self.preemption_timer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Values of PINBASED, PROCBASED, PROCBASED2, ENTRY, EXIT and PREEMPTION_TIMER, in the
+    // format the host reports them
+    fn fixture() -> CapabilityReport {
+        CapabilityReport::from_raw(
+            0x0000_007f_0000_0016,
+            0xfff9_fffe_0401_e172,
+            0x0000_00a2_0000_0000,
+            0x0000_f3ff_0000_11ff,
+            0x007f_ffff_0003_6dff,
+            0x0000_0000_0000_0005,
+        )
+    }
+
+    fn support(report: &CapabilityReport, field: ControlField, name: &str) -> Support {
+        report.control(field).features().iter()
+            .find(|f| f.name == name)
+            .unwrap_or_else(|| panic!("no feature {}", name))
+            .support
+    }
+
+    #[test]
+    fn decodes_support_from_raw_values() {
+        let report = fixture();
+        assert_eq!(report.control(ControlField::CpuBased).cap(),
+            ControlCap { required: 0x0401_e172, allowed: 0xfff9_fffe });
+
+        let cases = [
+            (ControlField::PinBased, "INTR", Support::Optional),
+            (ControlField::PinBased, "POSTED_INTR", Support::Unsupported),
+            (ControlField::CpuBased, "HLT", Support::Optional),
+            (ControlField::CpuBased, "CR3_LOAD", Support::Required),
+            (ControlField::CpuBased, "TERTIARY_CTLS", Support::Unsupported),
+            (ControlField::CpuBased2, "EPT", Support::Optional),
+            (ControlField::CpuBased2, "UNRESTRICTED", Support::Optional),
+            (ControlField::CpuBased2, "RDTSCP", Support::Unsupported),
+            (ControlField::Entry, "LOAD_DBG_CONTROLS", Support::Required),
+            (ControlField::Entry, "GUEST_IA32E", Support::Optional),
+            (ControlField::Entry, "SMM", Support::Unsupported),
+            (ControlField::Exit, "SAVE_VMX_TIMER", Support::Optional),
+            (ControlField::Exit, "CLEAR_IA32_BNDCFGS", Support::Unsupported),
+        ];
+        for &(field, name, expected) in &cases {
+            assert_eq!(support(&report, field, name), expected, "{:?} {}", field, name);
+        }
+
+        assert!(report.supports(ControlField::CpuBased2, 0xa2));
+        assert!(!report.supports(ControlField::CpuBased2, 0x1));
+        assert_eq!(report.preemption_timer(), 5);
+        assert_eq!(report.preemption_timer_rate(), 5);
+    }
+
+    #[test]
+    fn rate_is_the_low_five_bits() {
+        let report = CapabilityReport::from_raw(0, 0, 0, 0, 0, 0xffff_ffe0 | 31);
+        assert_eq!(report.preemption_timer_rate(), 31);
+    }
+
+    #[test]
+    fn json_output() {
+        let expected = [
+            r#"{"pin_based":{"required":"0x16","allowed":"0x7f","#,
+            r#""features":{"INTR":"optional","NMI":"optional","VIRTUAL_NMI":"optional","#,
+            r#""PREEMPTION_TIMER":"optional","POSTED_INTR":"unsupported"}},"#,
+            r#""cpu_based":{"required":"0x401e172","allowed":"0xfff9fffe","#,
+            r#""features":{"IRQ_WND":"optional","TSC_OFFSET":"optional","HLT":"optional","#,
+            r#""INVLPG":"optional","MWAIT":"optional","RDPMC":"optional","#,
+            r#""RDTSC":"optional","CR3_LOAD":"required","CR3_STORE":"required","#,
+            r#""TERTIARY_CTLS":"unsupported","CR8_LOAD":"optional","CR8_STORE":"optional","#,
+            r#""TPR_SHADOW":"optional","VIRTUAL_NMI_WND":"optional","MOV_DR":"optional","#,
+            r#""UNCOND_IO":"optional","IO_BITMAPS":"optional","MTF":"optional","#,
+            r#""MSR_BITMAPS":"optional","MONITOR":"optional","PAUSE":"optional","#,
+            r#""SECONDARY_CTLS":"optional"}},"cpu_based2":{"required":"0x0","#,
+            r#""allowed":"0xa2","features":{"VIRTUAL_APIC":"unsupported","EPT":"optional","#,
+            r#""DESC_TABLE":"unsupported","RDTSCP":"unsupported","X2APIC":"unsupported","#,
+            r#""VPID":"optional","WBINVD":"unsupported","UNRESTRICTED":"optional","#,
+            r#""APIC_REG_VIRT":"unsupported","VIRT_INTR_DELIVERY":"unsupported","#,
+            r#""PAUSE_LOOP":"unsupported","RDRAND":"unsupported","INVPCID":"unsupported","#,
+            r#""VMFUNC":"unsupported","VMCS_SHADOW":"unsupported","ENCLS":"unsupported","#,
+            r#""RDSEED":"unsupported","PML":"unsupported","EPT_VE":"unsupported","#,
+            r#""PT_CONCEAL_VMX":"unsupported","XSAVES_XRSTORS":"unsupported","#,
+            r#""PASID_TRANSLATION":"unsupported","MODE_BASED_EPT_EXEC":"unsupported","#,
+            r#""SUBPAGE_WRITE":"unsupported","PT_GUEST_PHYSICAL":"unsupported","#,
+            r#""TSC_SCALING":"unsupported","USER_WAIT_PAUSE":"unsupported","#,
+            r#""PCONFIG":"unsupported","ENCLV":"unsupported","#,
+            r#""BUS_LOCK_DETECTION":"unsupported","INSTR_TIMEOUT":"unsupported"}},"#,
+            r#""entry":{"required":"0x11ff","allowed":"0xf3ff","#,
+            r#""features":{"LOAD_DBG_CONTROLS":"required","GUEST_IA32E":"optional","#,
+            r#""SMM":"unsupported","DEACTIVATE_DUAL_MONITOR":"unsupported","#,
+            r#""LOAD_IA32_PERF_GLOBAL_CTRL":"optional","LOAD_IA32_PAT":"optional","#,
+            r#""LOAD_EFER":"optional","LOAD_IA32_BNDCFGS":"unsupported","#,
+            r#""PT_CONCEAL_VMX":"unsupported","LOAD_IA32_RTIT_CTL":"unsupported","#,
+            r#""LOAD_UINV":"unsupported","LOAD_CET_STATE":"unsupported","#,
+            r#""LOAD_IA32_LBR_CTL":"unsupported","LOAD_PKRS":"unsupported"}},"#,
+            r#""exit":{"required":"0x36dff","allowed":"0x7fffff","#,
+            r#""features":{"SAVE_DBG_CONTROLS":"required","HOST_IA32E":"optional","#,
+            r#""LOAD_IA32_PERF_GLOBAL_CTRL":"optional","ACK_INTR":"optional","#,
+            r#""SAVE_IA32_PAT":"optional","LOAD_IA32_PAT":"optional","#,
+            r#""SAVE_EFER":"optional","LOAD_EFER":"optional","SAVE_VMX_TIMER":"optional","#,
+            r#""CLEAR_IA32_BNDCFGS":"unsupported","PT_CONCEAL_VMX":"unsupported","#,
+            r#""CLEAR_IA32_RTIT_CTL":"unsupported","CLEAR_IA32_LBR_CTL":"unsupported","#,
+            r#""CLEAR_UINV":"unsupported","LOAD_CET_STATE":"unsupported","#,
+            r#""LOAD_PKRS":"unsupported","SAVE_IA32_PERF_GLOBAL_CTRL":"unsupported","#,
+            r#""SECONDARY_CTLS":"unsupported"}},"preemption_timer":{"raw":"0x5","rate":5}}"#,
+        ].concat();
+        assert_eq!(fixture().to_json(), expected);
+    }
+}
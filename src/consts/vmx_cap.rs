@@ -39,6 +39,7 @@ pub const CPU_BASED_RDPMC                    : uint64_t = 1 << 11;
 pub const CPU_BASED_RDTSC                    : uint64_t = 1 << 12;
 pub const CPU_BASED_CR3_LOAD                 : uint64_t = 1 << 15;
 pub const CPU_BASED_CR3_STORE                : uint64_t = 1 << 16;
+pub const CPU_BASED_TERTIARY_CTLS            : uint64_t = 1 << 17;
 pub const CPU_BASED_CR8_LOAD                 : uint64_t = 1 << 19;
 pub const CPU_BASED_CR8_STORE                : uint64_t = 1 << 20;
 pub const CPU_BASED_TPR_SHADOW               : uint64_t = 1 << 21;
@@ -67,9 +68,22 @@ pub const CPU_BASED2_RDRAND                  : uint64_t = 1 << 11;
 pub const CPU_BASED2_INVPCID                 : uint64_t = 1 << 12;
 pub const CPU_BASED2_VMFUNC                  : uint64_t = 1 << 13;
 pub const CPU_BASED2_VMCS_SHADOW             : uint64_t = 1 << 14;
+pub const CPU_BASED2_ENCLS                   : uint64_t = 1 << 15;
 pub const CPU_BASED2_RDSEED                  : uint64_t = 1 << 16;
+pub const CPU_BASED2_PML                     : uint64_t = 1 << 17;
 pub const CPU_BASED2_EPT_VE                  : uint64_t = 1 << 18;
+pub const CPU_BASED2_PT_CONCEAL_VMX          : uint64_t = 1 << 19;
 pub const CPU_BASED2_XSAVES_XRSTORS          : uint64_t = 1 << 20;
+pub const CPU_BASED2_PASID_TRANSLATION       : uint64_t = 1 << 21;
+pub const CPU_BASED2_MODE_BASED_EPT_EXEC     : uint64_t = 1 << 22;
+pub const CPU_BASED2_SUBPAGE_WRITE           : uint64_t = 1 << 23;
+pub const CPU_BASED2_PT_GUEST_PHYSICAL       : uint64_t = 1 << 24;
+pub const CPU_BASED2_TSC_SCALING             : uint64_t = 1 << 25;
+pub const CPU_BASED2_USER_WAIT_PAUSE         : uint64_t = 1 << 26;
+pub const CPU_BASED2_PCONFIG                 : uint64_t = 1 << 27;
+pub const CPU_BASED2_ENCLV                   : uint64_t = 1 << 28;
+pub const CPU_BASED2_BUS_LOCK_DETECTION      : uint64_t = 1 << 30;
+pub const CPU_BASED2_INSTR_TIMEOUT           : uint64_t = 1 << 31;
 
 pub const VMX_EPT_VPID_SUPPORT_AD            : uint64_t = 1 << 21;
 pub const VMX_EPT_VPID_SUPPORT_EXONLY        : uint64_t = 1 <<  0;
@@ -83,6 +97,15 @@ pub const VMEXIT_LOAD_IA32_PAT               : uint64_t = 1 << 19;
 pub const VMEXIT_SAVE_EFER                   : uint64_t = 1 << 20;
 pub const VMEXIT_LOAD_EFER                   : uint64_t = 1 << 21;
 pub const VMEXIT_SAVE_VMX_TIMER              : uint64_t = 1 << 22;
+pub const VMEXIT_CLEAR_IA32_BNDCFGS          : uint64_t = 1 << 23;
+pub const VMEXIT_PT_CONCEAL_VMX              : uint64_t = 1 << 24;
+pub const VMEXIT_CLEAR_IA32_RTIT_CTL         : uint64_t = 1 << 25;
+pub const VMEXIT_CLEAR_IA32_LBR_CTL          : uint64_t = 1 << 26;
+pub const VMEXIT_CLEAR_UINV                  : uint64_t = 1 << 27;
+pub const VMEXIT_LOAD_CET_STATE              : uint64_t = 1 << 28;
+pub const VMEXIT_LOAD_PKRS                   : uint64_t = 1 << 29;
+pub const VMEXIT_SAVE_IA32_PERF_GLOBAL_CTRL  : uint64_t = 1 << 30;
+pub const VMEXIT_SECONDARY_CTLS              : uint64_t = 1 << 31;
 
 pub const VMENTRY_LOAD_DBG_CONTROLS          : uint64_t = 1 <<  2;
 pub const VMENTRY_GUEST_IA32E                : uint64_t = 1 <<  9;
@@ -91,3 +114,10 @@ pub const VMENTRY_DEACTIVATE_DUAL_MONITOR    : uint64_t = 1 << 11;
 pub const VMENTRY_LOAD_IA32_PERF_GLOBAL_CTRL : uint64_t = 1 << 13;
 pub const VMENTRY_LOAD_IA32_PAT              : uint64_t = 1 << 14;
 pub const VMENTRY_LOAD_EFER                  : uint64_t = 1 << 15;
+pub const VMENTRY_LOAD_IA32_BNDCFGS          : uint64_t = 1 << 16;
+pub const VMENTRY_PT_CONCEAL_VMX             : uint64_t = 1 << 17;
+pub const VMENTRY_LOAD_IA32_RTIT_CTL         : uint64_t = 1 << 18;
+pub const VMENTRY_LOAD_UINV                  : uint64_t = 1 << 19;
+pub const VMENTRY_LOAD_CET_STATE             : uint64_t = 1 << 20;
+pub const VMENTRY_LOAD_IA32_LBR_CTL          : uint64_t = 1 << 21;
+pub const VMENTRY_LOAD_PKRS                  : uint64_t = 1 << 22;
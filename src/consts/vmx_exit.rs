@@ -81,6 +81,22 @@ pub const VMX_REASON_APIC_WRITE        : uint64_t = 56;
 pub const VMX_REASON_RDRAND            : uint64_t = 57;
 pub const VMX_REASON_INVPCID           : uint64_t = 58;
 pub const VMX_REASON_VMFUNC            : uint64_t = 59;
+pub const VMX_REASON_ENCLS             : uint64_t = 60;
 pub const VMX_REASON_RDSEED            : uint64_t = 61;
+pub const VMX_REASON_PML_FULL          : uint64_t = 62;
 pub const VMX_REASON_XSAVES            : uint64_t = 63;
 pub const VMX_REASON_XRSTORS           : uint64_t = 64;
+pub const VMX_REASON_PCONFIG           : uint64_t = 65;
+pub const VMX_REASON_SPP               : uint64_t = 66;
+pub const VMX_REASON_UMWAIT            : uint64_t = 67;
+pub const VMX_REASON_TPAUSE            : uint64_t = 68;
+pub const VMX_REASON_LOADIWKEY         : uint64_t = 69;
+pub const VMX_REASON_ENCLV             : uint64_t = 70;
+pub const VMX_REASON_ENQCMD_PASID      : uint64_t = 72;
+pub const VMX_REASON_ENQCMDS_PASID     : uint64_t = 73;
+pub const VMX_REASON_BUS_LOCK          : uint64_t = 74;
+pub const VMX_REASON_INSTR_TIMEOUT     : uint64_t = 75;
+pub const VMX_REASON_SEAMCALL          : uint64_t = 76;
+pub const VMX_REASON_TDCALL            : uint64_t = 77;
+pub const VMX_REASON_RDMSRLIST         : uint64_t = 78;
+pub const VMX_REASON_WRMSRLIST         : uint64_t = 79;
@@ -0,0 +1,608 @@
+/*
+Copyright (c) 2016 Saurav Sachidanand
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in
+all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+THE SOFTWARE.
+*/
+
+//! VMX controls
+//!
+//! Each VMX control field has its own flags type, so controls of one field can't end up in
+//! another. The host decides which bits of each field may be 0 and which may be 1, and reports
+//! them through `read_vmx_cap`. `VmxControls` collects the controls wanted in each field and
+//! adjusts them to values the host accepts.
+
+use consts::vmx_cap::*;
+use super::{read_vmx_cap, HvError, VMXCap, VmcsField, VmcsSnapshot};
+
+flags! {
+    /// Pin-based VM-execution controls
+    pub struct PinBasedControls: u64 {
+        /// External-interrupt exiting
+        const INTR = PIN_BASED_INTR;
+        /// NMI exiting
+        const NMI = PIN_BASED_NMI;
+        /// Virtual NMIs
+        const VIRTUAL_NMI = PIN_BASED_VIRTUAL_NMI;
+        /// Activate VMX-preemption timer
+        const PREEMPTION_TIMER = PIN_BASED_PREEMPTION_TIMER;
+        /// Process posted interrupts
+        const POSTED_INTR = PIN_BASED_POSTED_INTR;
+    }
+}
+
+flags! {
+    /// Primary processor-based VM-execution controls
+    pub struct PrimaryProcControls: u64 {
+        /// Interrupt-window exiting
+        const IRQ_WND = CPU_BASED_IRQ_WND;
+        /// Use TSC offsetting
+        const TSC_OFFSET = CPU_BASED_TSC_OFFSET;
+        /// HLT exiting
+        const HLT = CPU_BASED_HLT;
+        /// INVLPG exiting
+        const INVLPG = CPU_BASED_INVLPG;
+        /// MWAIT exiting
+        const MWAIT = CPU_BASED_MWAIT;
+        /// RDPMC exiting
+        const RDPMC = CPU_BASED_RDPMC;
+        /// RDTSC exiting
+        const RDTSC = CPU_BASED_RDTSC;
+        /// CR3-load exiting
+        const CR3_LOAD = CPU_BASED_CR3_LOAD;
+        /// CR3-store exiting
+        const CR3_STORE = CPU_BASED_CR3_STORE;
+        /// Activate tertiary controls
+        const TERTIARY_CTLS = CPU_BASED_TERTIARY_CTLS;
+        /// CR8-load exiting
+        const CR8_LOAD = CPU_BASED_CR8_LOAD;
+        /// CR8-store exiting
+        const CR8_STORE = CPU_BASED_CR8_STORE;
+        /// Use TPR shadow
+        const TPR_SHADOW = CPU_BASED_TPR_SHADOW;
+        /// NMI-window exiting
+        const VIRTUAL_NMI_WND = CPU_BASED_VIRTUAL_NMI_WND;
+        /// MOV-DR exiting
+        const MOV_DR = CPU_BASED_MOV_DR;
+        /// Unconditional I/O exiting
+        const UNCOND_IO = CPU_BASED_UNCOND_IO;
+        /// Use I/O bitmaps
+        const IO_BITMAPS = CPU_BASED_IO_BITMAPS;
+        /// Monitor trap flag
+        const MTF = CPU_BASED_MTF;
+        /// Use MSR bitmaps
+        const MSR_BITMAPS = CPU_BASED_MSR_BITMAPS;
+        /// MONITOR exiting
+        const MONITOR = CPU_BASED_MONITOR;
+        /// PAUSE exiting
+        const PAUSE = CPU_BASED_PAUSE;
+        /// Activate secondary controls
+        const SECONDARY_CTLS = CPU_BASED_SECONDARY_CTLS;
+    }
+}
+
+flags! {
+    /// Secondary processor-based VM-execution controls
+    pub struct SecondaryProcControls: u64 {
+        /// Virtualize APIC accesses
+        const VIRTUAL_APIC = CPU_BASED2_VIRTUAL_APIC;
+        /// Enable EPT
+        const EPT = CPU_BASED2_EPT;
+        /// Descriptor-table exiting
+        const DESC_TABLE = CPU_BASED2_DESC_TABLE;
+        /// Enable RDTSCP
+        const RDTSCP = CPU_BASED2_RDTSCP;
+        /// Virtualize x2APIC mode
+        const X2APIC = CPU_BASED2_X2APIC;
+        /// Enable VPID
+        const VPID = CPU_BASED2_VPID;
+        /// WBINVD exiting
+        const WBINVD = CPU_BASED2_WBINVD;
+        /// Unrestricted guest
+        const UNRESTRICTED = CPU_BASED2_UNRESTRICTED;
+        /// APIC-register virtualization
+        const APIC_REG_VIRT = CPU_BASED2_APIC_REG_VIRT;
+        /// Virtual-interrupt delivery
+        const VIRT_INTR_DELIVERY = CPU_BASED2_VIRT_INTR_DELIVERY;
+        /// PAUSE-loop exiting
+        const PAUSE_LOOP = CPU_BASED2_PAUSE_LOOP;
+        /// RDRAND exiting
+        const RDRAND = CPU_BASED2_RDRAND;
+        /// Enable INVPCID
+        const INVPCID = CPU_BASED2_INVPCID;
+        /// Enable VM functions
+        const VMFUNC = CPU_BASED2_VMFUNC;
+        /// VMCS shadowing
+        const VMCS_SHADOW = CPU_BASED2_VMCS_SHADOW;
+        /// Enable ENCLS exiting
+        const ENCLS = CPU_BASED2_ENCLS;
+        /// RDSEED exiting
+        const RDSEED = CPU_BASED2_RDSEED;
+        /// Enable PML
+        const PML = CPU_BASED2_PML;
+        /// EPT-violation #VE
+        const EPT_VE = CPU_BASED2_EPT_VE;
+        /// Conceal VMX from PT
+        const PT_CONCEAL_VMX = CPU_BASED2_PT_CONCEAL_VMX;
+        /// Enable XSAVES/XRSTORS
+        const XSAVES_XRSTORS = CPU_BASED2_XSAVES_XRSTORS;
+        /// PASID translation
+        const PASID_TRANSLATION = CPU_BASED2_PASID_TRANSLATION;
+        /// Mode-based execute control for EPT
+        const MODE_BASED_EPT_EXEC = CPU_BASED2_MODE_BASED_EPT_EXEC;
+        /// Sub-page write permissions for EPT
+        const SUBPAGE_WRITE = CPU_BASED2_SUBPAGE_WRITE;
+        /// Intel PT uses guest physical addresses
+        const PT_GUEST_PHYSICAL = CPU_BASED2_PT_GUEST_PHYSICAL;
+        /// Use TSC scaling
+        const TSC_SCALING = CPU_BASED2_TSC_SCALING;
+        /// Enable user wait and pause
+        const USER_WAIT_PAUSE = CPU_BASED2_USER_WAIT_PAUSE;
+        /// Enable PCONFIG
+        const PCONFIG = CPU_BASED2_PCONFIG;
+        /// Enable ENCLV exiting
+        const ENCLV = CPU_BASED2_ENCLV;
+        /// VMM bus-lock detection
+        const BUS_LOCK_DETECTION = CPU_BASED2_BUS_LOCK_DETECTION;
+        /// Instruction timeout
+        const INSTR_TIMEOUT = CPU_BASED2_INSTR_TIMEOUT;
+    }
+}
+
+flags! {
+    /// VM-entry controls
+    pub struct EntryControls: u64 {
+        /// Load debug controls
+        const LOAD_DBG_CONTROLS = VMENTRY_LOAD_DBG_CONTROLS;
+        /// IA-32e mode guest
+        const GUEST_IA32E = VMENTRY_GUEST_IA32E;
+        /// Entry to SMM
+        const SMM = VMENTRY_SMM;
+        /// Deactivate dual-monitor treatment
+        const DEACTIVATE_DUAL_MONITOR = VMENTRY_DEACTIVATE_DUAL_MONITOR;
+        /// Load IA32_PERF_GLOBAL_CTRL
+        const LOAD_IA32_PERF_GLOBAL_CTRL = VMENTRY_LOAD_IA32_PERF_GLOBAL_CTRL;
+        /// Load IA32_PAT
+        const LOAD_IA32_PAT = VMENTRY_LOAD_IA32_PAT;
+        /// Load IA32_EFER
+        const LOAD_EFER = VMENTRY_LOAD_EFER;
+        /// Load IA32_BNDCFGS
+        const LOAD_IA32_BNDCFGS = VMENTRY_LOAD_IA32_BNDCFGS;
+        /// Conceal VMX from PT
+        const PT_CONCEAL_VMX = VMENTRY_PT_CONCEAL_VMX;
+        /// Load IA32_RTIT_CTL
+        const LOAD_IA32_RTIT_CTL = VMENTRY_LOAD_IA32_RTIT_CTL;
+        /// Load UINV
+        const LOAD_UINV = VMENTRY_LOAD_UINV;
+        /// Load CET state
+        const LOAD_CET_STATE = VMENTRY_LOAD_CET_STATE;
+        /// Load guest IA32_LBR_CTL
+        const LOAD_IA32_LBR_CTL = VMENTRY_LOAD_IA32_LBR_CTL;
+        /// Load PKRS
+        const LOAD_PKRS = VMENTRY_LOAD_PKRS;
+    }
+}
+
+flags! {
+    /// VM-exit controls
+    pub struct ExitControls: u64 {
+        /// Save debug controls
+        const SAVE_DBG_CONTROLS = VMEXIT_SAVE_DBG_CONTROLS;
+        /// Host address-space size
+        const HOST_IA32E = VMEXIT_HOST_IA32E;
+        /// Load IA32_PERF_GLOBAL_CTRL
+        const LOAD_IA32_PERF_GLOBAL_CTRL = VMEXIT_LOAD_IA32_PERF_GLOBAL_CTRL;
+        /// Acknowledge interrupt on exit
+        const ACK_INTR = VMEXIT_ACK_INTR;
+        /// Save IA32_PAT
+        const SAVE_IA32_PAT = VMEXIT_SAVE_IA32_PAT;
+        /// Load IA32_PAT
+        const LOAD_IA32_PAT = VMEXIT_LOAD_IA32_PAT;
+        /// Save IA32_EFER
+        const SAVE_EFER = VMEXIT_SAVE_EFER;
+        /// Load IA32_EFER
+        const LOAD_EFER = VMEXIT_LOAD_EFER;
+        /// Save VMX-preemption timer value
+        const SAVE_VMX_TIMER = VMEXIT_SAVE_VMX_TIMER;
+        /// Clear IA32_BNDCFGS
+        const CLEAR_IA32_BNDCFGS = VMEXIT_CLEAR_IA32_BNDCFGS;
+        /// Conceal VMX from PT
+        const PT_CONCEAL_VMX = VMEXIT_PT_CONCEAL_VMX;
+        /// Clear IA32_RTIT_CTL
+        const CLEAR_IA32_RTIT_CTL = VMEXIT_CLEAR_IA32_RTIT_CTL;
+        /// Clear IA32_LBR_CTL
+        const CLEAR_IA32_LBR_CTL = VMEXIT_CLEAR_IA32_LBR_CTL;
+        /// Clear UINV
+        const CLEAR_UINV = VMEXIT_CLEAR_UINV;
+        /// Load CET state
+        const LOAD_CET_STATE = VMEXIT_LOAD_CET_STATE;
+        /// Load PKRS
+        const LOAD_PKRS = VMEXIT_LOAD_PKRS;
+        /// Save IA32_PERF_GLOBAL_CTRL
+        const SAVE_IA32_PERF_GLOBAL_CTRL = VMEXIT_SAVE_IA32_PERF_GLOBAL_CTRL;
+        /// Activate secondary controls
+        const SECONDARY_CTLS = VMEXIT_SECONDARY_CTLS;
+    }
+}
+
+// Converts each flags type to and from the raw value of its VMCS field
+macro_rules! raw_conversions {
+    ($( $name:ident ),*) => {
+        $(
+            impl From<$name> for u64 {
+                fn from(controls: $name) -> u64 {
+                    controls.bits()
+                }
+            }
+
+            impl From<u64> for $name {
+                /// Keeps every bit, including reserved ones
+                fn from(bits: u64) -> $name {
+                    $name::from_bits_retain(bits)
+                }
+            }
+        )*
+    };
+}
+
+raw_conversions!(PinBasedControls, PrimaryProcControls, SecondaryProcControls, EntryControls,
+    ExitControls);
+
+/// VMX control field
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ControlField {
+    /// Pin-based VM-execution controls
+    PinBased,
+    /// Primary processor-based VM-execution controls
+    CpuBased,
+    /// Secondary processor-based VM-execution controls
+    CpuBased2,
+    /// VM-entry controls
+    Entry,
+    /// VM-exit controls
+    Exit,
+}
+
+impl ControlField {
+    /// Every control field
+    pub const ALL: [ControlField; 5] = [ControlField::PinBased, ControlField::CpuBased,
+        ControlField::CpuBased2, ControlField::Entry, ControlField::Exit];
+
+    /// Returns the capability describing the field
+    pub fn cap(self) -> VMXCap {
+        match self {
+            ControlField::PinBased => VMXCap::PINBASED,
+            ControlField::CpuBased => VMXCap::PROCBASED,
+            ControlField::CpuBased2 => VMXCap::PROCBASED2,
+            ControlField::Entry => VMXCap::ENTRY,
+            ControlField::Exit => VMXCap::EXIT,
+        }
+    }
+
+    /// Returns the VMCS field holding the controls
+    pub fn vmcs_field(self) -> VmcsField {
+        match self {
+            ControlField::PinBased => VmcsField::CtrlPinBased,
+            ControlField::CpuBased => VmcsField::CtrlCpuBased,
+            ControlField::CpuBased2 => VmcsField::CtrlCpuBased2,
+            ControlField::Entry => VmcsField::CtrlVmentryControls,
+            ControlField::Exit => VmcsField::CtrlVmexitControls,
+        }
+    }
+
+    /// Returns the name and bits of each named control of the field
+    pub fn flags(self) -> Vec<(&'static str, u64)> {
+        match self {
+            ControlField::PinBased => named(PinBasedControls::FLAGS),
+            ControlField::CpuBased => named(PrimaryProcControls::FLAGS),
+            ControlField::CpuBased2 => named(SecondaryProcControls::FLAGS),
+            ControlField::Entry => named(EntryControls::FLAGS),
+            ControlField::Exit => named(ExitControls::FLAGS),
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+// Turns the flags of a control field into raw bits
+fn named<T: Copy + Into<u64>>(flags: &[(&'static str, T)]) -> Vec<(&'static str, u64)> {
+    flags.iter().map(|&(name, flag)| (name, flag.into())).collect()
+}
+
+/// Settings the host allows for a VMX control field
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct ControlCap {
+    /// Bits that must be 1 (the allowed-0 settings)
+    pub required: u32,
+    /// Bits that may be 1 (the allowed-1 settings)
+    pub allowed: u32,
+}
+
+impl ControlCap {
+    /// Decodes a value read with `read_vmx_cap`, which holds the allowed-0 settings in its low
+    /// half and the allowed-1 settings in its high half
+    pub fn from_raw(cap: u64) -> ControlCap {
+        ControlCap {
+            required: cap as u32,
+            allowed: (cap >> 32) as u32,
+        }
+    }
+
+    /// Returns whether a control may be 0
+    pub fn can_clear(&self, bits: u64) -> bool {
+        bits & u64::from(self.required) == 0
+    }
+
+    /// Returns whether a control may be 1
+    pub fn can_set(&self, bits: u64) -> bool {
+        bits & !u64::from(self.allowed) == 0
+    }
+
+    /// Adjusts the desired controls to a value the host accepts, returning it along with the
+    /// desired bits that had to be dropped
+    pub fn adjust(&self, desired: u64) -> (u64, u64) {
+        let value = (desired | u64::from(self.required)) & u64::from(self.allowed);
+        (value, desired & !u64::from(self.allowed))
+    }
+}
+
+/// Settings the host allows for every VMX control field
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct VmxCaps {
+    caps: [ControlCap; 5],
+}
+
+impl VmxCaps {
+    /// Decodes values read with `read_vmx_cap` for the pin-based, primary processor-based,
+    /// secondary processor-based, VM-entry and VM-exit capabilities
+    pub fn from_raw(pin_based: u64, cpu_based: u64, cpu_based2: u64, entry: u64, exit: u64)
+        -> VmxCaps {
+        VmxCaps {
+            caps: [ControlCap::from_raw(pin_based), ControlCap::from_raw(cpu_based),
+                ControlCap::from_raw(cpu_based2), ControlCap::from_raw(entry),
+                ControlCap::from_raw(exit)],
+        }
+    }
+
+    /// Reads the capabilities of the host processor
+    pub fn read() -> Result<VmxCaps, HvError> {
+        let mut caps = VmxCaps::default();
+        for &field in &ControlField::ALL {
+            caps.caps[field.index()] = ControlCap::from_raw(read_vmx_cap(&field.cap())?);
+        }
+        Ok(caps)
+    }
+
+    /// Returns the settings allowed for a control field
+    pub fn get(&self, field: ControlField) -> ControlCap {
+        self.caps[field.index()]
+    }
+}
+
+/// Builder of VMX control values
+///
+/// Collects the controls wanted in each field, without regard for what the host allows, and
+/// adjusts them with `adjust` or `adjust_for_host`. Asking for any secondary control also
+/// asks for `PrimaryProcControls::SECONDARY_CTLS`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct VmxControls {
+    desired: [u64; 5],
+}
+
+impl VmxControls {
+    /// Creates a builder that asks for no controls
+    pub fn new() -> VmxControls {
+        VmxControls::default()
+    }
+
+    /// Asks for controls in a field, as raw bits
+    pub fn with(mut self, field: ControlField, bits: u64) -> VmxControls {
+        self.desired[field.index()] |= bits;
+        if field == ControlField::CpuBased2 && bits != 0 {
+            self.desired[ControlField::CpuBased.index()] |= CPU_BASED_SECONDARY_CTLS;
+        }
+        self
+    }
+
+    /// Asks for pin-based VM-execution controls
+    pub fn pin_based(self, controls: PinBasedControls) -> VmxControls {
+        self.with(ControlField::PinBased, controls.bits())
+    }
+
+    /// Asks for primary processor-based VM-execution controls
+    pub fn cpu_based(self, controls: PrimaryProcControls) -> VmxControls {
+        self.with(ControlField::CpuBased, controls.bits())
+    }
+
+    /// Asks for secondary processor-based VM-execution controls
+    pub fn cpu_based2(self, controls: SecondaryProcControls) -> VmxControls {
+        self.with(ControlField::CpuBased2, controls.bits())
+    }
+
+    /// Asks for VM-entry controls
+    pub fn entry(self, controls: EntryControls) -> VmxControls {
+        self.with(ControlField::Entry, controls.bits())
+    }
+
+    /// Asks for VM-exit controls
+    pub fn exit(self, controls: ExitControls) -> VmxControls {
+        self.with(ControlField::Exit, controls.bits())
+    }
+
+    /// Returns the controls asked for in a field
+    pub fn desired(&self, field: ControlField) -> u64 {
+        self.desired[field.index()]
+    }
+
+    /// Adjusts the controls to the given capabilities
+    pub fn adjust(&self, caps: &VmxCaps) -> AdjustedControls {
+        let mut adjusted = AdjustedControls::default();
+        for &field in &ControlField::ALL {
+            let i = field.index();
+            let (value, unsupported) = caps.get(field).adjust(self.desired[i]);
+            adjusted.values[i] = value;
+            adjusted.unsupported[i] = unsupported;
+        }
+
+        // The secondary controls only apply when the primary controls activate them, so without
+        // SECONDARY_CTLS every secondary control asked for is unsupported
+        let cpu_based2 = ControlField::CpuBased2.index();
+        if !adjusted.secondary_enabled() {
+            adjusted.values[cpu_based2] = 0;
+            adjusted.unsupported[cpu_based2] = self.desired[cpu_based2];
+        }
+        adjusted
+    }
+
+    /// Adjusts the controls to the capabilities of the host processor
+    pub fn adjust_for_host(&self) -> Result<AdjustedControls, HvError> {
+        Ok(self.adjust(&VmxCaps::read()?))
+    }
+}
+
+/// VMX control values adjusted to what the host allows
+///
+/// Written to a vCPU with `vCPU::set_controls`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct AdjustedControls {
+    values: [u64; 5],
+    unsupported: [u64; 5],
+}
+
+impl AdjustedControls {
+    /// Returns the controls held by a VMCS snapshot, with nothing unsupported
+    ///
+    /// Fields missing from the snapshot count as 0.
+    pub fn from_snapshot(snapshot: &VmcsSnapshot) -> AdjustedControls {
+        let mut controls = AdjustedControls::default();
+        for &field in &ControlField::ALL {
+            controls.values[field.index()] = snapshot.get(field.vmcs_field()).unwrap_or(0);
+        }
+        controls
+    }
+
+    /// Returns the value of a control field
+    pub fn get(&self, field: ControlField) -> u64 {
+        self.values[field.index()]
+    }
+
+    /// Returns the pin-based VM-execution controls
+    pub fn pin_based(&self) -> PinBasedControls {
+        self.get(ControlField::PinBased).into()
+    }
+
+    /// Returns the primary processor-based VM-execution controls
+    pub fn cpu_based(&self) -> PrimaryProcControls {
+        self.get(ControlField::CpuBased).into()
+    }
+
+    /// Returns the secondary processor-based VM-execution controls
+    pub fn cpu_based2(&self) -> SecondaryProcControls {
+        self.get(ControlField::CpuBased2).into()
+    }
+
+    /// Returns the VM-entry controls
+    pub fn entry(&self) -> EntryControls {
+        self.get(ControlField::Entry).into()
+    }
+
+    /// Returns the VM-exit controls
+    pub fn exit(&self) -> ExitControls {
+        self.get(ControlField::Exit).into()
+    }
+
+    /// Returns the controls asked for in a field that the host doesn't support
+    pub fn unsupported(&self, field: ControlField) -> u64 {
+        self.unsupported[field.index()]
+    }
+
+    /// Returns whether the host supports every control asked for
+    pub fn is_complete(&self) -> bool {
+        self.unsupported.iter().all(|&bits| bits == 0)
+    }
+
+    /// Returns whether the primary processor-based controls activate the secondary ones
+    pub fn secondary_enabled(&self) -> bool {
+        self.get(ControlField::CpuBased) & CPU_BASED_SECONDARY_CTLS != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use consts::vmx_cap::*;
+    use super::*;
+
+    #[test]
+    fn cap_adjusts_to_allowed_settings() {
+        // Bit 1 must be 1, and bits 1 to 3 may be 1
+        let cap = ControlCap::from_raw(0x0000_000e_0000_0002);
+        assert_eq!(cap, ControlCap { required: 0x2, allowed: 0xe });
+        assert!(!cap.can_clear(0x2));
+        assert!(cap.can_clear(0x4));
+        assert!(cap.can_set(0xc));
+        assert!(!cap.can_set(0x11));
+
+        assert_eq!(cap.adjust(0), (0x2, 0));
+        assert_eq!(cap.adjust(0x8), (0xa, 0));
+        assert_eq!(cap.adjust(0x19), (0xa, 0x11));
+    }
+
+    #[test]
+    fn controls_report_unsupported_bits() {
+        let caps = VmxCaps::from_raw(
+            0x0000_007f_0000_0016,
+            0xffff_fffe_0401_e172,
+            0x0000_0000_0000_0000,
+            0x0000_ffff_0000_11ff,
+            0x00ff_ffff_0003_6dff,
+        );
+        let adjusted = VmxControls::new()
+            .pin_based(PinBasedControls::INTR | PinBasedControls::POSTED_INTR)
+            .cpu_based(PrimaryProcControls::HLT)
+            .entry(EntryControls::GUEST_IA32E)
+            .exit(ExitControls::HOST_IA32E)
+            .adjust(&caps);
+
+        assert_eq!(adjusted.get(ControlField::PinBased), 0x17);
+        assert_eq!(adjusted.unsupported(ControlField::PinBased), PIN_BASED_POSTED_INTR);
+        assert_eq!(adjusted.get(ControlField::CpuBased), 0x0401_e172 | CPU_BASED_HLT);
+        assert_eq!(adjusted.get(ControlField::Entry), 0x11ff | VMENTRY_GUEST_IA32E);
+        assert_eq!(adjusted.get(ControlField::Exit), 0x0003_6dff | VMEXIT_HOST_IA32E);
+        assert!(!adjusted.is_complete());
+    }
+
+    #[test]
+    fn secondary_controls_need_secondary_ctls() {
+        let enabled = VmxCaps::from_raw(0, 0xffff_ffff_0000_0000, 0xffff_ffff_0000_0000, 0, 0);
+        let adjusted = VmxControls::new().cpu_based2(SecondaryProcControls::EPT).adjust(&enabled);
+        assert!(adjusted.secondary_enabled());
+        assert_eq!(adjusted.get(ControlField::CpuBased2), CPU_BASED2_EPT);
+        assert!(adjusted.is_complete());
+
+        // Without SECONDARY_CTLS, the secondary controls are dropped even where their own
+        // capability allows them
+        let disabled = VmxCaps::from_raw(0, 0x7fff_ffff_0000_0000, 0xffff_ffff_0000_0001, 0, 0);
+        let adjusted = VmxControls::new().cpu_based2(SecondaryProcControls::EPT).adjust(&disabled);
+        assert!(!adjusted.secondary_enabled());
+        assert_eq!(adjusted.get(ControlField::CpuBased2), 0);
+        assert_eq!(adjusted.unsupported(ControlField::CpuBased), CPU_BASED_SECONDARY_CTLS);
+        assert_eq!(adjusted.unsupported(ControlField::CpuBased2), CPU_BASED2_EPT);
+    }
+}
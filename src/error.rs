@@ -0,0 +1,349 @@
+/*
+Copyright (c) 2016 Saurav Sachidanand
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in
+all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+THE SOFTWARE.
+*/
+
+//! Errors returned by the safe interface
+
+use std::error;
+use std::fmt;
+
+use consts::vmx_exit::*;
+use ffi::*;
+
+/// Kind of failure reported by the hypervisor
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ErrorKind {
+    /// Error
+    Error,
+    /// Busy
+    Busy,
+    /// Bad argument
+    BadArg,
+    /// No resources
+    NoRes,
+    /// No device
+    NoDev,
+    /// Unsupported
+    Unsupp
+}
+
+impl ErrorKind {
+    /// Returns the kind of a `hv_return_t`, where codes the crate doesn't
+    /// know about count as `ErrorKind::Error`
+    pub fn from_code(code: hv_return_t) -> ErrorKind {
+        match code {
+            HV_BUSY         => ErrorKind::Busy,
+            HV_BAD_ARGUMENT => ErrorKind::BadArg,
+            HV_NO_RESOURCES => ErrorKind::NoRes,
+            HV_NO_DEVICE    => ErrorKind::NoDev,
+            HV_UNSUPPORTED  => ErrorKind::Unsupp,
+            _               => ErrorKind::Error
+        }
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ErrorKind::Error  => write!(f, "error"),
+            ErrorKind::Busy   => write!(f, "busy"),
+            ErrorKind::BadArg => write!(f, "bad argument"),
+            ErrorKind::NoRes  => write!(f, "no resources"),
+            ErrorKind::NoDev  => write!(f, "no device"),
+            ErrorKind::Unsupp => write!(f, "unsupported"),
+        }
+    }
+}
+
+macro_rules! instruction_errors {
+    ($($(#[$attr:meta])* $name:ident = $code:expr, $description:expr;)*) => {
+        /// Error number of a failed VMX instruction, as stored in `VMCS_RO_INSTR_ERROR`
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+        pub enum VmInstructionError {
+            $($(#[$attr])* $name = $code,)*
+        }
+
+        impl VmInstructionError {
+            /// Returns the error with an error number, if the SDM defines one
+            pub fn from_code(code: u32) -> Option<VmInstructionError> {
+                match code {
+                    $($code => Some(VmInstructionError::$name),)*
+                    _ => None,
+                }
+            }
+
+            /// Returns the description of the error given by the SDM
+            pub fn description(&self) -> &'static str {
+                match *self {
+                    $(VmInstructionError::$name => $description,)*
+                }
+            }
+        }
+    }
+}
+
+instruction_errors! {
+    /// VMCALL executed in VMX root operation
+    VmcallInRoot = 1, "VMCALL executed in VMX root operation";
+    /// VMCLEAR with invalid physical address
+    VmclearInvalidAddress = 2, "VMCLEAR with invalid physical address";
+    /// VMCLEAR with VMXON pointer
+    VmclearVmxonPointer = 3, "VMCLEAR with VMXON pointer";
+    /// VMLAUNCH with non-clear VMCS
+    VmlaunchNonClear = 4, "VMLAUNCH with non-clear VMCS";
+    /// VMRESUME with non-launched VMCS
+    VmresumeNonLaunched = 5, "VMRESUME with non-launched VMCS";
+    /// VMRESUME after VMXOFF
+    VmresumeAfterVmxoff = 6, "VMRESUME after VMXOFF";
+    /// VM entry with invalid control fields
+    EntryInvalidControls = 7, "VM entry with invalid control field(s)";
+    /// VM entry with invalid host-state fields
+    EntryInvalidHostState = 8, "VM entry with invalid host-state field(s)";
+    /// VMPTRLD with invalid physical address
+    VmptrldInvalidAddress = 9, "VMPTRLD with invalid physical address";
+    /// VMPTRLD with VMXON pointer
+    VmptrldVmxonPointer = 10, "VMPTRLD with VMXON pointer";
+    /// VMPTRLD with incorrect VMCS revision identifier
+    VmptrldBadRevision = 11, "VMPTRLD with incorrect VMCS revision identifier";
+    /// VMREAD or VMWRITE of an unsupported VMCS component
+    UnsupportedComponent = 12, "VMREAD/VMWRITE from/to unsupported VMCS component";
+    /// VMWRITE to read-only VMCS component
+    VmwriteReadOnly = 13, "VMWRITE to read-only VMCS component";
+    /// VMXON executed in VMX root operation
+    VmxonInRoot = 15, "VMXON executed in VMX root operation";
+    /// VM entry with invalid executive-VMCS pointer
+    EntryInvalidExecutivePointer = 16, "VM entry with invalid executive-VMCS pointer";
+    /// VM entry with non-launched executive VMCS
+    EntryNonLaunchedExecutive = 17, "VM entry with non-launched executive VMCS";
+    /// VM entry with executive-VMCS pointer not VMXON pointer
+    EntryExecutiveNotVmxon = 18,
+        "VM entry with executive-VMCS pointer not VMXON pointer";
+    /// VMCALL with non-clear VMCS
+    VmcallNonClear = 19, "VMCALL with non-clear VMCS";
+    /// VMCALL with invalid VM-exit control fields
+    VmcallInvalidExitControls = 20, "VMCALL with invalid VM-exit control fields";
+    /// VMCALL with incorrect MSEG revision identifier
+    VmcallBadMsegRevision = 22, "VMCALL with incorrect MSEG revision identifier";
+    /// VMXOFF under dual-monitor treatment of SMIs and SMM
+    VmxoffDualMonitor = 23, "VMXOFF under dual-monitor treatment of SMIs and SMM";
+    /// VMCALL with invalid SMM-monitor features
+    VmcallInvalidSmmFeatures = 24, "VMCALL with invalid SMM-monitor features";
+    /// VM entry with invalid VM-execution control fields in executive VMCS
+    EntryInvalidExecutiveControls = 25,
+        "VM entry with invalid VM-execution control fields in executive VMCS";
+    /// VM entry with events blocked by MOV SS
+    EntryBlockedByMovSs = 26, "VM entry with events blocked by MOV SS";
+    /// Invalid operand to INVEPT or INVVPID
+    InvalidInveptOperand = 28, "Invalid operand to INVEPT/INVVPID";
+}
+
+impl VmInstructionError {
+    /// Returns the error number
+    pub fn code(&self) -> u32 {
+        *self as u32
+    }
+}
+
+impl fmt::Display for VmInstructionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (VM-instruction error {})", self.description(), self.code())
+    }
+}
+
+/// Guest-state check that failed VM entry, from the exit qualification
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum GuestStateCheck {
+    /// A check the processor doesn't identify
+    Unspecified,
+    /// Loading the PDPTEs
+    PdpteLoad,
+    /// Injecting an NMI while blocking by STI
+    NmiBlockedBySti,
+    /// The VMCS link pointer
+    VmcsLinkPointer,
+    /// An exit qualification the SDM doesn't define
+    Other(u64),
+}
+
+impl GuestStateCheck {
+    /// Decodes the exit qualification of a failed VM entry
+    pub fn from_qualification(qualification: u64) -> GuestStateCheck {
+        match qualification {
+            0 | 1 => GuestStateCheck::Unspecified,
+            2 => GuestStateCheck::PdpteLoad,
+            3 => GuestStateCheck::NmiBlockedBySti,
+            4 => GuestStateCheck::VmcsLinkPointer,
+            other => GuestStateCheck::Other(other),
+        }
+    }
+}
+
+/// Reason VM entry failed after the VMX controls and host state were checked
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum EntryFailure {
+    /// Invalid guest state
+    GuestState(GuestStateCheck),
+    /// Loading an MSR from the VM-entry MSR-load area
+    MsrLoad {
+        /// Index of the entry in the area, counting from 1
+        index: u64,
+    },
+    /// Machine-check event
+    MachineCheck,
+}
+
+impl EntryFailure {
+    /// Decodes the basic exit reason and exit qualification of a failed VM entry
+    ///
+    /// Returns `None` if the reason isn't one VM entry fails with.
+    pub fn from_exit(reason: u32, qualification: u64) -> Option<EntryFailure> {
+        match u64::from(reason) {
+            VMX_REASON_VMENTRY_GUEST =>
+                Some(EntryFailure::GuestState(GuestStateCheck::from_qualification(qualification))),
+            VMX_REASON_VMENTRY_MSR => Some(EntryFailure::MsrLoad { index: qualification }),
+            VMX_REASON_VMENTRY_MC => Some(EntryFailure::MachineCheck),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for EntryFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EntryFailure::GuestState(check) => {
+                write!(f, "VM entry with invalid guest state")?;
+                match check {
+                    GuestStateCheck::Unspecified => Ok(()),
+                    GuestStateCheck::PdpteLoad => write!(f, ": failure loading PDPTEs"),
+                    GuestStateCheck::NmiBlockedBySti =>
+                        write!(f, ": NMI injected while blocking by STI"),
+                    GuestStateCheck::VmcsLinkPointer => write!(f, ": invalid VMCS link pointer"),
+                    GuestStateCheck::Other(q) => write!(f, " (exit qualification {:#x})", q),
+                }
+            }
+            EntryFailure::MsrLoad { index } =>
+                write!(f, "VM entry failed loading MSR entry {} of the MSR-load area", index),
+            EntryFailure::MachineCheck => write!(f, "VM entry failed by a machine-check event"),
+        }
+    }
+}
+
+/// What the processor reported about a failed call
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ErrorDetail {
+    /// A VMX instruction failed
+    Instruction(VmInstructionError),
+    /// VM entry failed
+    Entry(EntryFailure),
+}
+
+impl fmt::Display for ErrorDetail {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ErrorDetail::Instruction(ref error) => error.fmt(f),
+            ErrorDetail::Entry(ref failure) => failure.fmt(f),
+        }
+    }
+}
+
+/// Error returned when a hypervisor call fails
+///
+/// Carries the raw `hv_return_t`, the name of the call that failed, the
+/// ID of the vCPU it was made on, if any, and what the processor reported
+/// about the failure, if anything.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HvError {
+    code: hv_return_t,
+    op: &'static str,
+    vcpu: Option<u32>,
+    detail: Option<ErrorDetail>,
+}
+
+impl HvError {
+    /// Creates an error for a failed call
+    ///
+    /// * `code` Return code of the call
+    /// * `op` Name of the call, such as `"hv_vcpu_run"`
+    pub fn new(code: hv_return_t, op: &'static str) -> HvError {
+        HvError { code, op, vcpu: None, detail: None }
+    }
+
+    /// Attaches the ID of the vCPU the call was made on
+    pub fn with_vcpu(mut self, vcpu: u32) -> HvError {
+        self.vcpu = Some(vcpu);
+        self
+    }
+
+    /// Attaches what the processor reported about the failure
+    pub fn with_detail(mut self, detail: ErrorDetail) -> HvError {
+        self.detail = Some(detail);
+        self
+    }
+
+    /// Returns the raw return code
+    pub fn code(&self) -> hv_return_t {
+        self.code
+    }
+
+    /// Returns the kind of the return code
+    pub fn kind(&self) -> ErrorKind {
+        ErrorKind::from_code(self.code)
+    }
+
+    /// Returns the name of the call that failed
+    pub fn op(&self) -> &'static str {
+        self.op
+    }
+
+    /// Returns the ID of the vCPU the call was made on, if any
+    pub fn vcpu(&self) -> Option<u32> {
+        self.vcpu
+    }
+
+    /// Returns what the processor reported about the failure, if anything
+    pub fn detail(&self) -> Option<ErrorDetail> {
+        self.detail
+    }
+}
+
+impl fmt::Display for HvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} failed", self.op)?;
+        if let Some(vcpu) = self.vcpu {
+            write!(f, " on vCPU {}", vcpu)?;
+        }
+        write!(f, ": {} ({:#x})", self.kind(), self.code)?;
+        if let Some(detail) = self.detail {
+            write!(f, ": {}", detail)?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for HvError {}
+
+// Turns the return code of a call into a Result
+pub fn check(code: hv_return_t, op: &'static str) -> Result<(), HvError> {
+    match code {
+        HV_SUCCESS => Ok(()),
+        _ => Err(HvError::new(code, op)),
+    }
+}
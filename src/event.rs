@@ -0,0 +1,617 @@
+/*
+Copyright (c) 2016 Saurav Sachidanand
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in
+all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+THE SOFTWARE.
+*/
+
+//! Event injection
+//!
+//! `vCPU::inject` queues an `Event` on the vCPU, and `vCPU::run` injects the next event the guest
+//! can take before entering it. Exceptions and software interrupts are injected whatever the
+//! guest's state. An NMI waits while the guest blocks NMIs, and an external interrupt while
+//! RFLAGS.IF is clear or the guest blocks interrupts after STI or MOV SS. While an event waits,
+//! `run` turns on interrupt-window or NMI-window exiting, and turns it off again once no event
+//! needs it. NMI-window exiting requires the virtual NMIs pin-based control.
+//!
+//! When a VM exit interrupts the delivery of an event, `run` takes the event from the IDT-vectoring
+//! information fields and re-injects it on the next entry, ahead of the queue and whatever the
+//! guest's state. An exception injected in the meantime is combined with a re-injected exception
+//! the way the processor would, which may turn them into a double fault, or a triple fault that
+//! can't be injected. The handler of the exit can take the event back with
+//! `vCPU::cancel_reinjection`. After an exit with NMI unblocking due to IRET and no event to
+//! re-inject, `run` blocks NMIs again, as the IRET didn't complete.
+
+use std::collections::VecDeque;
+
+use consts::vmx_cap::{CPU_BASED_IRQ_WND, CPU_BASED_VIRTUAL_NMI_WND};
+use consts::vmx_exit::{VMX_REASON_EPT_VIOLATION, VMX_REASON_EXC_NMI};
+use super::{vCPU, EptViolationQualification, HvError, InterruptionInfo, InterruptionType,
+    VmcsField};
+
+const RFLAGS_IF: u64 = 1 << 9;
+
+const BLOCKING_STI: u64 = 1 << 0;
+const BLOCKING_MOV_SS: u64 = 1 << 1;
+const BLOCKING_NMI: u64 = 1 << 3;
+
+const INFO_VALID: u64 = 1 << 31;
+const INFO_NMI_UNBLOCKING: u64 = 1 << 12;
+
+const EXC_DF: u8 = 8;
+const EXC_PF: u8 = 14;
+
+/// Event to inject into a guest
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Event {
+    /// External interrupt with a vector
+    ExternalInterrupt(u8),
+    /// Non-maskable interrupt
+    Nmi,
+    /// Hardware exception
+    Exception {
+        /// Vector
+        vector: u8,
+        /// Error code, for the exceptions that deliver one
+        error_code: Option<u32>,
+    },
+    /// Software interrupt, as by INT n
+    SoftwareInterrupt {
+        /// Vector
+        vector: u8,
+        /// Length of the INT instruction
+        instruction_len: u32,
+    },
+    /// Software exception, as by INT3 or INTO
+    SoftwareException {
+        /// Vector
+        vector: u8,
+        /// Length of the instruction
+        instruction_len: u32,
+    },
+    /// Privileged software exception, as by INT1
+    PrivilegedSoftwareException {
+        /// Length of the INT1 instruction
+        instruction_len: u32,
+    },
+}
+
+/// What became of an event passed to `vCPU::inject`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Injection {
+    /// The event was queued
+    Queued,
+    /// The exception was combined with the exception being re-injected into a double fault,
+    /// which replaces both
+    DoubleFault,
+    /// The exception was raised while delivering a double fault, so the guest would shut down.
+    /// Neither is injected.
+    TripleFault,
+}
+
+impl Event {
+    /// Returns the event as interruption information
+    pub fn info(&self) -> InterruptionInfo {
+        let (vector, kind, error_code) = match *self {
+            Event::ExternalInterrupt(vector) => (vector, InterruptionType::External, None),
+            Event::Nmi => (2, InterruptionType::Nmi, None),
+            Event::Exception { vector, error_code } =>
+                (vector, InterruptionType::HardwareException, error_code),
+            Event::SoftwareInterrupt { vector, .. } =>
+                (vector, InterruptionType::SoftwareInterrupt, None),
+            Event::SoftwareException { vector, .. } =>
+                (vector, InterruptionType::SoftwareException, None),
+            Event::PrivilegedSoftwareException { .. } =>
+                (1, InterruptionType::PrivilegedSoftwareException, None),
+        };
+        InterruptionInfo { vector, kind, error_code, nmi_unblocking: false }
+    }
+
+    /// Returns the event described by interruption information, with the instruction length
+    /// software events need, or `None` for a reserved type
+    pub fn from_info(info: &InterruptionInfo, instruction_len: u32) -> Option<Event> {
+        let vector = info.vector;
+        Some(match info.kind {
+            InterruptionType::External => Event::ExternalInterrupt(vector),
+            InterruptionType::Nmi => Event::Nmi,
+            InterruptionType::HardwareException =>
+                Event::Exception { vector, error_code: info.error_code },
+            InterruptionType::SoftwareInterrupt =>
+                Event::SoftwareInterrupt { vector, instruction_len },
+            InterruptionType::SoftwareException =>
+                Event::SoftwareException { vector, instruction_len },
+            InterruptionType::PrivilegedSoftwareException =>
+                Event::PrivilegedSoftwareException { instruction_len },
+            InterruptionType::Reserved | InterruptionType::Other => return None,
+        })
+    }
+
+    /// Returns the instruction length VM entry needs to inject the event, or 0
+    pub fn instruction_len(&self) -> u32 {
+        match *self {
+            Event::SoftwareInterrupt { instruction_len, .. }
+            | Event::SoftwareException { instruction_len, .. }
+            | Event::PrivilegedSoftwareException { instruction_len } => instruction_len,
+            _ => 0,
+        }
+    }
+
+    // Returns whether the guest can take the event, given its RFLAGS and interruptibility state
+    fn can_inject(&self, rflags: u64, interruptibility: u64) -> bool {
+        match *self {
+            Event::ExternalInterrupt(_) => rflags & RFLAGS_IF != 0
+                && interruptibility & (BLOCKING_STI | BLOCKING_MOV_SS) == 0,
+            Event::Nmi => interruptibility & (BLOCKING_STI | BLOCKING_MOV_SS | BLOCKING_NMI) == 0,
+            _ => true,
+        }
+    }
+}
+
+// Class of an exception, for combining exceptions
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Class {
+    Benign,
+    Contributory,
+    PageFault,
+    DoubleFault,
+}
+
+fn class(vector: u8) -> Class {
+    match vector {
+        0 | 10..=13 => Class::Contributory,
+        EXC_PF => Class::PageFault,
+        EXC_DF => Class::DoubleFault,
+        _ => Class::Benign,
+    }
+}
+
+/// Events waiting to be injected into a vCPU
+///
+/// An event being re-injected goes first. Then come exceptions and software interrupts, then
+/// NMIs, then external interrupts, each in the order they were pushed.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EventQueue {
+    reinject: Option<Event>,
+    events: VecDeque<Event>,
+    // Window-exiting controls the queue turned on
+    windows: u64,
+}
+
+impl EventQueue {
+    /// Creates an empty queue
+    pub fn new() -> EventQueue {
+        EventQueue::default()
+    }
+
+    /// Adds an event to the queue
+    ///
+    /// A hardware exception is combined with a hardware exception being re-injected, as if the
+    /// processor raised it while delivering the other.
+    pub fn push(&mut self, event: Event) -> Injection {
+        let (first, second) = match (self.reinject, event) {
+            (Some(Event::Exception { vector: first, .. }), Event::Exception { vector, .. }) =>
+                (first, vector),
+            (Some(reinject), Event::Exception { .. }) => {
+                // The exception comes first, and the interrupted event after it, as if the
+                // processor was interrupted before delivering either. A software event is raised
+                // again when the guest retries its instruction.
+                self.reinject = None;
+                if let Event::ExternalInterrupt(_) | Event::Nmi = reinject {
+                    self.events.push_front(reinject);
+                }
+                self.events.push_back(event);
+                return Injection::Queued;
+            }
+            _ => {
+                self.events.push_back(event);
+                return Injection::Queued;
+            }
+        };
+
+        match (class(first), class(second)) {
+            (Class::Contributory, Class::Contributory)
+            | (Class::PageFault, Class::Contributory)
+            | (Class::PageFault, Class::PageFault) => {
+                self.reinject = Some(Event::Exception { vector: EXC_DF, error_code: Some(0) });
+                Injection::DoubleFault
+            }
+            (Class::DoubleFault, Class::Contributory) | (Class::DoubleFault, Class::PageFault) => {
+                self.reinject = None;
+                Injection::TripleFault
+            }
+            // Otherwise the exceptions are handled serially, and the first is raised again when
+            // the guest retries its instruction
+            _ => {
+                self.reinject = None;
+                self.events.push_back(event);
+                Injection::Queued
+            }
+        }
+    }
+
+    /// Returns the event to re-inject, if any
+    pub fn reinjection(&self) -> Option<Event> {
+        self.reinject
+    }
+
+    /// Sets the event to re-inject on the next entry, replacing any other
+    pub fn set_reinjection(&mut self, event: Option<Event>) {
+        self.reinject = event;
+    }
+
+    /// Returns the number of events in the queue, including one to re-inject
+    pub fn len(&self) -> usize {
+        self.events.len() + self.reinject.iter().count()
+    }
+
+    /// Returns whether the queue is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Removes every event from the queue, including one to re-inject
+    pub fn clear(&mut self) {
+        self.reinject = None;
+        self.events.clear();
+    }
+
+    /// Returns the events in the queue, starting with one to re-inject, then in the order they
+    /// were pushed
+    pub fn iter(&self) -> impl Iterator<Item = &Event> + '_ {
+        self.reinject.iter().chain(self.events.iter())
+    }
+
+    /// Removes and returns the next event the guest can take, given its RFLAGS and the value of
+    /// its interruptibility state field
+    ///
+    /// An event to re-inject is returned whatever the state of the guest.
+    pub fn pop(&mut self, rflags: u64, interruptibility: u64) -> Option<Event> {
+        if let Some(event) = self.reinject.take() {
+            return Some(event);
+        }
+
+        let rank = |e: &Event| match *e {
+            Event::Nmi => 1,
+            Event::ExternalInterrupt(_) => 2,
+            _ => 0,
+        };
+        let index = self.events.iter().enumerate()
+            .filter(|&(_, e)| e.can_inject(rflags, interruptibility))
+            .min_by_key(|&(i, e)| (rank(e), i))
+            .map(|(i, _)| i)?;
+        self.events.remove(index)
+    }
+
+    /// Returns the window-exiting controls the events in the queue wait for
+    pub fn windows(&self) -> u64 {
+        self.events.iter().fold(0, |windows, e| match *e {
+            Event::ExternalInterrupt(_) => windows | CPU_BASED_IRQ_WND,
+            Event::Nmi => windows | CPU_BASED_VIRTUAL_NMI_WND,
+            _ => windows,
+        })
+    }
+
+    // Injects the next event the vCPU can take, unless VM entry already injects one, and turns
+    // window exiting on or off for the events left
+    pub(crate) fn deliver(&mut self, vcpu: &vCPU) -> Result<(), HvError> {
+        if self.is_empty() && self.windows == 0 {
+            return Ok(());
+        }
+
+        // An event written to the VMCS by hand overrides one to re-inject
+        if vcpu.read_field(VmcsField::CtrlVmentryIrqInfo)? & INFO_VALID != 0 {
+            self.reinject = None;
+        } else {
+            let rflags = vcpu.read_field(VmcsField::GuestRflags)?;
+            let interruptibility = vcpu.read_field(VmcsField::GuestIgnoreIrq)?;
+            if let Some(event) = self.pop(rflags, interruptibility) {
+                let info = event.info();
+                if let Some(error_code) = info.error_code {
+                    vcpu.write_field(VmcsField::CtrlVmentryExcError, u64::from(error_code))?;
+                }
+                if event.instruction_len() != 0 {
+                    vcpu.write_field(VmcsField::CtrlVmentryInstrLen,
+                        u64::from(event.instruction_len()))?;
+                }
+                vcpu.write_field(VmcsField::CtrlVmentryIrqInfo, u64::from(info.to_raw()))?;
+            }
+        }
+
+        // Only turn off the window exiting the queue turned on
+        let needed = self.windows();
+        let controls = vcpu.read_field(VmcsField::CtrlCpuBased)?;
+        let set = needed & !controls;
+        let clear = self.windows & !needed;
+        let updated = (controls | set) & !clear;
+        if updated != controls {
+            vcpu.write_field(VmcsField::CtrlCpuBased, updated)?;
+        }
+        self.windows = (self.windows | set) & !clear;
+        Ok(())
+    }
+
+    // Takes the event whose delivery a VM exit interrupted for re-injection, or else restores
+    // the blocking of NMIs an interrupted IRET lifted
+    pub(crate) fn record_exit(&mut self, vcpu: &vCPU, reason: u32) -> Result<(), HvError> {
+        let info = vcpu.read_field(VmcsField::RoIdtVectorInfo)?;
+        if info & INFO_VALID != 0 {
+            let error_code = vcpu.read_field(VmcsField::RoIdtVectorError)?;
+            let instruction_len = vcpu.read_field(VmcsField::RoVmexitInstrLen)?;
+            self.reinject = InterruptionInfo::from_raw(info as u32, error_code as u32)
+                .and_then(|info| Event::from_info(&info, instruction_len as u32));
+            return Ok(());
+        }
+
+        let nmi_unblocking = match u64::from(reason) {
+            VMX_REASON_EXC_NMI => {
+                let info = vcpu.read_field(VmcsField::RoVmexitIrqInfo)?;
+                info & INFO_NMI_UNBLOCKING != 0 && info as u8 != EXC_DF
+            }
+            VMX_REASON_EPT_VIOLATION => {
+                let qualification = vcpu.read_field(VmcsField::RoExitQualific)?;
+                EptViolationQualification::from_raw(qualification).nmi_unblocking
+            }
+            _ => false,
+        };
+        if nmi_unblocking {
+            let interruptibility = vcpu.read_field(VmcsField::GuestIgnoreIrq)?;
+            vcpu.write_field(VmcsField::GuestIgnoreIrq, interruptibility | BLOCKING_NMI)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IF: u64 = RFLAGS_IF;
+
+    fn exception(vector: u8) -> Event {
+        Event::Exception { vector, error_code: None }
+    }
+
+    #[test]
+    fn pop_order() {
+        let mut queue = EventQueue::new();
+        let int = Event::SoftwareInterrupt { vector: 0x80, instruction_len: 2 };
+        for &event in &[Event::ExternalInterrupt(0x20), Event::Nmi, exception(6), int,
+            Event::ExternalInterrupt(0x21), Event::Nmi] {
+            assert_eq!(queue.push(event), Injection::Queued);
+        }
+        queue.set_reinjection(Some(Event::ExternalInterrupt(0x30)));
+        assert_eq!(queue.len(), 7);
+
+        let order: Vec<_> = (0..8).map(|_| queue.pop(IF, 0)).collect();
+        assert_eq!(order, vec![
+            Some(Event::ExternalInterrupt(0x30)),
+            Some(exception(6)),
+            Some(int),
+            Some(Event::Nmi),
+            Some(Event::Nmi),
+            Some(Event::ExternalInterrupt(0x20)),
+            Some(Event::ExternalInterrupt(0x21)),
+            None,
+        ]);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn pop_waits_for_the_guest() {
+        let mut queue = EventQueue::new();
+        queue.push(Event::ExternalInterrupt(0x20));
+        queue.push(Event::Nmi);
+
+        // External interrupts wait for IF and for blocking by STI or MOV SS to end, NMIs for
+        // blocking by STI, MOV SS or NMI to end
+        for &interruptibility in &[BLOCKING_STI, BLOCKING_MOV_SS] {
+            assert_eq!(queue.pop(IF, interruptibility), None);
+        }
+        assert_eq!(queue.pop(0, BLOCKING_NMI), None);
+        assert_eq!(queue.pop(0, 0), Some(Event::Nmi));
+        assert_eq!(queue.pop(0, 0), None);
+        assert_eq!(queue.pop(IF, BLOCKING_NMI), Some(Event::ExternalInterrupt(0x20)));
+
+        // Exceptions and events to re-inject don't wait
+        queue.push(exception(13));
+        queue.set_reinjection(Some(Event::ExternalInterrupt(0x20)));
+        let blocked = BLOCKING_STI | BLOCKING_MOV_SS | BLOCKING_NMI;
+        assert_eq!(queue.pop(0, blocked), Some(Event::ExternalInterrupt(0x20)));
+        assert_eq!(queue.pop(0, blocked), Some(exception(13)));
+    }
+
+    #[test]
+    fn windows() {
+        let mut queue = EventQueue::new();
+        queue.push(exception(13));
+        assert_eq!(queue.windows(), 0);
+        queue.push(Event::Nmi);
+        assert_eq!(queue.windows(), CPU_BASED_VIRTUAL_NMI_WND);
+        queue.push(Event::ExternalInterrupt(0x20));
+        assert_eq!(queue.windows(), CPU_BASED_VIRTUAL_NMI_WND | CPU_BASED_IRQ_WND);
+        queue.clear();
+        assert_eq!(queue.windows(), 0);
+    }
+
+    #[test]
+    fn exceptions_combine_into_a_double_fault() {
+        let df = Some(Event::Exception { vector: EXC_DF, error_code: Some(0) });
+        // Contributory then contributory, page fault then page fault, page fault then
+        // contributory
+        for &(first, second) in &[(0, 13), (11, 12), (EXC_PF, EXC_PF), (EXC_PF, 10)] {
+            let mut queue = EventQueue::new();
+            queue.set_reinjection(Some(exception(first)));
+            assert_eq!(queue.push(exception(second)), Injection::DoubleFault,
+                "{} then {}", first, second);
+            assert_eq!(queue.reinjection(), df);
+            assert_eq!(queue.len(), 1);
+        }
+    }
+
+    #[test]
+    fn faults_while_delivering_a_double_fault_shut_down() {
+        for &second in &[0, 13, EXC_PF] {
+            let mut queue = EventQueue::new();
+            queue.set_reinjection(Some(Event::Exception { vector: EXC_DF, error_code: Some(0) }));
+            assert_eq!(queue.push(exception(second)), Injection::TripleFault, "{}", second);
+            assert!(queue.is_empty());
+        }
+    }
+
+    #[test]
+    fn benign_exceptions_are_handled_serially() {
+        // Benign then anything, contributory then page fault, anything then benign
+        for &(first, second) in &[(1, 13), (6, EXC_PF), (3, 6), (13, EXC_PF), (EXC_PF, 6),
+            (EXC_DF, 6)] {
+            let mut queue = EventQueue::new();
+            queue.set_reinjection(Some(exception(first)));
+            assert_eq!(queue.push(exception(second)), Injection::Queued,
+                "{} then {}", first, second);
+            assert_eq!(queue.reinjection(), None);
+            assert_eq!(queue.iter().collect::<Vec<_>>(), vec![&exception(second)]);
+        }
+
+        // An interrupted NMI is delivered after the exception
+        let mut queue = EventQueue::new();
+        queue.set_reinjection(Some(Event::Nmi));
+        assert_eq!(queue.push(exception(13)), Injection::Queued);
+        assert_eq!(queue.pop(0, 0), Some(exception(13)));
+        assert_eq!(queue.pop(0, 0), Some(Event::Nmi));
+    }
+
+    #[cfg(feature = "mock")]
+    mod deliver {
+        use backend::mock;
+        use consts::vmcs::*;
+        use super::super::super::VirtualMachine;
+        use super::*;
+
+        fn controls(vcpu: &vCPU) -> u64 {
+            mock::vmcs(vcpu.id() as _, VMCS_CTRL_CPU_BASED)
+        }
+
+        fn injected(vcpu: &vCPU) -> u64 {
+            mock::vmcs(vcpu.id() as _, VMCS_CTRL_VMENTRY_IRQ_INFO)
+        }
+
+        #[test]
+        fn blocked_interrupt_opens_the_window() {
+            let _lock = mock::lock();
+            let vm = VirtualMachine::new().unwrap();
+            let vcpu = vm.create_vcpu().unwrap();
+            let mut queue = EventQueue::new();
+            queue.push(Event::ExternalInterrupt(0x20));
+
+            for &(rflags, interruptibility) in &[(0x2, 0), (0x2 | IF, BLOCKING_STI),
+                (0x2 | IF, BLOCKING_MOV_SS)] {
+                vcpu.write_field(VmcsField::GuestRflags, rflags).unwrap();
+                vcpu.write_field(VmcsField::GuestIgnoreIrq, interruptibility).unwrap();
+                queue.deliver(&vcpu).unwrap();
+                assert_eq!(injected(&vcpu), 0);
+                assert_eq!(controls(&vcpu), CPU_BASED_IRQ_WND);
+            }
+
+            // Once the guest can take the interrupt, it is injected and the window closed
+            vcpu.write_field(VmcsField::GuestIgnoreIrq, 0).unwrap();
+            queue.deliver(&vcpu).unwrap();
+            assert_eq!(injected(&vcpu), 0x8000_0020);
+            assert_eq!(controls(&vcpu), 0);
+            assert!(queue.is_empty());
+        }
+
+        #[test]
+        fn blocked_nmi_opens_the_nmi_window() {
+            let _lock = mock::lock();
+            let vm = VirtualMachine::new().unwrap();
+            let vcpu = vm.create_vcpu().unwrap();
+            let mut queue = EventQueue::new();
+            queue.push(Event::Nmi);
+
+            // Window exiting the queue didn't turn on is left alone
+            vcpu.write_field(VmcsField::CtrlCpuBased, CPU_BASED_IRQ_WND).unwrap();
+            vcpu.write_field(VmcsField::GuestIgnoreIrq, BLOCKING_NMI).unwrap();
+            queue.deliver(&vcpu).unwrap();
+            assert_eq!(injected(&vcpu), 0);
+            assert_eq!(controls(&vcpu), CPU_BASED_IRQ_WND | CPU_BASED_VIRTUAL_NMI_WND);
+
+            vcpu.write_field(VmcsField::GuestIgnoreIrq, 0).unwrap();
+            queue.deliver(&vcpu).unwrap();
+            assert_eq!(injected(&vcpu), 0x8000_0202);
+            assert_eq!(controls(&vcpu), CPU_BASED_IRQ_WND);
+        }
+    }
+
+    #[cfg(feature = "mock")]
+    mod record_exit {
+        use backend::mock::{self, MockExit};
+        use consts::vmcs::*;
+        use consts::vmx_exit::*;
+        use super::super::super::VirtualMachine;
+        use super::*;
+
+        fn interruptibility(vcpu: &vCPU) -> u64 {
+            mock::vmcs(vcpu.id() as _, VMCS_GUEST_IGNORE_IRQ)
+        }
+
+        #[test]
+        fn interrupted_nmi_is_reinjected() {
+            let _lock = mock::lock();
+            let vm = VirtualMachine::new().unwrap();
+            let vcpu = vm.create_vcpu().unwrap();
+
+            let exit = MockExit::new(VMX_REASON_EPT_VIOLATION)
+                .field(VMCS_RO_IDT_VECTOR_INFO, 0x8000_0202);
+            mock::push_exit(vcpu.id() as _, exit);
+            vcpu.run().unwrap();
+            assert_eq!(vcpu.reinjection(), Some(Event::Nmi));
+
+            // The NMI is injected on the next entry
+            mock::push_exit(vcpu.id() as _, MockExit::new(VMX_REASON_HLT));
+            mock::take_calls();
+            vcpu.run().unwrap();
+            assert!(mock::calls().contains(&mock::Call::WriteVmcs(vcpu.id() as _,
+                VMCS_CTRL_VMENTRY_IRQ_INFO, 0x8000_0202)));
+            assert_eq!(vcpu.reinjection(), None);
+            assert!(vcpu.pending_events().is_empty());
+        }
+
+        #[test]
+        fn interrupted_iret_restores_nmi_blocking() {
+            let _lock = mock::lock();
+            let vm = VirtualMachine::new().unwrap();
+            let vcpu = vm.create_vcpu().unwrap();
+
+            // A #PF and an EPT violation in IRET, and a #DF, which doesn't count
+            let cases = [
+                (MockExit::new(VMX_REASON_EXC_NMI).field(VMCS_RO_VMEXIT_IRQ_INFO, 0x8000_1b0e),
+                    BLOCKING_NMI),
+                (MockExit::new(VMX_REASON_EPT_VIOLATION).qualification(1 << 12), BLOCKING_NMI),
+                (MockExit::new(VMX_REASON_EXC_NMI).field(VMCS_RO_VMEXIT_IRQ_INFO, 0x8000_1b08),
+                    0),
+                (MockExit::new(VMX_REASON_EPT_VIOLATION), 0),
+            ];
+            for (exit, expected) in cases.iter().cloned() {
+                vcpu.write_field(VmcsField::GuestIgnoreIrq, 0).unwrap();
+                mock::push_exit(vcpu.id() as _, exit);
+                vcpu.run().unwrap();
+                assert_eq!(interruptibility(&vcpu), expected);
+                assert_eq!(vcpu.reinjection(), None);
+            }
+        }
+    }
+}
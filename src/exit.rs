@@ -0,0 +1,815 @@
+/*
+Copyright (c) 2016 Saurav Sachidanand
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in
+all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+THE SOFTWARE.
+*/
+
+//! VM exits
+//!
+//! `vCPU::exit_info` reads the exit information fields of the VMCS into `ExitFields`, and
+//! decodes them into a `VmExit`. Decoding doesn't touch the vCPU, so exits can also be
+//! decoded from fields put together by hand.
+
+use consts::vmx_exit::*;
+use qualification::{CrAccessQualification, DrAccessQualification, EptViolationQualification,
+    IoQualification, TaskSwitchQualification};
+use super::{vCPU, HvError, VmcsField};
+
+/// Type of an event, as encoded in interruption information fields
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum InterruptionType {
+    /// External interrupt
+    External,
+    /// Reserved encoding
+    Reserved,
+    /// Non-maskable interrupt
+    Nmi,
+    /// Hardware exception
+    HardwareException,
+    /// Software interrupt (`INT n`)
+    SoftwareInterrupt,
+    /// Privileged software exception (`INT1`)
+    PrivilegedSoftwareException,
+    /// Software exception (`INT3` or `INTO`)
+    SoftwareException,
+    /// Other event
+    Other,
+}
+
+impl InterruptionType {
+    /// Returns the type with an encoding, of which only the low 3 bits are used
+    pub fn from_raw(raw: u32) -> InterruptionType {
+        match raw & 0x7 {
+            0 => InterruptionType::External,
+            1 => InterruptionType::Reserved,
+            2 => InterruptionType::Nmi,
+            3 => InterruptionType::HardwareException,
+            4 => InterruptionType::SoftwareInterrupt,
+            5 => InterruptionType::PrivilegedSoftwareException,
+            6 => InterruptionType::SoftwareException,
+            _ => InterruptionType::Other,
+        }
+    }
+
+    /// Returns the encoding of the type
+    pub fn to_raw(self) -> u32 {
+        self as u32
+    }
+}
+
+const INFO_ERROR_VALID: u32 = 1 << 11;
+const INFO_NMI_UNBLOCKING: u32 = 1 << 12;
+const INFO_VALID: u32 = 1 << 31;
+
+/// Event described by an interruption information field
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct InterruptionInfo {
+    /// Vector
+    pub vector: u8,
+    /// Type
+    pub kind: InterruptionType,
+    /// Error code, if the event delivers one
+    pub error_code: Option<u32>,
+    /// NMI unblocking due to IRET (only in VM-exit interruption information)
+    pub nmi_unblocking: bool,
+}
+
+impl InterruptionInfo {
+    /// Decodes an interruption information field and its error code field, returning `None` if
+    /// the valid bit is clear
+    pub fn from_raw(info: u32, error_code: u32) -> Option<InterruptionInfo> {
+        if info & INFO_VALID == 0 {
+            return None;
+        }
+
+        Some(InterruptionInfo::decode(info, error_code))
+    }
+
+    // Decodes an interruption information field whatever its valid bit
+    fn decode(info: u32, error_code: u32) -> InterruptionInfo {
+        InterruptionInfo {
+            vector: info as u8,
+            kind: InterruptionType::from_raw(info >> 8),
+            error_code: if info & INFO_ERROR_VALID != 0 { Some(error_code) } else { None },
+            nmi_unblocking: info & INFO_NMI_UNBLOCKING != 0,
+        }
+    }
+
+    /// Encodes the event as an interruption information field, with the valid bit set
+    pub fn to_raw(&self) -> u32 {
+        let mut info = INFO_VALID | u32::from(self.vector) | (self.kind.to_raw() << 8);
+        if self.error_code.is_some() {
+            info |= INFO_ERROR_VALID;
+        }
+        if self.nmi_unblocking {
+            info |= INFO_NMI_UNBLOCKING;
+        }
+        info
+    }
+}
+
+/// Raw exit information fields of the VMCS
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct ExitFields {
+    /// Exit reason
+    pub reason: u32,
+    /// Exit qualification
+    pub qualification: u64,
+    /// VM-exit instruction length
+    pub instruction_len: u32,
+    /// VM-exit instruction information
+    pub instruction_info: u32,
+    /// Guest-linear address
+    pub guest_linear_address: u64,
+    /// Guest-physical address
+    pub guest_physical_address: u64,
+    /// VM-exit interruption information
+    pub interruption_info: u32,
+    /// VM-exit interruption error code
+    pub interruption_error: u32,
+    /// IDT-vectoring information
+    pub idt_vectoring_info: u32,
+    /// IDT-vectoring error code
+    pub idt_vectoring_error: u32,
+}
+
+impl ExitFields {
+    /// Reads the exit information fields of a vCPU
+    pub fn read(vcpu: &vCPU) -> Result<ExitFields, HvError> {
+        Ok(ExitFields {
+            reason: vcpu.read_field(VmcsField::RoExitReason)? as u32,
+            qualification: vcpu.read_field(VmcsField::RoExitQualific)?,
+            instruction_len: vcpu.read_field(VmcsField::RoVmexitInstrLen)? as u32,
+            instruction_info: vcpu.read_field(VmcsField::RoVmxInstrInfo)? as u32,
+            guest_linear_address: vcpu.read_field(VmcsField::RoGuestLinAddr)?,
+            guest_physical_address: vcpu.read_field(VmcsField::GuestPhysicalAddress)?,
+            interruption_info: vcpu.read_field(VmcsField::RoVmexitIrqInfo)? as u32,
+            interruption_error: vcpu.read_field(VmcsField::RoVmexitIrqError)? as u32,
+            idt_vectoring_info: vcpu.read_field(VmcsField::RoIdtVectorInfo)? as u32,
+            idt_vectoring_error: vcpu.read_field(VmcsField::RoIdtVectorError)? as u32,
+        })
+    }
+
+    /// Returns the basic exit reason, without the flags in the upper bits
+    pub fn basic_reason(&self) -> u32 {
+        self.reason & 0xffff
+    }
+
+    /// Returns whether VM entry failed
+    pub fn entry_failed(&self) -> bool {
+        self.reason & (1 << 31) != 0
+    }
+
+    fn idt_vectoring(&self) -> Option<InterruptionInfo> {
+        InterruptionInfo::from_raw(self.idt_vectoring_info, self.idt_vectoring_error)
+    }
+}
+
+/// Decoded VM exit
+///
+/// There is one variant per basic exit reason, except that every failed VM entry is an
+/// `EntryFailure`. Events that were being delivered when the exit happened are reported as
+/// `idt_vectoring` by the exits that can interrupt event delivery.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VmExit {
+    /// Exception or non-maskable interrupt
+    ExceptionNmi {
+        /// Event that caused the exit
+        info: InterruptionInfo,
+        /// Exit qualification, the faulting address of a page fault or the debug exception
+        /// bits of a debug exception
+        qualification: u64,
+        /// Event being delivered
+        idt_vectoring: Option<InterruptionInfo>,
+    },
+    /// External interrupt
+    ExternalInterrupt {
+        /// Interrupt, if acknowledged on exit
+        info: Option<InterruptionInfo>,
+    },
+    /// Triple fault
+    TripleFault {
+        /// Event being delivered
+        idt_vectoring: Option<InterruptionInfo>,
+    },
+    /// INIT signal
+    Init,
+    /// Start-up IPI
+    Sipi {
+        /// SIPI vector
+        vector: u8,
+    },
+    /// I/O system-management interrupt
+    IoSmi,
+    /// Other system-management interrupt
+    OtherSmi,
+    /// Interrupt window
+    InterruptWindow,
+    /// NMI window
+    NmiWindow,
+    /// Task switch
+    TaskSwitch {
+        /// Exit qualification
+        qualification: TaskSwitchQualification,
+        /// Instruction length, if caused by an instruction
+        instruction_len: u32,
+        /// Event being delivered
+        idt_vectoring: Option<InterruptionInfo>,
+    },
+    /// CPUID
+    Cpuid {
+        /// Instruction length
+        instruction_len: u32,
+    },
+    /// GETSEC
+    Getsec {
+        /// Instruction length
+        instruction_len: u32,
+    },
+    /// HLT
+    Hlt {
+        /// Instruction length
+        instruction_len: u32,
+    },
+    /// INVD
+    Invd {
+        /// Instruction length
+        instruction_len: u32,
+    },
+    /// INVLPG
+    Invlpg {
+        /// Linear address operand
+        linear_address: u64,
+        /// Instruction length
+        instruction_len: u32,
+    },
+    /// RDPMC
+    Rdpmc {
+        /// Instruction length
+        instruction_len: u32,
+    },
+    /// RDTSC
+    Rdtsc {
+        /// Instruction length
+        instruction_len: u32,
+    },
+    /// RSM in SMM
+    Rsm {
+        /// Instruction length
+        instruction_len: u32,
+    },
+    /// VMCALL
+    Vmcall {
+        /// Instruction length
+        instruction_len: u32,
+    },
+    /// VMX instruction (VMCLEAR, VMLAUNCH, VMPTRLD, VMPTRST, VMREAD, VMRESUME, VMWRITE, VMXOFF,
+    /// VMXON, INVEPT or INVVPID)
+    VmxInstruction {
+        /// Basic exit reason, one of the `VMX_REASON_*` constants of the instruction
+        reason: u32,
+        /// Exit qualification, the displacement of a memory operand
+        qualification: u64,
+        /// Instruction length
+        instruction_len: u32,
+        /// VM-exit instruction information
+        instruction_info: u32,
+    },
+    /// MOV to or from a control register, CLTS or LMSW
+    CrAccess {
+        /// Exit qualification
+        qualification: CrAccessQualification,
+        /// Instruction length
+        instruction_len: u32,
+    },
+    /// MOV to or from a debug register
+    DrAccess {
+        /// Exit qualification
+        qualification: DrAccessQualification,
+        /// Instruction length
+        instruction_len: u32,
+    },
+    /// I/O instruction
+    Io {
+        /// Exit qualification
+        qualification: IoQualification,
+        /// Instruction length
+        instruction_len: u32,
+        /// Linear address of the memory operand of a string instruction
+        guest_linear_address: Option<u64>,
+    },
+    /// RDMSR
+    Rdmsr {
+        /// Instruction length
+        instruction_len: u32,
+    },
+    /// WRMSR
+    Wrmsr {
+        /// Instruction length
+        instruction_len: u32,
+    },
+    /// Failed VM entry, which `vCPU::run` also returns as an error
+    EntryFailure {
+        /// Basic exit reason, `VMX_REASON_VMENTRY_GUEST`, `VMX_REASON_VMENTRY_MSR` or
+        /// `VMX_REASON_VMENTRY_MC`
+        reason: u32,
+        /// Exit qualification
+        qualification: u64,
+    },
+    /// MWAIT
+    Mwait {
+        /// Instruction length
+        instruction_len: u32,
+    },
+    /// Monitor trap flag
+    MonitorTrap,
+    /// MONITOR
+    Monitor {
+        /// Instruction length
+        instruction_len: u32,
+    },
+    /// PAUSE
+    Pause {
+        /// Instruction length
+        instruction_len: u32,
+    },
+    /// TPR below threshold
+    TprThreshold,
+    /// APIC access
+    ApicAccess {
+        /// Exit qualification
+        qualification: u64,
+        /// Event being delivered
+        idt_vectoring: Option<InterruptionInfo>,
+    },
+    /// Virtualized EOI
+    VirtualizedEoi {
+        /// Vector of the EOI
+        vector: u8,
+    },
+    /// Access to GDTR or IDTR
+    GdtrIdtr {
+        /// Exit qualification
+        qualification: u64,
+        /// Instruction length
+        instruction_len: u32,
+        /// VM-exit instruction information
+        instruction_info: u32,
+    },
+    /// Access to LDTR or TR
+    LdtrTr {
+        /// Exit qualification
+        qualification: u64,
+        /// Instruction length
+        instruction_len: u32,
+        /// VM-exit instruction information
+        instruction_info: u32,
+    },
+    /// EPT violation
+    EptViolation {
+        /// Exit qualification
+        qualification: EptViolationQualification,
+        /// Guest-physical address of the access
+        guest_physical_address: u64,
+        /// Guest-linear address of the access, if valid
+        guest_linear_address: Option<u64>,
+        /// Event being delivered
+        idt_vectoring: Option<InterruptionInfo>,
+    },
+    /// EPT misconfiguration
+    EptMisconfig {
+        /// Guest-physical address of the access
+        guest_physical_address: u64,
+        /// Event being delivered
+        idt_vectoring: Option<InterruptionInfo>,
+    },
+    /// RDTSCP
+    Rdtscp {
+        /// Instruction length
+        instruction_len: u32,
+    },
+    /// VMX-preemption timer expired
+    PreemptionTimer,
+    /// WBINVD or WBNOINVD
+    Wbinvd {
+        /// Instruction length
+        instruction_len: u32,
+    },
+    /// XSETBV
+    Xsetbv {
+        /// Instruction length
+        instruction_len: u32,
+    },
+    /// APIC write
+    ApicWrite {
+        /// Offset of the register written on the APIC page
+        offset: u32,
+    },
+    /// Instruction with operands described by the VM-exit instruction information (RDRAND,
+    /// INVPCID, RDSEED, XSAVES, XRSTORS, ENQCMD or ENQCMDS)
+    OperandInstruction {
+        /// Basic exit reason, one of the `VMX_REASON_*` constants of the instruction
+        reason: u32,
+        /// Exit qualification
+        qualification: u64,
+        /// Instruction length
+        instruction_len: u32,
+        /// VM-exit instruction information
+        instruction_info: u32,
+    },
+    /// VMFUNC
+    Vmfunc {
+        /// Instruction length
+        instruction_len: u32,
+    },
+    /// ENCLS
+    Encls {
+        /// Instruction length
+        instruction_len: u32,
+    },
+    /// Page-modification log full
+    PmlFull {
+        /// Event being delivered
+        idt_vectoring: Option<InterruptionInfo>,
+    },
+    /// PCONFIG
+    Pconfig {
+        /// Instruction length
+        instruction_len: u32,
+    },
+    /// SPP-related event
+    Spp {
+        /// Exit qualification
+        qualification: u64,
+        /// Guest-physical address of the access
+        guest_physical_address: u64,
+    },
+    /// UMWAIT
+    Umwait {
+        /// Instruction length
+        instruction_len: u32,
+    },
+    /// TPAUSE
+    Tpause {
+        /// Instruction length
+        instruction_len: u32,
+    },
+    /// LOADIWKEY
+    Loadiwkey {
+        /// Instruction length
+        instruction_len: u32,
+    },
+    /// ENCLV
+    Enclv {
+        /// Instruction length
+        instruction_len: u32,
+    },
+    /// Bus lock
+    BusLock,
+    /// Instruction timeout
+    InstructionTimeout,
+    /// SEAMCALL
+    Seamcall {
+        /// Instruction length
+        instruction_len: u32,
+    },
+    /// TDCALL
+    Tdcall {
+        /// Instruction length
+        instruction_len: u32,
+    },
+    /// RDMSRLIST
+    Rdmsrlist {
+        /// Instruction length
+        instruction_len: u32,
+    },
+    /// WRMSRLIST
+    Wrmsrlist {
+        /// Instruction length
+        instruction_len: u32,
+    },
+    /// Exit reason this crate doesn't know
+    Unknown {
+        /// Basic exit reason
+        reason: u32,
+        /// Exit qualification
+        qualification: u64,
+    },
+}
+
+impl VmExit {
+    /// Decodes exit information fields
+    pub fn decode(fields: &ExitFields) -> VmExit {
+        let reason = fields.basic_reason();
+        let qualification = fields.qualification;
+        let instruction_len = fields.instruction_len;
+        let instruction_info = fields.instruction_info;
+        let idt_vectoring = fields.idt_vectoring();
+
+        if fields.entry_failed() {
+            return VmExit::EntryFailure { reason, qualification };
+        }
+
+        match u64::from(reason) {
+            VMX_REASON_EXC_NMI => VmExit::ExceptionNmi {
+                info: InterruptionInfo::decode(fields.interruption_info, fields.interruption_error),
+                qualification,
+                idt_vectoring,
+            },
+            VMX_REASON_IRQ => VmExit::ExternalInterrupt {
+                info: InterruptionInfo::from_raw(
+                    fields.interruption_info, fields.interruption_error
+                ),
+            },
+            VMX_REASON_TRIPLE_FAULT => VmExit::TripleFault { idt_vectoring },
+            VMX_REASON_INIT => VmExit::Init,
+            VMX_REASON_SIPI => VmExit::Sipi { vector: qualification as u8 },
+            VMX_REASON_IO_SMI => VmExit::IoSmi,
+            VMX_REASON_OTHER_SMI => VmExit::OtherSmi,
+            VMX_REASON_IRQ_WND => VmExit::InterruptWindow,
+            VMX_REASON_VIRTUAL_NMI_WND => VmExit::NmiWindow,
+            VMX_REASON_TASK => VmExit::TaskSwitch {
+                qualification: TaskSwitchQualification::from_raw(qualification),
+                instruction_len,
+                idt_vectoring,
+            },
+            VMX_REASON_CPUID => VmExit::Cpuid { instruction_len },
+            VMX_REASON_GETSEC => VmExit::Getsec { instruction_len },
+            VMX_REASON_HLT => VmExit::Hlt { instruction_len },
+            VMX_REASON_INVD => VmExit::Invd { instruction_len },
+            VMX_REASON_INVLPG => VmExit::Invlpg { linear_address: qualification, instruction_len },
+            VMX_REASON_RDPMC => VmExit::Rdpmc { instruction_len },
+            VMX_REASON_RDTSC => VmExit::Rdtsc { instruction_len },
+            VMX_REASON_RSM => VmExit::Rsm { instruction_len },
+            VMX_REASON_VMCALL => VmExit::Vmcall { instruction_len },
+            VMX_REASON_VMCLEAR | VMX_REASON_VMLAUNCH | VMX_REASON_VMPTRLD | VMX_REASON_VMPTRST
+            | VMX_REASON_VMREAD | VMX_REASON_VMRESUME | VMX_REASON_VMWRITE | VMX_REASON_VMOFF
+            | VMX_REASON_VMON | VMX_REASON_EPT_INVEPT | VMX_REASON_INVVPID => {
+                VmExit::VmxInstruction { reason, qualification, instruction_len, instruction_info }
+            }
+            VMX_REASON_MOV_CR => VmExit::CrAccess {
+                qualification: CrAccessQualification::from_raw(qualification),
+                instruction_len,
+            },
+            VMX_REASON_MOV_DR => VmExit::DrAccess {
+                qualification: DrAccessQualification::from_raw(qualification),
+                instruction_len,
+            },
+            VMX_REASON_IO => {
+                let qualification = IoQualification::from_raw(qualification);
+                VmExit::Io {
+                    qualification,
+                    instruction_len,
+                    guest_linear_address: if qualification.string {
+                        Some(fields.guest_linear_address)
+                    } else {
+                        None
+                    },
+                }
+            }
+            VMX_REASON_RDMSR => VmExit::Rdmsr { instruction_len },
+            VMX_REASON_WRMSR => VmExit::Wrmsr { instruction_len },
+            VMX_REASON_VMENTRY_GUEST | VMX_REASON_VMENTRY_MSR | VMX_REASON_VMENTRY_MC => {
+                VmExit::EntryFailure { reason, qualification }
+            }
+            VMX_REASON_MWAIT => VmExit::Mwait { instruction_len },
+            VMX_REASON_MTF => VmExit::MonitorTrap,
+            VMX_REASON_MONITOR => VmExit::Monitor { instruction_len },
+            VMX_REASON_PAUSE => VmExit::Pause { instruction_len },
+            VMX_REASON_TPR_THRESHOLD => VmExit::TprThreshold,
+            VMX_REASON_APIC_ACCESS => VmExit::ApicAccess { qualification, idt_vectoring },
+            VMX_REASON_VIRTUALIZED_EOI => VmExit::VirtualizedEoi { vector: qualification as u8 },
+            VMX_REASON_GDTR_IDTR => {
+                VmExit::GdtrIdtr { qualification, instruction_len, instruction_info }
+            }
+            VMX_REASON_LDTR_TR => {
+                VmExit::LdtrTr { qualification, instruction_len, instruction_info }
+            }
+            VMX_REASON_EPT_VIOLATION => {
+                let qualification = EptViolationQualification::from_raw(qualification);
+                VmExit::EptViolation {
+                    qualification,
+                    guest_physical_address: fields.guest_physical_address,
+                    guest_linear_address: if qualification.linear_address_valid {
+                        Some(fields.guest_linear_address)
+                    } else {
+                        None
+                    },
+                    idt_vectoring,
+                }
+            }
+            VMX_REASON_EPT_MISCONFIG => VmExit::EptMisconfig {
+                guest_physical_address: fields.guest_physical_address,
+                idt_vectoring,
+            },
+            VMX_REASON_RDTSCP => VmExit::Rdtscp { instruction_len },
+            VMX_REASON_VMX_TIMER_EXPIRED => VmExit::PreemptionTimer,
+            VMX_REASON_WBINVD => VmExit::Wbinvd { instruction_len },
+            VMX_REASON_XSETBV => VmExit::Xsetbv { instruction_len },
+            VMX_REASON_APIC_WRITE => VmExit::ApicWrite { offset: qualification as u32 & 0xfff },
+            VMX_REASON_RDRAND | VMX_REASON_INVPCID | VMX_REASON_RDSEED | VMX_REASON_XSAVES
+            | VMX_REASON_XRSTORS | VMX_REASON_ENQCMD_PASID | VMX_REASON_ENQCMDS_PASID => {
+                VmExit::OperandInstruction {
+                    reason, qualification, instruction_len, instruction_info
+                }
+            }
+            VMX_REASON_VMFUNC => VmExit::Vmfunc { instruction_len },
+            VMX_REASON_ENCLS => VmExit::Encls { instruction_len },
+            VMX_REASON_PML_FULL => VmExit::PmlFull { idt_vectoring },
+            VMX_REASON_PCONFIG => VmExit::Pconfig { instruction_len },
+            VMX_REASON_SPP => VmExit::Spp {
+                qualification,
+                guest_physical_address: fields.guest_physical_address,
+            },
+            VMX_REASON_UMWAIT => VmExit::Umwait { instruction_len },
+            VMX_REASON_TPAUSE => VmExit::Tpause { instruction_len },
+            VMX_REASON_LOADIWKEY => VmExit::Loadiwkey { instruction_len },
+            VMX_REASON_ENCLV => VmExit::Enclv { instruction_len },
+            VMX_REASON_BUS_LOCK => VmExit::BusLock,
+            VMX_REASON_INSTR_TIMEOUT => VmExit::InstructionTimeout,
+            VMX_REASON_SEAMCALL => VmExit::Seamcall { instruction_len },
+            VMX_REASON_TDCALL => VmExit::Tdcall { instruction_len },
+            VMX_REASON_RDMSRLIST => VmExit::Rdmsrlist { instruction_len },
+            VMX_REASON_WRMSRLIST => VmExit::Wrmsrlist { instruction_len },
+            _ => VmExit::Unknown { reason, qualification },
+        }
+    }
+
+    /// Returns the length of the instruction that caused the exit, for exits caused by
+    /// instructions
+    pub fn instruction_len(&self) -> Option<u32> {
+        match *self {
+            VmExit::Cpuid { instruction_len } | VmExit::Getsec { instruction_len }
+            | VmExit::Hlt { instruction_len } | VmExit::Invd { instruction_len }
+            | VmExit::Invlpg { instruction_len, .. } | VmExit::Rdpmc { instruction_len }
+            | VmExit::Rdtsc { instruction_len } | VmExit::Rsm { instruction_len }
+            | VmExit::Vmcall { instruction_len } | VmExit::VmxInstruction { instruction_len, .. }
+            | VmExit::CrAccess { instruction_len, .. } | VmExit::DrAccess { instruction_len, .. }
+            | VmExit::Io { instruction_len, .. } | VmExit::Rdmsr { instruction_len }
+            | VmExit::Wrmsr { instruction_len } | VmExit::Mwait { instruction_len }
+            | VmExit::Monitor { instruction_len } | VmExit::Pause { instruction_len }
+            | VmExit::GdtrIdtr { instruction_len, .. } | VmExit::LdtrTr { instruction_len, .. }
+            | VmExit::Rdtscp { instruction_len } | VmExit::Wbinvd { instruction_len }
+            | VmExit::Xsetbv { instruction_len }
+            | VmExit::OperandInstruction { instruction_len, .. }
+            | VmExit::Vmfunc { instruction_len } | VmExit::Encls { instruction_len }
+            | VmExit::Pconfig { instruction_len } | VmExit::Umwait { instruction_len }
+            | VmExit::Tpause { instruction_len } | VmExit::Loadiwkey { instruction_len }
+            | VmExit::Enclv { instruction_len } | VmExit::Seamcall { instruction_len }
+            | VmExit::Tdcall { instruction_len } | VmExit::Rdmsrlist { instruction_len }
+            | VmExit::Wrmsrlist { instruction_len } => Some(instruction_len),
+            _ => None,
+        }
+    }
+
+    /// Returns the event that was being delivered when the exit happened
+    pub fn idt_vectoring(&self) -> Option<InterruptionInfo> {
+        match *self {
+            VmExit::ExceptionNmi { idt_vectoring, .. } | VmExit::TripleFault { idt_vectoring }
+            | VmExit::TaskSwitch { idt_vectoring, .. } | VmExit::ApicAccess { idt_vectoring, .. }
+            | VmExit::EptViolation { idt_vectoring, .. }
+            | VmExit::EptMisconfig { idt_vectoring, .. }
+            | VmExit::PmlFull { idt_vectoring } => idt_vectoring,
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Page fault with error code 2 being delivered
+    const IDT_VECTORING: u32 = 0x8000_0b0e;
+
+    fn fields(reason: u32) -> ExitFields {
+        ExitFields {
+            reason,
+            qualification: 0x1234,
+            instruction_len: 3,
+            instruction_info: 0x5678,
+            guest_linear_address: 0x7000,
+            guest_physical_address: 0x9000,
+            idt_vectoring_info: IDT_VECTORING,
+            idt_vectoring_error: 2,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn entry_failures() {
+        let failure = |reason| VmExit::EntryFailure { reason, qualification: 0x1234 };
+        let cases = [
+            // Bit 31 makes any exit an entry failure
+            (1 << 31 | VMX_REASON_VMENTRY_GUEST as u32, failure(33)),
+            (1 << 31 | VMX_REASON_VMENTRY_MSR as u32, failure(34)),
+            (1 << 31 | VMX_REASON_VMENTRY_MC as u32, failure(41)),
+            (1 << 31 | VMX_REASON_EPT_VIOLATION as u32, failure(48)),
+            (VMX_REASON_VMENTRY_GUEST as u32, failure(33)),
+            // Other flags in the upper bits are dropped
+            (1 << 27 | VMX_REASON_HLT as u32, VmExit::Hlt { instruction_len: 3 }),
+        ];
+        for &(reason, expected) in &cases {
+            assert_eq!(VmExit::decode(&fields(reason)), expected, "{:#x}", reason);
+        }
+    }
+
+    #[test]
+    fn new_reasons() {
+        let idt_vectoring = InterruptionInfo::from_raw(IDT_VECTORING, 2);
+        let operand = |reason| VmExit::OperandInstruction {
+            reason,
+            qualification: 0x1234,
+            instruction_len: 3,
+            instruction_info: 0x5678,
+        };
+        let cases = [
+            VmExit::Encls { instruction_len: 3 },
+            operand(61),
+            VmExit::PmlFull { idt_vectoring },
+            operand(63),
+            operand(64),
+            VmExit::Pconfig { instruction_len: 3 },
+            VmExit::Spp { qualification: 0x1234, guest_physical_address: 0x9000 },
+            VmExit::Umwait { instruction_len: 3 },
+            VmExit::Tpause { instruction_len: 3 },
+            VmExit::Loadiwkey { instruction_len: 3 },
+            VmExit::Enclv { instruction_len: 3 },
+            VmExit::Unknown { reason: 71, qualification: 0x1234 },
+            operand(72),
+            operand(73),
+            VmExit::BusLock,
+            VmExit::InstructionTimeout,
+            VmExit::Seamcall { instruction_len: 3 },
+            VmExit::Tdcall { instruction_len: 3 },
+            VmExit::Rdmsrlist { instruction_len: 3 },
+            VmExit::Wrmsrlist { instruction_len: 3 },
+            VmExit::Unknown { reason: 80, qualification: 0x1234 },
+        ];
+        for (reason, expected) in (60..).zip(cases.iter()) {
+            assert_eq!(VmExit::decode(&fields(reason)), *expected, "{}", reason);
+        }
+    }
+
+    #[test]
+    fn payloads() {
+        let idt_vectoring = InterruptionInfo::from_raw(IDT_VECTORING, 2);
+        assert!(idt_vectoring.is_some());
+        let cases = [
+            (VMX_REASON_CPUID, Some(3), None),
+            (VMX_REASON_IO, Some(3), None),
+            (VMX_REASON_VMCALL, Some(3), None),
+            (VMX_REASON_GDTR_IDTR, Some(3), None),
+            (VMX_REASON_XRSTORS, Some(3), None),
+            (VMX_REASON_WRMSRLIST, Some(3), None),
+            (VMX_REASON_TRIPLE_FAULT, None, idt_vectoring),
+            (VMX_REASON_TASK, None, idt_vectoring),
+            (VMX_REASON_APIC_ACCESS, None, idt_vectoring),
+            (VMX_REASON_EPT_VIOLATION, None, idt_vectoring),
+            (VMX_REASON_EPT_MISCONFIG, None, idt_vectoring),
+            (VMX_REASON_PML_FULL, None, idt_vectoring),
+            (VMX_REASON_MTF, None, None),
+            (VMX_REASON_VMX_TIMER_EXPIRED, None, None),
+            (VMX_REASON_BUS_LOCK, None, None),
+        ];
+        for &(reason, instruction_len, vectoring) in &cases {
+            let exit = VmExit::decode(&fields(reason as u32));
+            assert_eq!(exit.instruction_len(), instruction_len, "{:?}", exit);
+            assert_eq!(exit.idt_vectoring(), vectoring, "{:?}", exit);
+        }
+
+        // Exceptions carry their own interruption information as well
+        let exit = VmExit::decode(&ExitFields {
+            interruption_info: 0x8000_0306,
+            ..fields(VMX_REASON_EXC_NMI as u32)
+        });
+        assert_eq!(exit.idt_vectoring(), idt_vectoring);
+        match exit {
+            VmExit::ExceptionNmi { info, .. } => assert_eq!(info.vector, 6),
+            _ => panic!("{:?}", exit),
+        }
+
+        // An invalid IDT-vectoring information field gives no event
+        let exit = VmExit::decode(&ExitFields {
+            idt_vectoring_info: IDT_VECTORING & !(1 << 31),
+            ..fields(VMX_REASON_EPT_MISCONFIG as u32)
+        });
+        assert_eq!(exit.idt_vectoring(), None);
+    }
+}
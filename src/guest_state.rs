@@ -0,0 +1,924 @@
+/*
+Copyright (c) 2016 Saurav Sachidanand
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in
+all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+THE SOFTWARE.
+*/
+
+//! Guest-state checks
+//!
+//! `check` runs the checks VM entry makes on the guest-state area (Intel SDM, section
+//! 26.3.1) against a `VmcsSnapshot` and the VMX controls, without a vCPU. Each failed check is
+//! reported as a `Violation` naming the offending fields. `vCPU::check_guest_state` runs the
+//! checks on the current state of a vCPU, and is meant to be called before `vCPU::run` in debug
+//! builds.
+//!
+//! Fields missing from the snapshot count as 0. The checks assume the fixed CR0 and CR4 bits of
+//! current processors, and a 48-bit linear-address width. Reserved bits that depend on the
+//! processor's CPUID, such as physical-address bits and performance counters, are only checked
+//! where every processor reserves them: physical addresses are taken to be 52 bits wide.
+//!
+//! Some checks aren't made:
+//!
+//! - IA32_BNDCFGS, whose guest field isn't exposed by Hypervisor.framework
+//! - the PDPTEs of a PAE guest without EPT, which VM entry loads from guest memory
+//! - the activity states and SMM checks that depend on IA32_VMX_MISC
+//! - the RTM bit of the pending debug exceptions, and the VMCS the link pointer references
+
+use std::fmt;
+
+use consts::vmcs::*;
+use consts::vmx_cap::*;
+use super::{AdjustedControls, SegReg, Segment, VmcsField, VmcsSnapshot};
+
+const CR0_PE: u64 = 1 << 0;
+const CR0_NE: u64 = 1 << 5;
+const CR0_PG: u64 = 1 << 31;
+const CR0_VALID: u64 = 0xe005_003f;
+
+const CR3_RESERVED: u64 = !0 << 52;
+
+const CR4_PAE: u64 = 1 << 5;
+const CR4_VMXE: u64 = 1 << 13;
+const CR4_PCIDE: u64 = 1 << 17;
+const CR4_VALID: u64 = 0x13ff_7fff;
+
+const EFER_LME: u64 = 1 << 8;
+const EFER_LMA: u64 = 1 << 10;
+const EFER_VALID: u64 = 0xd01;
+
+const RFLAGS_FIXED: u64 = 1 << 1;
+const RFLAGS_TF: u64 = 1 << 8;
+const RFLAGS_IF: u64 = 1 << 9;
+const RFLAGS_VM: u64 = 1 << 17;
+const RFLAGS_RESERVED: u64 = 0xffff_ffff_ffc0_8028;
+
+const DEBUGCTL_BTF: u64 = 1 << 1;
+
+const PERF_GLOBAL_CTRL_RESERVED: u64 = !0 << 49;
+
+const PDPTE_P: u64 = 1 << 0;
+const PDPTE_RESERVED: u64 = (!0 << 52) | 0x1e6;
+
+const AR_RESERVED: u64 = 0xfffe_0f00;
+
+const BLOCKING_STI: u64 = 1 << 0;
+const BLOCKING_MOV_SS: u64 = 1 << 1;
+const BLOCKING_SMI: u64 = 1 << 2;
+const BLOCKING_NMI: u64 = 1 << 3;
+const BLOCKING_RESERVED: u64 = !0x1f;
+
+const ACTIVITY_ACTIVE: u64 = 0;
+const ACTIVITY_HLT: u64 = 1;
+const ACTIVITY_SHUTDOWN: u64 = 2;
+const ACTIVITY_WAIT_FOR_SIPI: u64 = 3;
+
+const PENDING_DEBUG_BS: u64 = 1 << 14;
+const PENDING_DEBUG_RESERVED: u64 = 0xffff_ffff_fffe_aff0;
+
+const IRQ_INFO_VALID: u64 = 1 << 31;
+const IRQ_INFO_EXT_IRQ: u64 = 0;
+const IRQ_INFO_NMI: u64 = 2 << 8;
+const IRQ_INFO_HW_EXC: u64 = 3 << 8;
+const IRQ_INFO_OTHER: u64 = 7 << 8;
+
+const VECTOR_DB: u64 = 1;
+const VECTOR_MC: u64 = 18;
+
+/// Guest-state check that failed
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Violation {
+    fields: Vec<VmcsField>,
+    message: String,
+}
+
+impl Violation {
+    /// Returns the fields that failed the check
+    pub fn fields(&self) -> &[VmcsField] {
+        &self.fields
+    }
+
+    /// Returns a description of the check
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, field) in self.fields.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", field.const_name())?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+/// Runs the VM-entry checks on the guest-state area of a VMCS snapshot
+///
+/// Returns every check that fails, in the order of the Intel SDM.
+pub fn check(state: &VmcsSnapshot, controls: &AdjustedControls) -> Vec<Violation> {
+    let cpu_based2 = if controls.cpu_based().bits() & CPU_BASED_SECONDARY_CTLS != 0 {
+        controls.cpu_based2().bits()
+    } else {
+        0
+    };
+    let entry = controls.entry().bits();
+    let rflags = get(state, VMCS_GUEST_RFLAGS);
+
+    let mut checker = Checker {
+        state,
+        pin_based: controls.pin_based().bits(),
+        entry,
+        ia32e: entry & VMENTRY_GUEST_IA32E != 0,
+        unrestricted: cpu_based2 & CPU_BASED2_UNRESTRICTED != 0,
+        ept: cpu_based2 & CPU_BASED2_EPT != 0,
+        v86: rflags & RFLAGS_VM != 0,
+        cr0: get(state, VMCS_GUEST_CR0),
+        rflags,
+        violations: Vec::new(),
+    };
+    checker.control_registers();
+    checker.segments();
+    checker.descriptor_tables();
+    checker.rip_and_rflags();
+    checker.non_register_state();
+    checker.pdptes();
+    checker.violations
+}
+
+// Guest state with the controls that affect its checks
+struct Checker<'a> {
+    state: &'a VmcsSnapshot,
+    pin_based: u64,
+    entry: u64,
+    ia32e: bool,
+    unrestricted: bool,
+    ept: bool,
+    v86: bool,
+    cr0: u64,
+    rflags: u64,
+    violations: Vec<Violation>,
+}
+
+impl<'a> Checker<'a> {
+    fn get(&self, field: u32) -> u64 {
+        get(self.state, field)
+    }
+
+    fn segment(&self, seg: SegReg) -> Segment {
+        Segment::from_ar(self.get(seg.selector_field()) as u16, self.get(seg.base_field()),
+            self.get(seg.limit_field()) as u32, self.get(seg.ar_field()) as u32)
+    }
+
+    fn fail(&mut self, fields: &[u32], message: String) {
+        self.violations.push(Violation {
+            fields: fields.iter()
+                .map(|&f| VmcsField::from_encoding(f).expect("guest-state field"))
+                .collect(),
+            message,
+        });
+    }
+
+    fn control_registers(&mut self) {
+        let cr0 = self.cr0;
+        let cr4 = self.get(VMCS_GUEST_CR4);
+
+        let fixed0 = if self.unrestricted { CR0_NE } else { CR0_PE | CR0_NE | CR0_PG };
+        if cr0 & fixed0 != fixed0 {
+            self.fail(&[VMCS_GUEST_CR0], format!("bits {:#x} must be set", fixed0 & !cr0));
+        }
+        if cr0 & !CR0_VALID != 0 {
+            self.fail(&[VMCS_GUEST_CR0], format!("reserved bits {:#x} are set", cr0 & !CR0_VALID));
+        }
+        if cr0 & CR0_PG != 0 && cr0 & CR0_PE == 0 {
+            self.fail(&[VMCS_GUEST_CR0], "PG is set without PE".to_string());
+        }
+        let cr3 = self.get(VMCS_GUEST_CR3);
+        if cr3 & CR3_RESERVED != 0 {
+            self.fail(&[VMCS_GUEST_CR3],
+                format!("bits {:#x} are beyond the physical-address width", cr3 & CR3_RESERVED));
+        }
+        if cr4 & CR4_VMXE == 0 {
+            self.fail(&[VMCS_GUEST_CR4], "VMXE must be set".to_string());
+        }
+        if cr4 & !CR4_VALID != 0 {
+            self.fail(&[VMCS_GUEST_CR4], format!("reserved bits {:#x} are set", cr4 & !CR4_VALID));
+        }
+
+        if self.entry & VMENTRY_LOAD_DBG_CONTROLS != 0 {
+            let dr7 = self.get(VMCS_GUEST_DR7);
+            if dr7 >> 32 != 0 {
+                self.fail(&[VMCS_GUEST_DR7], "bits 63:32 must be clear".to_string());
+            }
+        }
+
+        for &field in &[VMCS_GUEST_SYSENTER_ESP, VMCS_GUEST_SYSENTER_EIP] {
+            if !canonical(self.get(field)) {
+                self.fail(&[field], "must be canonical".to_string());
+            }
+        }
+
+        if self.ia32e {
+            if cr0 & CR0_PG == 0 {
+                self.fail(&[VMCS_GUEST_CR0], "PG must be set for an IA-32e mode guest".to_string());
+            }
+            if cr4 & CR4_PAE == 0 {
+                self.fail(&[VMCS_GUEST_CR4],
+                    "PAE must be set for an IA-32e mode guest".to_string());
+            }
+        } else if cr4 & CR4_PCIDE != 0 {
+            self.fail(&[VMCS_GUEST_CR4],
+                "PCIDE must be clear outside IA-32e mode".to_string());
+        }
+
+        if self.entry & VMENTRY_LOAD_IA32_PERF_GLOBAL_CTRL != 0 {
+            let ctrl = self.get(VMCS_GUEST_IA32_PERF_GLOBAL_CTRL);
+            if ctrl & PERF_GLOBAL_CTRL_RESERVED != 0 {
+                self.fail(&[VMCS_GUEST_IA32_PERF_GLOBAL_CTRL],
+                    format!("reserved bits {:#x} are set", ctrl & PERF_GLOBAL_CTRL_RESERVED));
+            }
+        }
+
+        if self.entry & VMENTRY_LOAD_IA32_PAT != 0 {
+            let pat = self.get(VMCS_GUEST_IA32_PAT);
+            for i in 0..8 {
+                let memory_type = (pat >> (i * 8)) & 0xff;
+                if memory_type == 2 || memory_type == 3 || memory_type > 7 {
+                    self.fail(&[VMCS_GUEST_IA32_PAT],
+                        format!("PA{} has invalid memory type {}", i, memory_type));
+                }
+            }
+        }
+
+        if self.entry & VMENTRY_LOAD_EFER != 0 {
+            let efer = self.get(VMCS_GUEST_IA32_EFER);
+            if efer & !EFER_VALID != 0 {
+                self.fail(&[VMCS_GUEST_IA32_EFER],
+                    format!("reserved bits {:#x} are set", efer & !EFER_VALID));
+            }
+            if (efer & EFER_LMA != 0) != self.ia32e {
+                self.fail(&[VMCS_GUEST_IA32_EFER, VMCS_CTRL_VMENTRY_CONTROLS],
+                    "LMA must match the IA-32e mode guest control".to_string());
+            }
+            if cr0 & CR0_PG != 0 && (efer & EFER_LME != 0) != self.ia32e {
+                self.fail(&[VMCS_GUEST_IA32_EFER, VMCS_CTRL_VMENTRY_CONTROLS],
+                    "LME must match the IA-32e mode guest control when paging".to_string());
+            }
+        }
+    }
+
+    fn segments(&mut self) {
+        let cs = self.segment(SegReg::CS);
+        let ss = self.segment(SegReg::SS);
+        let tr = self.segment(SegReg::TR);
+        let ldtr = self.segment(SegReg::LDTR);
+
+        if tr.selector & 0x4 != 0 {
+            self.fail(&[VMCS_GUEST_TR], "TI flag must be clear".to_string());
+        }
+        if !ldtr.unusable && ldtr.selector & 0x4 != 0 {
+            self.fail(&[VMCS_GUEST_LDTR], "TI flag must be clear".to_string());
+        }
+        if !self.v86 && !self.unrestricted && ss.selector & 0x3 != cs.selector & 0x3 {
+            self.fail(&[VMCS_GUEST_SS, VMCS_GUEST_CS], "RPLs must be equal".to_string());
+        }
+
+        for &seg in &[SegReg::TR, SegReg::FS, SegReg::GS, SegReg::LDTR] {
+            let s = self.segment(seg);
+            if (seg != SegReg::LDTR || !s.unusable) && !canonical(s.base) {
+                self.fail(&[seg.base_field()], "base must be canonical".to_string());
+            }
+        }
+        for &seg in &[SegReg::CS, SegReg::SS, SegReg::DS, SegReg::ES] {
+            let s = self.segment(seg);
+            if (seg == SegReg::CS || !s.unusable) && s.base >> 32 != 0 {
+                self.fail(&[seg.base_field()], "bits 63:32 must be clear".to_string());
+            }
+        }
+
+        if self.v86 {
+            for &seg in &[SegReg::CS, SegReg::SS, SegReg::DS, SegReg::ES, SegReg::FS, SegReg::GS] {
+                let s = self.segment(seg);
+                if s.base != u64::from(s.selector) << 4 {
+                    self.fail(&[seg.base_field(), seg.selector_field()],
+                        "base must be the selector shifted left by 4 in virtual-8086 mode"
+                            .to_string());
+                }
+                if s.limit != 0xffff {
+                    self.fail(&[seg.limit_field()],
+                        "limit must be 0xffff in virtual-8086 mode".to_string());
+                }
+                if self.get(seg.ar_field()) != 0xf3 {
+                    self.fail(&[seg.ar_field()],
+                        "access rights must be 0xf3 in virtual-8086 mode".to_string());
+                }
+            }
+        } else {
+            self.code_segment(&cs, &ss);
+            self.stack_segment(&cs, &ss);
+            for &seg in &[SegReg::DS, SegReg::ES, SegReg::FS, SegReg::GS] {
+                self.data_segment(seg);
+            }
+        }
+
+        // TR and LDTR are checked in virtual-8086 mode too
+        self.task_register(&tr);
+        self.ldtr(&ldtr);
+    }
+
+    fn code_segment(&mut self, cs: &Segment, ss: &Segment) {
+        let ar = VMCS_GUEST_CS_AR;
+        match cs.seg_type {
+            9 | 11 => if cs.dpl != ss.dpl {
+                self.fail(&[ar, VMCS_GUEST_SS_AR],
+                    "DPL must equal SS.DPL for a non-conforming code segment".to_string());
+            },
+            13 | 15 => if cs.dpl > ss.dpl {
+                self.fail(&[ar, VMCS_GUEST_SS_AR],
+                    "DPL must not exceed SS.DPL for a conforming code segment".to_string());
+            },
+            3 if self.unrestricted => if cs.dpl != 0 {
+                self.fail(&[ar], "DPL must be 0 for a read/write data segment".to_string());
+            },
+            t => self.fail(&[ar], format!("type {} is not an accessed code segment", t)),
+        }
+        if self.ia32e && cs.l && cs.db {
+            self.fail(&[ar], "D/B must be clear for a 64-bit code segment".to_string());
+        }
+        self.descriptor(SegReg::CS, cs, true);
+    }
+
+    fn stack_segment(&mut self, cs: &Segment, ss: &Segment) {
+        let ar = VMCS_GUEST_SS_AR;
+        if !self.unrestricted && ss.dpl != (ss.selector & 0x3) as u8 {
+            self.fail(&[ar, VMCS_GUEST_SS], "DPL must equal the RPL".to_string());
+        }
+        if (cs.seg_type == 3 || self.cr0 & CR0_PE == 0) && ss.dpl != 0 {
+            self.fail(&[ar], "DPL must be 0 in real-address mode".to_string());
+        }
+        if ss.unusable {
+            return;
+        }
+        if ss.seg_type != 3 && ss.seg_type != 7 {
+            self.fail(&[ar], format!("type {} is not a read/write data segment", ss.seg_type));
+        }
+        self.descriptor(SegReg::SS, ss, true);
+    }
+
+    fn data_segment(&mut self, seg: SegReg) {
+        let s = self.segment(seg);
+        if s.unusable {
+            return;
+        }
+        let ar = seg.ar_field();
+        if s.seg_type & 0x1 == 0 {
+            self.fail(&[ar], "type must be accessed".to_string());
+        }
+        if s.seg_type & 0x8 != 0 && s.seg_type & 0x2 == 0 {
+            self.fail(&[ar], "a code segment must be readable".to_string());
+        }
+        if !self.unrestricted && s.seg_type <= 11 && s.dpl < (s.selector & 0x3) as u8 {
+            self.fail(&[ar, seg.selector_field()], "DPL must not be less than the RPL".to_string());
+        }
+        self.descriptor(seg, &s, true);
+    }
+
+    fn task_register(&mut self, tr: &Segment) {
+        let ar = VMCS_GUEST_TR_AR;
+        let valid = tr.seg_type == 11 || (!self.ia32e && tr.seg_type == 3);
+        if !valid {
+            self.fail(&[ar], format!("type {} is not a busy TSS", tr.seg_type));
+        }
+        if tr.unusable {
+            self.fail(&[ar], "TR must be usable".to_string());
+        }
+        self.descriptor(SegReg::TR, tr, false);
+    }
+
+    fn ldtr(&mut self, ldtr: &Segment) {
+        if ldtr.unusable {
+            return;
+        }
+        if ldtr.seg_type != 2 {
+            self.fail(&[VMCS_GUEST_LDTR_AR], format!("type {} is not an LDT", ldtr.seg_type));
+        }
+        self.descriptor(SegReg::LDTR, ldtr, false);
+    }
+
+    // Checks shared by every usable segment: S, P, reserved bits and granularity
+    fn descriptor(&mut self, seg: SegReg, s: &Segment, code_or_data: bool) {
+        let ar = seg.ar_field();
+        if s.s != code_or_data {
+            self.fail(&[ar], format!("S must be {}", if code_or_data { 1 } else { 0 }));
+        }
+        if !s.present {
+            self.fail(&[ar], "P must be set".to_string());
+        }
+        let raw = self.get(ar);
+        if raw & AR_RESERVED != 0 {
+            self.fail(&[ar], format!("reserved bits {:#x} are set", raw & AR_RESERVED));
+        }
+        if s.limit & 0xfff != 0xfff && s.g {
+            self.fail(&[ar, seg.limit_field()],
+                "G must be clear when limit bits 11:0 aren't all set".to_string());
+        }
+        if s.limit & 0xfff0_0000 != 0 && !s.g {
+            self.fail(&[ar, seg.limit_field()],
+                "G must be set when limit bits 31:20 aren't all clear".to_string());
+        }
+    }
+
+    fn descriptor_tables(&mut self) {
+        for &(base, limit) in &[(VMCS_GUEST_GDTR_BASE, VMCS_GUEST_GDTR_LIMIT),
+            (VMCS_GUEST_IDTR_BASE, VMCS_GUEST_IDTR_LIMIT)] {
+            if !canonical(self.get(base)) {
+                self.fail(&[base], "base must be canonical".to_string());
+            }
+            if self.get(limit) >> 16 != 0 {
+                self.fail(&[limit], "bits 31:16 must be clear".to_string());
+            }
+        }
+    }
+
+    fn rip_and_rflags(&mut self) {
+        let rip = self.get(VMCS_GUEST_RIP);
+        let long_mode = self.ia32e && self.segment(SegReg::CS).l;
+        if !long_mode && rip >> 32 != 0 {
+            self.fail(&[VMCS_GUEST_RIP],
+                "bits 63:32 must be clear outside 64-bit mode".to_string());
+        }
+        if long_mode && !canonical(rip) {
+            self.fail(&[VMCS_GUEST_RIP], "RIP must be canonical in 64-bit mode".to_string());
+        }
+
+        let rflags = self.rflags;
+        if rflags & RFLAGS_RESERVED != 0 {
+            self.fail(&[VMCS_GUEST_RFLAGS],
+                format!("reserved bits {:#x} are set", rflags & RFLAGS_RESERVED));
+        }
+        if rflags & RFLAGS_FIXED == 0 {
+            self.fail(&[VMCS_GUEST_RFLAGS], "bit 1 must be set".to_string());
+        }
+        if (self.ia32e || self.cr0 & CR0_PE == 0) && self.v86 {
+            self.fail(&[VMCS_GUEST_RFLAGS],
+                "VM must be clear in IA-32e mode and real-address mode".to_string());
+        }
+        if self.injecting(IRQ_INFO_EXT_IRQ) && rflags & RFLAGS_IF == 0 {
+            self.fail(&[VMCS_GUEST_RFLAGS, VMCS_CTRL_VMENTRY_IRQ_INFO],
+                "IF must be set to inject an external interrupt".to_string());
+        }
+    }
+
+    fn non_register_state(&mut self) {
+        let activity = self.get(VMCS_GUEST_ACTIVITY_STATE);
+        if activity > ACTIVITY_WAIT_FOR_SIPI {
+            self.fail(&[VMCS_GUEST_ACTIVITY_STATE], format!("invalid activity state {}", activity));
+        }
+        if activity == ACTIVITY_HLT && self.segment(SegReg::SS).dpl != 0 {
+            self.fail(&[VMCS_GUEST_ACTIVITY_STATE, VMCS_GUEST_SS_AR],
+                "HLT state requires SS.DPL to be 0".to_string());
+        }
+
+        let blocking = self.get(VMCS_GUEST_IGNORE_IRQ);
+        let irq = VMCS_GUEST_IGNORE_IRQ;
+        if blocking & BLOCKING_RESERVED != 0 {
+            self.fail(&[irq], format!("reserved bits {:#x} are set", blocking & BLOCKING_RESERVED));
+        }
+        if blocking & BLOCKING_STI != 0 && blocking & BLOCKING_MOV_SS != 0 {
+            self.fail(&[irq], "blocking by STI and by MOV SS can't both be set".to_string());
+        }
+        if blocking & BLOCKING_STI != 0 && self.rflags & RFLAGS_IF == 0 {
+            self.fail(&[irq, VMCS_GUEST_RFLAGS], "blocking by STI requires IF".to_string());
+        }
+        if self.injecting(IRQ_INFO_EXT_IRQ) && blocking & (BLOCKING_STI | BLOCKING_MOV_SS) != 0 {
+            self.fail(&[irq, VMCS_CTRL_VMENTRY_IRQ_INFO],
+                "an external interrupt can't be injected while blocking by STI or MOV SS"
+                    .to_string());
+        }
+        if self.injecting(IRQ_INFO_NMI) && blocking & BLOCKING_MOV_SS != 0 {
+            self.fail(&[irq, VMCS_CTRL_VMENTRY_IRQ_INFO],
+                "an NMI can't be injected while blocking by MOV SS".to_string());
+        }
+        if blocking & BLOCKING_SMI != 0 && self.entry & VMENTRY_SMM == 0 {
+            self.fail(&[irq], "blocking by SMI must be clear outside SMM".to_string());
+        }
+        if self.pin_based & PIN_BASED_VIRTUAL_NMI != 0 && self.injecting(IRQ_INFO_NMI)
+            && blocking & BLOCKING_NMI != 0 {
+            self.fail(&[irq, VMCS_CTRL_VMENTRY_IRQ_INFO],
+                "an NMI can't be injected while blocking by NMI with virtual NMIs".to_string());
+        }
+        if activity != ACTIVITY_ACTIVE && blocking & (BLOCKING_STI | BLOCKING_MOV_SS) != 0 {
+            self.fail(&[VMCS_GUEST_ACTIVITY_STATE, irq],
+                "activity state must be active while blocking by STI or MOV SS".to_string());
+        }
+        self.injection_in_activity_state(activity);
+
+        let pending = self.get(VMCS_GUEST_DEBUG_EXC);
+        if pending & PENDING_DEBUG_RESERVED != 0 {
+            self.fail(&[VMCS_GUEST_DEBUG_EXC],
+                format!("reserved bits {:#x} are set", pending & PENDING_DEBUG_RESERVED));
+        }
+        if blocking & (BLOCKING_STI | BLOCKING_MOV_SS) != 0 || activity == ACTIVITY_HLT {
+            let single_step = self.rflags & RFLAGS_TF != 0
+                && self.get(VMCS_GUEST_IA32_DEBUGCTL) & DEBUGCTL_BTF == 0;
+            let fields = [VMCS_GUEST_DEBUG_EXC, VMCS_GUEST_RFLAGS, VMCS_GUEST_IA32_DEBUGCTL];
+            if single_step && pending & PENDING_DEBUG_BS == 0 {
+                self.fail(&fields, "BS must be set when TF is set and BTF is clear".to_string());
+            }
+            if !single_step && pending & PENDING_DEBUG_BS != 0 {
+                self.fail(&fields, "BS must be clear when TF is clear or BTF is set".to_string());
+            }
+        }
+
+        let link = self.get(VMCS_GUEST_LINK_POINTER);
+        if link != !0 && link & 0xfff != 0 {
+            self.fail(&[VMCS_GUEST_LINK_POINTER],
+                "must be 0xffffffffffffffff or 4-KByte aligned".to_string());
+        }
+    }
+
+    // Events that can be injected depend on the activity state: a HLT state allows external
+    // interrupts, NMIs, #DB, #MC and pending MTF; a shutdown state allows NMIs and #MC.
+    fn injection_in_activity_state(&mut self, activity: u64) {
+        let info = self.get(VMCS_CTRL_VMENTRY_IRQ_INFO);
+        if info & IRQ_INFO_VALID == 0 {
+            return;
+        }
+        let vector = info & 0xff;
+        let allowed = match (activity, info & 0x700) {
+            (ACTIVITY_HLT, IRQ_INFO_EXT_IRQ) | (ACTIVITY_HLT, IRQ_INFO_NMI) => true,
+            (ACTIVITY_HLT, IRQ_INFO_HW_EXC) => vector == VECTOR_DB || vector == VECTOR_MC,
+            (ACTIVITY_HLT, IRQ_INFO_OTHER) => vector == 0,
+            (ACTIVITY_SHUTDOWN, IRQ_INFO_NMI) => true,
+            (ACTIVITY_SHUTDOWN, IRQ_INFO_HW_EXC) => vector == VECTOR_MC,
+            (ACTIVITY_HLT, _) | (ACTIVITY_SHUTDOWN, _) | (ACTIVITY_WAIT_FOR_SIPI, _) => false,
+            _ => true,
+        };
+        if !allowed {
+            self.fail(&[VMCS_GUEST_ACTIVITY_STATE, VMCS_CTRL_VMENTRY_IRQ_INFO],
+                format!("event {:#x} can't be injected in activity state {}", info, activity));
+        }
+    }
+
+    // PDPTEs are checked when VM entry loads them from the VMCS: PAE paging with EPT
+    fn pdptes(&mut self) {
+        let pae_paging = self.cr0 & CR0_PG != 0 && self.get(VMCS_GUEST_CR4) & CR4_PAE != 0;
+        if !pae_paging || self.ia32e || !self.ept {
+            return;
+        }
+        for &field in &[VMCS_GUEST_PDPTE0, VMCS_GUEST_PDPTE1, VMCS_GUEST_PDPTE2,
+            VMCS_GUEST_PDPTE3] {
+            let pdpte = self.get(field);
+            if pdpte & PDPTE_P != 0 && pdpte & PDPTE_RESERVED != 0 {
+                self.fail(&[field],
+                    format!("reserved bits {:#x} are set", pdpte & PDPTE_RESERVED));
+            }
+        }
+    }
+
+    // Returns whether VM entry injects an event of a type
+    fn injecting(&self, kind: u64) -> bool {
+        let info = self.get(VMCS_CTRL_VMENTRY_IRQ_INFO);
+        info & IRQ_INFO_VALID != 0 && info & 0x700 == kind
+    }
+}
+
+fn get(state: &VmcsSnapshot, field: u32) -> u64 {
+    VmcsField::from_encoding(field).and_then(|f| state.get(f)).unwrap_or(0)
+}
+
+// Returns whether an address is canonical with 48-bit linear addresses
+fn canonical(address: u64) -> bool {
+    (((address << 16) as i64) >> 16) as u64 == address
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CODE_AR: u64 = 0xc09b;
+    const DATA_AR: u64 = 0xc093;
+
+    // Change to a valid state, with the field and message of the violation it causes
+    type Case = (fn(&mut VmcsSnapshot), u32, &'static str);
+
+    fn set(state: &mut VmcsSnapshot, field: u32, value: u64) {
+        state.insert(VmcsField::from_encoding(field).unwrap(), value);
+    }
+
+    fn or(state: &mut VmcsSnapshot, field: u32, value: u64) {
+        let old = get(state, field);
+        set(state, field, old | value);
+    }
+
+    // A flat 32-bit protected-mode guest with paging
+    fn valid_state() -> VmcsSnapshot {
+        let mut state = VmcsSnapshot::new();
+        set(&mut state, VMCS_GUEST_CR0, CR0_PE | CR0_NE | CR0_PG | 0x10);
+        set(&mut state, VMCS_GUEST_CR3, 0x1000);
+        set(&mut state, VMCS_GUEST_CR4, CR4_VMXE);
+        for &seg in &[SegReg::CS, SegReg::SS, SegReg::DS, SegReg::ES, SegReg::FS, SegReg::GS] {
+            let (selector, ar) = if seg == SegReg::CS { (0x8, CODE_AR) } else { (0x10, DATA_AR) };
+            set(&mut state, seg.selector_field(), selector);
+            set(&mut state, seg.limit_field(), 0xffff_ffff);
+            set(&mut state, seg.ar_field(), ar);
+        }
+        set(&mut state, VMCS_GUEST_TR, 0x18);
+        set(&mut state, VMCS_GUEST_TR_LIMIT, 0x67);
+        set(&mut state, VMCS_GUEST_TR_AR, 0x8b);
+        set(&mut state, VMCS_GUEST_LDTR_AR, 1 << 16);
+        set(&mut state, VMCS_GUEST_GDTR_LIMIT, 0xffff);
+        set(&mut state, VMCS_GUEST_IDTR_LIMIT, 0x7ff);
+        set(&mut state, VMCS_GUEST_RIP, 0x1000);
+        set(&mut state, VMCS_GUEST_RFLAGS, RFLAGS_FIXED);
+        set(&mut state, VMCS_GUEST_LINK_POINTER, !0);
+        state
+    }
+
+    fn long_mode(state: &mut VmcsSnapshot) {
+        or(state, VMCS_CTRL_VMENTRY_CONTROLS, VMENTRY_GUEST_IA32E | VMENTRY_LOAD_EFER);
+        set(state, VMCS_GUEST_IA32_EFER, EFER_LME | EFER_LMA);
+        or(state, VMCS_GUEST_CR4, CR4_PAE);
+        set(state, VMCS_GUEST_CS_AR, 0xa09b);
+    }
+
+    fn unrestricted(state: &mut VmcsSnapshot) {
+        or(state, VMCS_CTRL_CPU_BASED, CPU_BASED_SECONDARY_CTLS);
+        or(state, VMCS_CTRL_CPU_BASED2, CPU_BASED2_UNRESTRICTED | CPU_BASED2_EPT);
+    }
+
+    fn violations(state: &VmcsSnapshot) -> Vec<Violation> {
+        check(state, &AdjustedControls::from_snapshot(state))
+    }
+
+    #[test]
+    fn valid_states_pass() {
+        let mut states = vec![valid_state()];
+
+        let mut state = valid_state();
+        long_mode(&mut state);
+        set(&mut state, VMCS_GUEST_RIP, 0xffff_8000_0000_0000);
+        states.push(state);
+
+        // Real-address mode with an unrestricted guest
+        let mut state = valid_state();
+        unrestricted(&mut state);
+        set(&mut state, VMCS_GUEST_CR0, CR0_NE);
+        set(&mut state, VMCS_GUEST_CS_AR, 0x9b);
+        set(&mut state, VMCS_GUEST_CS_LIMIT, 0xffff);
+        states.push(state);
+
+        // Halted with an external interrupt to inject, single-stepping
+        let mut state = valid_state();
+        set(&mut state, VMCS_GUEST_ACTIVITY_STATE, ACTIVITY_HLT);
+        set(&mut state, VMCS_GUEST_RFLAGS, RFLAGS_FIXED | RFLAGS_IF | RFLAGS_TF);
+        set(&mut state, VMCS_GUEST_DEBUG_EXC, PENDING_DEBUG_BS);
+        set(&mut state, VMCS_CTRL_VMENTRY_IRQ_INFO, IRQ_INFO_VALID | IRQ_INFO_EXT_IRQ | 0x20);
+        states.push(state);
+
+        // PAE paging with EPT, and a PDPTE that isn't present
+        let mut state = valid_state();
+        unrestricted(&mut state);
+        or(&mut state, VMCS_GUEST_CR4, CR4_PAE);
+        set(&mut state, VMCS_GUEST_PDPTE0, 0x2001);
+        set(&mut state, VMCS_GUEST_PDPTE1, !0 << 1);
+        states.push(state);
+
+        for state in &states {
+            assert_eq!(violations(state), vec![]);
+        }
+    }
+
+    #[test]
+    fn each_check_is_reported() {
+        let cases: &[Case] = &[
+            // Control registers, debug registers and MSRs
+            (|s| set(s, VMCS_GUEST_CR0, CR0_NE), VMCS_GUEST_CR0, "must be set"),
+            (|s| or(s, VMCS_GUEST_CR0, 1 << 6), VMCS_GUEST_CR0, "reserved bits 0x40"),
+            (|s| {
+                unrestricted(s);
+                set(s, VMCS_GUEST_CR0, CR0_NE | CR0_PG);
+            }, VMCS_GUEST_CR0, "PG is set without PE"),
+            (|s| or(s, VMCS_GUEST_CR3, 1 << 60), VMCS_GUEST_CR3, "physical-address width"),
+            (|s| set(s, VMCS_GUEST_CR4, 0), VMCS_GUEST_CR4, "VMXE must be set"),
+            (|s| or(s, VMCS_GUEST_CR4, 1 << 15), VMCS_GUEST_CR4, "reserved bits 0x8000"),
+            (|s| {
+                set(s, VMCS_CTRL_VMENTRY_CONTROLS, VMENTRY_LOAD_DBG_CONTROLS);
+                set(s, VMCS_GUEST_DR7, 1 << 32);
+            }, VMCS_GUEST_DR7, "bits 63:32 must be clear"),
+            (|s| set(s, VMCS_GUEST_SYSENTER_ESP, 1 << 47), VMCS_GUEST_SYSENTER_ESP,
+                "must be canonical"),
+            (|s| set(s, VMCS_GUEST_SYSENTER_EIP, 1 << 63), VMCS_GUEST_SYSENTER_EIP,
+                "must be canonical"),
+            (|s| {
+                unrestricted(s);
+                long_mode(s);
+                set(s, VMCS_GUEST_CR0, CR0_PE | CR0_NE);
+            }, VMCS_GUEST_CR0, "PG must be set for an IA-32e mode guest"),
+            (|s| {
+                long_mode(s);
+                set(s, VMCS_GUEST_CR4, CR4_VMXE);
+            }, VMCS_GUEST_CR4, "PAE must be set for an IA-32e mode guest"),
+            (|s| or(s, VMCS_GUEST_CR4, CR4_PCIDE), VMCS_GUEST_CR4, "PCIDE must be clear"),
+            (|s| {
+                set(s, VMCS_CTRL_VMENTRY_CONTROLS, VMENTRY_LOAD_IA32_PERF_GLOBAL_CTRL);
+                set(s, VMCS_GUEST_IA32_PERF_GLOBAL_CTRL, 1 << 60);
+            }, VMCS_GUEST_IA32_PERF_GLOBAL_CTRL, "reserved bits"),
+            (|s| {
+                set(s, VMCS_CTRL_VMENTRY_CONTROLS, VMENTRY_LOAD_IA32_PAT);
+                set(s, VMCS_GUEST_IA32_PAT, 0x0006_0406_0002_0406);
+            }, VMCS_GUEST_IA32_PAT, "PA2 has invalid memory type 2"),
+            (|s| {
+                set(s, VMCS_CTRL_VMENTRY_CONTROLS, VMENTRY_LOAD_EFER);
+                set(s, VMCS_GUEST_IA32_EFER, 1 << 1);
+            }, VMCS_GUEST_IA32_EFER, "reserved bits 0x2"),
+            (|s| {
+                set(s, VMCS_CTRL_VMENTRY_CONTROLS, VMENTRY_LOAD_EFER);
+                set(s, VMCS_GUEST_IA32_EFER, EFER_LMA);
+            }, VMCS_GUEST_IA32_EFER, "LMA must match"),
+            (|s| {
+                set(s, VMCS_CTRL_VMENTRY_CONTROLS, VMENTRY_LOAD_EFER);
+                set(s, VMCS_GUEST_IA32_EFER, EFER_LME);
+            }, VMCS_GUEST_IA32_EFER, "LME must match"),
+
+            // Segment registers
+            (|s| set(s, VMCS_GUEST_TR, 0x1c), VMCS_GUEST_TR, "TI flag must be clear"),
+            (|s| {
+                set(s, VMCS_GUEST_LDTR, 0x4);
+                set(s, VMCS_GUEST_LDTR_AR, 0x82);
+            }, VMCS_GUEST_LDTR, "TI flag must be clear"),
+            (|s| set(s, VMCS_GUEST_SS, 0x13), VMCS_GUEST_SS, "RPLs must be equal"),
+            (|s| set(s, VMCS_GUEST_TR_BASE, 1 << 47), VMCS_GUEST_TR_BASE,
+                "base must be canonical"),
+            (|s| set(s, VMCS_GUEST_CS_BASE, 1 << 32), VMCS_GUEST_CS_BASE,
+                "bits 63:32 must be clear"),
+            (|s| set(s, VMCS_GUEST_RFLAGS, RFLAGS_FIXED | RFLAGS_VM), VMCS_GUEST_CS_BASE,
+                "selector shifted left by 4"),
+            (|s| set(s, VMCS_GUEST_RFLAGS, RFLAGS_FIXED | RFLAGS_VM), VMCS_GUEST_SS_LIMIT,
+                "limit must be 0xffff"),
+            (|s| set(s, VMCS_GUEST_RFLAGS, RFLAGS_FIXED | RFLAGS_VM), VMCS_GUEST_DS_AR,
+                "access rights must be 0xf3"),
+            (|s| set(s, VMCS_GUEST_CS_AR, DATA_AR), VMCS_GUEST_CS_AR,
+                "type 3 is not an accessed code segment"),
+            (|s| set(s, VMCS_GUEST_CS_AR, CODE_AR | 0x60), VMCS_GUEST_CS_AR,
+                "DPL must equal SS.DPL"),
+            (|s| set(s, VMCS_GUEST_CS_AR, 0xc0ff), VMCS_GUEST_CS_AR, "DPL must not exceed SS.DPL"),
+            (|s| {
+                unrestricted(s);
+                set(s, VMCS_GUEST_CS_AR, DATA_AR | 0x60);
+            }, VMCS_GUEST_CS_AR, "DPL must be 0 for a read/write data segment"),
+            (|s| {
+                long_mode(s);
+                set(s, VMCS_GUEST_CS_AR, 0xe09b);
+            }, VMCS_GUEST_CS_AR, "D/B must be clear"),
+            (|s| set(s, VMCS_GUEST_SS_AR, DATA_AR | 0x60), VMCS_GUEST_SS_AR,
+                "DPL must equal the RPL"),
+            (|s| {
+                unrestricted(s);
+                set(s, VMCS_GUEST_CR0, CR0_NE);
+                set(s, VMCS_GUEST_SS_AR, DATA_AR | 0x60);
+            }, VMCS_GUEST_SS_AR, "DPL must be 0 in real-address mode"),
+            (|s| set(s, VMCS_GUEST_SS_AR, CODE_AR), VMCS_GUEST_SS_AR,
+                "type 11 is not a read/write data segment"),
+            (|s| set(s, VMCS_GUEST_DS_AR, 0xc092), VMCS_GUEST_DS_AR, "type must be accessed"),
+            (|s| set(s, VMCS_GUEST_ES_AR, 0xc099), VMCS_GUEST_ES_AR,
+                "a code segment must be readable"),
+            (|s| set(s, VMCS_GUEST_FS, 0x13), VMCS_GUEST_FS_AR,
+                "DPL must not be less than the RPL"),
+            (|s| set(s, VMCS_GUEST_GS_AR, 0xc083), VMCS_GUEST_GS_AR, "S must be 1"),
+            (|s| set(s, VMCS_GUEST_DS_AR, 0xc013), VMCS_GUEST_DS_AR, "P must be set"),
+            (|s| set(s, VMCS_GUEST_DS_AR, DATA_AR | 0x100), VMCS_GUEST_DS_AR,
+                "reserved bits 0x100"),
+            (|s| set(s, VMCS_GUEST_DS_LIMIT, 0xffff_f000), VMCS_GUEST_DS_LIMIT,
+                "G must be clear"),
+            (|s| set(s, VMCS_GUEST_DS_AR, 0x4093), VMCS_GUEST_DS_LIMIT, "G must be set"),
+            (|s| set(s, VMCS_GUEST_TR_AR, 0x89), VMCS_GUEST_TR_AR, "type 9 is not a busy TSS"),
+            (|s| set(s, VMCS_GUEST_TR_AR, 0x1008b), VMCS_GUEST_TR_AR, "TR must be usable"),
+            (|s| {
+                set(s, VMCS_GUEST_RFLAGS, RFLAGS_FIXED | RFLAGS_VM);
+                set(s, VMCS_GUEST_TR_AR, 0x1008b);
+            }, VMCS_GUEST_TR_AR, "TR must be usable"),
+            (|s| set(s, VMCS_GUEST_LDTR_AR, 0x83), VMCS_GUEST_LDTR_AR, "type 3 is not an LDT"),
+
+            // Descriptor tables, RIP and RFLAGS
+            (|s| set(s, VMCS_GUEST_GDTR_BASE, 1 << 47), VMCS_GUEST_GDTR_BASE,
+                "base must be canonical"),
+            (|s| set(s, VMCS_GUEST_IDTR_LIMIT, 0x10000), VMCS_GUEST_IDTR_LIMIT,
+                "bits 31:16 must be clear"),
+            (|s| set(s, VMCS_GUEST_RIP, 1 << 32), VMCS_GUEST_RIP, "outside 64-bit mode"),
+            (|s| {
+                long_mode(s);
+                set(s, VMCS_GUEST_RIP, 1 << 47);
+            }, VMCS_GUEST_RIP, "RIP must be canonical"),
+            (|s| or(s, VMCS_GUEST_RFLAGS, 1 << 3), VMCS_GUEST_RFLAGS, "reserved bits 0x8"),
+            (|s| set(s, VMCS_GUEST_RFLAGS, 0), VMCS_GUEST_RFLAGS, "bit 1 must be set"),
+            (|s| {
+                long_mode(s);
+                or(s, VMCS_GUEST_RFLAGS, RFLAGS_VM);
+            }, VMCS_GUEST_RFLAGS, "VM must be clear"),
+            (|s| set(s, VMCS_CTRL_VMENTRY_IRQ_INFO, IRQ_INFO_VALID | 0x20), VMCS_GUEST_RFLAGS,
+                "IF must be set"),
+
+            // Non-register state
+            (|s| set(s, VMCS_GUEST_ACTIVITY_STATE, 4), VMCS_GUEST_ACTIVITY_STATE,
+                "invalid activity state 4"),
+            (|s| {
+                set(s, VMCS_GUEST_ACTIVITY_STATE, ACTIVITY_HLT);
+                set(s, VMCS_GUEST_SS_AR, DATA_AR | 0x60);
+            }, VMCS_GUEST_ACTIVITY_STATE, "HLT state requires SS.DPL to be 0"),
+            (|s| set(s, VMCS_GUEST_IGNORE_IRQ, 1 << 5), VMCS_GUEST_IGNORE_IRQ,
+                "reserved bits 0x20"),
+            (|s| {
+                set(s, VMCS_GUEST_RFLAGS, RFLAGS_FIXED | RFLAGS_IF);
+                set(s, VMCS_GUEST_IGNORE_IRQ, BLOCKING_STI | BLOCKING_MOV_SS);
+            }, VMCS_GUEST_IGNORE_IRQ, "can't both be set"),
+            (|s| set(s, VMCS_GUEST_IGNORE_IRQ, BLOCKING_STI), VMCS_GUEST_IGNORE_IRQ,
+                "blocking by STI requires IF"),
+            (|s| {
+                set(s, VMCS_GUEST_RFLAGS, RFLAGS_FIXED | RFLAGS_IF);
+                set(s, VMCS_GUEST_IGNORE_IRQ, BLOCKING_STI);
+                set(s, VMCS_CTRL_VMENTRY_IRQ_INFO, IRQ_INFO_VALID | 0x20);
+            }, VMCS_CTRL_VMENTRY_IRQ_INFO, "an external interrupt can't be injected"),
+            (|s| {
+                set(s, VMCS_GUEST_IGNORE_IRQ, BLOCKING_MOV_SS);
+                set(s, VMCS_CTRL_VMENTRY_IRQ_INFO, IRQ_INFO_VALID | IRQ_INFO_NMI | 2);
+            }, VMCS_CTRL_VMENTRY_IRQ_INFO, "an NMI can't be injected while blocking by MOV SS"),
+            (|s| set(s, VMCS_GUEST_IGNORE_IRQ, BLOCKING_SMI), VMCS_GUEST_IGNORE_IRQ,
+                "blocking by SMI must be clear outside SMM"),
+            (|s| {
+                set(s, VMCS_CTRL_PIN_BASED, PIN_BASED_NMI | PIN_BASED_VIRTUAL_NMI);
+                set(s, VMCS_GUEST_IGNORE_IRQ, BLOCKING_NMI);
+                set(s, VMCS_CTRL_VMENTRY_IRQ_INFO, IRQ_INFO_VALID | IRQ_INFO_NMI | 2);
+            }, VMCS_CTRL_VMENTRY_IRQ_INFO, "while blocking by NMI with virtual NMIs"),
+            (|s| {
+                set(s, VMCS_GUEST_ACTIVITY_STATE, ACTIVITY_HLT);
+                set(s, VMCS_GUEST_IGNORE_IRQ, BLOCKING_MOV_SS);
+            }, VMCS_GUEST_ACTIVITY_STATE, "activity state must be active"),
+            (|s| {
+                set(s, VMCS_GUEST_ACTIVITY_STATE, ACTIVITY_HLT);
+                set(s, VMCS_CTRL_VMENTRY_IRQ_INFO, IRQ_INFO_VALID | IRQ_INFO_HW_EXC | 13);
+            }, VMCS_CTRL_VMENTRY_IRQ_INFO, "can't be injected in activity state 1"),
+            (|s| {
+                set(s, VMCS_GUEST_ACTIVITY_STATE, ACTIVITY_SHUTDOWN);
+                set(s, VMCS_CTRL_VMENTRY_IRQ_INFO, IRQ_INFO_VALID | IRQ_INFO_HW_EXC | VECTOR_DB);
+            }, VMCS_CTRL_VMENTRY_IRQ_INFO, "can't be injected in activity state 2"),
+            (|s| {
+                set(s, VMCS_GUEST_ACTIVITY_STATE, ACTIVITY_WAIT_FOR_SIPI);
+                set(s, VMCS_CTRL_VMENTRY_IRQ_INFO, IRQ_INFO_VALID | IRQ_INFO_NMI | 2);
+            }, VMCS_CTRL_VMENTRY_IRQ_INFO, "can't be injected in activity state 3"),
+            (|s| set(s, VMCS_GUEST_DEBUG_EXC, 1 << 15), VMCS_GUEST_DEBUG_EXC,
+                "reserved bits 0x8000"),
+            (|s| {
+                set(s, VMCS_GUEST_RFLAGS, RFLAGS_FIXED | RFLAGS_TF);
+                set(s, VMCS_GUEST_IGNORE_IRQ, BLOCKING_MOV_SS);
+            }, VMCS_GUEST_DEBUG_EXC, "BS must be set"),
+            (|s| {
+                set(s, VMCS_GUEST_IGNORE_IRQ, BLOCKING_MOV_SS);
+                set(s, VMCS_GUEST_DEBUG_EXC, PENDING_DEBUG_BS);
+            }, VMCS_GUEST_DEBUG_EXC, "BS must be clear"),
+            (|s| {
+                set(s, VMCS_GUEST_ACTIVITY_STATE, ACTIVITY_HLT);
+                set(s, VMCS_GUEST_RFLAGS, RFLAGS_FIXED | RFLAGS_TF);
+                set(s, VMCS_GUEST_IA32_DEBUGCTL, DEBUGCTL_BTF);
+                set(s, VMCS_GUEST_DEBUG_EXC, PENDING_DEBUG_BS);
+            }, VMCS_GUEST_IA32_DEBUGCTL, "BS must be clear"),
+            (|s| set(s, VMCS_GUEST_LINK_POINTER, 0x1008), VMCS_GUEST_LINK_POINTER,
+                "4-KByte aligned"),
+
+            // PDPTEs
+            (|s| {
+                unrestricted(s);
+                or(s, VMCS_GUEST_CR4, CR4_PAE);
+                set(s, VMCS_GUEST_PDPTE2, 0x2003);
+            }, VMCS_GUEST_PDPTE2, "reserved bits 0x2"),
+        ];
+
+        for (i, &(change, field, message)) in cases.iter().enumerate() {
+            let mut state = valid_state();
+            change(&mut state);
+            let field = VmcsField::from_encoding(field).unwrap();
+            let found = violations(&state).iter()
+                .any(|v| v.fields().contains(&field) && v.message().contains(message));
+            assert!(found, "case {}: no `{}` violation on {}: {:?}", i, message,
+                field.const_name(), violations(&state));
+        }
+    }
+}
@@ -33,8 +33,8 @@ To use this library, you need
 * OS X Yosemite (10.10), or newer
 
 * an Intel processor with the VT-x feature set that includes Extended Page
-Tables (EPT) and Unrestricted Mode. To verify this, run and expect the following
-in your Terminal:
+  Tables (EPT) and Unrestricted Mode. To verify this, run and expect the following
+  in your Terminal:
 
   ```shell
   $ sysctl kern.hv_support
@@ -45,154 +45,374 @@ in your Terminal:
 extern crate libc;
 extern crate core;
 
-#[allow(non_camel_case_types)]
+#[macro_use]
+mod macros;
+
+#[allow(non_camel_case_types, deprecated, missing_abi, clippy::identity_op)]
 pub mod ffi;
+pub mod caps;
+#[allow(deprecated)]
 pub mod consts;
+pub mod controls;
+pub mod backend;
+pub mod manager;
+pub mod memory;
+pub mod qualification;
+pub mod regs;
+pub mod runner;
+pub mod timer;
+pub mod vmcs;
+mod error;
+pub mod event;
+pub mod exit;
+pub mod guest_state;
 
 use self::core::fmt;
-use libc::*;
+use std::cell::RefCell;
+use std::marker::PhantomData;
+use std::mem;
+use std::ops::Deref;
+use std::time::Duration;
+use std::sync::{Mutex, MutexGuard};
 
 use self::ffi::*;
+use self::backend::{Backend, Platform};
+use self::consts::vmx_cap::{PIN_BASED_PREEMPTION_TIMER, VMEXIT_SAVE_VMX_TIMER};
+use self::error::check;
+
+pub use self::caps::CapabilityReport;
+pub use self::controls::{AdjustedControls, ControlCap, ControlField, EntryControls, ExitControls,
+    PinBasedControls, PrimaryProcControls, SecondaryProcControls, VmxCaps, VmxControls};
+pub use self::error::{EntryFailure, ErrorDetail, ErrorKind, GuestStateCheck, HvError,
+    VmInstructionError};
+pub use self::event::{Event, EventQueue, Injection};
+pub use self::exit::{ExitFields, InterruptionInfo, InterruptionType, VmExit};
+pub use self::guest_state::Violation;
+pub use self::manager::VcpuManager;
+pub use self::memory::{GuestAddressSpace, GuestMemory, GuestRegion, MemPerm, SavedPerms,
+    PAGE_SIZE};
+pub use self::qualification::{CrAccessQualification, CrAccessType, DrAccessQualification,
+    DrAccessType, EptViolationQualification, IoDirection, IoQualification, TaskSwitchQualification,
+    TaskSwitchSource};
+pub use self::regs::{ControlRegs, DebugRegs, DescriptorTables, SegReg, Segment, SegmentRegs,
+    StandardRegs};
+pub use self::runner::{ExitAction, ExitHandler, RunOutcome, RunnerHandle, VcpuRunner};
+pub use self::timer::PreemptionTimer;
+pub use self::vmcs::{VmcsChange, VmcsField, VmcsFieldType, VmcsSnapshot, VmcsWidth};
+
+/// Virtual machine of the current Mach task
+///
+/// Creating a `VirtualMachine` creates the VM instance of the task, and dropping it unmaps every
+/// region still mapped and destroys the instance. vCPUs and mappings borrow the
+/// `VirtualMachine`, so they can't outlive it.
+pub struct VirtualMachine {
+    // Every mapped region, which keeps its memory alive for as long as the guest can access it
+    space: Mutex<GuestAddressSpace>,
+}
+
+impl VirtualMachine {
+    /// Creates the VM instance for the current Mach task
+    pub fn new() -> Result<VirtualMachine, HvError> {
+        check(Platform::vm_create(HV_VM_DEFAULT), "hv_vm_create")?;
+
+        Ok(VirtualMachine {
+            space: Mutex::new(GuestAddressSpace::new())
+        })
+    }
+
+    fn space(&self) -> MutexGuard<'_, GuestAddressSpace> {
+        self.space.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Returns the guest physical address space of the virtual machine
+    ///
+    /// The address space is locked until the returned guard is dropped, so other threads can't
+    /// map, unmap or protect memory in the meantime.
+    pub fn address_space(&self) -> AddressSpaceGuard<'_> {
+        AddressSpaceGuard(self.space())
+    }
+
+    /// Creates a vCPU instance for the current thread
+    pub fn create_vcpu(&self) -> Result<vCPU<'_>, HvError> {
+        let mut vcpuid: hv_vcpuid_t = 0;
+
+        check(Platform::vcpu_create(&mut vcpuid, HV_VCPU_DEFAULT), "hv_vcpu_create")?;
+
+        Ok(vCPU {
+            id: vcpuid as u32,
+            events: RefCell::new(EventQueue::new()),
+            marker: PhantomData
+        })
+    }
+
+    /// Maps guest memory into the guest physical address space of the virtual machine
+    ///
+    /// The virtual machine keeps the memory alive until the returned `Mapping` is dropped or
+    /// unmapped. `gpa` must be a multiple of `PAGE_SIZE`, and the region must not overlap one
+    /// already mapped.
+    pub fn map_mem(&self, mem: GuestMemory, gpa: u64, mem_perm: MemPerm)
+        -> Result<Mapping<'_>, HvError> {
+        let mut space = self.space();
+
+        if !gpa.is_multiple_of(PAGE_SIZE as u64) || space.check_free(gpa, mem.size()).is_err() {
+            return Err(HvError::new(HV_BAD_ARGUMENT, "hv_vm_map"));
+        }
+
+        check(unsafe {
+            Platform::vm_map(mem.as_ptr() as hv_uvaddr_t, gpa as hv_gpaddr_t, mem.size(),
+                mem_perm.bits())
+        }, "hv_vm_map")?;
+
+        let size = mem.size();
+        space.insert(gpa, mem.clone(), mem_perm)
+            .map_err(|_| HvError::new(HV_BAD_ARGUMENT, "hv_vm_map"))?;
+
+        Ok(Mapping {
+            vm: self,
+            gpa,
+            size,
+            mem,
+            mapped: true
+        })
+    }
+
+    /// Unmaps a range of the guest physical address space of the virtual machine
+    ///
+    /// The range may cover parts of regions, which are split, or several regions, but must not
+    /// contain anything unmapped.
+    pub fn unmap_mem(&self, gpa: u64, size: usize) -> Result<(), HvError> {
+        let mut space = self.space();
+        let pieces = space.pieces(gpa, size)
+            .map_err(|_| HvError::new(HV_BAD_ARGUMENT, "hv_vm_unmap"))?;
+
+        self.unmap_pieces(&mut space, &pieces)
+    }
+
+    // Unmaps pieces of regions one at a time, forgetting each once it is unmapped
+    fn unmap_pieces(&self, space: &mut GuestAddressSpace, pieces: &[(u64, usize)])
+        -> Result<(), HvError> {
+        for &(gpa, size) in pieces {
+            check(Platform::vm_unmap(gpa as hv_gpaddr_t, size), "hv_vm_unmap")?;
+            let _ = space.remove(gpa, size);
+        }
+
+        Ok(())
+    }
+
+    /// Modifies the permissions of a range of the guest physical address space of the virtual
+    /// machine, returning the permissions it replaced
+    ///
+    /// The range may cover parts of regions, which are split, or several regions, but must not
+    /// contain anything unmapped. Neighbouring parts of a region that end up with the same
+    /// permissions are merged back. If the change fails part way, the parts already changed
+    /// are put back as they were.
+    pub fn protect_mem(&self, gpa: u64, size: usize, mem_perm: MemPerm)
+        -> Result<SavedPerms, HvError> {
+        let mut space = self.space();
+        let saved = space.save_perms(gpa, size)
+            .map_err(|_| HvError::new(HV_BAD_ARGUMENT, "hv_vm_protect"))?;
+
+        for (i, (gpa, size, _)) in saved.iter().enumerate() {
+            if let Err(e) = Self::protect_piece(&mut space, gpa, size, mem_perm) {
+                for (gpa, size, perm) in saved.iter().take(i) {
+                    let _ = Self::protect_piece(&mut space, gpa, size, perm);
+                }
+                return Err(e);
+            }
+        }
+
+        Ok(saved)
+    }
+
+    /// Restores permissions saved by `protect_mem`
+    ///
+    /// Every saved range must still be mapped.
+    pub fn restore_perms(&self, saved: &SavedPerms) -> Result<(), HvError> {
+        let mut space = self.space();
+        for (gpa, size, _) in saved.iter() {
+            space.pieces(gpa, size).map_err(|_| HvError::new(HV_BAD_ARGUMENT, "hv_vm_protect"))?;
+        }
+
+        for (gpa, size, perm) in saved.iter() {
+            Self::protect_piece(&mut space, gpa, size, perm)?;
+        }
+
+        Ok(())
+    }
+
+    // Modifies the permissions of a mapped range
+    fn protect_piece(space: &mut GuestAddressSpace, gpa: u64, size: usize, mem_perm: MemPerm)
+        -> Result<(), HvError> {
+        check(
+            Platform::vm_protect(gpa as hv_gpaddr_t, size, mem_perm.bits()),
+            "hv_vm_protect"
+        )?;
+        let _ = space.protect(gpa, size, mem_perm);
+
+        Ok(())
+    }
 
-/// Error returned after every call
-pub enum Error {
-    /// Success
-    Success,
-    /// Error
-    Error,
-    /// Busy
-    Busy,
-    /// Bad argument
-    BadArg,
-    /// No resources
-    NoRes,
-    /// No device
-    NoDev,
-    /// Unsupported
-    Unsupp
+    /// Synchronizes the guest Timestamp-Counters (TSC) across all vCPUs
+    ///
+    /// * `tsc` Guest TSC value
+    pub fn sync_tsc(&self, tsc: u64) -> Result<(), HvError> {
+        check(Platform::vm_sync_tsc(tsc), "hv_vm_sync_tsc")
+    }
+
+    // Unmaps whatever part of a range is still mapped to a particular memory
+    fn release(&self, gpa: u64, size: usize, mem: &GuestMemory) -> Result<(), HvError> {
+        let mut space = self.space();
+        let pieces = space.pieces_of(gpa, size, mem);
+
+        self.unmap_pieces(&mut space, &pieces)
+    }
 }
 
-impl fmt::Debug for Error {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match *self {
-            Error::Success => write!(f, "Success"),
-            Error::Error   => write!(f, "Error"),
-            Error::Busy    => write!(f, "Busy"),
-            Error::BadArg  => write!(f, "Bad argument"),
-            Error::NoRes   => write!(f, "No resources"),
-            Error::NoDev   => write!(f, "No device"),
-            Error::Unsupp  => write!(f, "Unsupported"),
+impl Drop for VirtualMachine {
+    fn drop(&mut self) {
+        let regions = self.space().take();
+        let mut leaked = Vec::new();
+        for region in regions {
+            if Platform::vm_unmap(region.gpa() as hv_gpaddr_t, region.size()) != HV_SUCCESS {
+                leaked.push(region);
+            }
+        }
+        if Platform::vm_destroy() != HV_SUCCESS {
+            // The guest may still be able to reach memory that failed to unmap
+            mem::forget(leaked);
         }
     }
 }
 
-// Returns an Error for a hv_return_t
-fn match_error_code(code: hv_return_t) -> Error {
-    match code {
-        HV_SUCCESS      => Error::Success,
-        HV_BUSY         => Error::Busy,
-        HV_BAD_ARGUMENT => Error::BadArg,
-        HV_NO_RESOURCES => Error::NoRes,
-        HV_NO_DEVICE    => Error::NoDev,
-        HV_UNSUPPORTED  => Error::Unsupp,
-        _               => Error::Error
+impl fmt::Debug for VirtualMachine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("VirtualMachine")
+            .field("space", &*self.space())
+            .finish()
     }
 }
 
-/// Creates a VM instance for the current Mach task
-pub fn create_vm() -> Error {
-    match_error_code(unsafe {
-        hv_vm_create(HV_VM_DEFAULT)
-    })
+/// Locked guest physical address space of a `VirtualMachine`
+pub struct AddressSpaceGuard<'a>(MutexGuard<'a, GuestAddressSpace>);
+
+impl<'a> Deref for AddressSpaceGuard<'a> {
+    type Target = GuestAddressSpace;
+
+    fn deref(&self) -> &GuestAddressSpace {
+        &self.0
+    }
 }
 
-/// Destroys the VM instance associated with the current Mach task
-pub fn destroy_vm() -> Error {
-    match_error_code(unsafe {
-        hv_vm_destroy()
-    })
+impl<'a> fmt::Debug for AddressSpaceGuard<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
 }
 
-/// Guest physical memory region permissions
-pub enum MemPerm {
-    /// Read
-    Read,
-    /// Write (implies read)
-    Write,
-    /// Execute
-    Exec,
-    /// Execute and write (implies read)
-    ExecAndWrite,
-    /// Execute and read
-    ExecAndRead
+/// Region of host memory mapped into the guest physical address space of a
+/// `VirtualMachine`
+///
+/// Whatever part of the region is still mapped when the `Mapping` is dropped is unmapped.
+pub struct Mapping<'a> {
+    vm: &'a VirtualMachine,
+    gpa: u64,
+    size: usize,
+    mem: GuestMemory,
+    mapped: bool
 }
 
-#[allow(non_snake_case)]
-#[inline(always)]
-fn match_MemPerm(mem_perm: &MemPerm) -> uint64_t {
-    match mem_perm {
-        &MemPerm::Read         => HV_MEMORY_READ,
-        &MemPerm::Write        => HV_MEMORY_WRITE | HV_MEMORY_READ,
-        &MemPerm::Exec         => HV_MEMORY_EXEC,
-        &MemPerm::ExecAndWrite => HV_MEMORY_EXEC | HV_MEMORY_WRITE | HV_MEMORY_READ,
-        &MemPerm::ExecAndRead  => HV_MEMORY_EXEC | HV_MEMORY_READ,
+impl<'a> Mapping<'a> {
+    /// Returns the guest physical address the region was mapped at
+    pub fn gpa(&self) -> u64 {
+        self.gpa
+    }
+
+    /// Returns the size of the region in bytes
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns the memory of the region
+    pub fn memory(&self) -> &GuestMemory {
+        &self.mem
+    }
+
+    /// Modifies the permissions of the region, all of which must still be mapped, returning the
+    /// permissions it replaced
+    pub fn protect(&self, mem_perm: MemPerm) -> Result<SavedPerms, HvError> {
+        self.vm.protect_mem(self.gpa, self.size, mem_perm)
+    }
+
+    /// Unmaps what is left of the region, returning the error that dropping the `Mapping` would
+    /// ignore
+    ///
+    /// Memory that fails to unmap stays with the virtual machine until it is dropped.
+    pub fn unmap(mut self) -> Result<(), HvError> {
+        self.mapped = false;
+        self.vm.release(self.gpa, self.size, &self.mem)
     }
 }
 
-/// Maps a region in the virtual address space of the current Mach task into the guest physical
-/// address space of the virutal machine
-pub fn map_mem(mem: &[u8], gpa: u64, mem_perm: &MemPerm) -> Error {
-    match_error_code(unsafe {
-        hv_vm_map(
-            mem.as_ptr() as *const c_void, gpa as hv_gpaddr_t, mem.len() as size_t,
-            match_MemPerm(mem_perm)
-        )
-    })
+impl<'a> Drop for Mapping<'a> {
+    fn drop(&mut self) {
+        if self.mapped {
+            let _ = self.vm.release(self.gpa, self.size, &self.mem);
+        }
+    }
 }
 
-/// Unmaps a region in the guest physical address space of the virutal machine
-pub fn unmap_mem(gpa: u64, size: usize) -> Error {
-    match_error_code(unsafe {
-        hv_vm_unmap(gpa as hv_gpaddr_t, size as size_t)
-    })
+impl<'a> fmt::Debug for Mapping<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Mapping {{ gpa: {:#x}, size: {:#x} }}", self.gpa, self.size)
+    }
 }
 
-/// Modifies the permissions of a region in the guest physical address space of the virtual
-/// machine
-pub fn protect_mem(gpa: u64, size: usize, mem_perm: &MemPerm) -> Error {
-    match_error_code(unsafe {
-        hv_vm_protect(gpa as hv_gpaddr_t, size as size_t, match_MemPerm(mem_perm))
-    })
+/// Forces an immediate VMEXIT of a set of vCPUs
+///
+/// * `kickers` Handles of the vCPUs
+pub fn interrupt_vcpus(kickers: &[VcpuKicker]) -> Result<(), HvError> {
+    let vcpu_ids: Vec<hv_vcpuid_t> = kickers.iter().map(|k| k.id).collect();
+
+    check(Platform::vcpu_interrupt(&vcpu_ids), "hv_vcpu_interrupt")
 }
 
-/// Synchronizes the guest Timestamp-Counters (TSC) across all vCPUs
+/// Virtual CPU of a `VirtualMachine`
 ///
-/// * `tsc` Guest TSC value
-pub fn sync_tsc(tsc: u64) -> Error {
-    match_error_code(unsafe {
-        hv_vm_sync_tsc(tsc as uint64_t)
-    })
+/// A vCPU can only be used on the thread that created it, so `vCPU` is neither `Send` nor
+/// `Sync`. Use a `VcpuKicker` to interrupt it from other threads. The vCPU instance is
+/// destroyed when the `vCPU` is dropped.
+#[allow(non_camel_case_types)]
+pub struct vCPU<'a> {
+    id: u32,
+    events: RefCell<EventQueue>,
+    // Borrows the VM, and opts out of Send and Sync
+    marker: PhantomData<(&'a VirtualMachine, *const ())>
 }
 
-/// Forces an immediate VMEXIT of a set of vCPUs
+/// Handle that forces VMEXITs of a vCPU from any thread
 ///
-/// * `vcpu_ids` Array of vCPU IDs
-pub fn interrupt_vcpus(vcpu_ids: &[u32]) -> Error {
-    match_error_code(unsafe {
-        hv_vcpu_interrupt(vcpu_ids.as_ptr(), vcpu_ids.len() as c_uint)
-    })
+/// Interrupting a vCPU that has been destroyed fails with `HV_BAD_ARGUMENT`.
+#[derive(Clone, Debug)]
+pub struct VcpuKicker {
+    id: u32
 }
 
-/// Virtual CPU
-#[allow(non_camel_case_types)]
-pub struct vCPU {
-    /// Virtual CPU ID
-    pub id: u32
+impl VcpuKicker {
+    /// Returns the ID of the vCPU
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Forces an immediate VMEXIT of the vCPU
+    pub fn interrupt(&self) -> Result<(), HvError> {
+        check(Platform::vcpu_interrupt(&[self.id]), "hv_vcpu_interrupt")
+            .map_err(|e| e.with_vcpu(self.id))
+    }
 }
 
 /// x86 architectural register
 #[allow(non_camel_case_types)]
-#[derive(Clone)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 #[repr(C)]
 pub enum x86Reg {
 	RIP,
@@ -249,179 +469,401 @@ pub enum x86Reg {
 	REGISTERS_MAX,
 }
 
-impl vCPU {
+impl<'a> vCPU<'a> {
 
-    /// Creates a vCPU instance for the current thread
-    pub fn new() -> Result<vCPU, Error> {
-        let mut vcpuid: hv_vcpuid_t = 0;
+    // Turns the return code of a call on this vCPU into a Result
+    fn check(&self, code: hv_return_t, op: &'static str) -> Result<(), HvError> {
+        check(code, op).map_err(|e| e.with_vcpu(self.id))
+    }
 
-        let error = match_error_code(unsafe {
-            hv_vcpu_create(&mut vcpuid, HV_VCPU_DEFAULT)
-        });
+    // Like check, but attaches the VM-instruction error of the vCPU to a failure
+    fn check_instruction(&self, code: hv_return_t, op: &'static str) -> Result<(), HvError> {
+        self.check(code, op).map_err(|e| {
+            match self.read_field(VmcsField::RoInstrError) {
+                Ok(error) => match VmInstructionError::from_code(error as u32) {
+                    Some(error) => e.with_detail(ErrorDetail::Instruction(error)),
+                    None => e,
+                },
+                Err(_) => e,
+            }
+        })
+    }
 
-        match error {
-            Error::Success => Ok(vCPU {
-                id: vcpuid as u32
-            }),
-            _ => Err(error)
+    /// Returns the ID of the vCPU
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Returns a handle that can interrupt the vCPU from other threads
+    pub fn kicker(&self) -> VcpuKicker {
+        VcpuKicker {
+            id: self.id
         }
     }
 
-    /// Destroys the vCPU instance associated with the current thread
-    pub fn destroy(&self) -> Error {
-        match_error_code(unsafe {
-            hv_vcpu_destroy(self.id as hv_vcpuid_t)
-        })
+    /// Destroys the vCPU instance, returning the error that dropping the `vCPU` would ignore
+    pub fn destroy(self) -> Result<(), HvError> {
+        let result = self.check(Platform::vcpu_destroy(self.id as hv_vcpuid_t), "hv_vcpu_destroy");
+        mem::forget(self);
+        result
     }
 
     /// Executes the vCPU
-    pub fn run(&self) -> Error {
-        match_error_code(unsafe {
-            hv_vcpu_run(self.id as hv_vcpuid_t)
+    ///
+    /// Injects the next queued event the guest can take first. A failed VM entry is returned as
+    /// an error, with the VM-instruction error or the exit reason and qualification of the
+    /// failure decoded into its detail.
+    pub fn run(&self) -> Result<(), HvError> {
+        self.events.borrow_mut().deliver(self)?;
+        self.check_instruction(Platform::vcpu_run(self.id as hv_vcpuid_t), "hv_vcpu_run")?;
+
+        let fields = ExitFields {
+            reason: self.read_field(VmcsField::RoExitReason)? as u32,
+            ..Default::default()
+        };
+        if !fields.entry_failed() {
+            return self.events.borrow_mut().record_exit(self, fields.basic_reason());
+        }
+        let fields = ExitFields {
+            qualification: self.read_field(VmcsField::RoExitQualific)?,
+            ..fields
+        };
+
+        let error = HvError::new(HV_ERROR, "hv_vcpu_run").with_vcpu(self.id);
+        Err(match EntryFailure::from_exit(fields.basic_reason(), fields.qualification) {
+            Some(failure) => error.with_detail(ErrorDetail::Entry(failure)),
+            None => error,
         })
     }
 
+    /// Executes the vCPU for at most `budget`, as timed by the VMX-preemption timer
+    ///
+    /// Activates the timer and has the VM exit save its value for the duration of the run, then
+    /// restores the controls. Returns the part of `budget` left when the VM exit happened, which
+    /// is zero if the timer expired. A budget longer than the timer can count is cut short, and
+    /// the part that didn't fit is added to the time left.
+    pub fn run_for(&self, preemption_timer: &PreemptionTimer, budget: Duration)
+        -> Result<Duration, HvError> {
+        let ticks = preemption_timer.ticks(budget);
+        let excess = if ticks == timer::MAX_TICKS {
+            budget - preemption_timer.duration(ticks)
+        } else {
+            Duration::from_secs(0)
+        };
+
+        let pin_based = self.read_field(VmcsField::CtrlPinBased)?;
+        let exit = self.read_field(VmcsField::CtrlVmexitControls)?;
+        self.write_field(VmcsField::CtrlPinBased, pin_based | PIN_BASED_PREEMPTION_TIMER)?;
+        self.write_field(VmcsField::CtrlVmexitControls, exit | VMEXIT_SAVE_VMX_TIMER)?;
+        self.write_field(VmcsField::GuestVmxTimerValue, ticks)?;
+
+        // The controls are restored whatever the run returns, but its error comes first
+        let result = self.run();
+        let restored_pin_based = self.write_field(VmcsField::CtrlPinBased, pin_based);
+        let restored_exit = self.write_field(VmcsField::CtrlVmexitControls, exit);
+        result?;
+        restored_pin_based?;
+        restored_exit?;
+
+        Ok(preemption_timer.duration(self.read_field(VmcsField::GuestVmxTimerValue)?) + excess)
+    }
+
+    /// Queues an event to inject into the guest
+    ///
+    /// `run` injects the event once the guest can take it. An exception may be combined with
+    /// one being re-injected; see the `event` module.
+    pub fn inject(&self, event: Event) -> Injection {
+        self.events.borrow_mut().push(event)
+    }
+
+    /// Returns the event `run` will re-inject because the last VM exit interrupted its delivery
+    pub fn reinjection(&self) -> Option<Event> {
+        self.events.borrow().reinjection()
+    }
+
+    /// Stops `run` from re-injecting an event whose delivery the last VM exit interrupted, and
+    /// returns the event
+    pub fn cancel_reinjection(&self) -> Option<Event> {
+        let mut events = self.events.borrow_mut();
+        let event = events.reinjection();
+        events.set_reinjection(None);
+        event
+    }
+
+    /// Returns the events waiting to be injected
+    pub fn pending_events(&self) -> Vec<Event> {
+        self.events.borrow().iter().cloned().collect()
+    }
+
+    /// Drops the events waiting to be injected
+    pub fn clear_events(&self) {
+        self.events.borrow_mut().clear();
+    }
+
+    /// Returns the reason for the last VM exit of the vCPU, decoded from the exit information
+    /// fields of the VMCS
+    pub fn exit_info(&self) -> Result<VmExit, HvError> {
+        Ok(VmExit::decode(&ExitFields::read(self)?))
+    }
+
     /// Forces an immediate VMEXIT of the vCPU
-    pub fn interrupt(&self) -> Error {
-        match_error_code(unsafe {
-            hv_vcpu_interrupt(&(self.id), 1 as c_uint)
-        })
+    pub fn interrupt(&self) -> Result<(), HvError> {
+        self.check(Platform::vcpu_interrupt(&[self.id]), "hv_vcpu_interrupt")
     }
 
     /// Returns the cumulative execution time of the vCPU in nanoseconds
-    pub fn exec_time(&self) -> Result<u64, Error> {
-        let mut exec_time: uint64_t = 0;
+    pub fn exec_time(&self) -> Result<u64, HvError> {
+        let mut exec_time: u64 = 0;
 
-        let error = match_error_code(unsafe {
-            hv_vcpu_get_exec_time(self.id, &mut exec_time)
-        });
+        self.check(Platform::vcpu_get_exec_time(self.id, &mut exec_time), "hv_vcpu_get_exec_time")?;
 
-        match error {
-            Error::Success => Ok(exec_time as u64),
-            _ => Err(error)
-        }
+        Ok(exec_time)
     }
 
     /// Forces flushing of cached vCPU state
-    pub fn flush(&self) -> Error {
-        match_error_code(unsafe {
-            hv_vcpu_flush(self.id as hv_vcpuid_t)
-        })
+    pub fn flush(&self) -> Result<(), HvError> {
+        self.check(Platform::vcpu_flush(self.id as hv_vcpuid_t), "hv_vcpu_flush")
     }
 
     /// Invalidates the translation lookaside buffer (TLB) of the vCPU
-    pub fn invalidate_tlb(&self) -> Error {
-        match_error_code(unsafe {
-            hv_vcpu_invalidate_tlb(self.id as hv_vcpuid_t)
-        })
+    pub fn invalidate_tlb(&self) -> Result<(), HvError> {
+        self.check(Platform::vcpu_invalidate_tlb(self.id as hv_vcpuid_t), "hv_vcpu_invalidate_tlb")
     }
 
     /// Enables an MSR to be used natively by the VM
-    pub fn enable_native_msr(&self, msr: u32, enable: bool) -> Error {
-        match_error_code(unsafe {
-            hv_vcpu_enable_native_msr(self.id as hv_vcpuid_t, msr as uint32_t, enable)
-        })
+    pub fn enable_native_msr(&self, msr: u32, enable: bool) -> Result<(), HvError> {
+        self.check(
+            Platform::vcpu_enable_native_msr(self.id as hv_vcpuid_t, msr, enable),
+            "hv_vcpu_enable_native_msr"
+        )
     }
 
     /// Returns the current value of an MSR of the vCPU
-    pub fn read_msr(&self, msr: u32) -> Result<u64, Error> {
-        let mut value: uint64_t = 0;
+    pub fn read_msr(&self, msr: u32) -> Result<u64, HvError> {
+        let mut value: u64 = 0;
 
-        let error = match_error_code(unsafe {
-            hv_vcpu_read_msr(self.id as hv_vcpuid_t, msr as uint32_t, &mut value)
-        });
+        self.check(
+            Platform::vcpu_read_msr(self.id as hv_vcpuid_t, msr, &mut value),
+            "hv_vcpu_read_msr"
+        )?;
 
-        match error {
-            Error::Success => Ok(value as u64),
-            _ => Err(error)
-        }
+        Ok(value)
     }
 
     /// Set the value of an MSR of the vCPU
-    pub fn write_msr(&self, msr: u32, value: u64) -> Error {
-        match_error_code(unsafe {
-            hv_vcpu_write_msr(self.id as hv_vcpuid_t, msr as uint32_t, &(value as uint64_t))
-        })
+    pub fn write_msr(&self, msr: u32, value: u64) -> Result<(), HvError> {
+        self.check(
+            Platform::vcpu_write_msr(self.id as hv_vcpuid_t, msr, value),
+            "hv_vcpu_write_msr"
+        )
     }
 
     /// Returns the current value of an architectural x86 register
     /// of the vCPU
-    pub fn read_register(&self, reg: &x86Reg) -> Result<u64, Error> {
-        let mut value: uint64_t = 0;
+    pub fn read_register(&self, reg: &x86Reg) -> Result<u64, HvError> {
+        let mut value: u64 = 0;
 
-        let error = match_error_code(unsafe {
-            hv_vcpu_read_register(self.id as hv_vcpuid_t, (*reg).clone(), &mut value)
-        });
+        self.check(
+            Platform::vcpu_read_register(self.id as hv_vcpuid_t, *reg, &mut value),
+            "hv_vcpu_read_register"
+        )?;
 
-        match error {
-            Error::Success => Ok(value as u64),
-            _ => Err(error)
-        }
+        Ok(value)
     }
 
     /// Sets the value of an architectural x86 register of the vCPU
-    pub fn write_register(&self, reg: &x86Reg, value: u64) -> Error {
-        match_error_code(unsafe {
-            hv_vcpu_write_register(self.id as hv_vcpuid_t, (*reg).clone(), value as uint64_t)
-        })
+    pub fn write_register(&self, reg: &x86Reg, value: u64) -> Result<(), HvError> {
+        self.check(
+            Platform::vcpu_write_register(self.id as hv_vcpuid_t, *reg, value),
+            "hv_vcpu_write_register"
+        )
+    }
+
+    /// Returns the general purpose registers, instruction pointer and flags of the vCPU
+    pub fn get_regs(&self) -> Result<StandardRegs, HvError> {
+        StandardRegs::read(self)
+    }
+
+    /// Sets the general purpose registers, instruction pointer and flags of the vCPU
+    pub fn set_regs(&self, regs: &StandardRegs) -> Result<(), HvError> {
+        regs.write(self)
+    }
+
+    /// Returns the segment selectors of the vCPU
+    pub fn get_segment_regs(&self) -> Result<SegmentRegs, HvError> {
+        SegmentRegs::read(self)
+    }
+
+    /// Sets the segment selectors of the vCPU
+    pub fn set_segment_regs(&self, regs: &SegmentRegs) -> Result<(), HvError> {
+        regs.write(self)
+    }
+
+    /// Returns the selector, base, limit and access rights of a segment register of the vCPU
+    pub fn get_segment(&self, seg: SegReg) -> Result<Segment, HvError> {
+        Segment::read(self, seg)
+    }
+
+    /// Sets the selector, base, limit and access rights of a segment register of the vCPU
+    ///
+    /// Fails without writing anything if `seg_type` or `dpl` is out of range. If a write fails
+    /// partway through, the fields already written are restored.
+    pub fn set_segment(&self, seg: SegReg, segment: &Segment) -> Result<(), HvError> {
+        segment.write(self, seg)
+    }
+
+    /// Returns the control registers of the vCPU
+    pub fn get_control_regs(&self) -> Result<ControlRegs, HvError> {
+        ControlRegs::read(self)
+    }
+
+    /// Sets the control registers of the vCPU
+    pub fn set_control_regs(&self, regs: &ControlRegs) -> Result<(), HvError> {
+        regs.write(self)
+    }
+
+    /// Returns the debug registers of the vCPU
+    pub fn get_debug_regs(&self) -> Result<DebugRegs, HvError> {
+        DebugRegs::read(self)
+    }
+
+    /// Sets the debug registers of the vCPU
+    pub fn set_debug_regs(&self, regs: &DebugRegs) -> Result<(), HvError> {
+        regs.write(self)
+    }
+
+    /// Returns the descriptor table registers of the vCPU
+    pub fn get_descriptor_tables(&self) -> Result<DescriptorTables, HvError> {
+        DescriptorTables::read(self)
+    }
+
+    /// Sets the descriptor table registers of the vCPU
+    pub fn set_descriptor_tables(&self, tables: &DescriptorTables) -> Result<(), HvError> {
+        tables.write(self)
     }
 
     /// Returns the current value of a VMCS field of the vCPU
-    pub fn read_vmcs(&self, field: u32) -> Result<u64, Error> {
-        let mut value: uint64_t = 0;
+    pub fn read_vmcs(&self, field: u32) -> Result<u64, HvError> {
+        let mut value: u64 = 0;
+
+        self.check(
+            Platform::vmx_vcpu_read_vmcs(self.id as hv_vcpuid_t, field, &mut value),
+            "hv_vmx_vcpu_read_vmcs"
+        )?;
 
-        let error = match_error_code(unsafe {
-            hv_vmx_vcpu_read_vmcs(self.id as hv_vcpuid_t, field as uint32_t, &mut value)
-        });
+        Ok(value)
+    }
+
+    /// Sets the value of a VMCS field of the vCPU
+    pub fn write_vmcs(&self, field: u32, value: u64) -> Result<(), HvError> {
+        self.check_instruction(
+            Platform::vmx_vcpu_write_vmcs(self.id as hv_vcpuid_t, field, value),
+            "hv_vmx_vcpu_write_vmcs"
+        )
+    }
+
+    /// Returns the current value of a typed VMCS field of the vCPU
+    pub fn read_field(&self, field: VmcsField) -> Result<u64, HvError> {
+        self.read_vmcs(field.encoding())
+    }
+
+    /// Sets the value of a typed VMCS field of the vCPU
+    ///
+    /// Fails without calling the hypervisor if the field is read-only or the value is wider
+    /// than the field.
+    pub fn write_field(&self, field: VmcsField, value: u64) -> Result<(), HvError> {
+        if field.is_read_only() || value > field.width().max_value() {
+            return self.check(HV_BAD_ARGUMENT, "hv_vmx_vcpu_write_vmcs");
+        }
+
+        self.write_vmcs(field.encoding(), value)
+    }
+
+    /// Writes adjusted VMX controls to the VMCS of the vCPU
+    ///
+    /// The secondary processor-based controls are only written if the primary ones activate
+    /// them, since hosts without them may lack the field.
+    pub fn set_controls(&self, controls: &AdjustedControls) -> Result<(), HvError> {
+        for &field in &ControlField::ALL {
+            if field == ControlField::CpuBased2 && !controls.secondary_enabled() {
+                continue;
+            }
+            self.write_field(field.vmcs_field(), controls.get(field))?;
+        }
+
+        Ok(())
+    }
+
+    /// Reads every VMCS field of the vCPU into a snapshot
+    ///
+    /// Fields the hypervisor can't read are left out. Fails only if no field can be read.
+    pub fn dump_vmcs(&self) -> Result<VmcsSnapshot, HvError> {
+        let mut snapshot = VmcsSnapshot::new();
+        let mut error = None;
+        for &field in VmcsField::ALL {
+            match self.read_field(field) {
+                Ok(value) => snapshot.insert(field, value),
+                Err(e) => error = error.or(Some(e)),
+            }
+        }
 
         match error {
-            Error::Success => Ok(value as u64),
-            _ => Err(error)
+            Some(e) if snapshot.is_empty() => Err(e),
+            _ => Ok(snapshot),
         }
     }
 
-    /// Sets the value of a VMCS field of the vCPU
-    pub fn write_vmcs(&self, field: u32, value: u64) -> Error {
-        match_error_code(unsafe {
-            hv_vmx_vcpu_write_vmcs(self.id as hv_vcpuid_t, field as uint32_t, value as uint64_t)
-        })
+    /// Runs the VM-entry checks on the guest state of the vCPU, with the controls in its VMCS
+    ///
+    /// Returns the checks that fail. Meant to be called before `run` in debug builds, as in
+    /// `if cfg!(debug_assertions)`; see `guest_state::check`.
+    pub fn check_guest_state(&self) -> Result<Vec<Violation>, HvError> {
+        let snapshot = self.dump_vmcs()?;
+        Ok(guest_state::check(&snapshot, &AdjustedControls::from_snapshot(&snapshot)))
     }
 
     /// Sets the address of the guest APIC for the vCPU in the
     /// guest physical address space of the VM
-    pub fn set_apic_addr(&self, gpa: u64) -> Error {
-        match_error_code(unsafe {
-            hv_vmx_vcpu_set_apic_address(self.id as hv_vcpuid_t, gpa as uint64_t)
-        })
+    pub fn set_apic_addr(&self, gpa: u64) -> Result<(), HvError> {
+        self.check(
+            Platform::vmx_vcpu_set_apic_address(self.id as hv_vcpuid_t, gpa),
+            "hv_vmx_vcpu_set_apic_address"
+        )
     }
 
     /// Reads the current architectural x86 floating point and SIMD state of the vCPU
-    pub fn read_fpstate(&self, buffer: &mut [u8]) -> Error {
-        match_error_code(unsafe {
-            hv_vcpu_read_fpstate(self.id as hv_vcpuid_t, buffer.as_mut_ptr() as *mut c_void,
-            buffer.len() as size_t)
-        })
+    pub fn read_fpstate(&self, buffer: &mut [u8]) -> Result<(), HvError> {
+        self.check(
+            Platform::vcpu_read_fpstate(self.id as hv_vcpuid_t, buffer),
+            "hv_vcpu_read_fpstate"
+        )
     }
 
     /// Sets the architectural x86 floating point and SIMD state of the vCPU
-    pub fn write_fpstate(&self, buffer: &[u8]) -> Error {
-        match_error_code(unsafe {
-            hv_vcpu_write_fpstate(self.id as hv_vcpuid_t, buffer.as_ptr() as *const c_void,
-            buffer.len() as size_t)
-        })
+    pub fn write_fpstate(&self, buffer: &[u8]) -> Result<(), HvError> {
+        self.check(
+            Platform::vcpu_write_fpstate(self.id as hv_vcpuid_t, buffer),
+            "hv_vcpu_write_fpstate"
+        )
     }
 
 }
 
-impl fmt::Debug for vCPU {
+impl<'a> Drop for vCPU<'a> {
+    fn drop(&mut self) {
+        let _ = Platform::vcpu_destroy(self.id as hv_vcpuid_t);
+    }
+}
+
+impl<'a> fmt::Debug for vCPU<'a> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "vCPU ID: {}", (*self).id)
+        write!(f, "vCPU ID: {}", self.id)
     }
 }
 
 /// VMX cabability
 #[allow(non_camel_case_types)]
-#[derive(Clone)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 #[repr(C)]
 pub enum VMXCap {
     /// Pin-based VMX capabilities
@@ -439,15 +881,10 @@ pub enum VMXCap {
 }
 
 /// Reads a VMX capability of the host processor
-pub fn read_vmx_cap(vmx_cap: &VMXCap) -> Result<u64, Error> {
-    let mut value: uint64_t = 0;
+pub fn read_vmx_cap(vmx_cap: &VMXCap) -> Result<u64, HvError> {
+    let mut value: u64 = 0;
 
-    let error = match_error_code(unsafe {
-        hv_vmx_read_capability((*vmx_cap).clone(), &mut value)
-    });
+    check(Platform::vmx_read_capability(*vmx_cap, &mut value), "hv_vmx_read_capability")?;
 
-    match error {
-        Error::Success => Ok(value as u64),
-        _ => Err(error)
-    }
+    Ok(value)
 }
@@ -0,0 +1,219 @@
+/*
+Copyright (c) 2016 Saurav Sachidanand
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in
+all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+THE SOFTWARE.
+*/
+
+//! Macros used across the crate
+
+// Defines a set of flags stored in an integer, with the usual set operations
+//
+// The flags are associated constants of the generated struct, which can be combined with `|`,
+// intersected with `&`, removed with `-` and complemented with `!`. Bits that aren't part of any
+// flag are only kept by `from_bits_retain`, for values such as VMCS fields whose reserved bits
+// must be preserved.
+macro_rules! flags {
+    (
+        $(#[$attr:meta])*
+        pub struct $name:ident: $ty:ty {
+            $(
+                $(#[$flag_attr:meta])*
+                const $flag:ident = $value:expr;
+            )*
+        }
+    ) => {
+        $(#[$attr])*
+        #[derive(Clone, Copy, PartialEq, Eq, Hash, Default, PartialOrd, Ord)]
+        pub struct $name {
+            bits: $ty,
+        }
+
+        impl $name {
+            $(
+                $(#[$flag_attr])*
+                pub const $flag: $name = $name { bits: $value };
+            )*
+
+            /// Every flag, with its name
+            pub const FLAGS: &'static [(&'static str, $name)] = &[
+                $( (stringify!($flag), $name::$flag), )*
+            ];
+
+            /// Returns the set with no flags
+            pub const fn empty() -> $name {
+                $name { bits: 0 }
+            }
+
+            /// Returns the set with every flag
+            pub const fn all() -> $name {
+                $name { bits: 0 $(| $value)* }
+            }
+
+            /// Returns the raw bits of the set
+            pub const fn bits(&self) -> $ty {
+                self.bits
+            }
+
+            /// Converts raw bits into a set, failing if any bit isn't a flag
+            pub fn from_bits(bits: $ty) -> Option<$name> {
+                if bits & !$name::all().bits == 0 {
+                    Some($name { bits })
+                } else {
+                    None
+                }
+            }
+
+            /// Converts raw bits into a set, keeping any bit that isn't a flag
+            pub const fn from_bits_retain(bits: $ty) -> $name {
+                $name { bits }
+            }
+
+            /// Converts raw bits into a set, dropping any bit that isn't a flag
+            pub const fn from_bits_truncate(bits: $ty) -> $name {
+                $name { bits: bits & $name::all().bits }
+            }
+
+            /// Returns whether no flag is set
+            pub const fn is_empty(&self) -> bool {
+                self.bits == 0
+            }
+
+            /// Returns whether every flag of `other` is set
+            pub const fn contains(&self, other: $name) -> bool {
+                self.bits & other.bits == other.bits
+            }
+
+            /// Returns whether any flag of `other` is set
+            pub const fn intersects(&self, other: $name) -> bool {
+                self.bits & other.bits != 0
+            }
+
+            /// Sets the flags of `other`
+            pub fn insert(&mut self, other: $name) {
+                self.bits |= other.bits;
+            }
+
+            /// Clears the flags of `other`
+            pub fn remove(&mut self, other: $name) {
+                self.bits &= !other.bits;
+            }
+
+            /// Sets or clears the flags of `other`
+            pub fn set(&mut self, other: $name, value: bool) {
+                if value {
+                    self.insert(other);
+                } else {
+                    self.remove(other);
+                }
+            }
+        }
+
+        impl ::std::ops::BitOr for $name {
+            type Output = $name;
+
+            fn bitor(self, other: $name) -> $name {
+                $name { bits: self.bits | other.bits }
+            }
+        }
+
+        impl ::std::ops::BitOrAssign for $name {
+            fn bitor_assign(&mut self, other: $name) {
+                self.bits |= other.bits;
+            }
+        }
+
+        impl ::std::ops::BitAnd for $name {
+            type Output = $name;
+
+            fn bitand(self, other: $name) -> $name {
+                $name { bits: self.bits & other.bits }
+            }
+        }
+
+        impl ::std::ops::BitAndAssign for $name {
+            fn bitand_assign(&mut self, other: $name) {
+                self.bits &= other.bits;
+            }
+        }
+
+        impl ::std::ops::BitXor for $name {
+            type Output = $name;
+
+            fn bitxor(self, other: $name) -> $name {
+                $name { bits: self.bits ^ other.bits }
+            }
+        }
+
+        impl ::std::ops::BitXorAssign for $name {
+            fn bitxor_assign(&mut self, other: $name) {
+                self.bits ^= other.bits;
+            }
+        }
+
+        impl ::std::ops::Sub for $name {
+            type Output = $name;
+
+            fn sub(self, other: $name) -> $name {
+                $name { bits: self.bits & !other.bits }
+            }
+        }
+
+        impl ::std::ops::SubAssign for $name {
+            fn sub_assign(&mut self, other: $name) {
+                self.bits &= !other.bits;
+            }
+        }
+
+        impl ::std::ops::Not for $name {
+            type Output = $name;
+
+            fn not(self) -> $name {
+                $name::from_bits_truncate(!self.bits)
+            }
+        }
+
+        impl ::std::fmt::Debug for $name {
+            fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+                let mut first = true;
+                write!(f, "{}(", stringify!($name))?;
+                $(
+                    if $value != 0 && self.contains($name::$flag) {
+                        if !first {
+                            write!(f, " | ")?;
+                        }
+                        first = false;
+                        write!(f, "{}", stringify!($flag))?;
+                    }
+                )*
+                let rest = self.bits & !$name::all().bits;
+                if rest != 0 {
+                    if !first {
+                        write!(f, " | ")?;
+                    }
+                    first = false;
+                    write!(f, "{:#x}", rest)?;
+                }
+                if first {
+                    write!(f, "empty")?;
+                }
+                write!(f, ")")
+            }
+        }
+    };
+}
@@ -0,0 +1,450 @@
+/*
+Copyright (c) 2016 Saurav Sachidanand
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in
+all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+THE SOFTWARE.
+*/
+
+//! Multi-vCPU manager
+//!
+//! A vCPU can only be used on the thread that created it. `VcpuManager` spawns a thread for each
+//! vCPU of a VM, which creates the vCPU, sets it up and runs it with a `VcpuRunner`. Between
+//! runs, the thread carries out commands sent from the manager, such as a closure passed to
+//! `with_vcpu`.
+//!
+//! `pause_all` forces every running vCPU out of the guest with `interrupt_vcpus`, and waits until
+//! each thread has returned from `run`, so that the state of every vCPU can be read.
+
+use std::panic;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+
+use ffi::HV_ERROR;
+use super::{interrupt_vcpus, vCPU, ExitHandler, HvError, RunOutcome, RunnerHandle, VcpuRunner,
+    VirtualMachine};
+
+// Command carried out by a vCPU thread between runs
+enum Command {
+    Run,
+    Call(Box<dyn FnOnce(&vCPU) + Send>),
+    Stop,
+}
+
+// What a vCPU thread is doing
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum State {
+    Idle,
+    Running,
+    Exited,
+}
+
+// State of every vCPU thread, with the outcome of its last run
+struct Shared {
+    states: Mutex<Vec<(State, Option<RunOutcome>)>>,
+    changed: Condvar,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Vec<(State, Option<RunOutcome>)>> {
+        self.states.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn set(&self, index: usize, state: State, outcome: Option<RunOutcome>) {
+        let mut states = self.lock();
+        states[index].0 = state;
+        if outcome.is_some() {
+            states[index].1 = outcome;
+        }
+        self.changed.notify_all();
+    }
+
+    // Waits until no vCPU thread is in a state
+    fn wait_until_none(&self, state: State) {
+        let mut states = self.lock();
+        while states.iter().any(|s| s.0 == state) {
+            states = self.changed.wait(states).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+}
+
+// Marks a vCPU thread as exited when dropped, even if the thread panics
+struct ExitGuard<'a> {
+    shared: &'a Shared,
+    index: usize,
+}
+
+impl<'a> Drop for ExitGuard<'a> {
+    fn drop(&mut self) {
+        self.shared.set(self.index, State::Exited, None);
+    }
+}
+
+// Thread running one vCPU
+struct VcpuThread {
+    handle: RunnerHandle,
+    commands: Sender<Command>,
+    thread: Option<JoinHandle<()>>,
+}
+
+/// Runs each vCPU of a VM on a thread of its own
+pub struct VcpuManager {
+    vm: Arc<VirtualMachine>,
+    threads: Vec<VcpuThread>,
+    shared: Arc<Shared>,
+}
+
+impl VcpuManager {
+    /// Spawns a thread for each of `count` vCPUs
+    ///
+    /// Each thread creates a vCPU and calls `setup` with its index and the vCPU, which sets up
+    /// the vCPU and returns the `ExitHandler` to run it with. The vCPUs start paused; call
+    /// `resume_all` to run them. Fails with the first error of a thread if any can't create or
+    /// set up its vCPU. A panic in `setup` is resumed on the calling thread.
+    pub fn new<F, H>(vm: Arc<VirtualMachine>, count: usize, setup: F)
+        -> Result<VcpuManager, HvError>
+        where F: Fn(usize, &vCPU) -> Result<H, HvError> + Send + Sync + 'static,
+              H: ExitHandler + 'static {
+        let shared = Arc::new(Shared {
+            states: Mutex::new(vec![(State::Idle, None); count]),
+            changed: Condvar::new(),
+        });
+        let setup = Arc::new(setup);
+        let mut manager = VcpuManager { vm, threads: Vec::with_capacity(count), shared };
+
+        for index in 0..count {
+            let (commands, receiver) = mpsc::channel();
+            let (started, start) = mpsc::channel();
+            let vm = manager.vm.clone();
+            let shared = manager.shared.clone();
+            let setup = setup.clone();
+            let thread = thread::spawn(move || {
+                let _exited = ExitGuard { shared: &shared, index };
+                vcpu_thread(index, &vm, &*setup, &shared, &started, &receiver);
+            });
+
+            // The thread drops its end of the channel if it fails before sending
+            match start.recv() {
+                Ok(Ok(handle)) => manager.threads.push(VcpuThread {
+                    handle,
+                    commands,
+                    thread: Some(thread),
+                }),
+                Ok(Err(e)) => {
+                    let _ = thread.join();
+                    return Err(e);
+                }
+                Err(_) => match thread.join() {
+                    Err(payload) => panic::resume_unwind(payload),
+                    Ok(()) => return Err(HvError::new(HV_ERROR, "hv_vcpu_create")),
+                },
+            }
+        }
+
+        Ok(manager)
+    }
+
+    /// Returns the VM
+    pub fn vm(&self) -> &Arc<VirtualMachine> {
+        &self.vm
+    }
+
+    /// Returns the number of vCPUs
+    pub fn len(&self) -> usize {
+        self.threads.len()
+    }
+
+    /// Returns whether the manager has no vCPUs
+    pub fn is_empty(&self) -> bool {
+        self.threads.is_empty()
+    }
+
+    /// Returns the outcome of the last run of a vCPU, if it has stopped since it was created
+    pub fn outcome(&self, index: usize) -> Option<RunOutcome> {
+        self.shared.lock().get(index).and_then(|s| s.1.clone())
+    }
+
+    /// Runs every vCPU that isn't running, including those that stopped on their own
+    pub fn resume_all(&self) {
+        let states = self.shared.lock();
+        for (thread, state) in self.threads.iter().zip(states.iter()) {
+            thread.handle.resume();
+            if state.0 == State::Idle {
+                let _ = thread.commands.send(Command::Run);
+            }
+        }
+    }
+
+    /// Stops every vCPU, returning once none is running
+    ///
+    /// Running vCPUs are forced out of the guest with `interrupt_vcpus`, and return
+    /// `RunOutcome::Paused`. vCPUs whose thread has exited, such as by panicking, are skipped.
+    pub fn pause_all(&self) -> Result<(), HvError> {
+        for thread in &self.threads {
+            thread.handle.request_pause();
+        }
+        let kickers: Vec<_> = {
+            let states = self.shared.lock();
+            self.threads.iter().zip(states.iter())
+                .filter(|&(_, state)| state.0 != State::Exited)
+                .map(|(t, _)| t.handle.kicker().clone())
+                .collect()
+        };
+        interrupt_vcpus(&kickers)?;
+        self.wait_all();
+        Ok(())
+    }
+
+    /// Waits until no vCPU is running, as when each has halted, shut down or been paused
+    pub fn wait_all(&self) {
+        // A command sent to a thread is carried out before the state is looked at again
+        let _ = self.with_all(|_| ());
+        self.shared.wait_until_none(State::Running);
+    }
+
+    /// Calls a function with a vCPU on its thread, and returns the result
+    ///
+    /// Waits for the vCPU to stop running first. Returns `None` if the thread of the vCPU has
+    /// exited.
+    pub fn with_vcpu<R, F>(&self, index: usize, f: F) -> Option<R>
+        where F: FnOnce(&vCPU) -> R + Send + 'static,
+              R: Send + 'static {
+        let (sender, receiver) = mpsc::channel();
+        let command = Command::Call(Box::new(move |vcpu| {
+            let _ = sender.send(f(vcpu));
+        }));
+        self.threads.get(index)?.commands.send(command).ok()?;
+        receiver.recv().ok()
+    }
+
+    // Calls a function with every vCPU on its thread
+    fn with_all<R, F>(&self, f: F) -> Vec<Option<R>>
+        where F: Fn(&vCPU) -> R + Send + Sync + 'static,
+              R: Send + 'static {
+        let f = Arc::new(f);
+        (0..self.threads.len()).map(|i| {
+            let f = f.clone();
+            self.with_vcpu(i, move |vcpu| f(vcpu))
+        }).collect()
+    }
+
+    /// Pauses every vCPU, and ends their threads, destroying the vCPUs
+    pub fn stop_all(mut self) -> Result<(), HvError> {
+        let result = self.pause_all();
+        self.join();
+        result
+    }
+
+    // Stops the threads once no vCPU is running, so none is interrupted after it is destroyed
+    fn join(&mut self) {
+        if self.threads.iter().all(|t| t.thread.is_none()) {
+            return;
+        }
+        let _ = self.pause_all();
+        for thread in &self.threads {
+            let _ = thread.commands.send(Command::Stop);
+        }
+        for thread in &mut self.threads {
+            if let Some(thread) = thread.thread.take() {
+                let _ = thread.join();
+            }
+        }
+    }
+}
+
+impl Drop for VcpuManager {
+    fn drop(&mut self) {
+        self.join();
+    }
+}
+
+// Creates and sets up a vCPU, then carries out commands until told to stop
+fn vcpu_thread<F, H>(index: usize, vm: &VirtualMachine, setup: &F, shared: &Shared,
+    started: &Sender<Result<RunnerHandle, HvError>>, commands: &Receiver<Command>)
+    where F: Fn(usize, &vCPU) -> Result<H, HvError>,
+          H: ExitHandler {
+    let vcpu = match vm.create_vcpu() {
+        Ok(vcpu) => vcpu,
+        Err(e) => {
+            let _ = started.send(Err(e));
+            return;
+        }
+    };
+    let mut handler = match setup(index, &vcpu) {
+        Ok(handler) => handler,
+        Err(e) => {
+            let _ = started.send(Err(e));
+            return;
+        }
+    };
+    let mut runner = VcpuRunner::new(vcpu);
+    runner.handle().request_pause();
+    if started.send(Ok(runner.handle())).is_err() {
+        return;
+    }
+
+    while let Ok(command) = commands.recv() {
+        match command {
+            Command::Run => {
+                shared.set(index, State::Running, None);
+                let outcome = runner.run(&mut handler);
+                shared.set(index, State::Idle, Some(outcome));
+            }
+            Command::Call(f) => f(runner.vcpu()),
+            Command::Stop => break,
+        }
+    }
+}
+
+#[cfg(all(test, feature = "mock"))]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use backend::mock::{self, MockExit};
+    use consts::vmcs::*;
+    use consts::vmx_exit::*;
+    use ffi::HV_BAD_ARGUMENT;
+    use super::super::{ExitAction, VmExit, VmcsField};
+    use super::*;
+
+    fn hlt() -> MockExit {
+        MockExit::new(VMX_REASON_HLT).field(VMCS_RO_VMEXIT_INSTR_LEN, 1)
+    }
+
+    fn cpuid() -> MockExit {
+        MockExit::new(VMX_REASON_CPUID).field(VMCS_RO_VMEXIT_INSTR_LEN, 2)
+    }
+
+    // Keeps running the guest on CPUID exits, counting them
+    struct Spin(Arc<AtomicUsize>);
+
+    impl ExitHandler for Spin {
+        fn cpuid(&mut self, vcpu: &vCPU) -> ExitAction {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            mock::push_exit(vcpu.id() as _, cpuid());
+            ExitAction::Resume
+        }
+    }
+
+    // Handler with every default
+    struct Defaults;
+
+    impl ExitHandler for Defaults {}
+
+    // Panics on HLT if set, and halts otherwise
+    struct PanicOnHlt(bool);
+
+    impl ExitHandler for PanicOnHlt {
+        fn hlt(&mut self, vcpu: &vCPU, exit: &VmExit) -> ExitAction {
+            if self.0 {
+                panic!("HLT");
+            }
+            Defaults.hlt(vcpu, exit)
+        }
+    }
+
+    fn states(manager: &VcpuManager) -> Vec<State> {
+        manager.shared.lock().iter().map(|s| s.0.clone()).collect()
+    }
+
+    #[test]
+    fn pause_all_stops_every_vcpu() {
+        let _lock = mock::lock();
+        let vm = Arc::new(VirtualMachine::new().unwrap());
+        let exits = Arc::new(AtomicUsize::new(0));
+        let counter = exits.clone();
+        let manager = VcpuManager::new(vm, 2, move |index, vcpu| {
+            vcpu.write_field(VmcsField::GuestRip, 0x1000 * index as u64)?;
+            mock::push_exit(vcpu.id() as _, cpuid());
+            Ok(Spin(counter.clone()))
+        }).unwrap();
+
+        manager.resume_all();
+        while exits.load(Ordering::SeqCst) < 100 {
+            thread::yield_now();
+        }
+        manager.pause_all().unwrap();
+        assert!(states(&manager).iter().all(|s| *s != State::Running));
+        for index in 0..2 {
+            assert_eq!(manager.outcome(index), Some(RunOutcome::Paused));
+            let rip = manager.with_vcpu(index, |vcpu| vcpu.read_field(VmcsField::GuestRip));
+            assert_eq!(rip, Some(Ok(0x1000 * index as u64)));
+        }
+        manager.stop_all().unwrap();
+    }
+
+    #[test]
+    fn resume_all_after_halting() {
+        let _lock = mock::lock();
+        let vm = Arc::new(VirtualMachine::new().unwrap());
+        let manager = VcpuManager::new(vm, 1, |_, vcpu| {
+            mock::push_exit(vcpu.id() as _, hlt());
+            Ok(Defaults)
+        }).unwrap();
+        assert_eq!(manager.outcome(0), None);
+
+        manager.resume_all();
+        manager.wait_all();
+        assert_eq!(manager.outcome(0), Some(RunOutcome::Halted));
+
+        // The vCPU runs again, skipping the second HLT as well
+        let id = manager.with_vcpu(0, |vcpu| vcpu.id()).unwrap();
+        mock::push_exit(id as _, hlt());
+        manager.resume_all();
+        manager.wait_all();
+        assert_eq!(manager.outcome(0), Some(RunOutcome::Halted));
+        let rip = manager.with_vcpu(0, |vcpu| vcpu.read_field(VmcsField::GuestRip));
+        assert_eq!(rip, Some(Ok(2)));
+    }
+
+    #[test]
+    fn setup_error_is_returned() {
+        let _lock = mock::lock();
+        let vm = Arc::new(VirtualMachine::new().unwrap());
+        let result = VcpuManager::new(vm, 3, |index, _| {
+            if index == 1 { Err(HvError::new(HV_BAD_ARGUMENT, "setup")) } else { Ok(Defaults) }
+        });
+        assert_eq!(result.err(), Some(HvError::new(HV_BAD_ARGUMENT, "setup")));
+
+        // Every vCPU that was created has been destroyed
+        let calls = mock::take_calls();
+        let created = calls.iter().filter(|c| matches!(c, mock::Call::VcpuCreate(_))).count();
+        let destroyed = calls.iter().filter(|c| matches!(c, mock::Call::VcpuDestroy(_))).count();
+        assert_eq!((created, destroyed), (2, 2));
+    }
+
+    #[test]
+    fn panicking_handler_exits() {
+        let _lock = mock::lock();
+        let vm = Arc::new(VirtualMachine::new().unwrap());
+        let manager = VcpuManager::new(vm, 2, |index, vcpu| {
+            mock::push_exit(vcpu.id() as _, hlt());
+            Ok(PanicOnHlt(index == 0))
+        }).unwrap();
+
+        manager.resume_all();
+        manager.wait_all();
+        assert_eq!(states(&manager), vec![State::Exited, State::Idle]);
+        assert_eq!(manager.outcome(0), None);
+        assert_eq!(manager.outcome(1), Some(RunOutcome::Halted));
+        assert!(manager.with_vcpu(0, |vcpu| vcpu.id()).is_none());
+
+        manager.pause_all().unwrap();
+        drop(manager);
+    }
+}
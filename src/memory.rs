@@ -0,0 +1,896 @@
+/*
+Copyright (c) 2016 Saurav Sachidanand
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in
+all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+THE SOFTWARE.
+*/
+
+//! Guest memory
+//!
+//! `GuestMemory` is page-aligned host memory that can be mapped into the guest physical address
+//! space with `VirtualMachine::map_mem`. The guest may write to it at any time, so the host
+//! only gets to it through bounds-checked, volatile accesses.
+//!
+//! `GuestAddressSpace` records what is mapped where, and with which `MemPerm` permissions, and
+//! offers the same accessors by guest physical address.
+
+use std::collections::BTreeMap;
+use std::error;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+use std::sync::Arc;
+
+use libc;
+
+use ffi::{HV_MEMORY_EXEC, HV_MEMORY_READ, HV_MEMORY_WRITE};
+
+/// Size of a page of guest memory, which mappings must be aligned to
+pub const PAGE_SIZE: usize = 0x1000;
+
+flags! {
+    /// Permissions of guest physical memory
+    ///
+    /// Any combination is allowed, and none implies another. `MemPerm::NONE` makes every access
+    /// exit, which suits guard pages and trapping MMIO.
+    pub struct MemPerm: u64 {
+        /// No access
+        const NONE = 0;
+        /// Read
+        const READ = HV_MEMORY_READ;
+        /// Write
+        const WRITE = HV_MEMORY_WRITE;
+        /// Execute
+        const EXEC = HV_MEMORY_EXEC;
+    }
+}
+
+/// Types that can be copied to and from guest memory as raw bytes
+///
+/// # Safety
+///
+/// Every bit pattern must be a valid value of the type, and the type must not contain padding.
+pub unsafe trait DataInit: Copy + Send + Sync + 'static {}
+
+unsafe impl DataInit for u8 {}
+unsafe impl DataInit for u16 {}
+unsafe impl DataInit for u32 {}
+unsafe impl DataInit for u64 {}
+unsafe impl DataInit for u128 {}
+unsafe impl DataInit for usize {}
+unsafe impl DataInit for i8 {}
+unsafe impl DataInit for i16 {}
+unsafe impl DataInit for i32 {}
+unsafe impl DataInit for i64 {}
+unsafe impl DataInit for i128 {}
+unsafe impl DataInit for isize {}
+unsafe impl<T: DataInit, const N: usize> DataInit for [T; N] {}
+
+/// Error returned by accesses to guest memory
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MemoryError {
+    /// Access that doesn't fit in the memory
+    OutOfBounds {
+        /// Offset of the access
+        offset: usize,
+        /// Length of the access
+        len: usize,
+        /// Size of the memory
+        size: usize,
+    },
+    /// Guest physical address that nothing is mapped at
+    Unmapped {
+        /// Guest physical address
+        gpa: u64,
+    },
+    /// Mapping that overlaps a region already mapped
+    Overlap {
+        /// Guest physical address of the mapping
+        gpa: u64,
+        /// Size of the mapping
+        size: usize,
+    },
+    /// Range of guest physical addresses spanning regions that aren't contiguous in the host
+    Discontiguous {
+        /// Guest physical address of the range
+        gpa: u64,
+        /// Length of the range
+        len: usize,
+    },
+    /// Range of guest physical addresses that runs past the end of the address space
+    Overflow {
+        /// Guest physical address of the range
+        gpa: u64,
+        /// Length of the range
+        len: usize,
+    },
+}
+
+impl fmt::Display for MemoryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MemoryError::OutOfBounds { offset, len, size } => write!(f,
+                "access of {:#x} bytes at offset {:#x} is out of bounds of {:#x} bytes of memory",
+                len, offset, size),
+            MemoryError::Unmapped { gpa } => write!(f, "nothing is mapped at {:#x}", gpa),
+            MemoryError::Overlap { gpa, size } => write!(f,
+                "mapping of {:#x} bytes at {:#x} overlaps another region", size, gpa),
+            MemoryError::Discontiguous { gpa, len } => write!(f,
+                "range of {:#x} bytes at {:#x} spans discontiguous regions", len, gpa),
+            MemoryError::Overflow { gpa, len } => write!(f,
+                "range of {:#x} bytes at {:#x} runs past the end of the address space", len, gpa),
+        }
+    }
+}
+
+impl error::Error for MemoryError {}
+
+struct Inner {
+    ptr: *mut u8,
+    size: usize,
+    file: Option<File>,
+}
+
+// The memory is only accessed through volatile copies, which may race with the guest and with
+// each other
+unsafe impl Send for Inner {}
+unsafe impl Sync for Inner {}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr as *mut libc::c_void, self.size) };
+    }
+}
+
+/// Page-aligned host memory for a guest
+///
+/// Clones share the same memory, which is unmapped from the host once every clone, including
+/// the one kept by a `VirtualMachine` while it is mapped into the guest, is dropped.
+#[derive(Clone)]
+pub struct GuestMemory {
+    inner: Arc<Inner>,
+}
+
+impl GuestMemory {
+    /// Allocates zeroed anonymous memory
+    ///
+    /// * `size` Size in bytes, a nonzero multiple of `PAGE_SIZE`
+    pub fn new(size: usize) -> io::Result<GuestMemory> {
+        GuestMemory::mmap(size, libc::MAP_PRIVATE | libc::MAP_ANON, -1, 0, None)
+    }
+
+    /// Maps a file, sharing writes with it
+    ///
+    /// * `offset` Offset in the file, a multiple of `PAGE_SIZE`
+    /// * `size` Size in bytes, a nonzero multiple of `PAGE_SIZE`
+    ///
+    /// The file must extend to at least `offset + size`, since touching mapped pages past its
+    /// end raises `SIGBUS`.
+    pub fn from_file(file: File, offset: u64, size: usize) -> io::Result<GuestMemory> {
+        if !offset.is_multiple_of(PAGE_SIZE as u64) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                "file offset is not page-aligned"));
+        }
+        match offset.checked_add(size as u64) {
+            Some(end) if end <= file.metadata()?.len() => (),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                "file is shorter than the mapping")),
+        }
+        let fd = file.as_raw_fd();
+        GuestMemory::mmap(size, libc::MAP_SHARED, fd, offset as libc::off_t, Some(file))
+    }
+
+    /// Allocates memory backed by an anonymous memfd, which can be shared with other processes
+    /// through `file`
+    ///
+    /// * `name` Name of the memfd, for debugging
+    /// * `size` Size in bytes, a nonzero multiple of `PAGE_SIZE`
+    #[cfg(target_os = "linux")]
+    pub fn memfd(name: &str, size: usize) -> io::Result<GuestMemory> {
+        use std::ffi::CString;
+        use std::os::unix::io::FromRawFd;
+
+        let name = CString::new(name)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let fd = unsafe { libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let file = unsafe { File::from_raw_fd(fd) };
+        file.set_len(size as u64)?;
+        GuestMemory::from_file(file, 0, size)
+    }
+
+    fn mmap(size: usize, flags: libc::c_int, fd: libc::c_int, offset: libc::off_t,
+        file: Option<File>) -> io::Result<GuestMemory> {
+        if size == 0 || !size.is_multiple_of(PAGE_SIZE) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                "size is not a nonzero multiple of the page size"));
+        }
+
+        let ptr = unsafe {
+            libc::mmap(ptr::null_mut(), size, libc::PROT_READ | libc::PROT_WRITE, flags, fd,
+                offset)
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(GuestMemory {
+            inner: Arc::new(Inner { ptr: ptr as *mut u8, size, file })
+        })
+    }
+
+    /// Returns the size of the memory in bytes
+    pub fn size(&self) -> usize {
+        self.inner.size
+    }
+
+    /// Returns the file backing the memory, if any
+    pub fn file(&self) -> Option<&File> {
+        self.inner.file.as_ref()
+    }
+
+    /// Returns the host address of the memory
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.inner.ptr
+    }
+
+    /// Returns whether two handles share the same memory
+    pub fn ptr_eq(&self, other: &GuestMemory) -> bool {
+        Arc::ptr_eq(&self.inner, &other.inner)
+    }
+
+    /// Returns the host address of a range of the memory, after checking that it is in bounds
+    pub fn get_host_address(&self, offset: usize, len: usize) -> Result<*mut u8, MemoryError> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.size() => Ok(unsafe { self.as_ptr().add(offset) }),
+            _ => Err(MemoryError::OutOfBounds { offset, len, size: self.size() }),
+        }
+    }
+
+    /// Copies bytes out of the memory
+    pub fn read_slice(&self, offset: usize, buf: &mut [u8]) -> Result<(), MemoryError> {
+        let src = self.get_host_address(offset, buf.len())?;
+        unsafe { copy_volatile(src, buf.as_mut_ptr(), buf.len()) };
+        Ok(())
+    }
+
+    /// Copies bytes into the memory
+    pub fn write_slice(&self, offset: usize, buf: &[u8]) -> Result<(), MemoryError> {
+        let dst = self.get_host_address(offset, buf.len())?;
+        unsafe { copy_volatile(buf.as_ptr(), dst, buf.len()) };
+        Ok(())
+    }
+
+    /// Reads a value from the memory, which needn't be aligned
+    pub fn read_obj<T: DataInit>(&self, offset: usize) -> Result<T, MemoryError> {
+        let src = self.get_host_address(offset, mem::size_of::<T>())?;
+        let mut value = mem::MaybeUninit::<T>::uninit();
+        unsafe {
+            copy_volatile(src, value.as_mut_ptr() as *mut u8, mem::size_of::<T>());
+            Ok(value.assume_init())
+        }
+    }
+
+    /// Writes a value to the memory, which needn't be aligned
+    pub fn write_obj<T: DataInit>(&self, offset: usize, value: T) -> Result<(), MemoryError> {
+        let dst = self.get_host_address(offset, mem::size_of::<T>())?;
+        unsafe { copy_volatile(&value as *const T as *const u8, dst, mem::size_of::<T>()) };
+        Ok(())
+    }
+}
+
+impl fmt::Debug for GuestMemory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "GuestMemory {{ ptr: {:p}, size: {:#x} }}", self.as_ptr(), self.size())
+    }
+}
+
+/// Region of guest memory mapped into the guest physical address space
+#[derive(Clone)]
+pub struct GuestRegion {
+    gpa: u64,
+    mem: GuestMemory,
+    offset: usize,
+    size: usize,
+    perm: MemPerm,
+}
+
+impl GuestRegion {
+    /// Returns the guest physical address of the region
+    pub fn gpa(&self) -> u64 {
+        self.gpa
+    }
+
+    /// Returns the size of the region in bytes
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns the guest physical address just past the region
+    pub fn end(&self) -> u64 {
+        self.gpa + self.size as u64
+    }
+
+    /// Returns the memory backing the region
+    pub fn memory(&self) -> &GuestMemory {
+        &self.mem
+    }
+
+    /// Returns the offset of the region in its memory
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Returns the permissions of the region
+    pub fn perm(&self) -> MemPerm {
+        self.perm
+    }
+
+    /// Returns whether the region contains a guest physical address
+    pub fn contains(&self, gpa: u64) -> bool {
+        self.gpa <= gpa && gpa < self.end()
+    }
+
+    // Returns the host address of a guest physical address in the region
+    fn host_address(&self, gpa: u64) -> *mut u8 {
+        unsafe { self.mem.as_ptr().add(self.offset + (gpa - self.gpa) as usize) }
+    }
+
+    // Returns whether a region continues this one, in both address spaces
+    fn continued_by(&self, next: &GuestRegion) -> bool {
+        self.end() == next.gpa && self.mem.ptr_eq(&next.mem)
+            && self.offset + self.size == next.offset && self.perm == next.perm
+    }
+}
+
+impl fmt::Debug for GuestRegion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "GuestRegion {{ gpa: {:#x}, size: {:#x}, offset: {:#x}, perm: {:?} }}",
+            self.gpa, self.size, self.offset, self.perm)
+    }
+}
+
+/// Map of the guest physical address space
+///
+/// Tracks the regions mapped into a `VirtualMachine`, which hands it out through
+/// `VirtualMachine::address_space`, and translates guest physical addresses into host memory.
+#[derive(Clone, Debug, Default)]
+pub struct GuestAddressSpace {
+    regions: BTreeMap<u64, GuestRegion>,
+}
+
+impl GuestAddressSpace {
+    /// Creates an empty address space
+    pub fn new() -> GuestAddressSpace {
+        GuestAddressSpace::default()
+    }
+
+    /// Returns the regions in order of guest physical address
+    pub fn regions(&self) -> impl Iterator<Item = &GuestRegion> {
+        self.regions.values()
+    }
+
+    /// Returns the region containing a guest physical address
+    pub fn find_region(&self, gpa: u64) -> Option<&GuestRegion> {
+        self.regions.range(..=gpa).next_back().map(|(_, r)| r).filter(|r| r.contains(gpa))
+    }
+
+    /// Returns the permissions of the page containing a guest physical address
+    pub fn perm(&self, gpa: u64) -> Option<MemPerm> {
+        self.find_region(gpa).map(|r| r.perm)
+    }
+
+    /// Returns the permissions of every page in a range of guest physical addresses, which
+    /// must all be mapped
+    pub fn save_perms(&self, gpa: u64, size: usize) -> Result<SavedPerms, MemoryError> {
+        let pieces = self.pieces(gpa, size)?;
+        Ok(SavedPerms {
+            pieces: pieces.into_iter()
+                .filter_map(|(gpa, size)| self.perm(gpa).map(|perm| (gpa, size, perm)))
+                .collect()
+        })
+    }
+
+    /// Returns the host address of a range of guest physical addresses, which must lie in one
+    /// region
+    pub fn get_host_address(&self, gpa: u64, len: usize) -> Result<*mut u8, MemoryError> {
+        let end = range_end(gpa, len)?;
+        let region = self.find_region(gpa).ok_or(MemoryError::Unmapped { gpa })?;
+        if end > region.end() {
+            return match self.find_region(region.end()) {
+                Some(_) => Err(MemoryError::Discontiguous { gpa, len }),
+                None => Err(MemoryError::Unmapped { gpa: region.end() }),
+            };
+        }
+        Ok(region.host_address(gpa))
+    }
+
+    // Calls f with the host address and length of each piece of a range, failing before any
+    // call if part of the range is unmapped
+    fn for_each_piece<F: FnMut(*mut u8, usize)>(&self, gpa: u64, len: usize, mut f: F)
+        -> Result<(), MemoryError> {
+        range_end(gpa, len)?;
+        let mut pieces = Vec::new();
+        let mut done = 0;
+        while done < len {
+            let cur = gpa + done as u64;
+            let region = self.find_region(cur).ok_or(MemoryError::Unmapped { gpa: cur })?;
+            let n = ::std::cmp::min(len - done, (region.end() - cur) as usize);
+            pieces.push((region.host_address(cur), n));
+            done += n;
+        }
+        for (p, n) in pieces {
+            f(p, n);
+        }
+        Ok(())
+    }
+
+    /// Copies bytes out of guest memory
+    pub fn read_slice(&self, gpa: u64, buf: &mut [u8]) -> Result<(), MemoryError> {
+        let mut done = 0;
+        self.for_each_piece(gpa, buf.len(), |p, n| {
+            unsafe { copy_volatile(p, buf[done..].as_mut_ptr(), n) };
+            done += n;
+        })
+    }
+
+    /// Copies bytes into guest memory
+    pub fn write_slice(&self, gpa: u64, buf: &[u8]) -> Result<(), MemoryError> {
+        let mut done = 0;
+        self.for_each_piece(gpa, buf.len(), |p, n| {
+            unsafe { copy_volatile(buf[done..].as_ptr(), p, n) };
+            done += n;
+        })
+    }
+
+    /// Reads a value from guest memory, which needn't be aligned
+    pub fn read_obj<T: DataInit>(&self, gpa: u64) -> Result<T, MemoryError> {
+        let mut value = mem::MaybeUninit::<T>::uninit();
+        let dst = value.as_mut_ptr() as *mut u8;
+        let mut done = 0;
+        self.for_each_piece(gpa, mem::size_of::<T>(), |p, n| {
+            unsafe { copy_volatile(p, dst.add(done), n) };
+            done += n;
+        })?;
+        Ok(unsafe { value.assume_init() })
+    }
+
+    /// Writes a value to guest memory, which needn't be aligned
+    pub fn write_obj<T: DataInit>(&self, gpa: u64, value: T) -> Result<(), MemoryError> {
+        let buf = unsafe {
+            ::std::slice::from_raw_parts(&value as *const T as *const u8, mem::size_of::<T>())
+        };
+        self.write_slice(gpa, buf)
+    }
+
+    // Checks that a new mapping doesn't overlap any region
+    pub(crate) fn check_free(&self, gpa: u64, size: usize) -> Result<(), MemoryError> {
+        let end = range_end(gpa, size)?;
+        let before = self.regions.range(..end).next_back().map(|(_, r)| r);
+        match before {
+            Some(r) if r.end() > gpa => Err(MemoryError::Overlap { gpa, size }),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn insert(&mut self, gpa: u64, mem: GuestMemory, perm: MemPerm)
+        -> Result<(), MemoryError> {
+        let size = mem.size();
+        self.check_free(gpa, size)?;
+        self.regions.insert(gpa, GuestRegion { gpa, mem, offset: 0, size, perm });
+        Ok(())
+    }
+
+    // Returns the pieces of the regions covering a range, as (gpa, size) pairs, failing if part
+    // of the range is unmapped
+    pub(crate) fn pieces(&self, gpa: u64, size: usize) -> Result<Vec<(u64, usize)>, MemoryError> {
+        let mut pieces = Vec::new();
+        let end = range_end(gpa, size)?;
+        let mut cur = gpa;
+        while cur < end {
+            let region = self.find_region(cur).ok_or(MemoryError::Unmapped { gpa: cur })?;
+            let piece_end = ::std::cmp::min(end, region.end());
+            pieces.push((cur, (piece_end - cur) as usize));
+            cur = piece_end;
+        }
+        Ok(pieces)
+    }
+
+    // Returns the pieces of a range that are backed by a particular memory
+    pub(crate) fn pieces_of(&self, gpa: u64, size: usize, mem: &GuestMemory) -> Vec<(u64, usize)> {
+        let end = gpa.saturating_add(size as u64);
+        let first = self.find_region(gpa).map_or(gpa, |r| r.gpa);
+        self.regions.range(first..end)
+            .map(|(_, r)| r)
+            .filter(|r| r.mem.ptr_eq(mem))
+            .map(|r| {
+                let start = ::std::cmp::max(gpa, r.gpa);
+                (start, (::std::cmp::min(end, r.end()) - start) as usize)
+            })
+            .collect()
+    }
+
+    // Splits the region containing a guest physical address so that a region starts there
+    fn split_at(&mut self, gpa: u64) {
+        let mut tail = match self.find_region(gpa) {
+            Some(r) if r.gpa != gpa => r.clone(),
+            _ => return,
+        };
+        let head_size = (gpa - tail.gpa) as usize;
+        if let Some(head) = self.regions.get_mut(&tail.gpa) {
+            head.size = head_size;
+        }
+        tail.gpa = gpa;
+        tail.offset += head_size;
+        tail.size -= head_size;
+        self.regions.insert(gpa, tail);
+    }
+
+    // Merges the regions around a guest physical address if one continues the other
+    fn merge_at(&mut self, gpa: u64) {
+        let prev = match self.regions.range(..gpa).next_back() {
+            Some((_, r)) => r.gpa,
+            None => return,
+        };
+        let mergeable = match (self.regions.get(&prev), self.regions.get(&gpa)) {
+            (Some(a), Some(b)) => a.continued_by(b),
+            _ => false,
+        };
+        if mergeable {
+            if let Some(next) = self.regions.remove(&gpa) {
+                if let Some(r) = self.regions.get_mut(&prev) {
+                    r.size += next.size;
+                }
+            }
+        }
+    }
+
+    // Removes a fully mapped range, splitting the regions at its ends, and returns the removed
+    // regions
+    pub(crate) fn remove(&mut self, gpa: u64, size: usize)
+        -> Result<Vec<GuestRegion>, MemoryError> {
+        self.pieces(gpa, size)?;
+        let end = range_end(gpa, size)?;
+        self.split_at(gpa);
+        self.split_at(end);
+        let keys: Vec<u64> = self.regions.range(gpa..end).map(|(&k, _)| k).collect();
+        Ok(keys.iter().filter_map(|k| self.regions.remove(k)).collect())
+    }
+
+    // Changes the permissions of a fully mapped range, splitting the regions at its ends and
+    // merging the ones that end up alike
+    pub(crate) fn protect(&mut self, gpa: u64, size: usize, perm: MemPerm)
+        -> Result<(), MemoryError> {
+        self.pieces(gpa, size)?;
+        let end = range_end(gpa, size)?;
+        self.split_at(gpa);
+        self.split_at(end);
+        let keys: Vec<u64> = self.regions.range(gpa..end).map(|(&k, _)| k).collect();
+        for k in &keys {
+            if let Some(r) = self.regions.get_mut(k) {
+                r.perm = perm;
+            }
+        }
+        self.merge_at(end);
+        for k in keys.iter().rev() {
+            self.merge_at(*k);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn take(&mut self) -> Vec<GuestRegion> {
+        mem::take(&mut self.regions).into_values().collect()
+    }
+}
+
+/// Permissions of a range of guest physical addresses, saved to be restored later
+///
+/// `VirtualMachine::protect_mem` returns the permissions it replaced, and
+/// `VirtualMachine::restore_perms` puts them back.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SavedPerms {
+    pieces: Vec<(u64, usize, MemPerm)>,
+}
+
+impl SavedPerms {
+    /// Returns the saved ranges, as guest physical address, size and permissions, in order of
+    /// guest physical address
+    pub fn iter(&self) -> impl Iterator<Item = (u64, usize, MemPerm)> + '_ {
+        self.pieces.iter().cloned()
+    }
+}
+
+// Returns the end of a range of guest physical addresses, failing if it runs past the end of the
+// address space
+fn range_end(gpa: u64, len: usize) -> Result<u64, MemoryError> {
+    gpa.checked_add(len as u64).ok_or(MemoryError::Overflow { gpa, len })
+}
+
+// Copies bytes with volatile accesses, so that concurrent writes by the guest can't be assumed
+// away, using whole words where both sides are aligned
+unsafe fn copy_volatile(src: *const u8, dst: *mut u8, len: usize) {
+    let mut i = 0;
+    let word = mem::size_of::<u64>();
+    if (src as usize).is_multiple_of(word) && (dst as usize).is_multiple_of(word) {
+        while i + word <= len {
+            let value = ptr::read_volatile(src.add(i) as *const u64);
+            ptr::write_volatile(dst.add(i) as *mut u64, value);
+            i += word;
+        }
+    }
+    while i < len {
+        ptr::write_volatile(dst.add(i), ptr::read_volatile(src.add(i)));
+        i += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_accesses() {
+        let mem = GuestMemory::new(2 * PAGE_SIZE).unwrap();
+        assert_eq!(mem.read_obj::<u64>(0), Ok(0));
+
+        // Values needn't be aligned, and may cross pages
+        mem.write_obj(PAGE_SIZE - 3, 0x0123_4567_89ab_cdefu64).unwrap();
+        assert_eq!(mem.read_obj::<u64>(PAGE_SIZE - 3), Ok(0x0123_4567_89ab_cdef));
+        assert_eq!(mem.read_obj::<u8>(PAGE_SIZE - 3), Ok(0xef));
+        assert_eq!(mem.read_obj::<[u8; 2]>(PAGE_SIZE + 3), Ok([0x23, 0x01]));
+
+        let end = 2 * PAGE_SIZE;
+        mem.write_slice(end - 5, b"hello").unwrap();
+        let mut buf = [0; 5];
+        mem.read_slice(end - 5, &mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+        mem.read_slice(end, &mut []).unwrap();
+
+        // Clones share the memory
+        let clone = mem.clone();
+        assert!(clone.ptr_eq(&mem));
+        assert_eq!(clone.read_obj::<u8>(end - 1), Ok(b'o'));
+        assert!(!GuestMemory::new(PAGE_SIZE).unwrap().ptr_eq(&mem));
+    }
+
+    #[test]
+    fn memory_bounds() {
+        let size = 2 * PAGE_SIZE;
+        let mem = GuestMemory::new(size).unwrap();
+        let out = |offset, len| Some(MemoryError::OutOfBounds { offset, len, size });
+
+        assert_eq!(mem.read_slice(size - 1, &mut [0; 2]).err(), out(size - 1, 2));
+        assert_eq!(mem.write_slice(size, &[0]).err(), out(size, 1));
+        assert_eq!(mem.read_obj::<u32>(size - 3).err(), out(size - 3, 4));
+        assert_eq!(mem.write_obj(size - 7, 0u64).err(), out(size - 7, 8));
+        assert_eq!(mem.get_host_address(0, size + 1).err(), out(0, size + 1));
+
+        // Offsets whose end overflows
+        assert_eq!(mem.read_obj::<u64>(usize::MAX - 3).err(), out(usize::MAX - 3, 8));
+        assert_eq!(mem.write_slice(usize::MAX, &[0; 2]).err(), out(usize::MAX, 2));
+
+        // Nothing was written
+        let mut buf = [0xff; 8];
+        mem.read_slice(size - 8, &mut buf).unwrap();
+        assert_eq!(buf, [0; 8]);
+    }
+
+    #[test]
+    fn memory_size_must_be_whole_pages() {
+        for &size in &[0, 1, PAGE_SIZE + 1] {
+            let error = GuestMemory::new(size).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput, "{:#x}", size);
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn memory_from_file() {
+        use std::os::unix::fs::FileExt;
+
+        let mem = GuestMemory::memfd("memory_from_file", 2 * PAGE_SIZE).unwrap();
+        let file = || mem.file().unwrap().try_clone().unwrap();
+
+        // Mappings of the same file share writes with each other and with the file
+        let second = GuestMemory::from_file(file(), PAGE_SIZE as u64, PAGE_SIZE).unwrap();
+        second.write_obj(8, 0xdead_beefu32).unwrap();
+        assert_eq!(mem.read_obj::<u32>(PAGE_SIZE + 8), Ok(0xdead_beef));
+        let mut buf = [0; 4];
+        file().read_at(&mut buf, PAGE_SIZE as u64 + 8).unwrap();
+        assert_eq!(buf, [0xef, 0xbe, 0xad, 0xde]);
+
+        let cases = [
+            // Misaligned offset
+            (1, PAGE_SIZE),
+            (PAGE_SIZE as u64 / 2, PAGE_SIZE),
+            // Mappings past the end of the file
+            (0, 3 * PAGE_SIZE),
+            (2 * PAGE_SIZE as u64, PAGE_SIZE),
+            (!(PAGE_SIZE as u64 - 1), PAGE_SIZE),
+            // Sizes that aren't whole pages
+            (0, 0),
+            (0, PAGE_SIZE / 2),
+        ];
+        for &(offset, size) in &cases {
+            let error = GuestMemory::from_file(file(), offset, size).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput, "{:#x} at {:#x}", size, offset);
+        }
+    }
+
+    // Returns the gpa, size, offset and permissions of each region
+    fn layout(space: &GuestAddressSpace) -> Vec<(u64, usize, usize, MemPerm)> {
+        space.regions().map(|r| (r.gpa(), r.size(), r.offset(), r.perm())).collect()
+    }
+
+    #[test]
+    fn overlapping_inserts_are_rejected() {
+        let rw = MemPerm::READ | MemPerm::WRITE;
+        let mut space = GuestAddressSpace::new();
+        space.insert(0x10000, GuestMemory::new(2 * PAGE_SIZE).unwrap(), rw).unwrap();
+
+        let overlap = |gpa, size| Err(MemoryError::Overlap { gpa, size });
+        let cases = [
+            (0x10000, PAGE_SIZE, overlap(0x10000, PAGE_SIZE)),
+            (0x11000, 2 * PAGE_SIZE, overlap(0x11000, 2 * PAGE_SIZE)),
+            (0xf000, 2 * PAGE_SIZE, overlap(0xf000, 2 * PAGE_SIZE)),
+            (0x8000, 16 * PAGE_SIZE, overlap(0x8000, 16 * PAGE_SIZE)),
+            (0xfffff000, 2 * PAGE_SIZE, Ok(())),
+            (0xffff_ffff_ffff_f000, 2 * PAGE_SIZE,
+                Err(MemoryError::Overflow { gpa: 0xffff_ffff_ffff_f000, len: 2 * PAGE_SIZE })),
+            // Adjacent regions are fine
+            (0xf000, PAGE_SIZE, Ok(())),
+            (0x12000, PAGE_SIZE, Ok(())),
+        ];
+        for &(gpa, size, ref expected) in &cases {
+            let mem = GuestMemory::new(size).unwrap();
+            assert_eq!(space.insert(gpa, mem, rw), *expected, "{:#x} bytes at {:#x}", size, gpa);
+        }
+        assert_eq!(layout(&space), vec![
+            (0xf000, PAGE_SIZE, 0, rw),
+            (0x10000, 2 * PAGE_SIZE, 0, rw),
+            (0x12000, PAGE_SIZE, 0, rw),
+            (0xfffff000, 2 * PAGE_SIZE, 0, rw),
+        ]);
+    }
+
+    #[test]
+    fn partial_remove_splits_a_region() {
+        let rw = MemPerm::READ | MemPerm::WRITE;
+        let mut space = GuestAddressSpace::new();
+        space.insert(0x10000, GuestMemory::new(4 * PAGE_SIZE).unwrap(), rw).unwrap();
+        space.write_obj(0x13ffc, 0x1234_5678u32).unwrap();
+
+        let removed = space.remove(0x11000, PAGE_SIZE).unwrap();
+        assert_eq!(removed.iter().map(|r| (r.gpa(), r.size(), r.offset())).collect::<Vec<_>>(),
+            vec![(0x11000, PAGE_SIZE, PAGE_SIZE)]);
+        assert_eq!(layout(&space), vec![
+            (0x10000, PAGE_SIZE, 0, rw),
+            (0x12000, 2 * PAGE_SIZE, 2 * PAGE_SIZE, rw),
+        ]);
+
+        // The rest of the region still reaches the same memory
+        assert_eq!(space.read_obj::<u32>(0x13ffc), Ok(0x1234_5678));
+        assert_eq!(space.read_obj::<u32>(0x11000), Err(MemoryError::Unmapped { gpa: 0x11000 }));
+        assert_eq!(space.read_obj::<u32>(0x10ffe), Err(MemoryError::Unmapped { gpa: 0x11000 }));
+
+        // A range that isn't fully mapped is left alone
+        assert_eq!(space.remove(0x10000, 3 * PAGE_SIZE).map(|_| ()),
+            Err(MemoryError::Unmapped { gpa: 0x11000 }));
+        assert_eq!(space.regions().count(), 2);
+
+        // Removing whole regions, or the ends of them
+        let removed = space.remove(0x13000, PAGE_SIZE).unwrap();
+        assert_eq!(removed.len(), 1);
+        assert_eq!(layout(&space), vec![
+            (0x10000, PAGE_SIZE, 0, rw),
+            (0x12000, PAGE_SIZE, 2 * PAGE_SIZE, rw),
+        ]);
+        space.remove(0x10000, PAGE_SIZE).unwrap();
+        space.remove(0x12000, PAGE_SIZE).unwrap();
+        assert_eq!(space.regions().count(), 0);
+    }
+
+    #[test]
+    fn adjacent_pieces_merge() {
+        let rw = MemPerm::READ | MemPerm::WRITE;
+        let mut space = GuestAddressSpace::new();
+        let mem = GuestMemory::new(4 * PAGE_SIZE).unwrap();
+        space.insert(0x10000, mem.clone(), rw).unwrap();
+        space.insert(0x14000, GuestMemory::new(PAGE_SIZE).unwrap(), rw).unwrap();
+
+        space.split_at(0x11000);
+        space.split_at(0x12000);
+        space.split_at(0x12000);
+        assert_eq!(layout(&space), vec![
+            (0x10000, PAGE_SIZE, 0, rw),
+            (0x11000, PAGE_SIZE, PAGE_SIZE, rw),
+            (0x12000, 2 * PAGE_SIZE, 2 * PAGE_SIZE, rw),
+            (0x14000, PAGE_SIZE, 0, rw),
+        ]);
+
+        // Pieces with other permissions or memory stay apart
+        space.regions.get_mut(&0x11000).unwrap().perm = MemPerm::READ;
+        space.merge_at(0x11000);
+        space.merge_at(0x14000);
+        assert_eq!(space.regions().count(), 4);
+
+        space.regions.get_mut(&0x11000).unwrap().perm = rw;
+        space.merge_at(0x12000);
+        space.merge_at(0x11000);
+        assert_eq!(layout(&space), vec![
+            (0x10000, 4 * PAGE_SIZE, 0, rw),
+            (0x14000, PAGE_SIZE, 0, rw),
+        ]);
+        assert!(space.find_region(0x13fff).unwrap().memory().ptr_eq(&mem));
+    }
+
+    #[test]
+    fn protect_splits_and_restoring_merges() {
+        let (r, rw, rx) = (MemPerm::READ, MemPerm::READ | MemPerm::WRITE,
+            MemPerm::READ | MemPerm::EXEC);
+        let mut space = GuestAddressSpace::new();
+        space.insert(0x10000, GuestMemory::new(4 * PAGE_SIZE).unwrap(), rw).unwrap();
+        space.insert(0x14000, GuestMemory::new(PAGE_SIZE).unwrap(), rx).unwrap();
+
+        // Protecting the middle of a region splits it in three
+        let saved = space.save_perms(0x11000, 2 * PAGE_SIZE).unwrap();
+        assert_eq!(saved.iter().collect::<Vec<_>>(), vec![(0x11000, 2 * PAGE_SIZE, rw)]);
+        space.protect(0x11000, 2 * PAGE_SIZE, r).unwrap();
+        assert_eq!(layout(&space), vec![
+            (0x10000, PAGE_SIZE, 0, rw),
+            (0x11000, 2 * PAGE_SIZE, PAGE_SIZE, r),
+            (0x13000, PAGE_SIZE, 3 * PAGE_SIZE, rw),
+            (0x14000, PAGE_SIZE, 0, rx),
+        ]);
+        assert_eq!(space.perm(0x12fff), Some(r));
+
+        // Restoring the saved permissions merges the pieces again
+        for (gpa, size, perm) in saved.iter() {
+            space.protect(gpa, size, perm).unwrap();
+        }
+        assert_eq!(layout(&space), vec![
+            (0x10000, 4 * PAGE_SIZE, 0, rw),
+            (0x14000, PAGE_SIZE, 0, rx),
+        ]);
+
+        // A range across regions saves the permissions of each piece
+        let saved = space.save_perms(0x13000, 2 * PAGE_SIZE).unwrap();
+        assert_eq!(saved.iter().collect::<Vec<_>>(),
+            vec![(0x13000, PAGE_SIZE, rw), (0x14000, PAGE_SIZE, rx)]);
+        space.protect(0x13000, 2 * PAGE_SIZE, MemPerm::NONE).unwrap();
+        assert_eq!(space.regions().count(), 3);
+        for (gpa, size, perm) in saved.iter() {
+            space.protect(gpa, size, perm).unwrap();
+        }
+        assert_eq!(layout(&space), vec![
+            (0x10000, 4 * PAGE_SIZE, 0, rw),
+            (0x14000, PAGE_SIZE, 0, rx),
+        ]);
+
+        // Unmapped ranges can't be saved or protected
+        let unmapped = Err(MemoryError::Unmapped { gpa: 0x15000 });
+        assert_eq!(space.save_perms(0x14000, 2 * PAGE_SIZE), unmapped);
+        assert_eq!(space.protect(0x14000, 2 * PAGE_SIZE, r), unmapped.map(|_| ()));
+        assert_eq!(space.perm(0x14000), Some(rx));
+    }
+}
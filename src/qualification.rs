@@ -0,0 +1,384 @@
+/*
+Copyright (c) 2016 Saurav Sachidanand
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in
+all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+THE SOFTWARE.
+*/
+
+//! Exit qualifications
+//!
+//! The layout of the exit qualification depends on the exit reason. Each type here decodes the
+//! layout of one reason, and encodes back to the same raw value, so exits can be synthesized as
+//! well as decoded.
+
+/// Direction of an I/O instruction
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum IoDirection {
+    /// OUT or OUTS
+    Out,
+    /// IN or INS
+    In,
+}
+
+/// Exit qualification of an I/O instruction
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct IoQualification {
+    /// Port number
+    pub port: u16,
+    /// Size of the access in bytes
+    pub size: u8,
+    /// Direction
+    pub direction: IoDirection,
+    /// String instruction
+    pub string: bool,
+    /// REP prefixed
+    pub rep: bool,
+    /// Port given as an immediate, as opposed to in DX
+    pub immediate: bool,
+}
+
+impl IoQualification {
+    /// Decodes an exit qualification
+    pub fn from_raw(raw: u64) -> IoQualification {
+        IoQualification {
+            port: (raw >> 16) as u16,
+            size: (raw & 0x7) as u8 + 1,
+            direction: if raw & (1 << 3) != 0 { IoDirection::In } else { IoDirection::Out },
+            string: raw & (1 << 4) != 0,
+            rep: raw & (1 << 5) != 0,
+            immediate: raw & (1 << 6) != 0,
+        }
+    }
+
+    /// Encodes the exit qualification
+    pub fn to_raw(&self) -> u64 {
+        (u64::from(self.port) << 16)
+            | u64::from(self.size.wrapping_sub(1) & 0x7)
+            | bit(3, self.direction == IoDirection::In)
+            | bit(4, self.string)
+            | bit(5, self.rep)
+            | bit(6, self.immediate)
+    }
+}
+
+/// Type of a control register access
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CrAccessType {
+    /// MOV to the control register
+    MovToCr,
+    /// MOV from the control register
+    MovFromCr,
+    /// CLTS
+    Clts,
+    /// LMSW
+    Lmsw,
+}
+
+/// Exit qualification of a control register access
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct CrAccessQualification {
+    /// Number of the control register
+    pub cr: u8,
+    /// Type of access
+    pub access: CrAccessType,
+    /// LMSW operand in memory, as opposed to in a register
+    pub lmsw_memory: bool,
+    /// General purpose register of a MOV, numbered as in instruction encodings (RAX is 0)
+    pub gpr: u8,
+    /// Source data of LMSW
+    pub lmsw_source: u16,
+}
+
+impl CrAccessQualification {
+    /// Decodes an exit qualification
+    pub fn from_raw(raw: u64) -> CrAccessQualification {
+        CrAccessQualification {
+            cr: (raw & 0xf) as u8,
+            access: match (raw >> 4) & 0x3 {
+                0 => CrAccessType::MovToCr,
+                1 => CrAccessType::MovFromCr,
+                2 => CrAccessType::Clts,
+                _ => CrAccessType::Lmsw,
+            },
+            lmsw_memory: raw & (1 << 6) != 0,
+            gpr: ((raw >> 8) & 0xf) as u8,
+            lmsw_source: (raw >> 16) as u16,
+        }
+    }
+
+    /// Encodes the exit qualification
+    pub fn to_raw(&self) -> u64 {
+        u64::from(self.cr & 0xf)
+            | ((self.access as u64) << 4)
+            | bit(6, self.lmsw_memory)
+            | (u64::from(self.gpr & 0xf) << 8)
+            | (u64::from(self.lmsw_source) << 16)
+    }
+}
+
+/// Type of a debug register access
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum DrAccessType {
+    /// MOV to the debug register
+    MovToDr,
+    /// MOV from the debug register
+    MovFromDr,
+}
+
+/// Exit qualification of a debug register access
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct DrAccessQualification {
+    /// Number of the debug register
+    pub dr: u8,
+    /// Type of access
+    pub access: DrAccessType,
+    /// General purpose register, numbered as in instruction encodings (RAX is 0)
+    pub gpr: u8,
+}
+
+impl DrAccessQualification {
+    /// Decodes an exit qualification
+    pub fn from_raw(raw: u64) -> DrAccessQualification {
+        DrAccessQualification {
+            dr: (raw & 0x7) as u8,
+            access: if raw & (1 << 4) != 0 {
+                DrAccessType::MovFromDr
+            } else {
+                DrAccessType::MovToDr
+            },
+            gpr: ((raw >> 8) & 0xf) as u8,
+        }
+    }
+
+    /// Encodes the exit qualification
+    pub fn to_raw(&self) -> u64 {
+        u64::from(self.dr & 0x7)
+            | bit(4, self.access == DrAccessType::MovFromDr)
+            | (u64::from(self.gpr & 0xf) << 8)
+    }
+}
+
+/// Exit qualification of an EPT violation
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct EptViolationQualification {
+    /// The access was a data read
+    pub read: bool,
+    /// The access was a data write
+    pub write: bool,
+    /// The access was an instruction fetch
+    pub execute: bool,
+    /// The guest-physical address was readable
+    pub readable: bool,
+    /// The guest-physical address was writable
+    pub writable: bool,
+    /// The guest-physical address was executable (by supervisor mode, with mode-based execute
+    /// control)
+    pub executable: bool,
+    /// The guest-physical address was executable by user mode, with mode-based execute control
+    pub user_executable: bool,
+    /// The guest-linear address field is valid
+    pub linear_address_valid: bool,
+    /// The access was to the translated linear address, as opposed to a paging structure
+    pub translated_access: bool,
+    /// The linear address was a user-mode address, as opposed to a supervisor-mode one
+    pub user_mode: bool,
+    /// The linear address translated to a read/write page
+    pub read_write_page: bool,
+    /// The linear address translated to an execute-disable page
+    pub execute_disable_page: bool,
+    /// NMI unblocking due to IRET
+    pub nmi_unblocking: bool,
+}
+
+impl EptViolationQualification {
+    /// Decodes an exit qualification
+    pub fn from_raw(raw: u64) -> EptViolationQualification {
+        let set = |n: u32| raw & (1 << n) != 0;
+        EptViolationQualification {
+            read: set(0),
+            write: set(1),
+            execute: set(2),
+            readable: set(3),
+            writable: set(4),
+            executable: set(5),
+            user_executable: set(6),
+            linear_address_valid: set(7),
+            translated_access: set(8),
+            user_mode: set(9),
+            read_write_page: set(10),
+            execute_disable_page: set(11),
+            nmi_unblocking: set(12),
+        }
+    }
+
+    /// Encodes the exit qualification
+    pub fn to_raw(&self) -> u64 {
+        bit(0, self.read)
+            | bit(1, self.write)
+            | bit(2, self.execute)
+            | bit(3, self.readable)
+            | bit(4, self.writable)
+            | bit(5, self.executable)
+            | bit(6, self.user_executable)
+            | bit(7, self.linear_address_valid)
+            | bit(8, self.translated_access)
+            | bit(9, self.user_mode)
+            | bit(10, self.read_write_page)
+            | bit(11, self.execute_disable_page)
+            | bit(12, self.nmi_unblocking)
+    }
+}
+
+/// Source of a task switch
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TaskSwitchSource {
+    /// CALL
+    Call,
+    /// IRET
+    Iret,
+    /// JMP
+    Jmp,
+    /// Task gate in the IDT
+    TaskGate,
+}
+
+/// Exit qualification of a task switch
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct TaskSwitchQualification {
+    /// Selector of the TSS switched to
+    pub selector: u16,
+    /// Source of the task switch
+    pub source: TaskSwitchSource,
+}
+
+impl TaskSwitchQualification {
+    /// Decodes an exit qualification
+    pub fn from_raw(raw: u64) -> TaskSwitchQualification {
+        TaskSwitchQualification {
+            selector: raw as u16,
+            source: match (raw >> 30) & 0x3 {
+                0 => TaskSwitchSource::Call,
+                1 => TaskSwitchSource::Iret,
+                2 => TaskSwitchSource::Jmp,
+                _ => TaskSwitchSource::TaskGate,
+            },
+        }
+    }
+
+    /// Encodes the exit qualification
+    pub fn to_raw(&self) -> u64 {
+        u64::from(self.selector) | ((self.source as u64) << 30)
+    }
+}
+
+// Returns a bit if it is set
+fn bit(n: u32, set: bool) -> u64 {
+    u64::from(set) << n
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn io_round_trip() {
+        // The size field encodes 1, 2 and 4 bytes as 0, 1 and 3
+        for &(encoded, size) in &[(0, 1), (1, 2), (3, 4)] {
+            let raw = 0x3f8_0000 | encoded;
+            let qual = IoQualification::from_raw(raw);
+            assert_eq!(qual.size, size);
+            assert_eq!(qual.to_raw(), raw);
+        }
+
+        let raw = 0x00cf_0000 | 1 | (1 << 3) | (1 << 4) | (1 << 5) | (1 << 6);
+        let qual = IoQualification::from_raw(raw);
+        assert_eq!(qual, IoQualification {
+            port: 0xcf,
+            size: 2,
+            direction: IoDirection::In,
+            string: true,
+            rep: true,
+            immediate: true,
+        });
+        assert_eq!(qual.to_raw(), raw);
+    }
+
+    #[test]
+    fn cr_access_round_trip() {
+        let mov = CrAccessQualification::from_raw(0x0000_0503);
+        assert_eq!(mov, CrAccessQualification {
+            cr: 3,
+            access: CrAccessType::MovToCr,
+            lmsw_memory: false,
+            gpr: 5,
+            lmsw_source: 0,
+        });
+        assert_eq!(mov.to_raw(), 0x0000_0503);
+
+        for &(bits, access) in &[(0, CrAccessType::MovToCr), (1, CrAccessType::MovFromCr),
+            (2, CrAccessType::Clts), (3, CrAccessType::Lmsw)] {
+            let raw = bits << 4;
+            assert_eq!(CrAccessQualification::from_raw(raw).access, access);
+            assert_eq!(CrAccessQualification::from_raw(raw).to_raw(), raw);
+        }
+
+        let lmsw = CrAccessQualification::from_raw(0x8001_0070);
+        assert_eq!(lmsw.access, CrAccessType::Lmsw);
+        assert!(lmsw.lmsw_memory);
+        assert_eq!(lmsw.lmsw_source, 0x8001);
+        assert_eq!(lmsw.to_raw(), 0x8001_0070);
+    }
+
+    #[test]
+    fn dr_access_round_trip() {
+        let raw = 0x0000_0c17;
+        let qual = DrAccessQualification::from_raw(raw);
+        assert_eq!(qual, DrAccessQualification { dr: 7, access: DrAccessType::MovFromDr, gpr: 12 });
+        assert_eq!(qual.to_raw(), raw);
+
+        let qual = DrAccessQualification { dr: 0, access: DrAccessType::MovToDr, gpr: 1 };
+        assert_eq!(DrAccessQualification::from_raw(qual.to_raw()), qual);
+    }
+
+    #[test]
+    fn ept_violation_round_trip() {
+        for n in 0..13 {
+            let raw = 1 << n;
+            let qual = EptViolationQualification::from_raw(raw);
+            assert_ne!(qual, EptViolationQualification::default());
+            assert_eq!(qual.to_raw(), raw);
+        }
+
+        let qual = EptViolationQualification::from_raw(0x182);
+        assert!(qual.write && qual.linear_address_valid && qual.translated_access);
+        assert!(!qual.read && !qual.execute && !qual.readable);
+        assert_eq!(qual.to_raw(), 0x182);
+    }
+
+    #[test]
+    fn task_switch_round_trip() {
+        // The source is in bits 31:30
+        for &(bits, source) in &[(0, TaskSwitchSource::Call), (1, TaskSwitchSource::Iret),
+            (2, TaskSwitchSource::Jmp), (3, TaskSwitchSource::TaskGate)] {
+            let raw = (bits << 30) | 0x28;
+            let qual = TaskSwitchQualification::from_raw(raw);
+            assert_eq!(qual, TaskSwitchQualification { selector: 0x28, source });
+            assert_eq!(qual.to_raw(), raw);
+        }
+    }
+}
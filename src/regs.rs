@@ -0,0 +1,463 @@
+/*
+Copyright (c) 2016 Saurav Sachidanand
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in
+all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+THE SOFTWARE.
+*/
+
+//! Register state of a vCPU in bulk
+//!
+//! Each struct groups registers that are usually read or written together, and is transferred
+//! with one `vCPU` method call in place of a `read_register` or `write_register` call per
+//! register.
+//!
+//! `Segment` gathers the state of a segment register, which is spread across its selector and
+//! the base, limit and access rights fields of the VMCS.
+
+use consts::vmcs::*;
+use ffi::HV_BAD_ARGUMENT;
+use super::{vCPU, x86Reg, HvError, VmcsField};
+
+// Defines a struct of registers, each transferred as the given x86Reg
+macro_rules! regs {
+    (
+        $(#[$attr:meta])*
+        pub struct $name:ident {
+            $(
+                $(#[$field_attr:meta])*
+                $field:ident: $ty:ident = $reg:ident,
+            )*
+        }
+    ) => {
+        $(#[$attr])*
+        #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+        pub struct $name {
+            $(
+                $(#[$field_attr])*
+                pub $field: $ty,
+            )*
+        }
+
+        impl $name {
+            pub(crate) fn read(vcpu: &vCPU) -> Result<$name, HvError> {
+                Ok($name {
+                    $( $field: vcpu.read_register(&x86Reg::$reg)? as $ty, )*
+                })
+            }
+
+            pub(crate) fn write(&self, vcpu: &vCPU) -> Result<(), HvError> {
+                $( vcpu.write_register(&x86Reg::$reg, u64::from(self.$field))?; )*
+                Ok(())
+            }
+        }
+    };
+}
+
+regs! {
+    /// General purpose registers, instruction pointer and flags
+    pub struct StandardRegs {
+        /// RAX
+        rax: u64 = RAX,
+        /// RBX
+        rbx: u64 = RBX,
+        /// RCX
+        rcx: u64 = RCX,
+        /// RDX
+        rdx: u64 = RDX,
+        /// RSI
+        rsi: u64 = RSI,
+        /// RDI
+        rdi: u64 = RDI,
+        /// RSP
+        rsp: u64 = RSP,
+        /// RBP
+        rbp: u64 = RBP,
+        /// R8
+        r8: u64 = R8,
+        /// R9
+        r9: u64 = R9,
+        /// R10
+        r10: u64 = R10,
+        /// R11
+        r11: u64 = R11,
+        /// R12
+        r12: u64 = R12,
+        /// R13
+        r13: u64 = R13,
+        /// R14
+        r14: u64 = R14,
+        /// R15
+        r15: u64 = R15,
+        /// RIP
+        rip: u64 = RIP,
+        /// RFLAGS
+        rflags: u64 = RFLAGS,
+    }
+}
+
+regs! {
+    /// Segment selectors
+    pub struct SegmentRegs {
+        /// CS selector
+        cs: u16 = CS,
+        /// SS selector
+        ss: u16 = SS,
+        /// DS selector
+        ds: u16 = DS,
+        /// ES selector
+        es: u16 = ES,
+        /// FS selector
+        fs: u16 = FS,
+        /// GS selector
+        gs: u16 = GS,
+        /// LDTR selector
+        ldtr: u16 = LDTR,
+        /// TR selector
+        tr: u16 = TR,
+    }
+}
+
+regs! {
+    /// Control registers
+    pub struct ControlRegs {
+        /// CR0
+        cr0: u64 = CR0,
+        /// CR2
+        cr2: u64 = CR2,
+        /// CR3
+        cr3: u64 = CR3,
+        /// CR4
+        cr4: u64 = CR4,
+        /// Task priority register (CR8)
+        tpr: u64 = TPR,
+        /// XCR0
+        xcr0: u64 = XCR0,
+    }
+}
+
+regs! {
+    /// Debug registers
+    ///
+    /// DR4 and DR5 are left out, since they alias DR6 and DR7.
+    pub struct DebugRegs {
+        /// DR0
+        dr0: u64 = DR0,
+        /// DR1
+        dr1: u64 = DR1,
+        /// DR2
+        dr2: u64 = DR2,
+        /// DR3
+        dr3: u64 = DR3,
+        /// DR6
+        dr6: u64 = DR6,
+        /// DR7
+        dr7: u64 = DR7,
+    }
+}
+
+regs! {
+    /// Descriptor table registers
+    pub struct DescriptorTables {
+        /// GDTR base
+        gdt_base: u64 = GDT_BASE,
+        /// GDTR limit
+        gdt_limit: u32 = GDT_LIMIT,
+        /// IDTR base
+        idt_base: u64 = IDT_BASE,
+        /// IDTR limit
+        idt_limit: u32 = IDT_LIMIT,
+    }
+}
+
+/// Segment register
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SegReg {
+    /// ES
+    ES,
+    /// CS
+    CS,
+    /// SS
+    SS,
+    /// DS
+    DS,
+    /// FS
+    FS,
+    /// GS
+    GS,
+    /// LDTR
+    LDTR,
+    /// TR
+    TR,
+}
+
+impl SegReg {
+    /// Returns the register holding the selector
+    pub fn register(self) -> x86Reg {
+        match self {
+            SegReg::ES => x86Reg::ES,
+            SegReg::CS => x86Reg::CS,
+            SegReg::SS => x86Reg::SS,
+            SegReg::DS => x86Reg::DS,
+            SegReg::FS => x86Reg::FS,
+            SegReg::GS => x86Reg::GS,
+            SegReg::LDTR => x86Reg::LDTR,
+            SegReg::TR => x86Reg::TR,
+        }
+    }
+
+    /// Returns the VMCS field holding the selector
+    pub fn selector_field(self) -> u32 {
+        match self {
+            SegReg::ES => VMCS_GUEST_ES,
+            SegReg::CS => VMCS_GUEST_CS,
+            SegReg::SS => VMCS_GUEST_SS,
+            SegReg::DS => VMCS_GUEST_DS,
+            SegReg::FS => VMCS_GUEST_FS,
+            SegReg::GS => VMCS_GUEST_GS,
+            SegReg::LDTR => VMCS_GUEST_LDTR,
+            SegReg::TR => VMCS_GUEST_TR,
+        }
+    }
+
+    /// Returns the VMCS field holding the base address
+    pub fn base_field(self) -> u32 {
+        match self {
+            SegReg::ES => VMCS_GUEST_ES_BASE,
+            SegReg::CS => VMCS_GUEST_CS_BASE,
+            SegReg::SS => VMCS_GUEST_SS_BASE,
+            SegReg::DS => VMCS_GUEST_DS_BASE,
+            SegReg::FS => VMCS_GUEST_FS_BASE,
+            SegReg::GS => VMCS_GUEST_GS_BASE,
+            SegReg::LDTR => VMCS_GUEST_LDTR_BASE,
+            SegReg::TR => VMCS_GUEST_TR_BASE,
+        }
+    }
+
+    /// Returns the VMCS field holding the limit
+    pub fn limit_field(self) -> u32 {
+        match self {
+            SegReg::ES => VMCS_GUEST_ES_LIMIT,
+            SegReg::CS => VMCS_GUEST_CS_LIMIT,
+            SegReg::SS => VMCS_GUEST_SS_LIMIT,
+            SegReg::DS => VMCS_GUEST_DS_LIMIT,
+            SegReg::FS => VMCS_GUEST_FS_LIMIT,
+            SegReg::GS => VMCS_GUEST_GS_LIMIT,
+            SegReg::LDTR => VMCS_GUEST_LDTR_LIMIT,
+            SegReg::TR => VMCS_GUEST_TR_LIMIT,
+        }
+    }
+
+    /// Returns the VMCS field holding the access rights
+    pub fn ar_field(self) -> u32 {
+        match self {
+            SegReg::ES => VMCS_GUEST_ES_AR,
+            SegReg::CS => VMCS_GUEST_CS_AR,
+            SegReg::SS => VMCS_GUEST_SS_AR,
+            SegReg::DS => VMCS_GUEST_DS_AR,
+            SegReg::FS => VMCS_GUEST_FS_AR,
+            SegReg::GS => VMCS_GUEST_GS_AR,
+            SegReg::LDTR => VMCS_GUEST_LDTR_AR,
+            SegReg::TR => VMCS_GUEST_TR_AR,
+        }
+    }
+}
+
+const AR_S: u32 = 1 << 4;
+const AR_P: u32 = 1 << 7;
+const AR_AVL: u32 = 1 << 12;
+const AR_L: u32 = 1 << 13;
+const AR_DB: u32 = 1 << 14;
+const AR_G: u32 = 1 << 15;
+const AR_UNUSABLE: u32 = 1 << 16;
+
+/// State of a segment register
+///
+/// The access rights are decoded from the format of the VMCS access rights fields.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Segment {
+    /// Selector
+    pub selector: u16,
+    /// Base address
+    pub base: u64,
+    /// Limit, in bytes
+    pub limit: u32,
+    /// Segment type (4 bits)
+    pub seg_type: u8,
+    /// Code or data segment, as opposed to a system segment
+    pub s: bool,
+    /// Descriptor privilege level (2 bits)
+    pub dpl: u8,
+    /// Present
+    pub present: bool,
+    /// Available for use by system software
+    pub avl: bool,
+    /// 64-bit code segment
+    pub l: bool,
+    /// Default operation size or upper bound (D/B)
+    pub db: bool,
+    /// Granularity
+    pub g: bool,
+    /// Unusable
+    pub unusable: bool,
+}
+
+impl Segment {
+    /// Returns a segment with the given selector, base and limit, and decoded access rights
+    pub fn from_ar(selector: u16, base: u64, limit: u32, ar: u32) -> Segment {
+        Segment {
+            selector,
+            base,
+            limit,
+            seg_type: (ar & 0xf) as u8,
+            s: ar & AR_S != 0,
+            dpl: ((ar >> 5) & 0x3) as u8,
+            present: ar & AR_P != 0,
+            avl: ar & AR_AVL != 0,
+            l: ar & AR_L != 0,
+            db: ar & AR_DB != 0,
+            g: ar & AR_G != 0,
+            unusable: ar & AR_UNUSABLE != 0,
+        }
+    }
+
+    /// Returns a segment marked unusable, as loaded by a null selector
+    pub fn unusable() -> Segment {
+        Segment {
+            unusable: true,
+            ..Segment::default()
+        }
+    }
+
+    /// Returns the access rights in the format of the VMCS access rights fields
+    ///
+    /// Out of range `seg_type` and `dpl` values are truncated.
+    pub fn ar(&self) -> u32 {
+        let mut ar = u32::from(self.seg_type & 0xf) | (u32::from(self.dpl & 0x3) << 5);
+        for &(set, bit) in &[(self.s, AR_S), (self.present, AR_P), (self.avl, AR_AVL),
+            (self.l, AR_L), (self.db, AR_DB), (self.g, AR_G), (self.unusable, AR_UNUSABLE)] {
+            if set {
+                ar |= bit;
+            }
+        }
+        ar
+    }
+
+    pub(crate) fn read(vcpu: &vCPU, seg: SegReg) -> Result<Segment, HvError> {
+        Ok(Segment::from_ar(
+            vcpu.read_register(&seg.register())? as u16,
+            vcpu.read_field(field(seg.base_field()))?,
+            vcpu.read_field(field(seg.limit_field()))? as u32,
+            vcpu.read_field(field(seg.ar_field()))? as u32,
+        ))
+    }
+
+    pub(crate) fn write(&self, vcpu: &vCPU, seg: SegReg) -> Result<(), HvError> {
+        if self.seg_type > 0xf || self.dpl > 0x3 {
+            return Err(
+                HvError::new(HV_BAD_ARGUMENT, "hv_vmx_vcpu_write_vmcs").with_vcpu(vcpu.id())
+            );
+        }
+
+        // A failure partway through puts back the fields already written, so that the segment is
+        // never left half-written
+        let old = Segment::read(vcpu, seg)?;
+        self.write_fields(vcpu, seg).inspect_err(|_| {
+            let _ = old.write_fields(vcpu, seg);
+        })
+    }
+
+    fn write_fields(&self, vcpu: &vCPU, seg: SegReg) -> Result<(), HvError> {
+        vcpu.write_register(&seg.register(), u64::from(self.selector))?;
+        vcpu.write_field(field(seg.base_field()), self.base)?;
+        vcpu.write_field(field(seg.limit_field()), u64::from(self.limit))?;
+        vcpu.write_field(field(seg.ar_field()), u64::from(self.ar()))
+    }
+}
+
+// Returns the field of a segment register's encoding, all of which are known
+fn field(encoding: u32) -> VmcsField {
+    VmcsField::from_encoding(encoding).expect("unknown segment register field")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn access_rights_round_trip() {
+        let code64 = Segment {
+            seg_type: 0xb,
+            s: true,
+            present: true,
+            l: true,
+            ..Segment::default()
+        };
+        let cases = [
+            (0x209b, code64),
+            (0xc0f3, Segment { seg_type: 0x3, s: true, dpl: 3, present: true, db: true, g: true,
+                ..Segment::default() }),
+            (0x108b, Segment { seg_type: 0xb, present: true, avl: true, ..Segment::default() }),
+            (0x1_0000, Segment::unusable()),
+            (0x1_c093, Segment { seg_type: 0x3, s: true, present: true, db: true, g: true,
+                unusable: true, ..Segment::default() }),
+            (0, Segment::default()),
+        ];
+        for &(ar, segment) in &cases {
+            assert_eq!(Segment::from_ar(0, 0, 0, ar), segment, "{:#x}", ar);
+            assert_eq!(segment.ar(), ar, "{:?}", segment);
+        }
+
+        // Reserved bits 8-11 and 17-31 are dropped
+        for &ar in &[0x209b | 0xf00, 0x209b | 0xfffe_0000, 0x209b | 0x500 | 0x8002_0000] {
+            assert_eq!(Segment::from_ar(0, 0, 0, ar), code64, "{:#x}", ar);
+            assert_eq!(Segment::from_ar(0, 0, 0, ar).ar(), 0x209b);
+        }
+
+        // Out of range type and DPL are truncated
+        let segment = Segment { seg_type: 0x1b, dpl: 5, ..Segment::default() };
+        assert_eq!(segment.ar(), 0x2b);
+    }
+
+    #[cfg(feature = "mock")]
+    #[test]
+    fn segment_read_and_write() {
+        use backend::mock;
+        use super::super::VirtualMachine;
+
+        let _lock = mock::lock();
+        let vm = VirtualMachine::new().unwrap();
+        let vcpu = vm.create_vcpu().unwrap();
+        let segment = Segment {
+            selector: 0x10,
+            base: 0x1_0000,
+            limit: 0xffff_ffff,
+            ..Segment::from_ar(0, 0, 0, 0xc093)
+        };
+
+        segment.write(&vcpu, SegReg::DS).unwrap();
+        assert_eq!(mock::vmcs(vcpu.id() as _, VMCS_GUEST_DS_BASE), 0x1_0000);
+        assert_eq!(mock::vmcs(vcpu.id() as _, VMCS_GUEST_DS_LIMIT), 0xffff_ffff);
+        assert_eq!(mock::vmcs(vcpu.id() as _, VMCS_GUEST_DS_AR), 0xc093);
+        assert_eq!(Segment::read(&vcpu, SegReg::DS), Ok(segment));
+
+        // Invalid segments are rejected before anything is written
+        let invalid = Segment { dpl: 4, ..Segment::unusable() };
+        assert!(invalid.write(&vcpu, SegReg::DS).is_err());
+        assert_eq!(Segment::read(&vcpu, SegReg::DS), Ok(segment));
+    }
+}
@@ -0,0 +1,552 @@
+/*
+Copyright (c) 2016 Saurav Sachidanand
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in
+all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+THE SOFTWARE.
+*/
+
+//! Exit-dispatching run loop
+//!
+//! `VcpuRunner` owns a `vCPU` and runs it in a loop. After every VM exit it decodes the exit and
+//! passes it to the matching method of an `ExitHandler`, whose `ExitAction` says whether to
+//! resume the guest, skip the instruction the handler emulated, or stop and return a
+//! `RunOutcome`. Every `ExitHandler` method has a default, so a handler only implements the
+//! exits it cares about.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use super::{vCPU, CrAccessQualification, EptViolationQualification, Event, ExitFields, HvError,
+    InterruptionInfo, InterruptionType, IoDirection, IoQualification, VcpuKicker, VmExit,
+    VmcsField, x86Reg};
+
+const EXC_UD: u8 = 6;
+const EXC_GP: u8 = 13;
+const EXC_PF: u8 = 14;
+
+const BLOCKING_STI: u64 = 1 << 0;
+const BLOCKING_MOV_SS: u64 = 1 << 1;
+
+const PORT_RESET_CONTROL: u16 = 0xcf9;
+const RESET_CONTROL_RST_CPU: u64 = 1 << 2;
+const PORT_KBD_COMMAND: u16 = 0x64;
+const KBD_PULSE_RESET: u64 = 0xfe;
+
+/// Why a `VcpuRunner` stopped running its vCPU
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RunOutcome {
+    /// The guest shut down
+    Shutdown,
+    /// The guest asked to be reset, through the reset control register or the keyboard
+    /// controller
+    Reset,
+    /// The guest halted
+    Halted,
+    /// The runner was paused through a `RunnerHandle`
+    Paused,
+    /// No handler took care of an exit
+    Unhandled(VmExit),
+    /// A call to the hypervisor failed
+    Error(HvError),
+}
+
+/// What a `VcpuRunner` does after an `ExitHandler` has handled an exit
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ExitAction {
+    /// Resumes the guest at the same instruction
+    Resume,
+    /// Resumes the guest after the instruction that caused the exit, which the handler emulated
+    Advance,
+    /// Stops running the vCPU
+    Stop(RunOutcome),
+}
+
+impl From<HvError> for ExitAction {
+    fn from(error: HvError) -> ExitAction {
+        ExitAction::Stop(RunOutcome::Error(error))
+    }
+}
+
+// Turns the result of emulating an instruction into an action
+fn advance(result: Result<(), HvError>) -> ExitAction {
+    match result {
+        Ok(()) => ExitAction::Advance,
+        Err(e) => e.into(),
+    }
+}
+
+/// Handles the VM exits of a `VcpuRunner`
+///
+/// Exits without a method of their own go to `other`, which stops the runner with
+/// `RunOutcome::Unhandled`.
+pub trait ExitHandler {
+    /// Handles an I/O instruction
+    ///
+    /// By default, ignores OUT and reads all ones with IN, as from a port with nothing behind
+    /// it. A reset request, setting RST_CPU in the reset control register (port 0xcf9) or
+    /// writing 0xfe to the keyboard controller (port 0x64), stops the runner with
+    /// `RunOutcome::Reset`. String instructions are unhandled.
+    fn io(&mut self, vcpu: &vCPU, exit: &VmExit, qualification: IoQualification) -> ExitAction {
+        if qualification.string {
+            return ExitAction::Stop(RunOutcome::Unhandled(*exit));
+        }
+
+        let rax = match vcpu.read_register(&x86Reg::RAX) {
+            Ok(rax) => rax,
+            Err(e) => return e.into(),
+        };
+        if qualification.direction == IoDirection::Out {
+            return if reset_request(qualification.port, rax) {
+                ExitAction::Stop(RunOutcome::Reset)
+            } else {
+                ExitAction::Advance
+            };
+        }
+
+        // Like any write to EAX, a 4-byte IN clears bits 63:32 of RAX
+        let value = match qualification.size {
+            1 => rax | 0xff,
+            2 => rax | 0xffff,
+            _ => 0xffff_ffff,
+        };
+        advance(vcpu.write_register(&x86Reg::RAX, value))
+    }
+
+    /// Handles an EPT violation, the access of guest-physical memory that isn't mapped or
+    /// doesn't allow the access
+    ///
+    /// Unhandled by default.
+    fn mmio(&mut self, _vcpu: &vCPU, exit: &VmExit, _gpa: u64,
+        _qualification: EptViolationQualification) -> ExitAction {
+        ExitAction::Stop(RunOutcome::Unhandled(*exit))
+    }
+
+    /// Handles CPUID
+    ///
+    /// By default, returns zeros in EAX, EBX, ECX and EDX, as for a leaf the processor doesn't
+    /// support.
+    fn cpuid(&mut self, vcpu: &vCPU) -> ExitAction {
+        advance([x86Reg::RAX, x86Reg::RBX, x86Reg::RCX, x86Reg::RDX].iter()
+            .try_for_each(|reg| vcpu.write_register(reg, 0)))
+    }
+
+    /// Handles RDMSR
+    ///
+    /// By default, injects a general-protection fault, as for an MSR that doesn't exist.
+    fn rdmsr(&mut self, vcpu: &vCPU, _msr: u32) -> ExitAction {
+        vcpu.inject(Event::Exception { vector: EXC_GP, error_code: Some(0) });
+        ExitAction::Resume
+    }
+
+    /// Handles WRMSR
+    ///
+    /// By default, injects a general-protection fault, as for an MSR that doesn't exist.
+    fn wrmsr(&mut self, vcpu: &vCPU, _msr: u32, _value: u64) -> ExitAction {
+        vcpu.inject(Event::Exception { vector: EXC_GP, error_code: Some(0) });
+        ExitAction::Resume
+    }
+
+    /// Handles HLT
+    ///
+    /// By default, skips the instruction and stops the runner with `RunOutcome::Halted`.
+    fn hlt(&mut self, vcpu: &vCPU, exit: &VmExit) -> ExitAction {
+        match skip_instruction(vcpu, exit.instruction_len().unwrap_or(0)) {
+            Ok(()) => ExitAction::Stop(RunOutcome::Halted),
+            Err(e) => e.into(),
+        }
+    }
+
+    /// Handles VMCALL
+    ///
+    /// By default, injects an invalid-opcode exception, as outside VMX operation.
+    fn vmcall(&mut self, vcpu: &vCPU) -> ExitAction {
+        vcpu.inject(Event::Exception { vector: EXC_UD, error_code: None });
+        ExitAction::Resume
+    }
+
+    /// Handles a control-register access
+    ///
+    /// Unhandled by default.
+    fn cr_access(&mut self, _vcpu: &vCPU, exit: &VmExit,
+        _qualification: CrAccessQualification) -> ExitAction {
+        ExitAction::Stop(RunOutcome::Unhandled(*exit))
+    }
+
+    /// Handles an exception or NMI the exception bitmap intercepted
+    ///
+    /// By default, reflects a hardware exception into the guest, setting CR2 for a page fault,
+    /// and re-injects an NMI. Other events are unhandled.
+    fn exception(&mut self, vcpu: &vCPU, exit: &VmExit, info: InterruptionInfo,
+        qualification: u64) -> ExitAction {
+        match info.kind {
+            InterruptionType::HardwareException => {
+                if info.vector == EXC_PF {
+                    if let Err(e) = vcpu.write_register(&x86Reg::CR2, qualification) {
+                        return e.into();
+                    }
+                }
+                vcpu.inject(Event::Exception { vector: info.vector, error_code: info.error_code });
+                ExitAction::Resume
+            }
+            InterruptionType::Nmi => {
+                vcpu.inject(Event::Nmi);
+                ExitAction::Resume
+            }
+            _ => ExitAction::Stop(RunOutcome::Unhandled(*exit)),
+        }
+    }
+
+    /// Handles an external interrupt, including the one `vCPU::interrupt` forces
+    ///
+    /// Resumes the guest by default.
+    fn external_interrupt(&mut self, _vcpu: &vCPU) -> ExitAction {
+        ExitAction::Resume
+    }
+
+    /// Handles the opening of an interrupt or NMI window
+    ///
+    /// Resumes the guest by default, which injects the events waiting for the window.
+    fn window(&mut self, _vcpu: &vCPU) -> ExitAction {
+        ExitAction::Resume
+    }
+
+    /// Handles the expiry of the VMX-preemption timer
+    ///
+    /// Resumes the guest by default.
+    fn preemption_timer(&mut self, _vcpu: &vCPU) -> ExitAction {
+        ExitAction::Resume
+    }
+
+    /// Handles a triple fault
+    ///
+    /// Stops the runner with `RunOutcome::Shutdown` by default.
+    fn triple_fault(&mut self, _vcpu: &vCPU) -> ExitAction {
+        ExitAction::Stop(RunOutcome::Shutdown)
+    }
+
+    /// Handles every other exit
+    ///
+    /// Unhandled by default.
+    fn other(&mut self, _vcpu: &vCPU, exit: &VmExit) -> ExitAction {
+        ExitAction::Stop(RunOutcome::Unhandled(*exit))
+    }
+}
+
+/// Passes an exit to the matching method of a handler
+pub fn dispatch<H: ExitHandler + ?Sized>(handler: &mut H, vcpu: &vCPU, exit: &VmExit)
+    -> ExitAction {
+    match *exit {
+        VmExit::Io { qualification, .. } => handler.io(vcpu, exit, qualification),
+        VmExit::EptViolation { qualification, guest_physical_address, .. } =>
+            handler.mmio(vcpu, exit, guest_physical_address, qualification),
+        VmExit::Cpuid { .. } => handler.cpuid(vcpu),
+        VmExit::Rdmsr { .. } => match vcpu.read_register(&x86Reg::RCX) {
+            Ok(rcx) => handler.rdmsr(vcpu, rcx as u32),
+            Err(e) => e.into(),
+        },
+        VmExit::Wrmsr { .. } => match read_wrmsr(vcpu) {
+            Ok((msr, value)) => handler.wrmsr(vcpu, msr, value),
+            Err(e) => e.into(),
+        },
+        VmExit::Hlt { .. } => handler.hlt(vcpu, exit),
+        VmExit::Vmcall { .. } => handler.vmcall(vcpu),
+        VmExit::CrAccess { qualification, .. } => handler.cr_access(vcpu, exit, qualification),
+        VmExit::ExceptionNmi { info, qualification, .. } =>
+            handler.exception(vcpu, exit, info, qualification),
+        VmExit::ExternalInterrupt { .. } => handler.external_interrupt(vcpu),
+        VmExit::InterruptWindow | VmExit::NmiWindow => handler.window(vcpu),
+        VmExit::PreemptionTimer => handler.preemption_timer(vcpu),
+        VmExit::TripleFault { .. } => handler.triple_fault(vcpu),
+        _ => handler.other(vcpu, exit),
+    }
+}
+
+// Returns whether an OUT asks for the machine to be reset
+fn reset_request(port: u16, value: u64) -> bool {
+    match port {
+        PORT_RESET_CONTROL => value & RESET_CONTROL_RST_CPU != 0,
+        PORT_KBD_COMMAND => value & 0xff == KBD_PULSE_RESET,
+        _ => false,
+    }
+}
+
+// Returns the MSR and value of WRMSR, from ECX and EDX:EAX
+fn read_wrmsr(vcpu: &vCPU) -> Result<(u32, u64), HvError> {
+    let msr = vcpu.read_register(&x86Reg::RCX)? as u32;
+    let low = vcpu.read_register(&x86Reg::RAX)? & 0xffff_ffff;
+    let high = vcpu.read_register(&x86Reg::RDX)? & 0xffff_ffff;
+    Ok((msr, high << 32 | low))
+}
+
+/// Moves RIP past the instruction that caused the last VM exit, given its VM-exit instruction
+/// length, and ends blocking by STI or MOV SS as executing it would
+pub fn skip_instruction(vcpu: &vCPU, instruction_len: u32) -> Result<(), HvError> {
+    let rip = vcpu.read_field(VmcsField::GuestRip)?;
+    vcpu.write_field(VmcsField::GuestRip, rip.wrapping_add(u64::from(instruction_len)))?;
+
+    let interruptibility = vcpu.read_field(VmcsField::GuestIgnoreIrq)?;
+    if interruptibility & (BLOCKING_STI | BLOCKING_MOV_SS) != 0 {
+        vcpu.write_field(VmcsField::GuestIgnoreIrq,
+            interruptibility & !(BLOCKING_STI | BLOCKING_MOV_SS))?;
+    }
+    Ok(())
+}
+
+/// Handle that pauses a `VcpuRunner` from any thread
+#[derive(Clone, Debug)]
+pub struct RunnerHandle {
+    kicker: VcpuKicker,
+    paused: Arc<AtomicBool>,
+}
+
+impl RunnerHandle {
+    /// Asks the runner to stop with `RunOutcome::Paused`, forcing a VM exit if the vCPU is
+    /// running
+    ///
+    /// The runner stays paused, returning straight away from `run`, until `resume` is called.
+    pub fn pause(&self) -> Result<(), HvError> {
+        self.request_pause();
+        self.kicker.interrupt()
+    }
+
+    /// Asks the runner to stop with `RunOutcome::Paused` after the next VM exit, without forcing
+    /// one
+    pub fn request_pause(&self) {
+        self.paused.store(true, Ordering::SeqCst);
+    }
+
+    /// Lets the runner run again
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::SeqCst);
+    }
+
+    /// Returns whether the runner is asked to pause
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// Returns a handle that interrupts the vCPU without pausing the runner
+    pub fn kicker(&self) -> &VcpuKicker {
+        &self.kicker
+    }
+}
+
+/// Runs a vCPU, dispatching its VM exits to an `ExitHandler`
+#[derive(Debug)]
+pub struct VcpuRunner<'a> {
+    vcpu: vCPU<'a>,
+    paused: Arc<AtomicBool>,
+}
+
+impl<'a> VcpuRunner<'a> {
+    /// Creates a runner for a vCPU
+    pub fn new(vcpu: vCPU<'a>) -> VcpuRunner<'a> {
+        VcpuRunner { vcpu, paused: Arc::new(AtomicBool::new(false)) }
+    }
+
+    /// Returns the vCPU
+    pub fn vcpu(&self) -> &vCPU<'a> {
+        &self.vcpu
+    }
+
+    /// Returns the vCPU, giving up the runner
+    pub fn into_vcpu(self) -> vCPU<'a> {
+        self.vcpu
+    }
+
+    /// Returns a handle that pauses the runner from other threads
+    pub fn handle(&self) -> RunnerHandle {
+        RunnerHandle { kicker: self.vcpu.kicker(), paused: self.paused.clone() }
+    }
+
+    /// Runs the vCPU until a handler stops it, the runner is paused or a call fails
+    pub fn run<H: ExitHandler + ?Sized>(&mut self, handler: &mut H) -> RunOutcome {
+        loop {
+            if let Some(outcome) = self.step(handler) {
+                return outcome;
+            }
+        }
+    }
+
+    /// Runs the vCPU until one VM exit and handles it, returning the outcome if the runner
+    /// should stop
+    pub fn step<H: ExitHandler + ?Sized>(&mut self, handler: &mut H) -> Option<RunOutcome> {
+        if self.paused.load(Ordering::SeqCst) {
+            return Some(RunOutcome::Paused);
+        }
+
+        // The exit fields are read once, and the instruction length is reused to advance RIP
+        let fields = match self.vcpu.run().and_then(|_| ExitFields::read(&self.vcpu)) {
+            Ok(fields) => fields,
+            Err(e) => return Some(RunOutcome::Error(e)),
+        };
+        let exit = VmExit::decode(&fields);
+        let outcome = match dispatch(handler, &self.vcpu, &exit) {
+            ExitAction::Resume => None,
+            ExitAction::Advance => skip_instruction(&self.vcpu, fields.instruction_len).err()
+                .map(RunOutcome::Error),
+            ExitAction::Stop(outcome) => Some(outcome),
+        };
+
+        match outcome {
+            None if self.paused.load(Ordering::SeqCst) => Some(RunOutcome::Paused),
+            outcome => outcome,
+        }
+    }
+}
+
+#[cfg(all(test, feature = "mock"))]
+mod tests {
+    use backend::mock::{self, MockExit};
+    use consts::vmcs::*;
+    use consts::vmx_exit::*;
+    use super::super::VirtualMachine;
+    use super::*;
+
+    // Handler with every default
+    struct Defaults;
+
+    impl ExitHandler for Defaults {}
+
+    fn exit(reason: u64, instruction_len: u64) -> MockExit {
+        MockExit::new(reason).field(VMCS_RO_VMEXIT_INSTR_LEN, instruction_len)
+    }
+
+    fn io(port: u64, size: u64, direction_in: bool) -> MockExit {
+        let size = match size {
+            1 => 0,
+            2 => 1,
+            _ => 3,
+        };
+        exit(VMX_REASON_IO, 2).qualification(port << 16 | (direction_in as u64) << 3 | size)
+    }
+
+    fn push(runner: &VcpuRunner, exit: MockExit) {
+        mock::push_exit(runner.vcpu().id(), exit);
+    }
+
+    #[test]
+    fn advance_skips_the_instruction_and_ends_blocking() {
+        let _lock = mock::lock();
+        let vm = VirtualMachine::new().unwrap();
+        let mut runner = VcpuRunner::new(vm.create_vcpu().unwrap());
+        let vcpu_id = runner.vcpu().id();
+        runner.vcpu().write_field(VmcsField::GuestRip, 0x100).unwrap();
+        runner.vcpu().write_register(&x86Reg::RBX, 0x1234).unwrap();
+
+        // CPUID after STI, with blocking by NMI as well
+        runner.vcpu().write_field(VmcsField::GuestIgnoreIrq, BLOCKING_STI | 0x8).unwrap();
+        push(&runner, exit(VMX_REASON_CPUID, 2));
+        assert_eq!(runner.step(&mut Defaults), None);
+        assert_eq!(runner.vcpu().read_field(VmcsField::GuestRip).unwrap(), 0x102);
+        assert_eq!(runner.vcpu().read_field(VmcsField::GuestIgnoreIrq).unwrap(), 0x8);
+        assert_eq!(mock::register(vcpu_id, x86Reg::RBX), 0);
+
+        // An exit after MOV SS, with a longer instruction
+        runner.vcpu().write_field(VmcsField::GuestIgnoreIrq, BLOCKING_MOV_SS).unwrap();
+        push(&runner, io(0x80, 1, false).field(VMCS_RO_VMEXIT_INSTR_LEN, 3));
+        assert_eq!(runner.step(&mut Defaults), None);
+        assert_eq!(runner.vcpu().read_field(VmcsField::GuestRip).unwrap(), 0x105);
+        assert_eq!(runner.vcpu().read_field(VmcsField::GuestIgnoreIrq).unwrap(), 0);
+    }
+
+    #[test]
+    fn in_reads_all_ones() {
+        let _lock = mock::lock();
+        let vm = VirtualMachine::new().unwrap();
+        let mut runner = VcpuRunner::new(vm.create_vcpu().unwrap());
+        let rax = 0x1234_5678_9abc_def0;
+
+        for &(size, expected) in &[(1, 0x1234_5678_9abc_deff), (2, 0x1234_5678_9abc_ffff),
+            (4, 0xffff_ffff)] {
+            runner.vcpu().write_register(&x86Reg::RAX, rax).unwrap();
+            push(&runner, io(0x3f8, size, true));
+            assert_eq!(runner.step(&mut Defaults), None);
+            assert_eq!(runner.vcpu().read_register(&x86Reg::RAX).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn reset_requests() {
+        let _lock = mock::lock();
+        let vm = VirtualMachine::new().unwrap();
+        let mut runner = VcpuRunner::new(vm.create_vcpu().unwrap());
+
+        let cases = [
+            (0xcf9, 0x6, Some(RunOutcome::Reset)),
+            (0xcf9, 0x2, None),
+            (0x64, 0xfe, Some(RunOutcome::Reset)),
+            (0x64, 0xd1, None),
+            (0x80, 0xfe, None),
+        ];
+        for &(port, value, ref outcome) in &cases {
+            runner.vcpu().write_register(&x86Reg::RAX, value).unwrap();
+            push(&runner, io(port, 1, false));
+            assert_eq!(runner.step(&mut Defaults), *outcome, "{:#x} to port {:#x}", value, port);
+        }
+    }
+
+    #[test]
+    fn hlt_halts() {
+        let _lock = mock::lock();
+        let vm = VirtualMachine::new().unwrap();
+        let mut runner = VcpuRunner::new(vm.create_vcpu().unwrap());
+        runner.vcpu().write_field(VmcsField::GuestRip, 0x100).unwrap();
+
+        push(&runner, exit(VMX_REASON_HLT, 1));
+        assert_eq!(runner.run(&mut Defaults), RunOutcome::Halted);
+        assert_eq!(runner.vcpu().read_field(VmcsField::GuestRip).unwrap(), 0x101);
+    }
+
+    #[test]
+    fn rdmsr_injects_gp() {
+        let _lock = mock::lock();
+        let vm = VirtualMachine::new().unwrap();
+        let mut runner = VcpuRunner::new(vm.create_vcpu().unwrap());
+        runner.vcpu().write_field(VmcsField::GuestRip, 0x100).unwrap();
+        runner.vcpu().write_register(&x86Reg::RCX, 0x3a).unwrap();
+
+        push(&runner, exit(VMX_REASON_RDMSR, 2));
+        assert_eq!(runner.step(&mut Defaults), None);
+        assert_eq!(runner.vcpu().pending_events(),
+            vec![Event::Exception { vector: EXC_GP, error_code: Some(0) }]);
+        assert_eq!(runner.vcpu().read_field(VmcsField::GuestRip).unwrap(), 0x100);
+    }
+
+    #[test]
+    fn pause_stops_the_runner() {
+        let _lock = mock::lock();
+        let vm = VirtualMachine::new().unwrap();
+        let mut runner = VcpuRunner::new(vm.create_vcpu().unwrap());
+        let handle = runner.handle();
+
+        handle.pause().unwrap();
+        assert!(handle.is_paused());
+        assert_eq!(runner.run(&mut Defaults), RunOutcome::Paused);
+        assert_eq!(mock::pending_exits(runner.vcpu().id()), 0);
+
+        // A pause requested while the vCPU runs takes effect after the exit
+        handle.resume();
+        push(&runner, exit(VMX_REASON_CPUID, 2));
+        handle.request_pause();
+        assert_eq!(runner.step(&mut Defaults), Some(RunOutcome::Paused));
+        handle.resume();
+        push(&runner, exit(VMX_REASON_CPUID, 2));
+        assert_eq!(runner.step(&mut Defaults), None);
+    }
+}
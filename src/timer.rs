@@ -0,0 +1,235 @@
+/*
+Copyright (c) 2016 Saurav Sachidanand
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in
+all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+THE SOFTWARE.
+*/
+
+//! VMX-preemption timer
+//!
+//! While the guest runs, the VMX-preemption timer counts down once every 2^rate TSC cycles, and
+//! the VM exits when it reaches zero. `PreemptionTimer` converts between wall-clock time and
+//! timer ticks for a rate and TSC frequency, and `vCPU::run_for` uses it to bound how long the
+//! guest runs.
+
+use std::cmp;
+use std::time::Duration;
+
+use consts::vmx_cap::{PIN_BASED_PREEMPTION_TIMER, VMEXIT_SAVE_VMX_TIMER};
+use ffi::HV_UNSUPPORTED;
+use super::{CapabilityReport, ControlField, HvError};
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+/// Largest value of the VMX-preemption timer
+pub const MAX_TICKS: u64 = 0xffff_ffff;
+
+/// Rate and TSC frequency of the VMX-preemption timer
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct PreemptionTimer {
+    rate: u32,
+    tsc_frequency: u64,
+}
+
+impl PreemptionTimer {
+    /// Creates a timer that counts down once every 2^`rate` cycles of a TSC running at
+    /// `tsc_frequency` Hz
+    ///
+    /// # Panics
+    ///
+    /// Panics if `rate` is above 31 or `tsc_frequency` is zero.
+    pub fn new(rate: u32, tsc_frequency: u64) -> PreemptionTimer {
+        assert!(rate < 32, "preemption timer rate out of range");
+        assert!(tsc_frequency != 0, "TSC frequency is zero");
+
+        PreemptionTimer {
+            rate,
+            tsc_frequency,
+        }
+    }
+
+    /// Returns the timer of a host whose capabilities are in `report`, or `None` if the host
+    /// doesn't allow activating the timer and saving its value on VM exit
+    pub fn from_report(report: &CapabilityReport, tsc_frequency: u64) -> Option<PreemptionTimer> {
+        if report.supports(ControlField::PinBased, PIN_BASED_PREEMPTION_TIMER)
+            && report.supports(ControlField::Exit, VMEXIT_SAVE_VMX_TIMER) {
+            Some(PreemptionTimer::new(report.preemption_timer_rate(), tsc_frequency))
+        } else {
+            None
+        }
+    }
+
+    /// Reads the timer of the host processor, whose TSC runs at `tsc_frequency` Hz
+    ///
+    /// Fails with `HV_UNSUPPORTED` if the host doesn't allow activating the timer and saving its
+    /// value on VM exit.
+    pub fn read(tsc_frequency: u64) -> Result<PreemptionTimer, HvError> {
+        PreemptionTimer::from_report(&CapabilityReport::read()?, tsc_frequency)
+            .ok_or_else(|| HvError::new(HV_UNSUPPORTED, "hv_vmx_read_capability"))
+    }
+
+    /// Returns the rate of the timer
+    pub fn rate(&self) -> u32 {
+        self.rate
+    }
+
+    /// Returns the frequency of the TSC in Hz
+    pub fn tsc_frequency(&self) -> u64 {
+        self.tsc_frequency
+    }
+
+    /// Returns the number of whole ticks in `duration`, up to `MAX_TICKS`
+    pub fn ticks(&self, duration: Duration) -> u64 {
+        let cycles = duration.as_nanos() * self.tsc_frequency as u128 / NANOS_PER_SEC;
+        cmp::min(cycles >> self.rate, MAX_TICKS as u128) as u64
+    }
+
+    /// Returns the time `ticks` take
+    pub fn duration(&self, ticks: u64) -> Duration {
+        let nanos = ((ticks as u128) << self.rate) * NANOS_PER_SEC / self.tsc_frequency as u128;
+        Duration::new((nanos / NANOS_PER_SEC) as u64, (nanos % NANOS_PER_SEC) as u32)
+    }
+}
+
+/// Returns the frequency of the TSC of the host in Hz
+#[cfg(target_os = "macos")]
+pub fn host_tsc_frequency() -> Result<u64, HvError> {
+    use std::{mem, ptr};
+    use libc;
+    use ffi::HV_ERROR;
+
+    let mut frequency: u64 = 0;
+    let mut size = mem::size_of::<u64>();
+    let ret = unsafe {
+        libc::sysctlbyname(b"machdep.tsc.frequency\0".as_ptr() as *const libc::c_char,
+            &mut frequency as *mut u64 as *mut libc::c_void, &mut size, ptr::null_mut(), 0)
+    };
+    if ret != 0 || frequency == 0 {
+        return Err(HvError::new(HV_ERROR, "sysctlbyname"));
+    }
+
+    Ok(frequency)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ticks_round_trip() {
+        // At 1 GHz, a tick takes 1 ns at rate 0 and 2^31 ns at rate 31
+        for &rate in &[0, 31] {
+            let timer = PreemptionTimer::new(rate, 1_000_000_000);
+            for &ticks in &[0, 1, 2, 1000, MAX_TICKS] {
+                assert_eq!(timer.ticks(timer.duration(ticks)), ticks, "{} at rate {}", ticks, rate);
+            }
+        }
+        assert_eq!(PreemptionTimer::new(0, 1_000_000_000).duration(3), Duration::from_nanos(3));
+        assert_eq!(PreemptionTimer::new(31, 1_000_000_000).duration(1),
+            Duration::from_nanos(1 << 31));
+
+        // Otherwise a duration holds its whole ticks, and less than one tick more unless they
+        // saturate, give or take the rounding of durations to nanoseconds
+        for &rate in &[0, 31] {
+            let timer = PreemptionTimer::new(rate, 2_399_999_997);
+            let tick = timer.duration(1) + Duration::from_nanos(2);
+            for &nanos in &[0, 1, 417, 999_999_999, 1_000_000_000, 1_234_567_890_123] {
+                let duration = Duration::from_nanos(nanos);
+                let ticks = timer.ticks(duration);
+                let whole = timer.duration(ticks);
+                assert!(whole <= duration, "{:?} at rate {}", duration, rate);
+                assert!(ticks == MAX_TICKS || duration - whole < tick, "{:?} at rate {}",
+                    duration, rate);
+            }
+        }
+    }
+
+    #[test]
+    fn ticks_saturate() {
+        let timer = PreemptionTimer::new(0, 3_000_000_000);
+        assert_eq!(timer.ticks(Duration::from_secs(1)), 3_000_000_000);
+        assert_eq!(timer.ticks(Duration::from_secs(2)), MAX_TICKS);
+        assert_eq!(timer.ticks(Duration::new(u64::MAX, 999_999_999)), MAX_TICKS);
+
+        let timer = PreemptionTimer::new(31, 3_000_000_000);
+        assert_eq!(timer.ticks(Duration::from_secs(3600)), 5029);
+        assert_eq!(timer.ticks(Duration::new(u64::MAX, 999_999_999)), MAX_TICKS);
+        assert!(timer.duration(MAX_TICKS) <= Duration::new(u64::MAX, 999_999_999));
+    }
+
+    #[cfg(feature = "mock")]
+    mod run_for {
+        use backend::mock::{self, MockExit};
+        use consts::vmcs::*;
+        use consts::vmx_exit::VMX_REASON_VMX_TIMER_EXPIRED;
+        use ffi::HV_ERROR;
+        use super::super::super::{VirtualMachine, VmcsField};
+        use super::*;
+
+        const PIN_BASED: u64 = 0x16;
+        const EXIT: u64 = 0x36dff;
+
+        #[test]
+        fn run_for_reports_the_time_left() {
+            let _lock = mock::lock();
+            let vm = VirtualMachine::new().unwrap();
+            let vcpu = vm.create_vcpu().unwrap();
+            let timer = PreemptionTimer::new(0, 1_000_000_000);
+            vcpu.write_field(VmcsField::CtrlPinBased, PIN_BASED).unwrap();
+            vcpu.write_field(VmcsField::CtrlVmexitControls, EXIT).unwrap();
+
+            let exit = MockExit::new(VMX_REASON_VMX_TIMER_EXPIRED)
+                .field(VMCS_GUEST_VMX_TIMER_VALUE, 250);
+            mock::push_exit(vcpu.id() as _, exit);
+            assert_eq!(vcpu.run_for(&timer, Duration::from_nanos(1000)),
+                Ok(Duration::from_nanos(250)));
+            let calls = mock::take_calls();
+            let id = vcpu.id() as _;
+            for &(field, value) in &[(VMCS_CTRL_PIN_BASED, PIN_BASED | PIN_BASED_PREEMPTION_TIMER),
+                (VMCS_CTRL_VMEXIT_CONTROLS, EXIT | VMEXIT_SAVE_VMX_TIMER),
+                (VMCS_GUEST_VMX_TIMER_VALUE, 1000)] {
+                assert!(calls.contains(&mock::Call::WriteVmcs(id, field, value)), "{:#x}", field);
+            }
+            assert_eq!(mock::vmcs(id, VMCS_CTRL_PIN_BASED), PIN_BASED);
+            assert_eq!(mock::vmcs(id, VMCS_CTRL_VMEXIT_CONTROLS), EXIT);
+
+            // A budget past the largest timer value gets the excess back
+            let exit = MockExit::new(VMX_REASON_VMX_TIMER_EXPIRED)
+                .field(VMCS_GUEST_VMX_TIMER_VALUE, 0);
+            mock::push_exit(id, exit);
+            let budget = Duration::from_secs(10);
+            assert_eq!(vcpu.run_for(&timer, budget), Ok(budget - timer.duration(MAX_TICKS)));
+        }
+
+        #[test]
+        fn run_for_restores_the_controls_when_run_fails() {
+            let _lock = mock::lock();
+            let vm = VirtualMachine::new().unwrap();
+            let vcpu = vm.create_vcpu().unwrap();
+            let timer = PreemptionTimer::new(5, 1_000_000_000);
+            vcpu.write_field(VmcsField::CtrlPinBased, PIN_BASED).unwrap();
+            vcpu.write_field(VmcsField::CtrlVmexitControls, EXIT).unwrap();
+
+            mock::push_run_error(vcpu.id() as _, HV_ERROR);
+            let error = vcpu.run_for(&timer, Duration::from_millis(1)).unwrap_err();
+            assert_eq!((error.code(), error.op()), (HV_ERROR, "hv_vcpu_run"));
+            assert_eq!(mock::vmcs(vcpu.id() as _, VMCS_CTRL_PIN_BASED), PIN_BASED);
+            assert_eq!(mock::vmcs(vcpu.id() as _, VMCS_CTRL_VMEXIT_CONTROLS), EXIT);
+        }
+    }
+}
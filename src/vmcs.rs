@@ -0,0 +1,559 @@
+/*
+Copyright (c) 2016 Saurav Sachidanand
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in
+all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+THE SOFTWARE.
+*/
+
+//! Typed Virtual Machine Control Structure (VMCS) fields
+//!
+//! `VmcsField` names every field in `consts::vmcs`, and decodes its width and access class from
+//! its encoding. `VmcsSnapshot` holds the values of the fields of a vCPU, as read by
+//! `vCPU::dump_vmcs`.
+
+use std::collections::BTreeMap;
+use std::error;
+use std::fmt;
+use std::str::FromStr;
+
+use consts::vmcs::*;
+use super::ControlField;
+
+/// Width of a VMCS field
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum VmcsWidth {
+    /// 16 bits
+    Bits16,
+    /// 64 bits
+    Bits64,
+    /// 32 bits
+    Bits32,
+    /// Natural width, 64 bits on a 64-bit host
+    Natural,
+}
+
+impl VmcsWidth {
+    /// Returns the number of bits in a field of this width
+    pub fn bits(self) -> u32 {
+        match self {
+            VmcsWidth::Bits16 => 16,
+            VmcsWidth::Bits32 => 32,
+            VmcsWidth::Bits64 | VmcsWidth::Natural => 64,
+        }
+    }
+
+    /// Returns the largest value a field of this width holds
+    pub fn max_value(self) -> u64 {
+        match self {
+            VmcsWidth::Bits16 => u64::from(u16::MAX),
+            VmcsWidth::Bits32 => u64::from(u32::MAX),
+            VmcsWidth::Bits64 | VmcsWidth::Natural => u64::MAX,
+        }
+    }
+}
+
+/// Access class of a VMCS field
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum VmcsFieldType {
+    /// Control field
+    Control,
+    /// Read-only data field, such as exit information
+    ReadOnly,
+    /// Guest-state field
+    GuestState,
+    /// Host-state field
+    HostState,
+}
+
+// Defines VmcsField from the constant and SDM name of each field
+macro_rules! vmcs_fields {
+    ($( $variant:ident = $field:ident, $name:literal; )*) => {
+        /// Field of the Virtual Machine Control Structure (VMCS)
+        ///
+        /// The discriminant of each field is its encoding, and fields are ordered by encoding.
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+        #[repr(u32)]
+        pub enum VmcsField {
+            $(
+                #[doc = $name]
+                $variant = $field,
+            )*
+        }
+
+        impl VmcsField {
+            /// Every field, in order of encoding
+            pub const ALL: &'static [VmcsField] = &[$( VmcsField::$variant, )*];
+
+            /// Returns the field with an encoding
+            pub fn from_encoding(encoding: u32) -> Option<VmcsField> {
+                match encoding {
+                    $( $field => Some(VmcsField::$variant), )*
+                    _ => None,
+                }
+            }
+
+            /// Returns the name of the field in the Intel SDM
+            pub fn name(self) -> &'static str {
+                match self {
+                    $( VmcsField::$variant => $name, )*
+                }
+            }
+
+            /// Returns the name of the field's constant in `consts::vmcs`
+            pub fn const_name(self) -> &'static str {
+                match self {
+                    $( VmcsField::$variant => stringify!($field), )*
+                }
+            }
+        }
+    };
+}
+
+vmcs_fields! {
+    Vpid = VMCS_VPID, "Virtual-processor identifier";
+    CtrlPostedIntNVector = VMCS_CTRL_POSTED_INT_N_VECTOR, "Posted-interrupt notification vector";
+    CtrlEptpIndex = VMCS_CTRL_EPTP_INDEX, "EPTP index";
+    GuestEs = VMCS_GUEST_ES, "Guest ES selector";
+    GuestCs = VMCS_GUEST_CS, "Guest CS selector";
+    GuestSs = VMCS_GUEST_SS, "Guest SS selector";
+    GuestDs = VMCS_GUEST_DS, "Guest DS selector";
+    GuestFs = VMCS_GUEST_FS, "Guest FS selector";
+    GuestGs = VMCS_GUEST_GS, "Guest GS selector";
+    GuestLdtr = VMCS_GUEST_LDTR, "Guest LDTR selector";
+    GuestTr = VMCS_GUEST_TR, "Guest TR selector";
+    GuestIntStatus = VMCS_GUEST_INT_STATUS, "Guest interrupt status";
+    HostEs = VMCS_HOST_ES, "Host ES selector";
+    HostCs = VMCS_HOST_CS, "Host CS selector";
+    HostSs = VMCS_HOST_SS, "Host SS selector";
+    HostDs = VMCS_HOST_DS, "Host DS selector";
+    HostFs = VMCS_HOST_FS, "Host FS selector";
+    HostGs = VMCS_HOST_GS, "Host GS selector";
+    HostTr = VMCS_HOST_TR, "Host TR selector";
+    CtrlIoBitmapA = VMCS_CTRL_IO_BITMAP_A, "Address of I/O bitmap A";
+    CtrlIoBitmapB = VMCS_CTRL_IO_BITMAP_B, "Address of I/O bitmap B";
+    CtrlMsrBitmaps = VMCS_CTRL_MSR_BITMAPS, "Address of MSR bitmaps";
+    CtrlVmexitMsrStoreAddr = VMCS_CTRL_VMEXIT_MSR_STORE_ADDR, "VM-exit MSR-store address";
+    CtrlVmexitMsrLoadAddr = VMCS_CTRL_VMEXIT_MSR_LOAD_ADDR, "VM-exit MSR-load address";
+    CtrlVmentryMsrLoadAddr = VMCS_CTRL_VMENTRY_MSR_LOAD_ADDR, "VM-entry MSR-load address";
+    CtrlExecutiveVmcsPtr = VMCS_CTRL_EXECUTIVE_VMCS_PTR, "Executive-VMCS pointer";
+    CtrlTscOffset = VMCS_CTRL_TSC_OFFSET, "TSC offset";
+    CtrlVirtualApic = VMCS_CTRL_VIRTUAL_APIC, "Virtual-APIC address";
+    CtrlApicAccess = VMCS_CTRL_APIC_ACCESS, "APIC-access address";
+    CtrlPostedIntDescAddr = VMCS_CTRL_POSTED_INT_DESC_ADDR, "Posted-interrupt descriptor address";
+    CtrlVmfuncCtrl = VMCS_CTRL_VMFUNC_CTRL, "VM-function controls";
+    CtrlEptp = VMCS_CTRL_EPTP, "EPT pointer";
+    CtrlEoiExitBitmap0 = VMCS_CTRL_EOI_EXIT_BITMAP_0, "EOI-exit bitmap 0";
+    CtrlEoiExitBitmap1 = VMCS_CTRL_EOI_EXIT_BITMAP_1, "EOI-exit bitmap 1";
+    CtrlEoiExitBitmap2 = VMCS_CTRL_EOI_EXIT_BITMAP_2, "EOI-exit bitmap 2";
+    CtrlEoiExitBitmap3 = VMCS_CTRL_EOI_EXIT_BITMAP_3, "EOI-exit bitmap 3";
+    CtrlEptpListAddr = VMCS_CTRL_EPTP_LIST_ADDR, "EPTP-list address";
+    CtrlVmreadBitmapAddr = VMCS_CTRL_VMREAD_BITMAP_ADDR, "VMREAD-bitmap address";
+    CtrlVmwriteBitmapAddr = VMCS_CTRL_VMWRITE_BITMAP_ADDR, "VMWRITE-bitmap address";
+    CtrlVirtExcInfoAddr = VMCS_CTRL_VIRT_EXC_INFO_ADDR,
+        "Virtualization-exception information address";
+    CtrlXssExitingBitmap = VMCS_CTRL_XSS_EXITING_BITMAP, "XSS-exiting bitmap";
+    GuestPhysicalAddress = VMCS_GUEST_PHYSICAL_ADDRESS, "Guest-physical address";
+    GuestLinkPointer = VMCS_GUEST_LINK_POINTER, "VMCS link pointer";
+    GuestIa32Debugctl = VMCS_GUEST_IA32_DEBUGCTL, "Guest IA32_DEBUGCTL";
+    GuestIa32Pat = VMCS_GUEST_IA32_PAT, "Guest IA32_PAT";
+    GuestIa32Efer = VMCS_GUEST_IA32_EFER, "Guest IA32_EFER";
+    GuestIa32PerfGlobalCtrl = VMCS_GUEST_IA32_PERF_GLOBAL_CTRL, "Guest IA32_PERF_GLOBAL_CTRL";
+    GuestPdpte0 = VMCS_GUEST_PDPTE0, "Guest PDPTE0";
+    GuestPdpte1 = VMCS_GUEST_PDPTE1, "Guest PDPTE1";
+    GuestPdpte2 = VMCS_GUEST_PDPTE2, "Guest PDPTE2";
+    GuestPdpte3 = VMCS_GUEST_PDPTE3, "Guest PDPTE3";
+    HostIa32Pat = VMCS_HOST_IA32_PAT, "Host IA32_PAT";
+    HostIa32Efer = VMCS_HOST_IA32_EFER, "Host IA32_EFER";
+    HostIa32PerfGlobalCtrl = VMCS_HOST_IA32_PERF_GLOBAL_CTRL, "Host IA32_PERF_GLOBAL_CTRL";
+    CtrlPinBased = VMCS_CTRL_PIN_BASED, "Pin-based VM-execution controls";
+    CtrlCpuBased = VMCS_CTRL_CPU_BASED, "Primary processor-based VM-execution controls";
+    CtrlExcBitmap = VMCS_CTRL_EXC_BITMAP, "Exception bitmap";
+    CtrlPfErrorMask = VMCS_CTRL_PF_ERROR_MASK, "Page-fault error-code mask";
+    CtrlPfErrorMatch = VMCS_CTRL_PF_ERROR_MATCH, "Page-fault error-code match";
+    CtrlCr3Count = VMCS_CTRL_CR3_COUNT, "CR3-target count";
+    CtrlVmexitControls = VMCS_CTRL_VMEXIT_CONTROLS, "VM-exit controls";
+    CtrlVmexitMsrStoreCount = VMCS_CTRL_VMEXIT_MSR_STORE_COUNT, "VM-exit MSR-store count";
+    CtrlVmexitMsrLoadCount = VMCS_CTRL_VMEXIT_MSR_LOAD_COUNT, "VM-exit MSR-load count";
+    CtrlVmentryControls = VMCS_CTRL_VMENTRY_CONTROLS, "VM-entry controls";
+    CtrlVmentryMsrLoadCount = VMCS_CTRL_VMENTRY_MSR_LOAD_COUNT, "VM-entry MSR-load count";
+    CtrlVmentryIrqInfo = VMCS_CTRL_VMENTRY_IRQ_INFO, "VM-entry interruption-information field";
+    CtrlVmentryExcError = VMCS_CTRL_VMENTRY_EXC_ERROR, "VM-entry exception error code";
+    CtrlVmentryInstrLen = VMCS_CTRL_VMENTRY_INSTR_LEN, "VM-entry instruction length";
+    CtrlTprThreshold = VMCS_CTRL_TPR_THRESHOLD, "TPR threshold";
+    CtrlCpuBased2 = VMCS_CTRL_CPU_BASED2, "Secondary processor-based VM-execution controls";
+    CtrlPleGap = VMCS_CTRL_PLE_GAP, "PLE_Gap";
+    CtrlPleWindow = VMCS_CTRL_PLE_WINDOW, "PLE_Window";
+    RoInstrError = VMCS_RO_INSTR_ERROR, "VM-instruction error";
+    RoExitReason = VMCS_RO_EXIT_REASON, "Exit reason";
+    RoVmexitIrqInfo = VMCS_RO_VMEXIT_IRQ_INFO, "VM-exit interruption information";
+    RoVmexitIrqError = VMCS_RO_VMEXIT_IRQ_ERROR, "VM-exit interruption error code";
+    RoIdtVectorInfo = VMCS_RO_IDT_VECTOR_INFO, "IDT-vectoring information field";
+    RoIdtVectorError = VMCS_RO_IDT_VECTOR_ERROR, "IDT-vectoring error code";
+    RoVmexitInstrLen = VMCS_RO_VMEXIT_INSTR_LEN, "VM-exit instruction length";
+    RoVmxInstrInfo = VMCS_RO_VMX_INSTR_INFO, "VM-exit instruction information";
+    GuestEsLimit = VMCS_GUEST_ES_LIMIT, "Guest ES limit";
+    GuestCsLimit = VMCS_GUEST_CS_LIMIT, "Guest CS limit";
+    GuestSsLimit = VMCS_GUEST_SS_LIMIT, "Guest SS limit";
+    GuestDsLimit = VMCS_GUEST_DS_LIMIT, "Guest DS limit";
+    GuestFsLimit = VMCS_GUEST_FS_LIMIT, "Guest FS limit";
+    GuestGsLimit = VMCS_GUEST_GS_LIMIT, "Guest GS limit";
+    GuestLdtrLimit = VMCS_GUEST_LDTR_LIMIT, "Guest LDTR limit";
+    GuestTrLimit = VMCS_GUEST_TR_LIMIT, "Guest TR limit";
+    GuestGdtrLimit = VMCS_GUEST_GDTR_LIMIT, "Guest GDTR limit";
+    GuestIdtrLimit = VMCS_GUEST_IDTR_LIMIT, "Guest IDTR limit";
+    GuestEsAr = VMCS_GUEST_ES_AR, "Guest ES access rights";
+    GuestCsAr = VMCS_GUEST_CS_AR, "Guest CS access rights";
+    GuestSsAr = VMCS_GUEST_SS_AR, "Guest SS access rights";
+    GuestDsAr = VMCS_GUEST_DS_AR, "Guest DS access rights";
+    GuestFsAr = VMCS_GUEST_FS_AR, "Guest FS access rights";
+    GuestGsAr = VMCS_GUEST_GS_AR, "Guest GS access rights";
+    GuestLdtrAr = VMCS_GUEST_LDTR_AR, "Guest LDTR access rights";
+    GuestTrAr = VMCS_GUEST_TR_AR, "Guest TR access rights";
+    GuestIgnoreIrq = VMCS_GUEST_IGNORE_IRQ, "Guest interruptibility state";
+    GuestActivityState = VMCS_GUEST_ACTIVITY_STATE, "Guest activity state";
+    GuestSmbase = VMCS_GUEST_SMBASE, "Guest SMBASE";
+    GuestIa32SysenterCs = VMCS_GUEST_IA32_SYSENTER_CS, "Guest IA32_SYSENTER_CS";
+    GuestVmxTimerValue = VMCS_GUEST_VMX_TIMER_VALUE, "VMX-preemption timer value";
+    HostIa32SysenterCs = VMCS_HOST_IA32_SYSENTER_CS, "Host IA32_SYSENTER_CS";
+    CtrlCr0Mask = VMCS_CTRL_CR0_MASK, "CR0 guest/host mask";
+    CtrlCr4Mask = VMCS_CTRL_CR4_MASK, "CR4 guest/host mask";
+    CtrlCr0Shadow = VMCS_CTRL_CR0_SHADOW, "CR0 read shadow";
+    CtrlCr4Shadow = VMCS_CTRL_CR4_SHADOW, "CR4 read shadow";
+    CtrlCr3Value0 = VMCS_CTRL_CR3_VALUE0, "CR3-target value 0";
+    CtrlCr3Value1 = VMCS_CTRL_CR3_VALUE1, "CR3-target value 1";
+    CtrlCr3Value2 = VMCS_CTRL_CR3_VALUE2, "CR3-target value 2";
+    CtrlCr3Value3 = VMCS_CTRL_CR3_VALUE3, "CR3-target value 3";
+    RoExitQualific = VMCS_RO_EXIT_QUALIFIC, "Exit qualification";
+    RoIoRcx = VMCS_RO_IO_RCX, "I/O RCX";
+    RoIoRsi = VMCS_RO_IO_RSI, "I/O RSI";
+    RoIoRdi = VMCS_RO_IO_RDI, "I/O RDI";
+    RoIoRip = VMCS_RO_IO_RIP, "I/O RIP";
+    RoGuestLinAddr = VMCS_RO_GUEST_LIN_ADDR, "Guest-linear address";
+    GuestCr0 = VMCS_GUEST_CR0, "Guest CR0";
+    GuestCr3 = VMCS_GUEST_CR3, "Guest CR3";
+    GuestCr4 = VMCS_GUEST_CR4, "Guest CR4";
+    GuestEsBase = VMCS_GUEST_ES_BASE, "Guest ES base";
+    GuestCsBase = VMCS_GUEST_CS_BASE, "Guest CS base";
+    GuestSsBase = VMCS_GUEST_SS_BASE, "Guest SS base";
+    GuestDsBase = VMCS_GUEST_DS_BASE, "Guest DS base";
+    GuestFsBase = VMCS_GUEST_FS_BASE, "Guest FS base";
+    GuestGsBase = VMCS_GUEST_GS_BASE, "Guest GS base";
+    GuestLdtrBase = VMCS_GUEST_LDTR_BASE, "Guest LDTR base";
+    GuestTrBase = VMCS_GUEST_TR_BASE, "Guest TR base";
+    GuestGdtrBase = VMCS_GUEST_GDTR_BASE, "Guest GDTR base";
+    GuestIdtrBase = VMCS_GUEST_IDTR_BASE, "Guest IDTR base";
+    GuestDr7 = VMCS_GUEST_DR7, "Guest DR7";
+    GuestRsp = VMCS_GUEST_RSP, "Guest RSP";
+    GuestRip = VMCS_GUEST_RIP, "Guest RIP";
+    GuestRflags = VMCS_GUEST_RFLAGS, "Guest RFLAGS";
+    GuestDebugExc = VMCS_GUEST_DEBUG_EXC, "Guest pending debug exceptions";
+    GuestSysenterEsp = VMCS_GUEST_SYSENTER_ESP, "Guest IA32_SYSENTER_ESP";
+    GuestSysenterEip = VMCS_GUEST_SYSENTER_EIP, "Guest IA32_SYSENTER_EIP";
+    HostCr0 = VMCS_HOST_CR0, "Host CR0";
+    HostCr3 = VMCS_HOST_CR3, "Host CR3";
+    HostCr4 = VMCS_HOST_CR4, "Host CR4";
+    HostFsBase = VMCS_HOST_FS_BASE, "Host FS base";
+    HostGsBase = VMCS_HOST_GS_BASE, "Host GS base";
+    HostTrBase = VMCS_HOST_TR_BASE, "Host TR base";
+    HostGdtrBase = VMCS_HOST_GDTR_BASE, "Host GDTR base";
+    HostIdtrBase = VMCS_HOST_IDTR_BASE, "Host IDTR base";
+    HostIa32SysenterEsp = VMCS_HOST_IA32_SYSENTER_ESP, "Host IA32_SYSENTER_ESP";
+    HostIa32SysenterEip = VMCS_HOST_IA32_SYSENTER_EIP, "Host IA32_SYSENTER_EIP";
+    HostRsp = VMCS_HOST_RSP, "Host RSP";
+    HostRip = VMCS_HOST_RIP, "Host RIP";
+}
+
+impl VmcsField {
+    /// Returns the encoding of the field
+    pub fn encoding(self) -> u32 {
+        self as u32
+    }
+
+    /// Returns the width of the field
+    pub fn width(self) -> VmcsWidth {
+        match (self.encoding() >> 13) & 0x3 {
+            0 => VmcsWidth::Bits16,
+            1 => VmcsWidth::Bits64,
+            2 => VmcsWidth::Bits32,
+            _ => VmcsWidth::Natural,
+        }
+    }
+
+    /// Returns the access class of the field
+    pub fn field_type(self) -> VmcsFieldType {
+        match (self.encoding() >> 10) & 0x3 {
+            0 => VmcsFieldType::Control,
+            1 => VmcsFieldType::ReadOnly,
+            2 => VmcsFieldType::GuestState,
+            _ => VmcsFieldType::HostState,
+        }
+    }
+
+    /// Returns whether the field is read-only
+    pub fn is_read_only(self) -> bool {
+        self.field_type() == VmcsFieldType::ReadOnly
+    }
+}
+
+impl fmt::Display for VmcsField {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Error parsing a `VmcsField`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseVmcsFieldError {
+    name: String,
+}
+
+impl fmt::Display for ParseVmcsFieldError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unknown VMCS field: {}", self.name)
+    }
+}
+
+impl error::Error for ParseVmcsFieldError {}
+
+impl FromStr for VmcsField {
+    type Err = ParseVmcsFieldError;
+
+    /// Parses either the SDM name of a field or the name of its constant, ignoring case
+    fn from_str(s: &str) -> Result<VmcsField, ParseVmcsFieldError> {
+        VmcsField::ALL.iter().cloned()
+            .find(|f| f.name().eq_ignore_ascii_case(s) || f.const_name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| ParseVmcsFieldError { name: s.to_string() })
+    }
+}
+
+/// Values of the VMCS fields of a vCPU at one point in time
+///
+/// The `Display` implementation prints the fields grouped into guest state, host state, controls
+/// and exit information, and names the bits set in the VM-execution, VM-exit and VM-entry
+/// controls.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VmcsSnapshot {
+    fields: BTreeMap<VmcsField, u64>,
+}
+
+impl VmcsSnapshot {
+    /// Creates an empty snapshot
+    pub fn new() -> VmcsSnapshot {
+        VmcsSnapshot::default()
+    }
+
+    /// Returns the value of a field, if it was read
+    pub fn get(&self, field: VmcsField) -> Option<u64> {
+        self.fields.get(&field).cloned()
+    }
+
+    /// Sets the value of a field
+    pub fn insert(&mut self, field: VmcsField, value: u64) {
+        self.fields.insert(field, value);
+    }
+
+    /// Returns the fields and their values, in order of encoding
+    pub fn iter(&self) -> impl Iterator<Item = (VmcsField, u64)> + '_ {
+        self.fields.iter().map(|(&f, &v)| (f, v))
+    }
+
+    /// Returns the number of fields in the snapshot
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    /// Returns whether the snapshot has no fields
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    /// Returns the fields that differ in a later snapshot, in order of encoding
+    pub fn diff(&self, later: &VmcsSnapshot) -> Vec<VmcsChange> {
+        VmcsField::ALL.iter().cloned()
+            .map(|field| VmcsChange { field, old: self.get(field), new: later.get(field) })
+            .filter(|c| c.old != c.new)
+            .collect()
+    }
+}
+
+impl fmt::Display for VmcsSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let groups = [
+            (VmcsFieldType::GuestState, "Guest state"),
+            (VmcsFieldType::HostState, "Host state"),
+            (VmcsFieldType::Control, "Controls"),
+            (VmcsFieldType::ReadOnly, "Exit information"),
+        ];
+        for &(field_type, title) in &groups {
+            writeln!(f, "{}:", title)?;
+            let fields = self.iter().filter(|&(field, _)| field.field_type() == field_type);
+            for (field, value) in fields {
+                write!(f, "  {:<46} {:#x}", field.name(), value)?;
+                if let Some(names) = control_names(field, value) {
+                    write!(f, " [{}]", names)?;
+                }
+                writeln!(f)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Change to a VMCS field between two snapshots
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VmcsChange {
+    /// Field
+    pub field: VmcsField,
+    /// Value in the earlier snapshot, if it was read
+    pub old: Option<u64>,
+    /// Value in the later snapshot, if it was read
+    pub new: Option<u64>,
+}
+
+impl fmt::Display for VmcsChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: ", self.field.name())?;
+        match self.old {
+            Some(v) => write!(f, "{:#x}", v)?,
+            None => write!(f, "-")?,
+        }
+        match self.new {
+            Some(v) => write!(f, " -> {:#x}", v),
+            None => write!(f, " -> -"),
+        }
+    }
+}
+
+// Names the bits set in a control field, leaving any unnamed bits as a number
+fn control_names(field: VmcsField, value: u64) -> Option<String> {
+    let names = ControlField::ALL.iter().find(|c| c.vmcs_field() == field)?.flags();
+
+    let mut parts: Vec<String> = names.iter()
+        .filter(|&&(_, bit)| value & bit != 0)
+        .map(|&(name, _)| name.to_string())
+        .collect();
+    let rest = names.iter().fold(value, |rest, &(_, bit)| rest & !bit);
+    if rest != 0 {
+        parts.push(format!("{:#x}", rest));
+    }
+    Some(parts.join(" | "))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    #[test]
+    fn every_field() {
+        let mut names = HashSet::new();
+        for (i, &field) in VmcsField::ALL.iter().enumerate() {
+            let encoding = field.encoding();
+            // Full access, and no reserved bits
+            assert_eq!(encoding & !0x6ffe, 0, "{}", field.const_name());
+            assert!(i == 0 || VmcsField::ALL[i - 1] < field, "{} out of order", field.const_name());
+            assert_eq!(VmcsField::from_encoding(encoding), Some(field));
+
+            assert_eq!(field.width().bits(), [16, 64, 32, 64][(encoding >> 13) as usize & 3]);
+            let prefix = field.const_name().split('_').nth(1).unwrap();
+            let field_type = match (field, prefix) {
+                (VmcsField::GuestPhysicalAddress, _) | (_, "RO") => VmcsFieldType::ReadOnly,
+                (VmcsField::Vpid, _) | (_, "CTRL") => VmcsFieldType::Control,
+                (_, "GUEST") => VmcsFieldType::GuestState,
+                (_, "HOST") => VmcsFieldType::HostState,
+                _ => panic!("{}", field.const_name()),
+            };
+            assert_eq!(field.field_type(), field_type, "{}", field.const_name());
+            assert_eq!(field.is_read_only(), field_type == VmcsFieldType::ReadOnly);
+
+            assert_eq!(field.to_string(), field.name());
+            for name in &[field.name().to_string(), field.name().to_uppercase(),
+                field.const_name().to_string(), field.const_name().to_lowercase()] {
+                assert_eq!(name.parse(), Ok(field));
+            }
+            assert!(names.insert(field.name().to_lowercase()), "{} named twice", field.name());
+        }
+
+        let widths = [
+            (VmcsField::GuestCs, VmcsWidth::Bits16),
+            (VmcsField::CtrlEptp, VmcsWidth::Bits64),
+            (VmcsField::GuestPhysicalAddress, VmcsWidth::Bits64),
+            (VmcsField::CtrlPinBased, VmcsWidth::Bits32),
+            (VmcsField::RoExitReason, VmcsWidth::Bits32),
+            (VmcsField::GuestRip, VmcsWidth::Natural),
+            (VmcsField::HostRsp, VmcsWidth::Natural),
+        ];
+        for &(field, width) in &widths {
+            assert_eq!(field.width(), width, "{}", field);
+        }
+
+        assert_eq!(VmcsField::from_encoding(0x6c01), None);
+        assert_eq!("Guest RIPs".parse::<VmcsField>(),
+            Err(ParseVmcsFieldError { name: "Guest RIPs".to_string() }));
+    }
+
+    #[test]
+    fn snapshot_diff_and_display() {
+        let mut earlier = VmcsSnapshot::new();
+        earlier.insert(VmcsField::GuestRip, 0xfff0);
+        earlier.insert(VmcsField::GuestCs, 0xf000);
+        earlier.insert(VmcsField::HostRsp, 0x1000);
+        earlier.insert(VmcsField::CtrlPinBased, 0x16);
+        let mut later = earlier.clone();
+        later.insert(VmcsField::GuestRip, 0xfff2);
+        later.insert(VmcsField::CtrlPinBased, 0x5f);
+        later.insert(VmcsField::CtrlExcBitmap, 0x4000);
+        later.insert(VmcsField::RoExitReason, 0xc);
+        later.fields.remove(&VmcsField::HostRsp);
+
+        // Changes come in order of encoding
+        let changes = earlier.diff(&later);
+        let change = |field, old, new| VmcsChange { field, old, new };
+        assert_eq!(changes, vec![
+            change(VmcsField::CtrlPinBased, Some(0x16), Some(0x5f)),
+            change(VmcsField::CtrlExcBitmap, None, Some(0x4000)),
+            change(VmcsField::RoExitReason, None, Some(0xc)),
+            change(VmcsField::GuestRip, Some(0xfff0), Some(0xfff2)),
+            change(VmcsField::HostRsp, Some(0x1000), None),
+        ]);
+        let changes: Vec<_> = changes.iter().map(|c| c.to_string()).collect();
+        assert_eq!(changes, [
+            "Pin-based VM-execution controls: 0x16 -> 0x5f",
+            "Exception bitmap: - -> 0x4000",
+            "Exit reason: - -> 0xc",
+            "Guest RIP: 0xfff0 -> 0xfff2",
+            "Host RSP: 0x1000 -> -",
+        ]);
+        assert!(later.diff(&later).is_empty());
+
+        // Fields are grouped by type, and named bits of the controls are listed
+        assert_eq!(later.to_string(), [
+            "Guest state:\n",
+            "  Guest CS selector                              0xf000\n",
+            "  Guest RIP                                      0xfff2\n",
+            "Host state:\n",
+            "Controls:\n",
+            "  Pin-based VM-execution controls                0x5f [INTR | NMI | ",
+            "PREEMPTION_TIMER | 0x16]\n",
+            "  Exception bitmap                               0x4000\n",
+            "Exit information:\n",
+            "  Exit reason                                    0xc\n",
+        ].concat());
+    }
+}